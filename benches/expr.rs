@@ -0,0 +1,95 @@
+//! Compares incremental eval context updates against rebuilding the whole
+//! context, run with `cargo bench --bench expr`.
+
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion};
+use homectl_server::core::{expr::Expr, groups::Groups, scenes::Scenes};
+use homectl_server::types::{
+    color::Capabilities,
+    device::{ControllableDevice, Device, DeviceData, DeviceId, DevicesState, ManageKind},
+    group::GroupsConfig,
+    integration::IntegrationId,
+};
+
+const DEVICE_COUNT: usize = 300;
+
+fn mk_device(i: usize, power: bool) -> Device {
+    Device::new(
+        IntegrationId::from_str("bench").unwrap(),
+        DeviceId::new(&format!("{i}")),
+        format!("Light {i}"),
+        DeviceData::Controllable(ControllableDevice::new(
+            None,
+            power,
+            Some(0.5),
+            None,
+            None,
+            Capabilities::default(),
+            ManageKind::Full,
+        )),
+        Some(serde_json::json!({ "linkquality": i, "update": { "state": "idle" } })),
+    )
+}
+
+fn mk_devices_state() -> DevicesState {
+    let devices = (0..DEVICE_COUNT).map(|i| {
+        let device = mk_device(i, true);
+        (device.get_device_key(), device)
+    });
+
+    DevicesState(devices.collect::<BTreeMap<_, _>>())
+}
+
+fn device_update(c: &mut Criterion) {
+    let groups = Groups::new(GroupsConfig::new());
+    let scenes = Scenes::default();
+    let mut state = mk_devices_state();
+
+    let mut expr = Expr::new();
+    expr.invalidate(&state, &groups, &scenes);
+
+    let mut group = c.benchmark_group(format!("device update ({DEVICE_COUNT} devices)"));
+
+    group.bench_function("full rebuild", |b| {
+        b.iter(|| expr.invalidate(black_box(&state), &groups, &scenes))
+    });
+
+    let mut power = false;
+    group.bench_function("incremental", |b| {
+        b.iter(|| {
+            let old = mk_device(0, !power);
+            let new = mk_device(0, power);
+            state.0.insert(new.get_device_key(), new.clone());
+            expr.invalidate_device(&Some(old), black_box(&new), &state, &groups, &scenes);
+            power = !power;
+        })
+    });
+
+    // Removing variables, here the raw fields of a device, falls back to
+    // copying the remaining variables into a cleared context
+    let old = mk_device(0, true);
+    let mut new = old.clone();
+    new.raw = None;
+    state.0.insert(old.get_device_key(), old.clone());
+    expr.invalidate(&state, &groups, &scenes);
+    let mut removed_state = state.clone();
+    removed_state.0.insert(new.get_device_key(), new.clone());
+
+    group.bench_function("incremental with removed variables", |b| {
+        b.iter_batched_ref(
+            || expr.clone(),
+            |expr| {
+                let old = Some(old.clone());
+                expr.invalidate_device(&old, black_box(&new), &removed_state, &groups, &scenes)
+            },
+            BatchSize::LargeInput,
+        )
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, device_update);
+criterion_main!(benches);
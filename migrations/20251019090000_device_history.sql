@@ -0,0 +1,11 @@
+create table device_history (
+  id bigserial primary key not null,
+
+  integration_id text not null,
+  device_id text not null,
+  state jsonb not null,
+
+  recorded_at timestamptz not null default now()
+);
+
+create index device_history_device_idx on device_history (integration_id, device_id, recorded_at);
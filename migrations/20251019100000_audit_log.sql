@@ -0,0 +1,10 @@
+create table audit_log (
+  id bigserial primary key not null,
+
+  event jsonb not null,
+  origin jsonb not null,
+
+  recorded_at timestamptz not null default now()
+);
+
+create index audit_log_recorded_at_idx on audit_log (recorded_at);
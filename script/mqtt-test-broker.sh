@@ -0,0 +1,41 @@
+#! /bin/bash
+
+# Starts a Mosquitto broker for the MQTT integration tests, with listeners for
+# plain TCP (1883), WebSocket (8080) and TLS (8883) connections. Run the tests
+# with:
+#
+# MQTT_TEST_CA_FILE=target/mqtt-test-broker/ca.crt cargo test mqtt -- --ignored
+
+set -e
+
+DIR="$PWD/target/mqtt-test-broker"
+mkdir -p "$DIR"
+cd "$DIR"
+
+openssl req -x509 -newkey rsa:2048 -nodes -days 1 -subj "/CN=homectl test CA" \
+  -keyout ca.key -out ca.crt
+openssl req -newkey rsa:2048 -nodes -subj "/CN=localhost" \
+  -keyout server.key -out server.csr
+openssl x509 -req -days 1 -in server.csr -CA ca.crt -CAkey ca.key -CAcreateserial \
+  -extfile <(echo "subjectAltName=DNS:localhost,IP:127.0.0.1") -out server.crt
+chmod 644 server.key
+
+cat > mosquitto.conf <<CONF
+allow_anonymous true
+
+listener 1883
+
+listener 8080
+protocol websockets
+
+listener 8883
+cafile /mosquitto/certs/ca.crt
+certfile /mosquitto/certs/server.crt
+keyfile /mosquitto/certs/server.key
+CONF
+
+docker run -d --rm --name homectl-test-mosquitto \
+  -p 1883:1883 -p 8080:8080 -p 8883:8883 \
+  -v "$DIR/mosquitto.conf:/mosquitto/config/mosquitto.conf:ro" \
+  -v "$DIR:/mosquitto/certs:ro" \
+  eclipse-mosquitto:2
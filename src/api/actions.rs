@@ -1,7 +1,10 @@
-use std::sync::Arc;
+use std::{net::SocketAddr, sync::Arc};
 
 use crate::core::state::AppState;
-use crate::types::{action::Action, event::Event};
+use crate::types::{
+    action::Action,
+    event::{Event, EventOrigin},
+};
 use tokio::sync::RwLock;
 use warp::Filter;
 
@@ -19,17 +22,22 @@ fn post_action(
     warp::path("trigger")
         .and(warp::post())
         .and(warp::body::json())
+        .and(warp::addr::remote())
         .and(with_state(app_state))
         .and_then(post_action_impl)
 }
 
 async fn post_action_impl(
     action: Action,
+    remote_addr: Option<SocketAddr>,
     app_state: Arc<RwLock<AppState>>,
 ) -> Result<impl warp::Reply, warp::Rejection> {
     let app_state = app_state.read().await;
     let sender = app_state.event_tx.clone();
-    sender.send(Event::Action(action));
+    let origin = EventOrigin::Api {
+        remote_addr: remote_addr.map(|addr| addr.to_string()),
+    };
+    sender.send_with_origin(Event::Action(action), origin);
 
     Ok(warp::reply::json(&()))
 }
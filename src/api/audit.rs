@@ -0,0 +1,60 @@
+use std::convert::Infallible;
+
+use crate::db::actions::db_get_audit_log;
+use crate::types::audit::AuditLogEntry;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use warp::{http::StatusCode, Filter};
+
+#[derive(serde::Serialize)]
+pub struct AuditLogResponse {
+    entries: Vec<AuditLogEntry>,
+}
+
+pub fn audit() -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path("audit").and(get_audit_log())
+}
+
+#[derive(Serialize, Deserialize)]
+struct AuditLogQuery {
+    /// Defaults to 24 hours before `to`
+    from: Option<DateTime<Utc>>,
+
+    /// Defaults to current time
+    to: Option<DateTime<Utc>>,
+
+    /// Maximum number of returned entries, defaults to 1000
+    limit: Option<i64>,
+}
+
+fn get_audit_log() -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path::end()
+        .and(warp::get())
+        .and(warp::query::<AuditLogQuery>())
+        .and_then(get_audit_log_impl)
+}
+
+async fn get_audit_log_impl(query: AuditLogQuery) -> Result<impl warp::Reply, Infallible> {
+    let to = query.to.unwrap_or_else(Utc::now);
+    let from = query
+        .from
+        .unwrap_or_else(|| to - chrono::Duration::hours(24));
+    let limit = query.limit.unwrap_or(1000);
+
+    let result = db_get_audit_log(&from, &to, limit).await;
+
+    match result {
+        Ok(entries) => Ok(warp::reply::with_status(
+            warp::reply::json(&AuditLogResponse { entries }),
+            StatusCode::OK,
+        )),
+        Err(e) => {
+            error!("Failed to fetch audit log: {e}");
+
+            Ok(warp::reply::with_status(
+                warp::reply::json(&()),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ))
+        }
+    }
+}
@@ -0,0 +1,105 @@
+use std::{convert::Infallible, sync::Arc};
+
+use crate::core::{
+    backup::{create_backup, parse_backup, restore_backup, serialize_backup},
+    state::AppState,
+};
+use crate::types::backup::{BackupFormat, RestoreMode};
+use bytes::Bytes;
+use color_eyre::Result;
+use serde::{Deserialize, Serialize};
+use tokio::sync::RwLock;
+use warp::{http::StatusCode, Filter};
+
+use super::with_state;
+
+pub fn backup(
+    app_state: &Arc<RwLock<AppState>>,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path("backup").and(get_backup().or(post_backup(app_state)))
+}
+
+#[derive(Serialize, Deserialize)]
+struct GetQuery {
+    format: Option<BackupFormat>,
+}
+
+fn get_backup() -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path::end()
+        .and(warp::get())
+        .and(warp::query::<GetQuery>())
+        .and_then(get_backup_impl)
+}
+
+async fn get_backup_impl(query: GetQuery) -> Result<impl warp::Reply, Infallible> {
+    let format = query.format.unwrap_or_default();
+    let content_type = match format {
+        BackupFormat::Json => "application/json",
+        BackupFormat::Toml => "application/toml",
+    };
+
+    let result = create_backup()
+        .await
+        .and_then(|backup| serialize_backup(&backup, format));
+
+    match result {
+        Ok(contents) => Ok(warp::reply::with_status(
+            warp::reply::with_header(contents, "content-type", content_type),
+            StatusCode::OK,
+        )),
+        Err(e) => {
+            error!("Failed to create backup: {e}");
+
+            Ok(warp::reply::with_status(
+                warp::reply::with_header(e.to_string(), "content-type", "text/plain"),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ))
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct PostQuery {
+    format: Option<BackupFormat>,
+    mode: Option<RestoreMode>,
+}
+
+fn post_backup(
+    app_state: &Arc<RwLock<AppState>>,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path::end()
+        .and(warp::post())
+        .and(warp::query::<PostQuery>())
+        .and(warp::body::bytes())
+        .and(with_state(app_state))
+        .and_then(post_backup_impl)
+}
+
+async fn post_backup_impl(
+    query: PostQuery,
+    body: Bytes,
+    app_state: Arc<RwLock<AppState>>,
+) -> Result<impl warp::Reply, Infallible> {
+    let result = restore_from_body(&query, &body).await;
+
+    if let Err(e) = result {
+        error!("Failed to restore backup: {e}");
+
+        return Ok(warp::reply::with_status(
+            e.to_string(),
+            StatusCode::BAD_REQUEST,
+        ));
+    }
+
+    let mut app_state = app_state.write().await;
+    app_state.reload_db_state().await;
+
+    Ok(warp::reply::with_status(String::new(), StatusCode::OK))
+}
+
+async fn restore_from_body(query: &PostQuery, body: &[u8]) -> Result<()> {
+    let contents = std::str::from_utf8(body)?;
+    let backup = parse_backup(contents, query.format.unwrap_or_default())?;
+
+    restore_backup(&backup, query.mode.unwrap_or_default()).await
+}
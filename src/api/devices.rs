@@ -1,12 +1,17 @@
-use std::{convert::Infallible, sync::Arc};
+use std::{convert::Infallible, net::SocketAddr, sync::Arc};
 
+use crate::db::actions::db_get_device_history;
 use crate::types::{
     color::ColorMode,
-    device::{Device, DeviceId},
+    device::{Device, DeviceId, DeviceKey},
+    event::{Event, EventOrigin},
+    history::DeviceHistoryEntry,
+    integration::IntegrationId,
 };
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use tokio::sync::RwLock;
-use warp::Filter;
+use warp::{http::StatusCode, Filter};
 
 use crate::core::state::AppState;
 
@@ -20,7 +25,11 @@ pub struct DevicesResponse {
 pub fn devices(
     app_state: &Arc<RwLock<AppState>>,
 ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
-    warp::path("devices").and(get_devices(app_state).or(put_device(app_state)))
+    warp::path("devices").and(
+        get_device_history()
+            .or(get_devices(app_state))
+            .or(put_device(app_state)),
+    )
 }
 
 #[derive(Serialize, Deserialize)]
@@ -63,6 +72,7 @@ fn put_device(
     warp::path!(DeviceId)
         .and(warp::put())
         .and(warp::body::json())
+        .and(warp::addr::remote())
         .and(with_state(app_state))
         .and_then(put_device_impl)
 }
@@ -70,6 +80,7 @@ fn put_device(
 async fn put_device_impl(
     device_id: DeviceId,
     device: Device,
+    remote_addr: Option<SocketAddr>,
     app_state: Arc<RwLock<AppState>>,
 ) -> Result<impl warp::Reply, Infallible> {
     // Make sure device_id matches with provided device
@@ -79,7 +90,18 @@ async fn put_device_impl(
 
     let mut app_state = app_state.write().await;
 
-    app_state.devices.set_state(&device, false, false);
+    // State is set directly rather than via an event, so record the change in
+    // the audit log here
+    let origin = EventOrigin::Api {
+        remote_addr: remote_addr.map(|addr| addr.to_string()),
+    };
+    let event = Event::SetInternalState {
+        device: device.clone(),
+        skip_external_update: None,
+    };
+    app_state.audit.record(&event, &origin, &app_state.ws).await;
+
+    app_state.devices.set_state(&device, false, false, &origin);
 
     let devices = app_state.devices.get_state();
     let response = DevicesResponse {
@@ -88,3 +110,59 @@ async fn put_device_impl(
 
     Ok(warp::reply::json(&response))
 }
+
+#[derive(serde::Serialize)]
+pub struct DeviceHistoryResponse {
+    history: Vec<DeviceHistoryEntry>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct HistoryQuery {
+    /// Defaults to 24 hours before `to`
+    from: Option<DateTime<Utc>>,
+
+    /// Defaults to current time
+    to: Option<DateTime<Utc>>,
+
+    /// Only return the latest entry within each time bucket of this many
+    /// seconds, defaults to 1
+    resolution: Option<u64>,
+}
+
+fn get_device_history(
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!(IntegrationId / DeviceId / "history")
+        .and(warp::get())
+        .and(warp::query::<HistoryQuery>())
+        .and_then(get_device_history_impl)
+}
+
+async fn get_device_history_impl(
+    integration_id: IntegrationId,
+    device_id: DeviceId,
+    query: HistoryQuery,
+) -> Result<impl warp::Reply, Infallible> {
+    let device_key = DeviceKey::new(integration_id, device_id);
+    let to = query.to.unwrap_or_else(Utc::now);
+    let from = query
+        .from
+        .unwrap_or_else(|| to - chrono::Duration::hours(24));
+    let resolution = query.resolution.unwrap_or(1).max(1);
+
+    let result = db_get_device_history(&device_key, &from, &to, resolution as f64).await;
+
+    match result {
+        Ok(history) => Ok(warp::reply::with_status(
+            warp::reply::json(&DeviceHistoryResponse { history }),
+            StatusCode::OK,
+        )),
+        Err(e) => {
+            error!("Failed to fetch history for device {device_key}: {e}");
+
+            Ok(warp::reply::with_status(
+                warp::reply::json(&()),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ))
+        }
+    }
+}
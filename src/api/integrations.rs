@@ -0,0 +1,32 @@
+use std::{collections::BTreeMap, convert::Infallible, sync::Arc};
+
+use crate::core::state::AppState;
+use crate::types::integration::{IntegrationHealth, IntegrationId};
+use tokio::sync::RwLock;
+use warp::Filter;
+
+use super::with_state;
+
+#[derive(serde::Serialize)]
+pub struct IntegrationsResponse {
+    integrations: BTreeMap<IntegrationId, IntegrationHealth>,
+}
+
+pub fn integrations(
+    app_state: &Arc<RwLock<AppState>>,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path("integrations")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(with_state(app_state))
+        .and_then(get_integrations)
+}
+
+async fn get_integrations(
+    app_state: Arc<RwLock<AppState>>,
+) -> Result<impl warp::Reply, Infallible> {
+    let app_state = app_state.read().await;
+    let integrations = app_state.integrations.get_health();
+
+    Ok(warp::reply::json(&IntegrationsResponse { integrations }))
+}
@@ -0,0 +1,25 @@
+use std::convert::Infallible;
+
+use crate::types::{event::TxEventChannel, metrics::EventQueueMetrics};
+use warp::Filter;
+
+#[derive(serde::Serialize)]
+pub struct MetricsResponse {
+    event_queue: EventQueueMetrics,
+}
+
+pub fn metrics(
+    event_tx: TxEventChannel,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path("metrics")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(warp::any().map(move || event_tx.clone()))
+        .and_then(get_metrics)
+}
+
+async fn get_metrics(event_tx: TxEventChannel) -> Result<impl warp::Reply, Infallible> {
+    let event_queue = event_tx.metrics();
+
+    Ok(warp::reply::json(&MetricsResponse { event_queue }))
+}
@@ -1,13 +1,23 @@
 use std::sync::Arc;
 
-use crate::AppState;
+use crate::core::state::AppState;
 
 mod actions;
+mod audit;
+mod backup;
 mod devices;
+mod integrations;
+mod metrics;
+mod scenes;
 mod ws;
 
 use actions::*;
+use audit::*;
+use backup::*;
 use devices::*;
+use integrations::*;
+use metrics::*;
+use scenes::*;
 
 use color_eyre::Result;
 use tokio::sync::RwLock;
@@ -23,10 +33,20 @@ pub fn with_state(
 }
 
 // Example of warp usage: https://github.com/seanmonstar/warp/blob/master/examples/todos.rs
-pub fn init_api(app_state: &Arc<RwLock<AppState>>) -> Result<()> {
-    let api = warp::path("api")
-        .and(warp::path("v1"))
-        .and(devices(app_state).or(actions(app_state)));
+pub async fn init_api(app_state: &Arc<RwLock<AppState>>) -> Result<()> {
+    // Metrics are read straight from the event queue, so that they're
+    // available even while the event loop holds the state lock
+    let event_tx = app_state.read().await.event_tx.clone();
+
+    let api = warp::path("api").and(warp::path("v1")).and(
+        devices(app_state)
+            .or(actions(app_state))
+            .or(audit())
+            .or(backup(app_state))
+            .or(scenes(app_state))
+            .or(integrations(app_state))
+            .or(metrics(event_tx)),
+    );
 
     let ws = ws(app_state);
 
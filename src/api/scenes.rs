@@ -0,0 +1,90 @@
+use std::{convert::Infallible, sync::Arc};
+
+use crate::core::{
+    config::config_path,
+    scene_export::{export_db_scenes, promote_db_scene, render_scenes_toml},
+    state::AppState,
+};
+use crate::types::scene::SceneId;
+use serde::{Deserialize, Serialize};
+use tokio::sync::RwLock;
+use warp::{http::StatusCode, Filter};
+
+use super::with_state;
+
+pub fn scenes(
+    app_state: &Arc<RwLock<AppState>>,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path("scenes").and(export_scenes().or(promote_scene(app_state)))
+}
+
+#[derive(Serialize, Deserialize)]
+struct ExportQuery {
+    /// Only export this scene, defaults to all scenes stored in the DB
+    scene_id: Option<SceneId>,
+
+    /// Merge scene overrides into the exported scenes
+    overrides: Option<bool>,
+}
+
+fn export_scenes() -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("export")
+        .and(warp::get())
+        .and(warp::query::<ExportQuery>())
+        .and_then(export_scenes_impl)
+}
+
+async fn export_scenes_impl(query: ExportQuery) -> Result<impl warp::Reply, Infallible> {
+    let scene_ids: Vec<SceneId> = query.scene_id.into_iter().collect();
+
+    let result = export_db_scenes(&scene_ids, query.overrides.unwrap_or_default())
+        .await
+        .and_then(|scenes| render_scenes_toml(&scenes));
+
+    match result {
+        Ok(contents) => Ok(warp::reply::with_status(
+            warp::reply::with_header(contents, "content-type", "application/toml"),
+            StatusCode::OK,
+        )),
+        Err(e) => {
+            error!("Failed to export scenes: {e}");
+
+            Ok(warp::reply::with_status(
+                warp::reply::with_header(e.to_string(), "content-type", "text/plain"),
+                StatusCode::BAD_REQUEST,
+            ))
+        }
+    }
+}
+
+fn promote_scene(
+    app_state: &Arc<RwLock<AppState>>,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!(SceneId / "promote")
+        .and(warp::post())
+        .and(with_state(app_state))
+        .and_then(promote_scene_impl)
+}
+
+async fn promote_scene_impl(
+    scene_id: SceneId,
+    app_state: Arc<RwLock<AppState>>,
+) -> Result<impl warp::Reply, Infallible> {
+    let config = match promote_db_scene(&scene_id, &config_path()).await {
+        Ok(config) => config,
+        Err(e) => {
+            error!("Failed to promote scene {scene_id}: {e}");
+
+            return Ok(warp::reply::with_status(
+                e.to_string(),
+                StatusCode::BAD_REQUEST,
+            ));
+        }
+    };
+
+    let mut app_state = app_state.write().await;
+    app_state.scenes.insert_config_scene(scene_id, config);
+    app_state.reload_db_state().await;
+
+    Ok(warp::reply::with_status(String::new(), StatusCode::OK))
+}
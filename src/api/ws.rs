@@ -1,6 +1,6 @@
 use super::with_state;
-use crate::types::websockets::WebSocketRequest;
-use crate::AppState;
+use crate::core::state::AppState;
+use crate::types::{event::EventOrigin, websockets::WebSocketRequest};
 use futures::SinkExt;
 use futures_util::{StreamExt, TryFutureExt};
 use std::sync::{
@@ -53,6 +53,10 @@ async fn user_connected(ws: WebSocket, app_state: Arc<RwLock<AppState>>) {
 
     let app_state = app_state.read().await.clone();
 
+    let event_tx = app_state
+        .event_tx
+        .with_origin(EventOrigin::WebSocket { user_id: my_id });
+
     // Save the sender in our list of connected users.
     app_state.ws.user_connected(my_id, tx).await;
 
@@ -76,7 +80,7 @@ async fn user_connected(ws: WebSocket, app_state: Arc<RwLock<AppState>>) {
 
             match msg {
                 Ok(WebSocketRequest::EventMessage(event)) => {
-                    app_state.event_tx.send(event);
+                    event_tx.send(event);
                 }
                 Err(e) => warn!("Error while deserializing websocket message: {e}"),
             }
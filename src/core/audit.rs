@@ -0,0 +1,209 @@
+use std::time::Duration;
+
+use serde_json::json;
+
+use crate::db::{
+    actions::{db_insert_audit_log_entry, db_prune_audit_log},
+    spawn_db_write,
+};
+use crate::types::{
+    action::Action,
+    audit::{AuditConfig, AuditLogEntry},
+    event::{Event, EventOrigin},
+    websockets::WebSocketResponse,
+};
+use crate::utils::{cli::Cli, clock};
+
+use super::websockets::WebSockets;
+
+/// How often old audit log entries are pruned.
+const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Clone)]
+pub struct AuditLog {
+    config: AuditConfig,
+    cli: Cli,
+}
+
+/// Returns true if the event is something that may cause devices to change
+/// state, and should thus be recorded in the audit log.
+fn is_audited(event: &Event, origin: &EventOrigin) -> bool {
+    match event {
+        Event::Action(_) => true,
+
+        // Integrations such as circadian periodically push their own state,
+        // which is frequent and not interesting from an auditing point of view
+        Event::SetInternalState { .. } => !matches!(origin, EventOrigin::Integration { .. }),
+
+        Event::SetExternalState { .. } => origin == &EventOrigin::DriftCorrection,
+
+        _ => false,
+    }
+}
+
+pub fn action_to_json(action: &Action) -> serde_json::Value {
+    match action {
+        // Expressions can't be serialized, store their textual form instead
+        Action::EvalExpr(expr) => json!({ "action": "EvalExpr", "expr": expr.to_string() }),
+        action => {
+            serde_json::to_value(action).unwrap_or_else(|e| json!({ "error": e.to_string() }))
+        }
+    }
+}
+
+fn event_to_json(event: &Event) -> serde_json::Value {
+    match event {
+        Event::Action(action) => json!({ "Action": action_to_json(action) }),
+        event => serde_json::to_value(event).unwrap_or_else(|e| json!({ "error": e.to_string() })),
+    }
+}
+
+impl AuditLog {
+    pub fn new(config: AuditConfig, cli: &Cli) -> Self {
+        AuditLog {
+            config,
+            cli: cli.clone(),
+        }
+    }
+
+    /// Spawns a background task that periodically prunes entries older than
+    /// the configured retention period.
+    pub fn start(&self) {
+        if self.cli.dry_run {
+            return;
+        }
+
+        let retention = chrono::Duration::days(self.config.retention_days.unwrap_or(30) as i64);
+
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(MAINTENANCE_INTERVAL);
+
+            loop {
+                interval.tick().await;
+
+                match db_prune_audit_log(clock::now() - retention).await {
+                    Ok(count) if count > 0 => info!("Pruned {count} old audit log entries"),
+                    Ok(_) => {}
+                    Err(e) => error!("Failed to prune audit log: {e}"),
+                }
+            }
+        });
+    }
+
+    /// Appends the event to the audit log and streams the new entry to all
+    /// connected WebSocket peers.
+    pub async fn record(&self, event: &Event, origin: &EventOrigin, ws: &WebSockets) {
+        if !is_audited(event, origin) {
+            return;
+        }
+
+        let entry = AuditLogEntry {
+            recorded_at: clock::now(),
+            event: event_to_json(event),
+            origin: origin.clone(),
+        };
+
+        ws.send(None, &WebSocketResponse::AuditLogEntry(entry.clone()))
+            .await;
+
+        if self.cli.dry_run {
+            debug!("(dry run) would store audit log entry: {entry:?}");
+            return;
+        }
+
+        spawn_db_write(async move { db_insert_audit_log_entry(&entry).await });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+    use crate::types::{
+        device::{ControllableDevice, Device, DeviceData, DeviceId, ManageKind, SensorDevice},
+        integration::IntegrationId,
+        scene::{ActivateSceneDescriptor, SceneId},
+    };
+
+    fn mk_device(data: DeviceData) -> Device {
+        Device::new(
+            IntegrationId::from_str("dummy").unwrap(),
+            DeviceId::new("1"),
+            "Device".to_string(),
+            data,
+            None,
+        )
+    }
+
+    fn light() -> Device {
+        mk_device(DeviceData::Controllable(ControllableDevice::new(
+            None,
+            true,
+            Some(1.0),
+            None,
+            None,
+            Default::default(),
+            ManageKind::Full,
+        )))
+    }
+
+    fn sensor() -> Device {
+        mk_device(DeviceData::Sensor(SensorDevice::Boolean {
+            value: true,
+            meta: None,
+        }))
+    }
+
+    #[test]
+    fn test_is_audited() {
+        let action = Event::Action(Action::ActivateScene(ActivateSceneDescriptor {
+            scene_id: SceneId::from_str("bright").unwrap(),
+            device_keys: None,
+            group_keys: None,
+        }));
+        assert!(is_audited(&action, &EventOrigin::Internal));
+
+        let set_light = Event::SetInternalState {
+            device: light(),
+            skip_external_update: None,
+        };
+        assert!(is_audited(&set_light, &EventOrigin::Internal));
+
+        let set_sensor = Event::SetInternalState {
+            device: sensor(),
+            skip_external_update: None,
+        };
+        assert!(is_audited(&set_sensor, &EventOrigin::Internal));
+
+        // Periodic state pushes from integrations (e.g. circadian) are skipped
+        let integration = EventOrigin::Integration {
+            integration_id: IntegrationId::from_str("circadian").unwrap(),
+        };
+        assert!(!is_audited(&set_sensor, &integration));
+
+        let set_external = Event::SetExternalState { device: light() };
+        assert!(is_audited(&set_external, &EventOrigin::DriftCorrection));
+        assert!(!is_audited(&set_external, &EventOrigin::Internal));
+
+        let update = Event::ExternalStateUpdate { device: light() };
+        let origin = EventOrigin::Integration {
+            integration_id: IntegrationId::from_str("dummy").unwrap(),
+        };
+        assert!(!is_audited(&update, &origin));
+    }
+
+    #[test]
+    fn test_origin_serialization() {
+        let origin = EventOrigin::WebSocket { user_id: 3 };
+
+        assert_eq!(
+            serde_json::to_value(&origin).unwrap(),
+            json!({ "kind": "WebSocket", "user_id": 3 })
+        );
+        assert_eq!(
+            serde_json::from_value::<EventOrigin>(json!({ "kind": "Internal" })).unwrap(),
+            EventOrigin::Internal
+        );
+    }
+}
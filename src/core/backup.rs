@@ -0,0 +1,248 @@
+use std::{
+    io::{self, Write},
+    path::Path,
+};
+
+use chrono::Utc;
+use color_eyre::Result;
+use eyre::Context;
+
+use crate::db::actions::{
+    db_get_devices, db_get_scene_overrides, db_get_scenes, db_get_ui_state, db_restore_backup,
+};
+use crate::types::{
+    backup::{Backup, BackupFormat, RestoreMode, BACKUP_VERSION},
+    device::DevicesState,
+};
+
+/// Gathers all runtime state stored in the database into a [Backup].
+pub async fn create_backup() -> Result<Backup> {
+    let scenes = db_get_scenes().await?;
+    let scene_overrides = db_get_scene_overrides().await?;
+    let ui_state = db_get_ui_state().await?;
+    let devices = db_get_devices().await?;
+
+    Ok(Backup {
+        version: BACKUP_VERSION,
+        created_at: Utc::now(),
+        scenes,
+        scene_overrides,
+        ui_state,
+        devices: DevicesState(devices.into_iter().collect()),
+    })
+}
+
+/// Writes the contents of a [Backup] into the database.
+///
+/// Note that this does not refresh any state that has already been loaded from
+/// the database.
+pub async fn restore_backup(backup: &Backup, mode: RestoreMode) -> Result<()> {
+    if backup.version > BACKUP_VERSION {
+        return Err(eyre!(
+            "Unsupported backup version {}, this version of homectl supports versions up to {BACKUP_VERSION}",
+            backup.version
+        ));
+    }
+
+    db_restore_backup(backup, mode).await?;
+
+    info!(
+        "Restored backup from {created_at} ({mode:?}): {scenes} scenes, {devices} devices",
+        created_at = backup.created_at,
+        scenes = backup.scenes.len(),
+        devices = backup.devices.0.len(),
+    );
+
+    Ok(())
+}
+
+/// Fields of a [Backup] that hold arbitrary JSON, e.g. the raw state reported
+/// by integrations. TOML has no null, so these are stored as JSON strings in
+/// TOML backups.
+fn json_fields(backup: &mut serde_json::Value) -> Vec<&mut serde_json::Value> {
+    let mut fields = vec![];
+
+    for (key, value) in backup.as_object_mut().into_iter().flatten() {
+        match (key.as_str(), value) {
+            ("ui_state", serde_json::Value::Object(ui_state)) => {
+                fields.extend(ui_state.values_mut());
+            }
+            ("devices", serde_json::Value::Object(devices)) => {
+                fields.extend(
+                    devices
+                        .values_mut()
+                        .filter_map(|device| device.get_mut("raw"))
+                        .filter(|raw| !raw.is_null()),
+                );
+            }
+            _ => {}
+        }
+    }
+
+    fields
+}
+
+/// Removes null values left behind by unset optional fields
+fn remove_nulls(value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::Object(object) => {
+            object.retain(|_, value| !value.is_null());
+            object.values_mut().for_each(remove_nulls);
+        }
+        serde_json::Value::Array(array) => array.iter_mut().for_each(remove_nulls),
+        _ => {}
+    }
+}
+
+fn backup_to_toml(backup: &Backup) -> Result<String> {
+    let mut value = serde_json::to_value(backup)?;
+
+    for field in json_fields(&mut value) {
+        *field = serde_json::Value::String(field.to_string());
+    }
+    remove_nulls(&mut value);
+
+    Ok(toml::to_string_pretty(&value)?)
+}
+
+fn backup_from_toml(contents: &str) -> Result<Backup> {
+    let mut value: serde_json::Value = toml::from_str(contents)?;
+
+    for field in json_fields(&mut value) {
+        let json = field
+            .as_str()
+            .ok_or_else(|| eyre!("Expected JSON string in TOML backup, got {field}"))?;
+        let parsed = serde_json::from_str(json)?;
+        *field = parsed;
+    }
+
+    Ok(serde_json::from_value(value)?)
+}
+
+pub fn serialize_backup(backup: &Backup, format: BackupFormat) -> Result<String> {
+    let contents = match format {
+        BackupFormat::Json => serde_json::to_string_pretty(backup)?,
+        BackupFormat::Toml => backup_to_toml(backup)?,
+    };
+
+    Ok(contents)
+}
+
+pub fn parse_backup(contents: &str, format: BackupFormat) -> Result<Backup> {
+    let backup = match format {
+        BackupFormat::Json => serde_json::from_str(contents)?,
+        BackupFormat::Toml => backup_from_toml(contents)?,
+    };
+
+    Ok(backup)
+}
+
+fn format_from_path(path: &Path) -> BackupFormat {
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("toml") => BackupFormat::Toml,
+        _ => BackupFormat::Json,
+    }
+}
+
+/// Writes a backup of the database to `output`, or to stdout if not given.
+pub async fn export_backup_to(output: Option<&Path>, format: BackupFormat) -> Result<()> {
+    let backup = create_backup().await?;
+    let contents = serialize_backup(&backup, format)?;
+
+    match output {
+        Some(path) => std::fs::write(path, contents)
+            .wrap_err_with(|| format!("Failed to write backup to {}", path.display()))?,
+        None => io::stdout().write_all(contents.as_bytes())?,
+    }
+
+    Ok(())
+}
+
+/// Reads a backup from `path` and restores it into the database.
+pub async fn import_backup_from(path: &Path, mode: RestoreMode) -> Result<()> {
+    let contents = std::fs::read_to_string(path)
+        .wrap_err_with(|| format!("Failed to read backup from {}", path.display()))?;
+    let backup = parse_backup(&contents, format_from_path(path))?;
+
+    restore_backup(&backup, mode).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{BTreeMap, HashMap};
+    use std::str::FromStr;
+
+    use super::*;
+    use crate::types::{
+        color::Capabilities,
+        device::{ControllableDevice, Device, DeviceData, DeviceId, ManageKind},
+        integration::IntegrationId,
+        scene::{SceneConfig, SceneDeviceConfig, SceneDeviceState, SceneId},
+    };
+
+    fn mk_backup() -> Backup {
+        let device = Device::new(
+            IntegrationId::from_str("dummy").unwrap(),
+            DeviceId::new("1"),
+            "Kitchen".to_string(),
+            DeviceData::Controllable(ControllableDevice::new(
+                Some(SceneId::new("evening".to_string())),
+                true,
+                Some(0.5),
+                None,
+                None,
+                Capabilities::default(),
+                ManageKind::Full,
+            )),
+            Some(serde_json::json!({ "state": { "on": true, "effect": null } })),
+        );
+
+        let mut overrides = HashMap::new();
+        overrides.insert(
+            device.get_device_key(),
+            SceneDeviceConfig::DeviceState(SceneDeviceState {
+                power: Some(true),
+                color: None,
+                brightness: None,
+                transition: None,
+            }),
+        );
+
+        Backup {
+            version: BACKUP_VERSION,
+            created_at: Utc::now(),
+            scenes: BTreeMap::from([(
+                SceneId::new("evening".to_string()),
+                SceneConfig {
+                    name: "Evening".to_string(),
+                    devices: None,
+                    groups: None,
+                    hidden: None,
+                    expr: None,
+                },
+            )]),
+            scene_overrides: BTreeMap::from([(SceneId::new("evening".to_string()), overrides)]),
+            ui_state: HashMap::from([
+                ("theme".to_string(), serde_json::json!("dark")),
+                (
+                    "layout".to_string(),
+                    serde_json::json!({ "selected": null, "columns": [1, null] }),
+                ),
+                ("empty".to_string(), serde_json::Value::Null),
+            ]),
+            devices: DevicesState(BTreeMap::from([(device.get_device_key(), device)])),
+        }
+    }
+
+    #[test]
+    fn test_backup_roundtrip() {
+        let backup = mk_backup();
+
+        for format in [BackupFormat::Json, BackupFormat::Toml] {
+            let contents = serialize_backup(&backup, format).unwrap();
+            let parsed = parse_backup(&contents, format).unwrap();
+
+            assert_eq!(parsed, backup, "{format:?} roundtrip failed");
+        }
+    }
+}
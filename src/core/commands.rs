@@ -0,0 +1,52 @@
+use color_eyre::Result;
+
+use crate::utils::cli::Command;
+
+use super::{
+    backup::{export_backup_to, import_backup_from},
+    config::config_path,
+    scene_export::{export_scenes_to, promote_db_scene},
+};
+
+#[cfg(feature = "simulation")]
+use super::simulation::{run_replay, run_simulation};
+
+/// Runs a command given on the command line.
+pub async fn run_command(command: &Command) -> Result<()> {
+    match command {
+        Command::Export { output, format } => {
+            export_backup_to(output.as_deref(), *format).await?;
+        }
+        Command::Import { path, mode } => {
+            import_backup_from(path, *mode).await?;
+        }
+        Command::ExportScenes {
+            scene_ids,
+            overrides,
+            output,
+        } => {
+            export_scenes_to(output.as_deref(), scene_ids, *overrides).await?;
+        }
+        Command::PromoteScene { scene_id } => {
+            promote_db_scene(scene_id, &config_path()).await?;
+        }
+        #[cfg(feature = "simulation")]
+        Command::Simulate {
+            timeline,
+            format,
+            output,
+        } => {
+            run_simulation(timeline, *format, output.as_deref()).await?;
+        }
+        #[cfg(feature = "simulation")]
+        Command::Replay {
+            recording,
+            format,
+            output,
+        } => {
+            run_replay(recording, *format, output.as_deref()).await?;
+        }
+    }
+
+    Ok(())
+}
@@ -1,17 +1,29 @@
 use crate::types::{
+    audit::AuditConfig,
     group::GroupsConfig,
+    history::HistoryConfig,
     integration::{IntegrationId, IntegrationsConfig},
+    mqtt_bridge::MqttBridgeConfig,
     rule::RoutinesConfig,
     scene::ScenesConfig,
 };
 use color_eyre::Result;
 use eyre::Context;
 use serde::Deserialize;
-use std::{collections::HashMap, fs::File, io::Read};
+use std::{collections::HashMap, fs::File, io::Read, path::PathBuf};
 
 #[derive(Deserialize, Debug)]
 pub struct CoreConfig {
     pub warmup_time_seconds: Option<u64>,
+
+    /// Maximum number of queued events before device state reports from
+    /// integrations, and after those other events, start getting dropped,
+    /// defaults to 4096
+    pub event_queue_size: Option<usize>,
+
+    /// Maximum time in seconds allowed for each step of shutting down, e.g.
+    /// stopping integrations, defaults to 10
+    pub shutdown_timeout_seconds: Option<u64>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -21,9 +33,17 @@ pub struct Config {
     pub scenes: Option<ScenesConfig>,
     pub groups: Option<GroupsConfig>,
     pub routines: Option<RoutinesConfig>,
+    pub history: Option<HistoryConfig>,
+    pub audit: Option<AuditConfig>,
+    pub mqtt_bridge: Option<MqttBridgeConfig>,
 }
 
-type OpaqueIntegrationsConfigs = HashMap<IntegrationId, config::Value>;
+pub type OpaqueIntegrationsConfigs = HashMap<IntegrationId, config::Value>;
+
+/// Path to the main configuration file
+pub fn config_path() -> PathBuf {
+    std::env::current_dir().unwrap().join("Settings.toml")
+}
 
 pub fn read_config() -> Result<(Config, OpaqueIntegrationsConfigs)> {
     let builder = config::Config::builder();
@@ -31,7 +51,7 @@ pub fn read_config() -> Result<(Config, OpaqueIntegrationsConfigs)> {
     let root = std::env::current_dir().unwrap();
     let sample_path = root.join("Settings.toml.example");
 
-    let path = root.join("Settings.toml");
+    let path = config_path();
 
     if !path.exists() && std::env::var("SKIP_SAMPLE_CONFIG").is_err() {
         error!("Settings.toml not found, generating sample configuration.");
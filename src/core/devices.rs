@@ -1,4 +1,5 @@
 use crate::db::actions::{db_get_devices, db_update_device};
+use crate::db::spawn_db_write;
 use crate::types::integration::IntegrationId;
 use crate::utils::cli::Cli;
 
@@ -9,7 +10,7 @@ use crate::types::device::{cmp_device_states, ControllableDevice, DeviceRef, Man
 use crate::types::group::GroupId;
 use crate::types::{
     device::{Device, DeviceData, DeviceKey, DevicesState},
-    event::{Event, TxEventChannel},
+    event::{Event, EventOrigin, TxEventChannel},
     scene::{ActivateSceneDescriptor, SceneId},
 };
 use color_eyre::Result;
@@ -62,7 +63,7 @@ impl Devices {
 
                     let device = db_device;
 
-                    self.set_state(&device, true, true);
+                    self.set_state(&device, true, true, &EventOrigin::Internal);
                 }
                 info!("Restored devices from DB");
             }
@@ -74,7 +75,12 @@ impl Devices {
 
     /// Recomputes scene state for all devices and updates both internal and
     /// external state accordingly
-    pub fn invalidate(&mut self, invalidated_scenes: &HashSet<SceneId>, scenes: &Scenes) {
+    pub fn invalidate(
+        &mut self,
+        invalidated_scenes: &HashSet<SceneId>,
+        scenes: &Scenes,
+        origin: &EventOrigin,
+    ) {
         for scene_id in invalidated_scenes {
             let invalidated_devices: Vec<Device> = self
                 .state
@@ -85,16 +91,21 @@ impl Devices {
                 .collect();
 
             for device in invalidated_devices {
-                self.set_state(&device, false, false);
+                self.set_state(&device, false, false, origin);
             }
         }
     }
 
-    pub async fn discover_device(&mut self, device: &Device, scenes: &Scenes) {
+    pub async fn discover_device(
+        &mut self,
+        device: &Device,
+        scenes: &Scenes,
+        origin: &EventOrigin,
+    ) {
         info!("Discovered device: {device}");
         let device = device.set_scene(device.get_scene_id().as_ref(), scenes);
 
-        self.set_state(&device, !device.is_managed(), false);
+        self.set_state(&device, !device.is_managed(), false, origin);
     }
 
     /// Handles an incoming state update for a controllable device.
@@ -107,10 +118,11 @@ impl Devices {
         current: Device,
         incoming: &Device,
         incoming_state: &ControllableDevice,
+        origin: &EventOrigin,
     ) -> Result<()> {
         // If device is not managed, we set internal state and bail
         if !incoming.is_managed() {
-            self.set_state(incoming, true, false);
+            self.set_state(incoming, true, false, origin);
 
             return Ok(());
         }
@@ -156,13 +168,15 @@ impl Devices {
                 name = incoming.name,
             );
 
-            self.event_tx
-                .send(Event::SetExternalState { device: current });
+            self.event_tx.send_with_origin(
+                Event::SetExternalState { device: current },
+                EventOrigin::DriftCorrection,
+            );
         }
 
         // Always make sure device raw state is up to date, note that set_raw
         // bails out if there are no changes.
-        self.set_raw(incoming).await?;
+        self.set_raw(incoming, origin).await?;
 
         Ok(())
     }
@@ -173,6 +187,7 @@ impl Devices {
         &mut self,
         incoming: &Device,
         scenes: &Scenes,
+        origin: &EventOrigin,
     ) -> Result<()> {
         trace!("handle_external_state_update {incoming:?}");
 
@@ -188,19 +203,20 @@ impl Devices {
         match (&incoming.data, current) {
             // Device was seen for the first time
             (_, None) => {
-                self.discover_device(incoming, scenes).await;
+                self.discover_device(incoming, scenes, origin).await;
             }
 
             // Previously seen sensor, state is always updated
-            (DeviceData::Sensor(_), _) => {
-                self.set_state(incoming, false, false);
+            (DeviceData::Sensor(_), Some(current)) => {
+                let incoming = keep_sensor_updated_at(current, incoming);
+                self.set_state(&incoming, false, false, origin);
             }
 
             // Previously seen controllable device
             (DeviceData::Controllable(ref incoming_state), Some(current)) => {
                 let current = current.clone();
 
-                self.handle_controllable_update(current, incoming, incoming_state)
+                self.handle_controllable_update(current, incoming, incoming_state, origin)
                     .await?;
             }
         }
@@ -208,8 +224,16 @@ impl Devices {
         Ok(())
     }
 
-    /// Sets internal (and possibly external) state for given device
-    pub fn set_state(&mut self, device: &Device, skip_external_update: bool, skip_db_update: bool) {
+    /// Sets internal (and possibly external) state for given device. The
+    /// resulting events are tagged with `origin`, i.e. whatever caused the
+    /// state change.
+    pub fn set_state(
+        &mut self,
+        device: &Device,
+        skip_external_update: bool,
+        skip_db_update: bool,
+        origin: &EventOrigin,
+    ) {
         let device_key = device.get_device_key();
         let old = self.get_device(&device_key);
 
@@ -229,29 +253,26 @@ impl Devices {
             }
         }
 
-        // TODO: a solution which does not require cloning the entire state each
-        // time
-        let old_states = { self.state.clone() };
         let old = old.cloned();
         self.state.0.insert(device_key, device.clone());
 
-        self.event_tx.send(Event::InternalStateUpdate {
-            old_state: old_states,
-            new_state: self.state.clone(),
-            old,
-            new: device.clone(),
-        });
+        self.event_tx.send_with_origin(
+            Event::InternalStateUpdate {
+                old,
+                new: device.clone(),
+            },
+            origin.clone(),
+        );
 
         if !skip_external_update && !device.is_sensor() {
             let device = device.clone();
-            self.event_tx.send(Event::SetExternalState { device });
+            self.event_tx
+                .send_with_origin(Event::SetExternalState { device }, origin.clone());
         }
 
         if !skip_db_update {
             if !self.cli.dry_run {
-                tokio::spawn(async move {
-                    db_update_device(&device).await.ok();
-                });
+                spawn_db_write(async move { db_update_device(&device).await });
             } else {
                 debug!("(dry run) would store device: {device}");
             }
@@ -262,7 +283,7 @@ impl Devices {
     /// [Devices::set_state].
     ///
     /// If raw state hasn't changed, do nothing.
-    pub async fn set_raw(&mut self, incoming: &Device) -> Result<()> {
+    pub async fn set_raw(&mut self, incoming: &Device, origin: &EventOrigin) -> Result<()> {
         let device = self.get_device(&incoming.get_device_key()).ok_or_else(|| {
             eyre!(
                 "Could not find device {integration_id}/{name} while trying to set raw field",
@@ -279,7 +300,7 @@ impl Devices {
         let mut device = device.clone();
         device.raw.clone_from(&incoming.raw);
 
-        self.set_state(&device, true, true);
+        self.set_state(&device, true, true, origin);
 
         Ok(())
     }
@@ -288,6 +309,7 @@ impl Devices {
         self.state.0.get(device_key)
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub async fn activate_scene(
         &mut self,
         scene_id: &SceneId,
@@ -296,6 +318,7 @@ impl Devices {
         groups: &Groups,
         scenes: &Scenes,
         eval_context: &EvalContext,
+        origin: &EventOrigin,
     ) -> Option<bool> {
         let group_keys_description = if let Some(group_keys) = group_keys {
             format!(
@@ -343,7 +366,7 @@ impl Devices {
                     .set_scene(Some(scene_id), scenes)
                     .set_transition(None);
 
-                self.set_state(&device, false, false);
+                self.set_state(&device, false, false, origin);
             }
         }
 
@@ -356,6 +379,7 @@ impl Devices {
         _group_keys: &Option<Vec<GroupId>>,
         step: &Option<f32>,
         scenes: &Scenes,
+        origin: &EventOrigin,
     ) -> Option<bool> {
         info!("Dimming devices. Step: {}", step.unwrap_or(0.1));
 
@@ -364,7 +388,7 @@ impl Devices {
             let mut d = device.1.clone();
             d = d.dim_device(step.unwrap_or(0.1));
             d = d.set_scene(None, scenes);
-            self.set_state(&d, false, false);
+            self.set_state(&d, false, false, origin);
         }
 
         Some(true)
@@ -380,6 +404,7 @@ impl Devices {
         detection_group_keys: &Option<Vec<GroupId>>,
         scenes: &Scenes,
         eval_context: &EvalContext,
+        origin: &EventOrigin,
     ) -> Option<()> {
         let next_scene = {
             get_next_cycled_scene(
@@ -401,6 +426,7 @@ impl Devices {
             groups,
             scenes,
             eval_context,
+            origin,
         )
         .await;
 
@@ -419,3 +445,145 @@ impl Devices {
         self.state.0.get(&device_key)
     }
 }
+
+/// Keeps the time at which the current sensor value was first reported, if the
+/// incoming update doesn't change the value (e.g. when only the raw state
+/// differs)
+fn keep_sensor_updated_at(current: &Device, incoming: &Device) -> Device {
+    let mut incoming = incoming.clone();
+
+    if !current.data.is_state_eq(&incoming.data) {
+        return incoming;
+    }
+
+    if let (DeviceData::Sensor(current), DeviceData::Sensor(sensor)) =
+        (&current.data, &mut incoming.data)
+    {
+        if let (Some(current), Some(meta)) = (current.meta(), sensor.meta_mut()) {
+            meta.updated_at = current.updated_at;
+        }
+    }
+
+    incoming
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use chrono::{TimeZone, Timelike, Utc};
+    use serde_json::json;
+
+    use super::*;
+    use crate::types::{
+        device::{DeviceId, SensorDevice, SensorMeta},
+        event::mk_event_channel,
+    };
+
+    fn light(power: bool) -> Device {
+        Device::new(
+            IntegrationId::from_str("dummy").unwrap(),
+            DeviceId::new("light"),
+            "Light".to_string(),
+            DeviceData::Controllable(ControllableDevice::new(
+                None,
+                power,
+                Some(1.0),
+                None,
+                None,
+                Default::default(),
+                ManageKind::Full,
+            )),
+            None,
+        )
+    }
+
+    #[tokio::test]
+    async fn test_set_state_keeps_origin() {
+        let (event_tx, mut event_rx) = mk_event_channel();
+        let cli = Cli {
+            dry_run: true,
+            record: None,
+            command: None,
+        };
+        let mut devices = Devices::new(event_tx, &cli);
+
+        let origin = EventOrigin::WebSocket { user_id: 1 };
+        devices.set_state(&light(true), false, false, &origin);
+
+        let events = [event_rx.recv().await, event_rx.recv().await];
+        assert!(events.iter().all(|event| event.origin == origin));
+        assert!(events
+            .iter()
+            .any(|event| matches!(event.event, Event::InternalStateUpdate { .. })));
+        assert!(events
+            .iter()
+            .any(|event| matches!(event.event, Event::SetExternalState { .. })));
+    }
+
+    #[tokio::test]
+    async fn test_unchanged_sensor_value_keeps_updated_at() {
+        let (event_tx, _event_rx) = mk_event_channel();
+        let cli = Cli {
+            dry_run: true,
+            record: None,
+            command: None,
+        };
+        let mut devices = Devices::new(event_tx, &cli);
+        let scenes = Scenes::default();
+
+        let sensor = |value: f64, minute: u32, raw: serde_json::Value| {
+            Device::new(
+                IntegrationId::from_str("mqtt").unwrap(),
+                DeviceId::new("sensor"),
+                "Sensor".to_string(),
+                DeviceData::Sensor(SensorDevice::Number {
+                    value,
+                    meta: Some(SensorMeta {
+                        updated_at: Some(Utc.with_ymd_and_hms(2024, 6, 1, 12, minute, 0).unwrap()),
+                        ..Default::default()
+                    }),
+                }),
+                Some(raw),
+            )
+        };
+        let updated_at = |devices: &Devices| {
+            let device = devices.get_device(&sensor(0.0, 0, json!({})).get_device_key());
+            match &device.unwrap().data {
+                DeviceData::Sensor(sensor) => sensor.meta().unwrap().updated_at.unwrap().minute(),
+                data => panic!("Expected a sensor, got {data:?}"),
+            }
+        };
+
+        let origin = EventOrigin::Internal;
+        devices
+            .handle_external_state_update(
+                &sensor(21.5, 0, json!({ "rssi": -60 })),
+                &scenes,
+                &origin,
+            )
+            .await
+            .unwrap();
+
+        // Only the raw state changes
+        devices
+            .handle_external_state_update(
+                &sensor(21.5, 1, json!({ "rssi": -70 })),
+                &scenes,
+                &origin,
+            )
+            .await
+            .unwrap();
+        assert_eq!(updated_at(&devices), 0);
+
+        devices
+            .handle_external_state_update(
+                &sensor(22.0, 2, json!({ "rssi": -70 })),
+                &scenes,
+                &origin,
+            )
+            .await
+            .unwrap();
+        assert_eq!(updated_at(&devices), 2);
+    }
+}
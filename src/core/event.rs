@@ -1,6 +1,7 @@
-use std::collections::BTreeMap;
+use std::{collections::BTreeMap, sync::Arc};
 
 use color_eyre::Result;
+use tokio::sync::RwLock;
 
 use crate::types::{
     action::Action,
@@ -17,12 +18,29 @@ use crate::db::actions::{db_delete_scene, db_edit_scene, db_store_scene};
 
 use super::{expr::eval_action_expr, state::AppState};
 
-pub async fn handle_event(state: &mut AppState, event: &Event) -> Result<()> {
+/// Handles a single event, logging any errors.
+pub async fn process_event(state: &Arc<RwLock<AppState>>, event: OriginatedEvent) {
+    let OriginatedEvent { event, origin } = event;
+
+    let mut state = state.write().await;
+    let result = handle_event(&mut state, &event, &origin).await;
+
+    if let Err(err) = result {
+        error!(
+            "Error while handling event:\n    Event:\n    {event:#?}\n    Origin: {origin:?}\n\n    Err:\n    {err:#?}",
+        );
+    }
+}
+
+pub async fn handle_event(state: &mut AppState, event: &Event, origin: &EventOrigin) -> Result<()> {
+    state.audit.record(event, origin, &state.ws).await;
+    state.mqtt_bridge.publish_event(event, origin);
+
     match event {
         Event::ExternalStateUpdate { device } => {
             state
                 .devices
-                .handle_external_state_update(device, &state.scenes)
+                .handle_external_state_update(device, &state.scenes, origin)
                 .await?;
         }
         Event::StartupCompleted => {
@@ -40,57 +58,57 @@ pub async fn handle_event(state: &mut AppState, event: &Event) -> Result<()> {
                 .expr
                 .invalidate(state.devices.get_state(), &state.groups, &state.scenes);
 
+            state
+                .mqtt_bridge
+                .publish_all(&state.groups, state.devices.get_state());
+
             let device_count = state.devices.get_state().0.len();
             info!("Startup completed, discovered {device_count} devices");
         }
-        Event::InternalStateUpdate {
-            old_state,
-            new_state,
-            old,
-            new,
-        } => {
+        Event::InternalStateUpdate { old, new } => {
             if state.warming_up {
                 return Ok(());
             }
 
+            state.history.record(old, new);
+            state
+                .mqtt_bridge
+                .publish_device(new, &state.groups, state.devices.get_state());
+
             let invalidated_device = new;
             debug!("invalidating {name}", name = invalidated_device.name);
 
-            let _groups_invalidated = state
-                .groups
-                .invalidate(old_state, new_state, &state.devices);
-
-            // TODO: only invalidate changed devices/groups/scenes in expr context
-            state
-                .expr
-                .invalidate(new_state, &state.groups, &state.scenes);
+            state.groups.invalidate(old, &state.devices);
+            state.expr.invalidate_device(
+                old,
+                new,
+                state.devices.get_state(),
+                &state.groups,
+                &state.scenes,
+            );
 
             let invalidated_scenes = state.scenes.invalidate(
-                old_state,
-                new_state,
+                old,
                 invalidated_device,
                 &state.devices,
                 &state.groups,
                 state.expr.get_context(),
             );
 
-            state.devices.invalidate(&invalidated_scenes, &state.scenes);
-
-            // TODO: only invalidate changed devices/groups/scenes in expr context
             state
-                .expr
-                .invalidate(new_state, &state.groups, &state.scenes);
+                .devices
+                .invalidate(&invalidated_scenes, &state.scenes, origin);
+
+            state.expr.invalidate_scenes(
+                &invalidated_scenes,
+                state.devices.get_state(),
+                &state.groups,
+                &state.scenes,
+            );
 
             state
                 .rules
-                .handle_internal_state_update(
-                    old_state,
-                    new_state,
-                    old,
-                    &state.devices,
-                    &state.groups,
-                    &state.expr,
-                )
+                .handle_internal_state_update(old, new, &state.devices, &state.groups, &state.expr)
                 .await;
 
             state.event_tx.send(Event::WsBroadcastState);
@@ -111,9 +129,12 @@ pub async fn handle_event(state: &mut AppState, event: &Event) -> Result<()> {
 
             let device = device.set_scene(device.get_scene_id().as_ref(), &state.scenes);
 
-            state
-                .devices
-                .set_state(&device, skip_external_update.unwrap_or_default(), true);
+            state.devices.set_state(
+                &device,
+                skip_external_update.unwrap_or_default(),
+                true,
+                origin,
+            );
         }
         Event::SetExternalState { device } => {
             let device = device.color_to_preferred_mode();
@@ -165,6 +186,7 @@ pub async fn handle_event(state: &mut AppState, event: &Event) -> Result<()> {
                     &state.groups,
                     &state.scenes,
                     eval_context,
+                    origin,
                 )
                 .await;
         }
@@ -185,6 +207,7 @@ pub async fn handle_event(state: &mut AppState, event: &Event) -> Result<()> {
                     group_keys,
                     &state.scenes,
                     eval_context,
+                    origin,
                 )
                 .await;
         }
@@ -195,7 +218,7 @@ pub async fn handle_event(state: &mut AppState, event: &Event) -> Result<()> {
         })) => {
             state
                 .devices
-                .dim(device_keys, group_keys, step, &state.scenes)
+                .dim(device_keys, group_keys, step, &state.scenes, origin)
                 .await;
         }
         Event::Action(Action::Custom(CustomActionDescriptor {
@@ -213,10 +236,13 @@ pub async fn handle_event(state: &mut AppState, event: &Event) -> Result<()> {
             state.rules.force_trigger_routine(routine_id)?;
         }
         Event::Action(Action::SetDeviceState(device)) => {
-            state.event_tx.send(Event::SetInternalState {
-                device: device.clone(),
-                skip_external_update: None,
-            });
+            state.event_tx.send_with_origin(
+                Event::SetInternalState {
+                    device: device.clone(),
+                    skip_external_update: None,
+                },
+                origin.clone(),
+            );
         }
         Event::Action(Action::ToggleDeviceOverride {
             device_keys,
@@ -243,12 +269,10 @@ pub async fn handle_event(state: &mut AppState, event: &Event) -> Result<()> {
         }
         Event::Action(Action::EvalExpr(expr)) => {
             let eval_context = state.expr.get_context();
-            eval_action_expr(
-                expr,
-                eval_context,
-                state.devices.get_state(),
-                &state.event_tx,
-            )?;
+            let event_tx = state.event_tx.with_origin(EventOrigin::Expr {
+                expr: expr.to_string(),
+            });
+            eval_action_expr(expr, eval_context, state.devices.get_state(), &event_tx)?;
         }
         Event::Action(Action::Ui(action)) => {
             let UiActionDescriptor::StoreUIState { key, value } = action;
@@ -0,0 +1,458 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+};
+
+use tokio::sync::Notify;
+
+use super::recording::EventRecorder;
+use crate::types::{
+    device::DeviceKey,
+    event::{Event, EventOrigin, OriginatedEvent},
+    metrics::EventQueueMetrics,
+};
+
+/// Default maximum number of queued events, see [EventQueue].
+pub const DEFAULT_EVENT_QUEUE_SIZE: usize = 4096;
+
+/// Log a warning about dropped events only this often to avoid flooding logs
+const DROP_WARNING_INTERVAL: u64 = 1000;
+
+/// Events are handled in order of priority, and in FIFO order within the same
+/// priority.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum EventPriority {
+    /// Device state reports from integrations, these may be dropped when the
+    /// queue is full.
+    Low = 0,
+
+    /// Events sent by homectl itself, e.g. as a result of handling another
+    /// event. These are only dropped if the queue is full of them.
+    Normal = 1,
+
+    /// Events caused by users, i.e. sent via the WebSocket or REST API, or
+    /// the MQTT bridge.
+    High = 2,
+}
+
+impl EventPriority {
+    pub fn of(event: &Event, origin: &EventOrigin) -> EventPriority {
+        match (event, origin) {
+            (
+                _,
+                EventOrigin::WebSocket { .. } | EventOrigin::Api { .. } | EventOrigin::Mqtt { .. },
+            ) => EventPriority::High,
+            (Event::ExternalStateUpdate { .. }, _) => EventPriority::Low,
+            _ => EventPriority::Normal,
+        }
+    }
+}
+
+/// Pending events that are superseded by newer events with the same key.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+enum CoalesceKey {
+    ExternalStateUpdate(DeviceKey),
+    SetExternalState(DeviceKey),
+    WsBroadcastState,
+}
+
+impl CoalesceKey {
+    fn of(event: &Event) -> Option<CoalesceKey> {
+        match event {
+            Event::ExternalStateUpdate { device } => {
+                Some(CoalesceKey::ExternalStateUpdate(device.get_device_key()))
+            }
+            Event::SetExternalState { device } => {
+                Some(CoalesceKey::SetExternalState(device.get_device_key()))
+            }
+            Event::WsBroadcastState => Some(CoalesceKey::WsBroadcastState),
+            _ => None,
+        }
+    }
+}
+
+/// Returns true if a pending event can be replaced by a newer one with the
+/// same [CoalesceKey].
+fn supersedes(new: &Event, pending: &Event) -> bool {
+    match (new, pending) {
+        (
+            Event::ExternalStateUpdate { device: new },
+            Event::ExternalStateUpdate { device: pending },
+        ) => {
+            // Every sensor value transition may trigger routines (e.g. a
+            // button press followed by a release), so only reports that didn't
+            // change the sensor value are dropped.
+            !new.is_sensor() || new.data == pending.data
+        }
+        _ => true,
+    }
+}
+
+struct QueuedEvent {
+    event: OriginatedEvent,
+    coalesce_key: Option<CoalesceKey>,
+}
+
+/// FIFO queue of events with a single priority. Events are only ever removed
+/// from the front, so the position of a queued event can be derived from its
+/// sequence number.
+#[derive(Default)]
+struct PriorityQueue {
+    events: VecDeque<QueuedEvent>,
+    head_seq: u64,
+}
+
+impl PriorityQueue {
+    fn push(&mut self, event: QueuedEvent) -> u64 {
+        self.events.push_back(event);
+        self.head_seq + self.events.len() as u64 - 1
+    }
+
+    fn get_mut(&mut self, seq: u64) -> Option<&mut QueuedEvent> {
+        let index = seq.checked_sub(self.head_seq)?;
+        self.events.get_mut(index as usize)
+    }
+
+    fn pop(&mut self) -> Option<(u64, QueuedEvent)> {
+        let event = self.events.pop_front()?;
+        let seq = self.head_seq;
+        self.head_seq += 1;
+
+        Some((seq, event))
+    }
+}
+
+#[derive(Default)]
+struct QueueState {
+    /// Indexed by [EventPriority]
+    queues: [PriorityQueue; 3],
+
+    /// Location of pending events that can be coalesced
+    pending: HashMap<CoalesceKey, (EventPriority, u64)>,
+
+    metrics: EventQueueMetrics,
+}
+
+impl QueueState {
+    fn len(&self) -> usize {
+        self.queues.iter().map(|q| q.events.len()).sum()
+    }
+
+    fn pop_priority(&mut self, priority: EventPriority) -> Option<OriginatedEvent> {
+        let (seq, queued) = self.queues[priority as usize].pop()?;
+
+        if let Some(key) = queued.coalesce_key {
+            if self.pending.get(&key) == Some(&(priority, seq)) {
+                self.pending.remove(&key);
+            }
+        }
+
+        self.metrics.queued = self.len();
+        Some(queued.event)
+    }
+
+    fn pop(&mut self) -> Option<OriginatedEvent> {
+        [
+            EventPriority::High,
+            EventPriority::Normal,
+            EventPriority::Low,
+        ]
+        .into_iter()
+        .find_map(|priority| self.pop_priority(priority))
+    }
+
+    fn record_drop(&mut self) {
+        self.metrics.dropped += 1;
+
+        if self.metrics.dropped % DROP_WARNING_INTERVAL == 1 {
+            warn!(
+                "Event queue is full ({queued} events), {dropped} events dropped so far",
+                queued = self.len(),
+                dropped = self.metrics.dropped,
+            );
+        }
+    }
+
+    fn record_overflow(&mut self, event: &Event) {
+        self.metrics.dropped_overflow += 1;
+
+        if self.metrics.dropped_overflow % DROP_WARNING_INTERVAL == 1 {
+            error!(
+                "Event queue overflowed ({queued} events), dropping {event:?}. {dropped} events dropped so far",
+                queued = self.len(),
+                dropped = self.metrics.dropped_overflow,
+            );
+        }
+    }
+}
+
+/// A bounded event queue shared between all [crate::types::event::Sender]s and
+/// the event loop.
+///
+/// - Events caused by users are handled before internal events, which are
+///   handled before device state reports from integrations.
+/// - Pending events that are superseded by a newer event (e.g. multiple state
+///   reports for the same device) are replaced in place by the newest event.
+/// - When the queue is full, the oldest low priority event is dropped to make
+///   room. If there are no low priority events left, new normal priority
+///   events are dropped. High priority events may exceed the capacity up to
+///   twice its size, so that users can still act while homectl is busy, after
+///   which they are dropped too.
+pub struct EventQueue {
+    state: Mutex<QueueState>,
+    notify: Notify,
+    capacity: usize,
+    receiver_alive: AtomicBool,
+    recorder: OnceLock<EventRecorder>,
+}
+
+impl EventQueue {
+    pub fn new(capacity: usize) -> Arc<EventQueue> {
+        Arc::new(EventQueue {
+            state: Default::default(),
+            notify: Notify::new(),
+            capacity,
+            receiver_alive: AtomicBool::new(true),
+            recorder: OnceLock::new(),
+        })
+    }
+
+    pub fn push(&self, event: OriginatedEvent) {
+        if !self.receiver_alive.load(Ordering::Relaxed) {
+            debug!("Event loop has stopped, discarding event {:?}", event.event);
+            return;
+        }
+
+        if let Some(recorder) = self.recorder.get() {
+            recorder.record(&event);
+        }
+
+        let priority = EventPriority::of(&event.event, &event.origin);
+        let coalesce_key = CoalesceKey::of(&event.event);
+
+        let mut state = self.state.lock().unwrap();
+        state.metrics.received += 1;
+
+        // Replace a pending event with the same key, if any
+        if let Some(key) = &coalesce_key {
+            if let Some(&(pending_priority, seq)) = state.pending.get(key) {
+                if pending_priority == priority {
+                    if let Some(queued) = state.queues[priority as usize].get_mut(seq) {
+                        if supersedes(&event.event, &queued.event.event) {
+                            queued.event = event;
+                            state.metrics.coalesced += 1;
+                            return;
+                        }
+                    }
+                }
+            }
+        }
+
+        if state.len() >= self.capacity {
+            // Make room by dropping the oldest low priority event
+            let evicted =
+                priority > EventPriority::Low && state.pop_priority(EventPriority::Low).is_some();
+
+            if evicted {
+                state.record_drop();
+            } else if priority == EventPriority::Low {
+                state.record_drop();
+                return;
+            } else if priority == EventPriority::Normal || state.len() >= self.capacity * 2 {
+                state.record_overflow(&event.event);
+                return;
+            }
+        }
+
+        let seq = state.queues[priority as usize].push(QueuedEvent {
+            event,
+            coalesce_key: coalesce_key.clone(),
+        });
+
+        if let Some(key) = coalesce_key {
+            state.pending.insert(key, (priority, seq));
+        }
+
+        let queued = state.len();
+        state.metrics.queued = queued;
+        state.metrics.max_queued = state.metrics.max_queued.max(queued);
+        drop(state);
+
+        self.notify.notify_one();
+    }
+
+    /// Removes the next event from the queue, if any.
+    pub fn try_pop(&self) -> Option<OriginatedEvent> {
+        self.state.lock().unwrap().pop()
+    }
+
+    /// Waits until an event is available, and removes it from the queue.
+    pub async fn pop(&self) -> OriginatedEvent {
+        loop {
+            if let Some(event) = self.try_pop() {
+                return event;
+            }
+
+            self.notify.notified().await;
+        }
+    }
+
+    pub fn metrics(&self) -> EventQueueMetrics {
+        self.state.lock().unwrap().metrics.clone()
+    }
+
+    /// Records all events pushed from now on, including ones that end up
+    /// being coalesced or dropped.
+    pub fn record_to(&self, recorder: EventRecorder) {
+        if self.recorder.set(recorder).is_err() {
+            warn!("Event queue is already being recorded");
+        }
+    }
+
+    pub fn close(&self) {
+        self.receiver_alive.store(false, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+    use crate::types::{
+        action::Action,
+        device::{Device, DeviceData, DeviceId, SensorDevice},
+        integration::IntegrationId,
+        rule::{ForceTriggerRoutineDescriptor, RoutineId},
+    };
+
+    fn sensor_update(id: &str, value: bool, linkquality: u64) -> OriginatedEvent {
+        let device = Device::new(
+            IntegrationId::from_str("zigbee").unwrap(),
+            DeviceId::new(id),
+            id.to_string(),
+            DeviceData::Sensor(SensorDevice::Boolean { value, meta: None }),
+            Some(serde_json::json!({ "linkquality": linkquality })),
+        );
+
+        OriginatedEvent {
+            event: Event::ExternalStateUpdate { device },
+            origin: EventOrigin::Integration {
+                integration_id: IntegrationId::from_str("zigbee").unwrap(),
+            },
+        }
+    }
+
+    fn user_action() -> OriginatedEvent {
+        OriginatedEvent {
+            event: Event::Action(Action::ForceTriggerRoutine(ForceTriggerRoutineDescriptor {
+                routine_id: RoutineId("test".to_string()),
+            })),
+            origin: EventOrigin::WebSocket { user_id: 1 },
+        }
+    }
+
+    fn raw_linkquality(event: &OriginatedEvent) -> u64 {
+        match &event.event {
+            Event::ExternalStateUpdate { device } => device.raw.as_ref().unwrap()["linkquality"]
+                .as_u64()
+                .unwrap(),
+            _ => panic!("Expected ExternalStateUpdate, got {:?}", event.event),
+        }
+    }
+
+    #[test]
+    fn test_user_actions_are_prioritized() {
+        let queue = EventQueue::new(16);
+
+        queue.push(sensor_update("a", true, 1));
+        queue.push(OriginatedEvent {
+            event: Event::WsBroadcastState,
+            origin: EventOrigin::Internal,
+        });
+        queue.push(user_action());
+
+        assert!(matches!(queue.try_pop().unwrap().event, Event::Action(_)));
+        assert!(matches!(
+            queue.try_pop().unwrap().event,
+            Event::WsBroadcastState
+        ));
+        assert!(matches!(
+            queue.try_pop().unwrap().event,
+            Event::ExternalStateUpdate { .. }
+        ));
+        assert!(queue.try_pop().is_none());
+    }
+
+    #[test]
+    fn test_superseded_events_are_coalesced() {
+        let queue = EventQueue::new(16);
+
+        queue.push(sensor_update("a", true, 1));
+        queue.push(sensor_update("b", true, 1));
+        queue.push(sensor_update("a", true, 2));
+        queue.push(sensor_update("a", true, 3));
+
+        // The newest report replaces the pending one in place
+        let first = queue.try_pop().unwrap();
+        assert_eq!(raw_linkquality(&first), 3);
+        assert!(queue.try_pop().is_some());
+        assert!(queue.try_pop().is_none());
+
+        assert_eq!(queue.metrics().coalesced, 2);
+    }
+
+    #[test]
+    fn test_sensor_transitions_are_kept() {
+        let queue = EventQueue::new(16);
+
+        queue.push(sensor_update("button", true, 1));
+        queue.push(sensor_update("button", false, 1));
+
+        assert!(queue.try_pop().is_some());
+        assert!(queue.try_pop().is_some());
+        assert_eq!(queue.metrics().coalesced, 0);
+    }
+
+    #[test]
+    fn test_full_queue_drops_sensor_reports() {
+        let queue = EventQueue::new(4);
+
+        for i in 0..6 {
+            queue.push(sensor_update(&format!("sensor{i}"), true, 1));
+        }
+        assert_eq!(queue.metrics().dropped, 2);
+
+        // User actions are still accepted by dropping the oldest sensor report
+        queue.push(user_action());
+        assert_eq!(queue.metrics().dropped, 3);
+        assert!(matches!(queue.try_pop().unwrap().event, Event::Action(_)));
+        assert_eq!(queue.metrics().queued, 3);
+    }
+
+    #[test]
+    fn test_full_queue_stays_bounded() {
+        let queue = EventQueue::new(4);
+
+        for _ in 0..10 {
+            queue.push(OriginatedEvent {
+                event: Event::StartupCompleted,
+                origin: EventOrigin::Internal,
+            });
+        }
+        assert_eq!(queue.metrics().queued, 4);
+        assert_eq!(queue.metrics().dropped_overflow, 6);
+
+        // User actions may use up to twice the capacity
+        for _ in 0..10 {
+            queue.push(user_action());
+        }
+        assert_eq!(queue.metrics().queued, 8);
+        assert_eq!(queue.metrics().max_queued, 8);
+        assert_eq!(queue.metrics().dropped_overflow, 12);
+        assert_eq!(queue.metrics().dropped, 0);
+    }
+}
@@ -15,11 +15,16 @@ use crate::types::{
     group::{FlattenedGroupsConfig, GroupId},
     integration::{CustomActionDescriptor, IntegrationActionPayload, IntegrationId},
     rule::{ForceTriggerRoutineDescriptor, RoutineId},
-    scene::{ActivateSceneDescriptor, FlattenedScenesConfig, SceneDeviceConfig, SceneId},
+    scene::{
+        ActivateSceneDescriptor, FlattenedSceneConfig, FlattenedScenesConfig, SceneDeviceConfig,
+        SceneId,
+    },
 };
 
 use super::{
-    groups::{flattened_groups_to_eval_context_values, Groups},
+    groups::{
+        flattened_group_to_eval_context_values, flattened_groups_to_eval_context_values, Groups,
+    },
     scenes::Scenes,
 };
 
@@ -49,7 +54,7 @@ fn value_kv_pairs_deep(
     }
 }
 
-fn serde_value_to_evalexpr(value: &serde_json::Value) -> Result<Value> {
+pub fn serde_value_to_evalexpr(value: &serde_json::Value) -> Result<Value> {
     match value {
         serde_json::Value::Bool(b) => Ok(Value::Boolean(*b)),
         serde_json::Value::Number(n) => {
@@ -64,7 +69,7 @@ fn serde_value_to_evalexpr(value: &serde_json::Value) -> Result<Value> {
     }
 }
 
-fn evalexpr_value_to_serde(value: &Value) -> Result<serde_json::Value> {
+pub fn evalexpr_value_to_serde(value: &Value) -> Result<serde_json::Value> {
     match value {
         Value::Boolean(b) => Ok(serde_json::Value::Bool(*b)),
         Value::Float(f) => Ok(serde_json::Value::Number(
@@ -86,70 +91,98 @@ fn name_to_evalexpr(device_name: &str) -> String {
     device_name.to_lowercase().replace(' ', "_")
 }
 
-pub fn state_to_eval_context(
-    devices: &DevicesState,
-    flattened_scenes: &FlattenedScenesConfig,
-    flattened_groups: &FlattenedGroupsConfig,
-) -> Result<HashMapContext> {
-    let mut context = HashMapContext::new();
-    context.set_type_safety_checks_disabled(true)?;
+/// Returns eval context values describing the state of a single device
+fn device_eval_context_values(device: &Device) -> Vec<(String, serde_json::Value)> {
+    let prefix = format!(
+        "devices.{}.{}",
+        device.integration_id,
+        name_to_evalexpr(&device.name)
+    );
+
+    let mut values = value_kv_pairs_deep(&device.get_value(), &prefix);
+    if let Some(raw_value) = device.get_raw_value() {
+        let raw_prefix = format!("{prefix}.raw");
+        values.extend(value_kv_pairs_deep(raw_value, &raw_prefix));
+    }
 
-    let mut set_values = |prefix: &str, root: &serde_json::Value| {
-        let values = value_kv_pairs_deep(root, prefix);
+    values
+}
 
-        for (key, value) in values {
-            let value = serde_value_to_evalexpr(&value)?;
-            context.set_value(key, value)?;
-        }
+/// Returns eval context values describing the device states of a single scene
+fn scene_eval_context_values(
+    scene_id: &SceneId,
+    scene: &FlattenedSceneConfig,
+    devices: &DevicesState,
+) -> Result<Vec<(String, serde_json::Value)>> {
+    let prefix = format!("scenes.{}", name_to_evalexpr(&scene_id.to_string()));
+    let mut values = vec![];
 
-        Ok::<(), eyre::Error>(())
-    };
+    for (device_key, state) in &scene.devices.0 {
+        let device = devices.0.get(device_key);
 
-    for device in devices.0.values() {
-        let prefix = format!(
-            "devices.{}.{}",
-            device.integration_id,
-            name_to_evalexpr(&device.name)
-        );
+        let Some(device) = device else {
+            continue;
+        };
 
-        set_values(&prefix, &device.get_value())?;
-        if let Some(raw_value) = device.get_raw_value() {
-            let raw_prefix = format!("{prefix}.raw");
-            set_values(&raw_prefix, raw_value)?;
-        }
+        let integration_id = &device.integration_id;
+        let name = name_to_evalexpr(&device.name.to_lowercase());
+        let prefix = format!("{prefix}.{integration_id}.{name}");
+
+        let value = serde_json::to_value(state)?;
+        values.extend(value_kv_pairs_deep(&value, &prefix));
     }
 
-    for (scene_id, scene) in &flattened_scenes.0 {
-        let prefix = format!("scenes.{}", name_to_evalexpr(&scene_id.to_string()));
+    Ok(values)
+}
 
-        for (device_key, state) in &scene.devices.0 {
-            let device = devices.0.get(device_key);
+/// Converts values to evalexpr values and stores them in the context. Returns
+/// the names of all variables that were set.
+fn set_context_values(
+    context: &mut HashMapContext,
+    values: Vec<(String, serde_json::Value)>,
+) -> Result<HashSet<String>> {
+    let mut keys = HashSet::with_capacity(values.len());
 
-            let Some(device) = device else {
-                continue;
-            };
+    for (key, value) in values {
+        let value = serde_value_to_evalexpr(&value)?;
+        context.set_value(key.clone(), value)?;
+        keys.insert(key);
+    }
+
+    Ok(keys)
+}
 
-            let integration_id = &device.integration_id;
-            let name = name_to_evalexpr(&device.name.to_lowercase());
-            let prefix = format!("{prefix}.{integration_id}.{name}");
+/// Names of the context variables that were derived from each device and
+/// scene, used to clear out stale values during incremental updates.
+#[derive(Clone, Default)]
+struct ContextVars {
+    devices: HashMap<DeviceKey, HashSet<String>>,
+    scenes: HashMap<SceneId, HashSet<String>>,
+}
 
-            let value = serde_json::to_value(state)?;
-            let values = value_kv_pairs_deep(&value, &prefix.clone());
+fn state_to_eval_context(
+    devices: &DevicesState,
+    flattened_scenes: &FlattenedScenesConfig,
+    flattened_groups: &FlattenedGroupsConfig,
+) -> Result<(HashMapContext, ContextVars)> {
+    let mut context = HashMapContext::new();
+    context.set_type_safety_checks_disabled(true)?;
+    let mut vars = ContextVars::default();
 
-            for (key, value) in values {
-                let value = serde_value_to_evalexpr(&value)?;
-                context.set_value(key, value)?;
-            }
-        }
+    for (device_key, device) in &devices.0 {
+        let keys = set_context_values(&mut context, device_eval_context_values(device))?;
+        vars.devices.insert(device_key.clone(), keys);
+    }
+
+    for (scene_id, scene) in &flattened_scenes.0 {
+        let values = scene_eval_context_values(scene_id, scene, devices)?;
+        let keys = set_context_values(&mut context, values)?;
+        vars.scenes.insert(scene_id.clone(), keys);
     }
 
     let group_eval_context_values =
         flattened_groups_to_eval_context_values(flattened_groups, devices);
-
-    for (key, value) in group_eval_context_values {
-        let value = serde_value_to_evalexpr(&value)?;
-        context.set_value(key, value)?;
-    }
+    set_context_values(&mut context, group_eval_context_values)?;
 
     context.set_function("dbg".into(), {
         let context = context.clone();
@@ -164,7 +197,43 @@ pub fn state_to_eval_context(
         })
     })?;
 
-    Ok(context)
+    Ok((context, vars))
+}
+
+/// Stores new values in the context, and removes any variables in `prev_keys`
+/// that are no longer present. Returns the names of all variables that were
+/// set.
+///
+/// evalexpr contexts can't remove a single variable, so if any variables are
+/// stale (e.g. a field disappeared from a device's raw state) every remaining
+/// variable is copied into a cleared context. This is linear in the size of the
+/// whole context, see `benches/expr.rs` for how it compares to a full rebuild.
+fn replace_context_values(
+    context: &mut HashMapContext,
+    prev_keys: Option<&HashSet<String>>,
+    values: Vec<(String, serde_json::Value)>,
+) -> Result<HashSet<String>> {
+    let keys = set_context_values(context, values)?;
+
+    let stale_keys = prev_keys
+        .into_iter()
+        .flatten()
+        .filter(|key| !keys.contains(*key))
+        .collect::<HashSet<_>>();
+
+    if !stale_keys.is_empty() {
+        let retained = context
+            .iter_variables()
+            .filter(|(name, _)| !stale_keys.contains(name))
+            .collect::<Vec<_>>();
+
+        context.clear_variables();
+        for (name, value) in retained {
+            context.set_value(name, value)?;
+        }
+    }
+
+    Ok(keys)
 }
 
 fn tuple_value_to_vec_string(value: &Value) -> EvalexprResult<Vec<String>> {
@@ -413,12 +482,14 @@ pub fn debug_print_context(context: &HashMapContext) {
 #[derive(Clone)]
 pub struct Expr {
     context: HashMapContext,
+    vars: ContextVars,
 }
 
 impl Expr {
     pub fn new() -> Self {
         Expr {
             context: HashMapContext::new(),
+            vars: Default::default(),
         }
     }
 
@@ -426,12 +497,8 @@ impl Expr {
         &self.context
     }
 
-    pub fn recompute(
-        &self,
-        devices_state: &DevicesState,
-        groups: &Groups,
-        scenes: &Scenes,
-    ) -> HashMapContext {
+    /// Recomputes the whole eval context from current state.
+    pub fn invalidate(&mut self, devices_state: &DevicesState, groups: &Groups, scenes: &Scenes) {
         // TODO: decide whether we want to support scene expressions that reference
         // other scenes with expressions
 
@@ -439,13 +506,85 @@ impl Expr {
         let flattened_scenes = scenes.get_flattened_scenes();
         let flattened_groups = groups.get_flattened_groups();
 
-        state_to_eval_context(devices_state, flattened_scenes, flattened_groups)
-            .expect("Failed to create eval context")
-    }
+        let (context, vars) =
+            state_to_eval_context(devices_state, flattened_scenes, flattened_groups)
+                .expect("Failed to create eval context");
 
-    pub fn invalidate(&mut self, devices_state: &DevicesState, groups: &Groups, scenes: &Scenes) {
-        let context = self.recompute(devices_state, groups, scenes);
         self.context = context;
+        self.vars = vars;
+    }
+
+    /// Updates only the values of the given device and the groups it belongs
+    /// to. New or renamed devices cause the whole context to be recomputed, as
+    /// they may affect the variable names of other groups and scenes.
+    pub fn invalidate_device(
+        &mut self,
+        old: &Option<Device>,
+        new: &Device,
+        devices_state: &DevicesState,
+        groups: &Groups,
+        scenes: &Scenes,
+    ) {
+        let renamed = old.as_ref().map(|old| old.name != new.name);
+        if renamed != Some(false) {
+            self.invalidate(devices_state, groups, scenes);
+            return;
+        }
+
+        let result = (|| {
+            let device_key = new.get_device_key();
+
+            let keys = replace_context_values(
+                &mut self.context,
+                self.vars.devices.get(&device_key),
+                device_eval_context_values(new),
+            )?;
+            self.vars.devices.insert(device_key.clone(), keys);
+
+            for (group_id, group) in &groups.get_flattened_groups().0 {
+                if group.device_keys.contains(&device_key) {
+                    let values =
+                        flattened_group_to_eval_context_values(group_id, group, devices_state);
+                    set_context_values(&mut self.context, values)?;
+                }
+            }
+
+            Ok::<(), eyre::Error>(())
+        })();
+
+        if let Err(e) = result {
+            error!("Failed to update eval context for device {new}: {e}");
+            self.invalidate(devices_state, groups, scenes);
+        }
+    }
+
+    /// Updates only the values of the given scenes.
+    pub fn invalidate_scenes(
+        &mut self,
+        scene_ids: &HashSet<SceneId>,
+        devices_state: &DevicesState,
+        groups: &Groups,
+        scenes: &Scenes,
+    ) {
+        let flattened_scenes = scenes.get_flattened_scenes();
+
+        let result = scene_ids.iter().try_for_each(|scene_id| {
+            let values = match flattened_scenes.0.get(scene_id) {
+                Some(scene) => scene_eval_context_values(scene_id, scene, devices_state)?,
+                None => vec![],
+            };
+
+            let keys =
+                replace_context_values(&mut self.context, self.vars.scenes.get(scene_id), values)?;
+            self.vars.scenes.insert(scene_id.clone(), keys);
+
+            Ok::<(), eyre::Error>(())
+        });
+
+        if let Err(e) = result {
+            error!("Failed to update eval context for scenes: {e}");
+            self.invalidate(devices_state, groups, scenes);
+        }
     }
 }
 
@@ -504,3 +643,96 @@ pub fn get_expr_scene_deps(expr: &Node) -> HashSet<SceneId> {
         })
         .collect()
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, str::FromStr};
+
+    use super::*;
+    use crate::types::{
+        color::Capabilities,
+        device::{ControllableDevice, DeviceData, DeviceId, ManageKind},
+        group::GroupsConfig,
+        integration::IntegrationId,
+    };
+
+    const DEVICE_COUNT: usize = 300;
+
+    fn mk_devices_state() -> DevicesState {
+        let devices = (0..DEVICE_COUNT).map(|i| {
+            let device = Device::new(
+                IntegrationId::from_str("bench").unwrap(),
+                DeviceId::new(&format!("{i}")),
+                format!("Light {i}"),
+                DeviceData::Controllable(ControllableDevice::new(
+                    None,
+                    true,
+                    Some(0.5),
+                    None,
+                    None,
+                    Capabilities::default(),
+                    ManageKind::Full,
+                )),
+                Some(serde_json::json!({ "linkquality": i, "update": { "state": "idle" } })),
+            );
+
+            (device.get_device_key(), device)
+        });
+
+        DevicesState(devices.collect::<BTreeMap<_, _>>())
+    }
+
+    fn toggle(device: &Device, i: usize) -> Device {
+        let mut device = device.clone();
+        if let DeviceData::Controllable(ref mut controllable) = device.data {
+            controllable.state.power = i % 2 == 0;
+        }
+        device
+    }
+
+    #[test]
+    fn test_incremental_update_matches_full_recompute() {
+        let groups = Groups::new(GroupsConfig::new());
+        let scenes = Scenes::default();
+
+        let mut state = mk_devices_state();
+        let key = state.0.keys().next().unwrap().clone();
+
+        let mut expr = Expr::new();
+        expr.invalidate(&state, &groups, &scenes);
+
+        let old = Some(state.0[&key].clone());
+        let mut new = toggle(&state.0[&key], 1);
+        new.raw = None;
+        state.0.insert(key.clone(), new.clone());
+        expr.invalidate_device(&old, &new, &state, &groups, &scenes);
+
+        let mut full = Expr::new();
+        full.invalidate(&state, &groups, &scenes);
+
+        let prefix = "devices.bench.light_0";
+        for var in full.vars.devices[&key].iter() {
+            assert_eq!(
+                expr.get_context().get_value(var),
+                full.get_context().get_value(var),
+                "{var} differs"
+            );
+        }
+
+        // Raw values that no longer exist are removed
+        assert_eq!(
+            expr.get_context()
+                .get_value(&format!("{prefix}.raw.linkquality")),
+            None
+        );
+        assert_eq!(
+            expr.get_context().iter_variable_names().count(),
+            full.get_context().iter_variable_names().count()
+        );
+        assert_eq!(
+            expr.get_context()
+                .get_value(&format!("{prefix}.state.power")),
+            Some(&Value::Boolean(false))
+        );
+    }
+}
@@ -1,11 +1,9 @@
 use std::collections::{BTreeMap, BTreeSet};
 
-use crate::{
-    types::{
-        device::{Device, DeviceRef, DevicesState},
-        group::{FlattenedGroupConfig, FlattenedGroupsConfig, GroupConfig, GroupId, GroupsConfig},
-    },
-    utils::keys_match,
+use crate::types::{
+    device::{Device, DeviceRef, DevicesState},
+    group::{FlattenedGroupConfig, FlattenedGroupsConfig, GroupConfig, GroupId, GroupsConfig},
+    scene::SceneId,
 };
 
 use super::devices::Devices;
@@ -99,53 +97,73 @@ pub fn flattened_groups_to_eval_context_values(
         .0
         .iter()
         .flat_map(|(group_id, group)| {
-            let group_devices: Vec<&Device> = group
-                .device_keys
-                .iter()
-                .filter_map(|device_key| devices.0.get(device_key))
-                .collect();
-
-            let all_devices_powered_on = group_devices
-                .iter()
-                .all(|device| device.is_powered_on() == Some(true));
-
-            let first_group_device = group_devices.first();
-
-            // group_scene_id is set only if all devices have the same scene activated
-            let group_scene_id = {
-                let first_device_scene_id = first_group_device.and_then(|d| d.get_scene_id());
-                if group_devices
-                    .iter()
-                    .all(|device| device.get_scene_id() == first_device_scene_id)
-                {
-                    first_device_scene_id
-                } else {
-                    None
-                }
-            };
-
-            let prefix = format!("groups.{group_id}");
-
-            vec![
-                (
-                    format!("{prefix}.name"),
-                    serde_json::Value::String(group.name.clone()),
-                ),
-                (
-                    format!("{prefix}.power"),
-                    serde_json::Value::Bool(all_devices_powered_on),
-                ),
-                (
-                    format!("{prefix}.scene_id"),
-                    group_scene_id
-                        .map(|id| serde_json::Value::String(id.to_string()))
-                        .unwrap_or_else(|| serde_json::Value::Null),
-                ),
-            ]
+            flattened_group_to_eval_context_values(group_id, group, devices)
         })
         .collect()
 }
 
+/// Returns whether all devices of the group are powered on, and the scene
+/// that is active on all of them, if any
+pub fn flattened_group_state(
+    group: &FlattenedGroupConfig,
+    devices: &DevicesState,
+) -> (bool, Option<SceneId>) {
+    let group_devices: Vec<&Device> = group
+        .device_keys
+        .iter()
+        .filter_map(|device_key| devices.0.get(device_key))
+        .collect();
+
+    let all_devices_powered_on = group_devices
+        .iter()
+        .all(|device| device.is_powered_on() == Some(true));
+
+    let first_group_device = group_devices.first();
+
+    // group_scene_id is set only if all devices have the same scene activated
+    let group_scene_id = {
+        let first_device_scene_id = first_group_device.and_then(|d| d.get_scene_id());
+        if group_devices
+            .iter()
+            .all(|device| device.get_scene_id() == first_device_scene_id)
+        {
+            first_device_scene_id
+        } else {
+            None
+        }
+    };
+
+    (all_devices_powered_on, group_scene_id)
+}
+
+/// Returns the eval context values of a single group
+pub fn flattened_group_to_eval_context_values(
+    group_id: &GroupId,
+    group: &FlattenedGroupConfig,
+    devices: &DevicesState,
+) -> Vec<(String, serde_json::Value)> {
+    let (all_devices_powered_on, group_scene_id) = flattened_group_state(group, devices);
+
+    let prefix = format!("groups.{group_id}");
+
+    vec![
+        (
+            format!("{prefix}.name"),
+            serde_json::Value::String(group.name.clone()),
+        ),
+        (
+            format!("{prefix}.power"),
+            serde_json::Value::Bool(all_devices_powered_on),
+        ),
+        (
+            format!("{prefix}.scene_id"),
+            group_scene_id
+                .map(|id| serde_json::Value::String(id.to_string()))
+                .unwrap_or_else(|| serde_json::Value::Null),
+        ),
+    ]
+}
+
 impl Groups {
     pub fn new(config: GroupsConfig) -> Self {
         let device_refs_by_groups = mk_device_refs_by_groups(&config);
@@ -180,14 +198,11 @@ impl Groups {
             .collect()
     }
 
-    pub fn invalidate(
-        &mut self,
-        old_state: &DevicesState,
-        new_state: &DevicesState,
-        devices: &Devices,
-    ) -> bool {
-        // Only invalidate groups if device ids have changed
-        if !keys_match(&old_state.0, &new_state.0) {
+    /// Recomputes flattened groups if needed after `new` has changed state.
+    /// Returns true if groups were recomputed.
+    pub fn invalidate(&mut self, old: &Option<Device>, devices: &Devices) -> bool {
+        // Only invalidate groups if a new device was discovered
+        if old.is_none() {
             self.flattened_groups =
                 mk_flattened_groups(&self.config, &self.device_refs_by_groups, devices);
             true
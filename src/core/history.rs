@@ -0,0 +1,94 @@
+use std::time::Duration;
+
+use crate::db::actions::{
+    db_downsample_device_history, db_insert_device_history, db_prune_device_history,
+};
+use crate::db::spawn_db_write;
+use crate::types::{device::Device, history::HistoryConfig};
+use crate::utils::{cli::Cli, clock};
+
+/// How often old history entries are pruned and downsampled.
+const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Clone)]
+pub struct History {
+    config: HistoryConfig,
+    cli: Cli,
+}
+
+impl History {
+    pub fn new(config: HistoryConfig, cli: &Cli) -> Self {
+        History {
+            config,
+            cli: cli.clone(),
+        }
+    }
+
+    /// Spawns a background task that periodically enforces the configured
+    /// retention and downsampling policies.
+    pub fn start(&self) {
+        let nothing_tracked = self.config.devices.is_none() && self.config.integrations.is_none();
+        if self.cli.dry_run || nothing_tracked {
+            return;
+        }
+
+        let config = self.config.clone();
+
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(MAINTENANCE_INTERVAL);
+
+            loop {
+                interval.tick().await;
+                run_maintenance(&config).await;
+            }
+        });
+    }
+
+    /// Stores the new state of a device in the history table, if the device is
+    /// tracked and its state actually changed.
+    pub fn record(&self, old: &Option<Device>, new: &Device) {
+        // Changes to only the raw field are not interesting
+        if old
+            .as_ref()
+            .map(|old| old.data.is_state_eq(&new.data))
+            .unwrap_or_default()
+        {
+            return;
+        }
+
+        if !self.config.is_tracked(new) {
+            return;
+        }
+
+        if self.cli.dry_run {
+            debug!("(dry run) would record device history: {new}");
+            return;
+        }
+
+        let device = new.clone();
+        spawn_db_write(async move { db_insert_device_history(&device).await });
+    }
+}
+
+async fn run_maintenance(config: &HistoryConfig) {
+    let now = clock::now();
+
+    let retention = chrono::Duration::days(config.retention_days.unwrap_or(30) as i64);
+    match db_prune_device_history(now - retention).await {
+        Ok(count) if count > 0 => info!("Pruned {count} old device history entries"),
+        Ok(_) => {}
+        Err(e) => error!("Failed to prune device history: {e}"),
+    }
+
+    let Some(downsample_after_hours) = config.downsample_after_hours else {
+        return;
+    };
+
+    let downsample_after = chrono::Duration::hours(downsample_after_hours as i64);
+    let resolution = config.downsample_resolution_seconds.unwrap_or(300);
+    match db_downsample_device_history(now - downsample_after, resolution as f64).await {
+        Ok(count) if count > 0 => info!("Downsampled {count} device history entries"),
+        Ok(_) => {}
+        Err(e) => error!("Failed to downsample device history: {e}"),
+    }
+}
@@ -0,0 +1,329 @@
+use std::{
+    str::FromStr,
+    sync::{Arc, Mutex as StdMutex},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use color_eyre::Result;
+use tokio::{sync::Mutex, task::AbortHandle};
+
+use crate::types::{
+    device::{Device, DeviceData, DeviceId, SensorDevice},
+    event::{Event, TxEventChannel},
+    integration::{
+        Integration, IntegrationActionPayload, IntegrationHealth, IntegrationId, IntegrationStatus,
+    },
+};
+use crate::utils::{cli::Cli, clock};
+
+use super::integrations::load_custom_integration;
+
+/// How often running integrations are health checked
+const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Health checks and stopping a failed integration are given up after this
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A degraded integration is restarted after this many consecutive failed
+/// health checks
+const MAX_FAILED_HEALTH_CHECKS: u32 = 3;
+
+const MIN_RESTART_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_RESTART_BACKOFF: Duration = Duration::from_secs(300);
+
+/// Integration id of the synthetic sensor devices that report the status of
+/// each integration
+pub const STATUS_INTEGRATION_ID: &str = "homectl";
+
+/// Everything needed to create a new instance of an integration.
+#[derive(Clone)]
+pub struct IntegrationSpec {
+    pub module_name: String,
+    pub config: config::Value,
+    pub cli: Cli,
+    pub event_tx: TxEventChannel,
+}
+
+impl IntegrationSpec {
+    pub fn create(&self, integration_id: &IntegrationId) -> Result<Box<dyn Integration>> {
+        load_custom_integration(
+            &self.module_name,
+            integration_id,
+            &self.config,
+            &self.cli,
+            self.event_tx.clone(),
+        )
+    }
+}
+
+/// Stands in for an integration that could not be created. Every command
+/// fails until the integration is successfully restarted.
+pub struct NotLoaded {
+    id: IntegrationId,
+}
+
+impl NotLoaded {
+    pub fn boxed(id: &IntegrationId) -> Box<dyn Integration> {
+        Box::new(NotLoaded { id: id.clone() })
+    }
+}
+
+#[async_trait]
+impl Integration for NotLoaded {
+    fn new(id: &IntegrationId, _: &config::Value, _: &Cli, _: TxEventChannel) -> Result<Self> {
+        Ok(NotLoaded { id: id.clone() })
+    }
+
+    async fn set_integration_device_state(&mut self, device: &Device) -> Result<()> {
+        Err(eyre!(
+            "Integration {id} is not loaded, cannot set state of {device}",
+            id = self.id
+        ))
+    }
+
+    async fn run_integration_action(&mut self, payload: &IntegrationActionPayload) -> Result<()> {
+        Err(eyre!(
+            "Integration {id} is not loaded, cannot run action {payload}",
+            id = self.id
+        ))
+    }
+}
+
+/// Shared health of an integration. Status changes are logged and published
+/// as a synthetic sensor device.
+#[derive(Clone)]
+pub struct HealthHandle {
+    integration_id: IntegrationId,
+    health: Arc<StdMutex<IntegrationHealth>>,
+    event_tx: TxEventChannel,
+}
+
+impl HealthHandle {
+    pub fn new(
+        integration_id: &IntegrationId,
+        status: IntegrationStatus,
+        event_tx: TxEventChannel,
+    ) -> Self {
+        let handle = HealthHandle {
+            integration_id: integration_id.clone(),
+            health: Arc::new(StdMutex::new(IntegrationHealth {
+                status,
+                since: clock::now(),
+                restarts: 0,
+            })),
+            event_tx,
+        };
+
+        handle.publish();
+        handle
+    }
+
+    pub fn get(&self) -> IntegrationHealth {
+        self.health.lock().unwrap().clone()
+    }
+
+    pub fn set_status(&self, status: IntegrationStatus) {
+        {
+            let mut health = self.health.lock().unwrap();
+            if health.status == status {
+                return;
+            }
+
+            match status.reason() {
+                Some(reason) => warn!(
+                    "Integration {integration_id} is {status}: {reason}",
+                    integration_id = self.integration_id,
+                    status = status.name()
+                ),
+                None => info!(
+                    "Integration {integration_id} is {status}",
+                    integration_id = self.integration_id,
+                    status = status.name()
+                ),
+            }
+
+            health.status = status;
+            health.since = clock::now();
+        }
+
+        self.publish();
+    }
+
+    fn record_restart(&self) {
+        self.health.lock().unwrap().restarts += 1;
+    }
+
+    /// Sends the current status as a sensor device state update
+    fn publish(&self) {
+        let health = self.get();
+
+        let device = Device::new(
+            IntegrationId::from_str(STATUS_INTEGRATION_ID).unwrap(),
+            DeviceId::new(&format!("integration-{}", self.integration_id)),
+            format!("{} status", self.integration_id),
+            DeviceData::Sensor(SensorDevice::Text {
+                value: health.status.name().to_string(),
+                meta: None,
+            }),
+            serde_json::to_value(&health).ok(),
+        );
+
+        self.event_tx.send(Event::ExternalStateUpdate { device });
+    }
+}
+
+/// Spawns a task that periodically health checks a running integration, and
+/// restarts it with exponential backoff when it has failed. Integrations
+/// without a [IntegrationSpec] can't be restarted and are only health checked.
+pub fn spawn_supervisor(
+    integration_id: &IntegrationId,
+    spec: Option<IntegrationSpec>,
+    integration: Arc<Mutex<Box<dyn Integration>>>,
+    health: HealthHandle,
+) -> AbortHandle {
+    let integration_id = integration_id.clone();
+
+    let task = tokio::spawn(async move {
+        let mut backoff = MIN_RESTART_BACKOFF;
+        let mut failed_health_checks = 0;
+
+        loop {
+            if let IntegrationStatus::Failed { .. } = health.get().status {
+                let Some(spec) = &spec else {
+                    return;
+                };
+
+                debug!("Restarting integration {integration_id} in {backoff:?}");
+                tokio::time::sleep(backoff).await;
+
+                health.record_restart();
+                health.set_status(IntegrationStatus::Loading);
+
+                match restart(&integration_id, spec, &integration).await {
+                    Ok(()) => {
+                        backoff = MIN_RESTART_BACKOFF;
+                        failed_health_checks = 0;
+                        health.set_status(IntegrationStatus::Running);
+                    }
+                    Err(e) => {
+                        backoff = (backoff * 2).min(MAX_RESTART_BACKOFF);
+                        health.set_status(IntegrationStatus::Failed {
+                            reason: e.to_string(),
+                        });
+                    }
+                }
+
+                continue;
+            }
+
+            tokio::time::sleep(HEALTH_CHECK_INTERVAL).await;
+
+            // The worker holds the lock while the integration performs device
+            // I/O, a busy integration is not considered unhealthy
+            let Ok(mut locked) = integration.try_lock() else {
+                debug!("Integration {integration_id} is busy, skipping health check");
+                continue;
+            };
+
+            let result =
+                match tokio::time::timeout(HEALTH_CHECK_TIMEOUT, locked.health_check()).await {
+                    Ok(result) => result,
+                    Err(_) => Err(eyre!("Health check timed out")),
+                };
+            drop(locked);
+
+            match result {
+                Ok(()) => {
+                    failed_health_checks = 0;
+                    health.set_status(IntegrationStatus::Running);
+                }
+                Err(e) => {
+                    failed_health_checks += 1;
+
+                    let reason = e.to_string();
+                    if failed_health_checks >= MAX_FAILED_HEALTH_CHECKS {
+                        health.set_status(IntegrationStatus::Failed { reason });
+                    } else {
+                        health.set_status(IntegrationStatus::Degraded { reason });
+                    }
+                }
+            }
+        }
+    });
+
+    task.abort_handle()
+}
+
+/// Stops the current instance of an integration, and replaces it with a newly
+/// created, registered and started instance.
+async fn restart(
+    integration_id: &IntegrationId,
+    spec: &IntegrationSpec,
+    integration: &Arc<Mutex<Box<dyn Integration>>>,
+) -> Result<()> {
+    // Hold the lock so that the worker doesn't send commands meanwhile
+    let mut integration = integration.lock().await;
+
+    match tokio::time::timeout(HEALTH_CHECK_TIMEOUT, integration.stop()).await {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => debug!("Failed to stop integration {integration_id}: {e:?}"),
+        Err(_) => debug!("Timed out while stopping integration {integration_id}"),
+    }
+
+    let restarted = async {
+        let mut restarted = spec.create(integration_id)?;
+        restarted.register().await?;
+        restarted.start().await?;
+
+        Ok::<_, color_eyre::Report>(restarted)
+    };
+
+    match restarted.await {
+        Ok(restarted) => {
+            *integration = restarted;
+            Ok(())
+        }
+        Err(e) => {
+            *integration = NotLoaded::boxed(integration_id);
+            Err(e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::event::mk_event_channel;
+
+    struct Healthy;
+
+    #[async_trait]
+    impl Integration for Healthy {
+        fn new(_: &IntegrationId, _: &config::Value, _: &Cli, _: TxEventChannel) -> Result<Self> {
+            Ok(Healthy)
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_busy_integration_is_not_degraded() {
+        let (event_tx, _event_rx) = mk_event_channel();
+        let integration_id = IntegrationId::from_str("busy").unwrap();
+        let health = HealthHandle::new(&integration_id, IntegrationStatus::Running, event_tx);
+
+        let integration: Arc<Mutex<Box<dyn Integration>>> = Arc::new(Mutex::new(Box::new(Healthy)));
+        let supervisor =
+            spawn_supervisor(&integration_id, None, integration.clone(), health.clone());
+
+        // Hold the lock like the worker does during slow device I/O
+        let busy = integration.lock().await;
+        tokio::time::sleep(HEALTH_CHECK_INTERVAL * (MAX_FAILED_HEALTH_CHECKS + 1)).await;
+        assert_eq!(health.get().status, IntegrationStatus::Running);
+
+        drop(busy);
+        tokio::time::sleep(HEALTH_CHECK_INTERVAL * 2).await;
+        assert_eq!(health.get().status, IntegrationStatus::Running);
+
+        supervisor.abort();
+    }
+}
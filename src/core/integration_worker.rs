@@ -0,0 +1,226 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Arc,
+    time::Duration,
+};
+
+use color_eyre::Result;
+use tokio::{
+    sync::{Mutex, Notify},
+    time::Instant,
+};
+
+use crate::types::{
+    device::{Device, DeviceKey},
+    integration::{Integration, IntegrationActionPayload, IntegrationId},
+};
+
+/// Maximum number of pending integration actions. When an integration falls
+/// this far behind, new actions are dropped instead of blocking the core event
+/// loop. Device states don't count towards this limit as there is at most one
+/// pending state per device.
+pub const MAX_PENDING_ACTIONS: usize = 256;
+
+#[derive(Debug)]
+enum PendingJob {
+    SetDeviceState(DeviceKey),
+    RunAction(IntegrationActionPayload),
+}
+
+/// Work that is performed on an integration's worker task.
+#[derive(Debug)]
+enum Job {
+    SetDeviceStates(Vec<Device>),
+    RunAction(IntegrationActionPayload),
+}
+
+#[derive(Default)]
+struct PendingJobs {
+    order: VecDeque<PendingJob>,
+
+    /// Latest requested state of each device with a pending state update
+    states: HashMap<DeviceKey, Device>,
+
+    actions: usize,
+}
+
+impl PendingJobs {
+    fn is_empty(&self) -> bool {
+        self.order.is_empty()
+    }
+
+    /// Takes the next job from the front of the queue. If `batch` is set,
+    /// consecutive device state updates are combined into one job.
+    fn next(&mut self, batch: bool) -> Option<Job> {
+        match self.order.pop_front()? {
+            PendingJob::RunAction(payload) => {
+                self.actions -= 1;
+                Some(Job::RunAction(payload))
+            }
+            PendingJob::SetDeviceState(device_key) => {
+                let mut devices: Vec<Device> =
+                    self.states.remove(&device_key).into_iter().collect();
+
+                while batch && matches!(self.order.front(), Some(PendingJob::SetDeviceState(_))) {
+                    if let Some(PendingJob::SetDeviceState(device_key)) = self.order.pop_front() {
+                        devices.extend(self.states.remove(&device_key));
+                    }
+                }
+
+                Some(Job::SetDeviceStates(devices))
+            }
+        }
+    }
+}
+
+struct WorkerShared {
+    integration_id: IntegrationId,
+    pending: std::sync::Mutex<PendingJobs>,
+    notify: Notify,
+
+    /// Notified whenever the worker runs out of pending jobs
+    idle: Notify,
+}
+
+/// Runs device state updates and actions of a single integration on a
+/// dedicated task, so that slow integration I/O never blocks the core event
+/// loop or other integrations.
+///
+/// Pending state updates are coalesced per device, so that only the latest
+/// requested state is sent if the integration falls behind. Commands can
+/// optionally be rate limited, which gives more time for coalescing.
+#[derive(Clone)]
+pub struct IntegrationWorker {
+    shared: Arc<WorkerShared>,
+}
+
+impl IntegrationWorker {
+    /// Spawns a worker task for the integration. If `min_interval` is set, at
+    /// most one command per interval is sent to the integration, where a batch
+    /// of device states counts as a single command.
+    pub fn spawn(
+        integration_id: &IntegrationId,
+        integration: Arc<Mutex<Box<dyn Integration>>>,
+        supports_batch_updates: bool,
+        min_interval: Option<Duration>,
+    ) -> Self {
+        let shared = Arc::new(WorkerShared {
+            integration_id: integration_id.clone(),
+            pending: Default::default(),
+            notify: Notify::new(),
+            idle: Notify::new(),
+        });
+
+        {
+            let shared = shared.clone();
+
+            tokio::spawn(async move {
+                let mut last_command: Option<Instant> = None;
+
+                loop {
+                    loop {
+                        let is_empty = shared.pending.lock().unwrap().is_empty();
+                        if !is_empty {
+                            break;
+                        }
+
+                        shared.notify.notified().await;
+                    }
+
+                    if let (Some(min_interval), Some(last_command)) = (min_interval, last_command) {
+                        tokio::time::sleep_until(last_command + min_interval).await;
+                    }
+
+                    // Take the job only once the integration is locked, so that
+                    // holding the lock guarantees no job is in flight
+                    let mut integration = integration.lock().await;
+                    let job = shared.pending.lock().unwrap().next(supports_batch_updates);
+                    let Some(job) = job else {
+                        continue;
+                    };
+
+                    last_command = Some(Instant::now());
+
+                    let result = match &job {
+                        Job::SetDeviceStates(devices) => {
+                            integration.set_integration_device_states(devices).await
+                        }
+                        Job::RunAction(payload) => {
+                            integration.run_integration_action(payload).await
+                        }
+                    };
+
+                    if let Err(e) = result {
+                        error!(
+                            "Integration {integration_id} failed to run job {job:?}: {e:?}",
+                            integration_id = shared.integration_id
+                        );
+                    }
+
+                    drop(integration);
+
+                    let is_empty = shared.pending.lock().unwrap().is_empty();
+                    if is_empty {
+                        shared.idle.notify_waiters();
+                    }
+                }
+            });
+        }
+
+        IntegrationWorker { shared }
+    }
+
+    /// Queues a device state update, replacing any pending update for the
+    /// same device.
+    pub fn set_device_state(&self, device: Device) {
+        let mut pending = self.shared.pending.lock().unwrap();
+        let device_key = device.get_device_key();
+
+        if pending.states.insert(device_key.clone(), device).is_none() {
+            pending
+                .order
+                .push_back(PendingJob::SetDeviceState(device_key));
+        } else {
+            trace!("Coalesced pending state update for {device_key}");
+        }
+
+        drop(pending);
+        self.shared.notify.notify_one();
+    }
+
+    /// Queues an integration action. Returns an error if too many actions are
+    /// already pending.
+    pub fn run_action(&self, payload: IntegrationActionPayload) -> Result<()> {
+        let mut pending = self.shared.pending.lock().unwrap();
+
+        if pending.actions >= MAX_PENDING_ACTIONS {
+            return Err(eyre!(
+                "Too many pending actions for integration {integration_id}, dropping action {payload}",
+                integration_id = self.shared.integration_id
+            ));
+        }
+
+        pending.actions += 1;
+        pending.order.push_back(PendingJob::RunAction(payload));
+
+        drop(pending);
+        self.shared.notify.notify_one();
+
+        Ok(())
+    }
+
+    /// Waits until there are no more pending jobs. A job may still be in
+    /// flight when this returns, lock the integration to wait for it.
+    pub async fn wait_idle(&self) {
+        loop {
+            let idle = self.shared.idle.notified();
+
+            let is_empty = self.shared.pending.lock().unwrap().is_empty();
+            if is_empty {
+                return;
+            }
+
+            idle.await;
+        }
+    }
+}
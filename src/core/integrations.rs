@@ -1,22 +1,80 @@
 use crate::integrations::cron::Cron;
+#[cfg(feature = "wasm")]
+use crate::integrations::wasm::Wasm;
 use crate::integrations::{
-    circadian::Circadian, dummy::Dummy, mqtt::Mqtt, random::Random, timer::Timer,
+    circadian::Circadian, dummy::Dummy, exec::Exec, mqtt::Mqtt, random::Random, shelly::Shelly,
+    timer::Timer, wled::Wled, zigbee2mqtt::Zigbee2Mqtt,
 };
 use crate::types::{
     device::Device,
-    event::TxEventChannel,
-    integration::{Integration, IntegrationActionPayload, IntegrationId},
+    event::{EventOrigin, TxEventChannel},
+    integration::{
+        Integration, IntegrationActionPayload, IntegrationConfig, IntegrationHealth, IntegrationId,
+        IntegrationStatus,
+    },
 };
 use crate::utils::cli::Cli;
 use color_eyre::Result;
 use eyre::eyre;
-use std::{collections::HashMap, sync::Arc};
-use tokio::sync::Mutex;
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{sync::Mutex, task::AbortHandle};
+
+use super::integration_supervisor::{spawn_supervisor, HealthHandle, IntegrationSpec, NotLoaded};
+use super::integration_worker::IntegrationWorker;
 
 #[derive(Clone)]
 pub struct LoadedIntegration {
     integration: Arc<Mutex<Box<dyn Integration>>>,
     module_name: String,
+    worker: IntegrationWorker,
+    health: HealthHandle,
+
+    /// Used for restarting the integration, not set for integrations that were
+    /// not loaded from config
+    spec: Option<IntegrationSpec>,
+
+    supervisor: Option<AbortHandle>,
+}
+
+impl LoadedIntegration {
+    fn new(
+        integration_id: &IntegrationId,
+        module_name: &str,
+        integration: Box<dyn Integration>,
+        max_commands_per_second: Option<f64>,
+        health: HealthHandle,
+    ) -> Self {
+        let supports_batch_updates = integration.supports_batch_updates();
+        let integration = Arc::new(Mutex::new(integration));
+
+        let min_interval = max_commands_per_second
+            .filter(|rate| *rate > 0.0)
+            .map(|rate| Duration::from_secs_f64(1.0 / rate));
+
+        let worker = IntegrationWorker::spawn(
+            integration_id,
+            integration.clone(),
+            supports_batch_updates,
+            min_interval,
+        );
+
+        LoadedIntegration {
+            integration,
+            module_name: module_name.to_string(),
+            worker,
+            health,
+            spec: None,
+            supervisor: None,
+        }
+    }
+
+    fn is_loading(&self) -> bool {
+        self.health.get().status == IntegrationStatus::Loading
+    }
 }
 
 pub type CustomIntegrationsMap = HashMap<IntegrationId, LoadedIntegration>;
@@ -37,55 +95,195 @@ impl Integrations {
         }
     }
 
-    pub async fn load_integration(
+    /// Loads an integration from config. If the integration can't be
+    /// created, it's marked as failed and will be retried by its supervisor
+    /// once started.
+    pub fn load_integration(
         &mut self,
-        module_name: &str,
+        integration_config: &IntegrationConfig,
         integration_id: &IntegrationId,
         config: &config::Value,
         cli: &Cli,
-    ) -> Result<()> {
+    ) {
+        let module_name = &integration_config.plugin;
         info!("loading integration with module_name {module_name}");
 
-        let event_tx = self.event_tx.clone();
-        let integration =
-            load_custom_integration(module_name, integration_id, config, cli, event_tx)?;
+        let spec = IntegrationSpec {
+            module_name: module_name.clone(),
+            config: config.clone(),
+            cli: cli.clone(),
+            event_tx: self.event_tx.with_origin(EventOrigin::Integration {
+                integration_id: integration_id.clone(),
+            }),
+        };
 
-        let loaded_integration = LoadedIntegration {
-            integration: Arc::new(Mutex::new(integration)),
-            module_name: module_name.to_string(),
+        let (integration, status) = match spec.create(integration_id) {
+            Ok(integration) => (integration, IntegrationStatus::Loading),
+            Err(e) => {
+                error!("Failed to load integration {integration_id}: {e:?}");
+                (
+                    NotLoaded::boxed(integration_id),
+                    IntegrationStatus::Failed {
+                        reason: e.to_string(),
+                    },
+                )
+            }
         };
 
+        let mut loaded_integration = self.mk_loaded_integration(
+            integration_id,
+            module_name,
+            integration,
+            integration_config.max_commands_per_second,
+            status,
+        );
+        loaded_integration.spec = Some(spec);
+
         self.custom_integrations
             .insert(integration_id.clone(), loaded_integration);
+    }
 
-        Ok(())
+    fn mk_loaded_integration(
+        &self,
+        integration_id: &IntegrationId,
+        module_name: &str,
+        integration: Box<dyn Integration>,
+        max_commands_per_second: Option<f64>,
+        status: IntegrationStatus,
+    ) -> LoadedIntegration {
+        let health = HealthHandle::new(integration_id, status, self.event_tx.clone());
+
+        LoadedIntegration::new(
+            integration_id,
+            module_name,
+            integration,
+            max_commands_per_second,
+            health,
+        )
     }
 
-    pub async fn run_register_pass(&self) -> Result<()> {
+    #[cfg(test)]
+    fn insert_integration(
+        &mut self,
+        integration_id: &IntegrationId,
+        module_name: &str,
+        integration: Box<dyn Integration>,
+        max_commands_per_second: Option<f64>,
+    ) {
+        let loaded_integration = self.mk_loaded_integration(
+            integration_id,
+            module_name,
+            integration,
+            max_commands_per_second,
+            IntegrationStatus::Loading,
+        );
+
+        self.custom_integrations
+            .insert(integration_id.clone(), loaded_integration);
+    }
+
+    pub async fn run_register_pass(&self) {
         for (integration_id, li) in self.custom_integrations.iter() {
-            let mut integration = li.integration.lock().await;
+            if !li.is_loading() {
+                continue;
+            }
 
-            integration.register().await.unwrap();
-            info!(
-                "registered {} integration {}",
-                li.module_name, integration_id
-            );
+            let result = li.integration.lock().await.register().await;
+
+            match result {
+                Ok(()) => info!(
+                    "registered {} integration {}",
+                    li.module_name, integration_id
+                ),
+                Err(e) => {
+                    error!("Failed to register integration {integration_id}: {e:?}");
+                    li.health.set_status(IntegrationStatus::Failed {
+                        reason: e.to_string(),
+                    });
+                }
+            }
         }
+    }
 
-        Ok(())
+    /// Starts all successfully registered integrations, and spawns a
+    /// supervisor for every integration that restarts it if it fails.
+    pub async fn run_start_pass(&mut self) {
+        for (integration_id, li) in self.custom_integrations.iter_mut() {
+            if li.is_loading() {
+                let result = li.integration.lock().await.start().await;
+
+                match result {
+                    Ok(()) => {
+                        info!("started {} integration {}", li.module_name, integration_id);
+                        li.health.set_status(IntegrationStatus::Running);
+                    }
+                    Err(e) => {
+                        error!("Failed to start integration {integration_id}: {e:?}");
+                        li.health.set_status(IntegrationStatus::Failed {
+                            reason: e.to_string(),
+                        });
+                    }
+                }
+            }
+
+            li.supervisor = Some(spawn_supervisor(
+                integration_id,
+                li.spec.clone(),
+                li.integration.clone(),
+                li.health.clone(),
+            ));
+        }
     }
 
-    pub async fn run_start_pass(&self) -> Result<()> {
+    pub fn get_health(&self) -> BTreeMap<IntegrationId, IntegrationHealth> {
+        self.custom_integrations
+            .iter()
+            .map(|(integration_id, li)| (integration_id.clone(), li.health.get()))
+            .collect()
+    }
+
+    /// Sends any pending device states and actions, then stops every
+    /// integration. Integrations that don't finish within `timeout` are
+    /// skipped. Returns an error if any integration failed to stop cleanly.
+    pub async fn run_stop_pass(&self, timeout: Duration) -> Result<()> {
+        let mut failed = vec![];
+
         for (integration_id, li) in self.custom_integrations.iter() {
-            let mut integration = li.integration.lock().await;
+            // Don't let supervisors restart integrations while stopping them
+            if let Some(supervisor) = &li.supervisor {
+                supervisor.abort();
+            }
 
-            integration.start().await.unwrap();
-            info!("started {} integration {}", li.module_name, integration_id);
+            let stop = async {
+                li.worker.wait_idle().await;
+                li.integration.lock().await.stop().await
+            };
+
+            match tokio::time::timeout(timeout, stop).await {
+                Ok(Ok(())) => info!("stopped {} integration {}", li.module_name, integration_id),
+                Ok(Err(e)) => {
+                    error!("Failed to stop integration {integration_id}: {e:?}");
+                    failed.push(integration_id.to_string());
+                }
+                Err(_) => {
+                    error!("Timed out while stopping integration {integration_id}");
+                    failed.push(integration_id.to_string());
+                }
+            }
         }
 
-        Ok(())
+        if failed.is_empty() {
+            Ok(())
+        } else {
+            Err(eyre!(
+                "Integrations did not stop cleanly: {}",
+                failed.join(", ")
+            ))
+        }
     }
 
+    /// Queues a device state update to be sent by the integration on its
+    /// worker task, replacing any pending update for the same device.
     pub async fn set_integration_device_state(&self, device: Device) -> Result<()> {
         if device.is_readonly() {
             debug!(
@@ -110,13 +308,13 @@ impl Integrations {
                 )
             })?;
 
-        let mut integration = li.integration.lock().await;
+        li.worker.set_device_state(device);
 
-        integration
-            .set_integration_device_state(&device.clone())
-            .await
+        Ok(())
     }
 
+    /// Queues an integration action to be run on the integration's worker
+    /// task. Returns an error only if the action could not be queued.
     pub async fn run_integration_action(
         &self,
         integration_id: &IntegrationId,
@@ -126,15 +324,14 @@ impl Integrations {
             .custom_integrations
             .get(integration_id)
             .ok_or_else(|| eyre!("Expected to find integration by id {integration_id}"))?;
-        let mut integration = li.integration.lock().await;
 
-        integration.run_integration_action(payload).await
+        li.worker.run_action(payload.clone())
     }
 }
 
-// TODO: Load integrations dynamically as plugins:
-// https://michael-f-bryan.github.io/rust-ffi-guide/dynamic_loading.html
-fn load_custom_integration(
+// Integrations that are not built into homectl can be loaded with the `exec`
+// plugin, see docs/exec-plugins.md
+pub fn load_custom_integration(
     module_name: &str,
     id: &IntegrationId,
     config: &config::Value,
@@ -148,6 +345,373 @@ fn load_custom_integration(
         "timer" => Ok(Box::new(Timer::new(id, config, cli, event_tx)?)),
         "dummy" => Ok(Box::new(Dummy::new(id, config, cli, event_tx)?)),
         "mqtt" => Ok(Box::new(Mqtt::new(id, config, cli, event_tx)?)),
+        "zigbee2mqtt" => Ok(Box::new(Zigbee2Mqtt::new(id, config, cli, event_tx)?)),
+        "shelly" => Ok(Box::new(Shelly::new(id, config, cli, event_tx)?)),
+        "wled" => Ok(Box::new(Wled::new(id, config, cli, event_tx)?)),
+        "exec" => Ok(Box::new(Exec::new(id, config, cli, event_tx)?)),
+        #[cfg(feature = "wasm")]
+        "wasm" => Ok(Box::new(Wasm::new(id, config, cli, event_tx)?)),
+        #[cfg(not(feature = "wasm"))]
+        "wasm" => Err(eyre!(
+            "WebAssembly plugins are not supported by this build, rebuild with --features wasm"
+        )),
         _ => Err(eyre!("Unknown module name {module_name}!")),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{str::FromStr, time::Duration};
+
+    use async_trait::async_trait;
+    use tokio::sync::{mpsc::UnboundedSender, Semaphore};
+
+    use super::*;
+    use crate::core::integration_worker::MAX_PENDING_ACTIONS;
+    use crate::types::{
+        color::Capabilities,
+        device::{ControllableDevice, DeviceData, DeviceId, ManageKind, SensorDevice},
+        event::{mk_event_channel, Event},
+    };
+
+    /// Integration that never finishes sending device states or running
+    /// actions
+    struct Stalled;
+
+    /// Integration that reports every device state it's asked to send
+    struct Recording {
+        sent_tx: UnboundedSender<Device>,
+    }
+
+    /// Integration that supports batch updates, and waits for a permit from
+    /// `gate` before sending each batch
+    struct Batching {
+        sent_tx: UnboundedSender<Vec<Device>>,
+        gate: Arc<Semaphore>,
+    }
+
+    #[async_trait]
+    impl Integration for Stalled {
+        fn new(_: &IntegrationId, _: &config::Value, _: &Cli, _: TxEventChannel) -> Result<Self> {
+            Ok(Stalled)
+        }
+
+        async fn set_integration_device_state(&mut self, _device: &Device) -> Result<()> {
+            std::future::pending().await
+        }
+
+        async fn run_integration_action(&mut self, _: &IntegrationActionPayload) -> Result<()> {
+            std::future::pending().await
+        }
+    }
+
+    #[async_trait]
+    impl Integration for Recording {
+        fn new(_: &IntegrationId, _: &config::Value, _: &Cli, _: TxEventChannel) -> Result<Self> {
+            // Devices sent by an integration created this way are discarded
+            let (sent_tx, _) = tokio::sync::mpsc::unbounded_channel();
+            Ok(Recording { sent_tx })
+        }
+
+        async fn set_integration_device_state(&mut self, device: &Device) -> Result<()> {
+            self.sent_tx.send(device.clone()).ok();
+            Ok(())
+        }
+    }
+
+    #[async_trait]
+    impl Integration for Batching {
+        fn new(_: &IntegrationId, _: &config::Value, _: &Cli, _: TxEventChannel) -> Result<Self> {
+            // Batches are sent without waiting and then discarded
+            let (sent_tx, _) = tokio::sync::mpsc::unbounded_channel();
+            let gate = Arc::new(Semaphore::new(Semaphore::MAX_PERMITS));
+            Ok(Batching { sent_tx, gate })
+        }
+
+        fn supports_batch_updates(&self) -> bool {
+            true
+        }
+
+        async fn set_integration_device_states(&mut self, devices: &[Device]) -> Result<()> {
+            self.gate.acquire().await.unwrap().forget();
+            self.sent_tx.send(devices.to_vec()).ok();
+            Ok(())
+        }
+    }
+
+    /// Integration that fails to start
+    struct FailingStart;
+
+    #[async_trait]
+    impl Integration for FailingStart {
+        fn new(_: &IntegrationId, _: &config::Value, _: &Cli, _: TxEventChannel) -> Result<Self> {
+            Ok(FailingStart)
+        }
+
+        async fn start(&mut self) -> Result<()> {
+            Err(eyre!("Could not connect"))
+        }
+    }
+
+    fn mk_device(integration_id: &IntegrationId, device_id: &str, brightness: f32) -> Device {
+        Device::new(
+            integration_id.clone(),
+            DeviceId::new(device_id),
+            device_id.to_string(),
+            DeviceData::Controllable(ControllableDevice::new(
+                None,
+                true,
+                Some(brightness),
+                None,
+                None,
+                Capabilities::default(),
+                ManageKind::Full,
+            )),
+            None,
+        )
+    }
+
+    fn brightness(device: &Device) -> f32 {
+        device
+            .get_controllable_state()
+            .and_then(|state| state.brightness)
+            .unwrap()
+            .0
+    }
+
+    #[tokio::test]
+    async fn test_stalled_integration_does_not_block_others() {
+        let (event_tx, _event_rx) = mk_event_channel();
+        let mut integrations = Integrations::new(event_tx);
+
+        let stalled_id = IntegrationId::from_str("stalled").unwrap();
+        let recording_id = IntegrationId::from_str("recording").unwrap();
+        let (sent_tx, mut sent_rx) = tokio::sync::mpsc::unbounded_channel();
+
+        integrations.insert_integration(&stalled_id, "stalled", Box::new(Stalled), None);
+        integrations.insert_integration(
+            &recording_id,
+            "recording",
+            Box::new(Recording { sent_tx }),
+            None,
+        );
+
+        // Queueing states for the stalled integration must not wait for it
+        for i in 0..10 {
+            tokio::time::timeout(
+                Duration::from_millis(100),
+                integrations.set_integration_device_state(mk_device(
+                    &stalled_id,
+                    &format!("light{i}"),
+                    1.0,
+                )),
+            )
+            .await
+            .expect("Queueing a device state blocked on a stalled integration")
+            .unwrap();
+        }
+
+        integrations
+            .set_integration_device_state(mk_device(&recording_id, "light", 1.0))
+            .await
+            .unwrap();
+
+        let sent = tokio::time::timeout(Duration::from_secs(1), sent_rx.recv())
+            .await
+            .expect("Recording integration was blocked by stalled integration")
+            .unwrap();
+
+        assert_eq!(sent.integration_id, recording_id);
+    }
+
+    #[tokio::test]
+    async fn test_too_many_pending_actions_are_dropped() {
+        let (event_tx, _event_rx) = mk_event_channel();
+        let mut integrations = Integrations::new(event_tx);
+
+        let stalled_id = IntegrationId::from_str("stalled").unwrap();
+        integrations.insert_integration(&stalled_id, "stalled", Box::new(Stalled), None);
+
+        let payload = IntegrationActionPayload::from("test".to_string());
+        let results = futures::future::join_all(
+            (0..MAX_PENDING_ACTIONS + 10)
+                .map(|_| integrations.run_integration_action(&stalled_id, &payload)),
+        )
+        .await;
+
+        assert!(results.iter().any(|result| result.is_err()));
+    }
+
+    #[tokio::test]
+    async fn test_pending_states_are_coalesced_and_batched() {
+        let (event_tx, _event_rx) = mk_event_channel();
+        let mut integrations = Integrations::new(event_tx);
+
+        let batching_id = IntegrationId::from_str("batching").unwrap();
+        let gate = Arc::new(Semaphore::new(0));
+        let (sent_tx, mut sent_rx) = tokio::sync::mpsc::unbounded_channel();
+        integrations.insert_integration(
+            &batching_id,
+            "batching",
+            Box::new(Batching {
+                sent_tx,
+                gate: gate.clone(),
+            }),
+            None,
+        );
+
+        // Worker picks up the first state and waits for the gate
+        integrations
+            .set_integration_device_state(mk_device(&batching_id, "light1", 0.1))
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        for (device_id, brightness) in [("light1", 0.2), ("light2", 0.5), ("light1", 0.3)] {
+            integrations
+                .set_integration_device_state(mk_device(&batching_id, device_id, brightness))
+                .await
+                .unwrap();
+        }
+
+        gate.add_permits(2);
+
+        let first = sent_rx.recv().await.unwrap();
+        assert_eq!(first.len(), 1);
+        assert_eq!(brightness(&first[0]), 0.1);
+
+        let second = sent_rx.recv().await.unwrap();
+        let second: Vec<(String, f32)> = second
+            .iter()
+            .map(|device| (device.name.clone(), brightness(device)))
+            .collect();
+        assert_eq!(
+            second,
+            vec![("light1".to_string(), 0.3), ("light2".to_string(), 0.5)]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_commands_are_rate_limited() {
+        let (event_tx, _event_rx) = mk_event_channel();
+        let mut integrations = Integrations::new(event_tx);
+
+        let recording_id = IntegrationId::from_str("recording").unwrap();
+        let (sent_tx, mut sent_rx) = tokio::sync::mpsc::unbounded_channel();
+        integrations.insert_integration(
+            &recording_id,
+            "recording",
+            Box::new(Recording { sent_tx }),
+            Some(20.0),
+        );
+
+        let start = tokio::time::Instant::now();
+        for device_id in ["light1", "light2", "light3"] {
+            integrations
+                .set_integration_device_state(mk_device(&recording_id, device_id, 1.0))
+                .await
+                .unwrap();
+        }
+
+        for _ in 0..3 {
+            sent_rx.recv().await.unwrap();
+        }
+
+        // Three commands at 20 per second take at least two 50ms intervals
+        assert!(start.elapsed() >= Duration::from_millis(100));
+    }
+
+    #[tokio::test]
+    async fn test_stop_sends_pending_states() {
+        let (event_tx, _event_rx) = mk_event_channel();
+        let mut integrations = Integrations::new(event_tx);
+
+        let recording_id = IntegrationId::from_str("recording").unwrap();
+        let (sent_tx, mut sent_rx) = tokio::sync::mpsc::unbounded_channel();
+        integrations.insert_integration(
+            &recording_id,
+            "recording",
+            Box::new(Recording { sent_tx }),
+            Some(20.0),
+        );
+
+        for device_id in ["light1", "light2", "light3"] {
+            integrations
+                .set_integration_device_state(mk_device(&recording_id, device_id, 1.0))
+                .await
+                .unwrap();
+        }
+
+        integrations
+            .run_stop_pass(Duration::from_secs(1))
+            .await
+            .unwrap();
+
+        for _ in 0..3 {
+            assert!(sent_rx.try_recv().is_ok());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_stop_times_out_on_stalled_integration() {
+        let (event_tx, _event_rx) = mk_event_channel();
+        let mut integrations = Integrations::new(event_tx);
+
+        let stalled_id = IntegrationId::from_str("stalled").unwrap();
+        integrations.insert_integration(&stalled_id, "stalled", Box::new(Stalled), None);
+        integrations
+            .set_integration_device_state(mk_device(&stalled_id, "light", 1.0))
+            .await
+            .unwrap();
+
+        let result = integrations.run_stop_pass(Duration::from_millis(50)).await;
+
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_failing_integration_does_not_prevent_startup() {
+        let (event_tx, mut event_rx) = mk_event_channel();
+        let mut integrations = Integrations::new(event_tx);
+
+        let failing_id = IntegrationId::from_str("failing").unwrap();
+        let recording_id = IntegrationId::from_str("recording").unwrap();
+        let (sent_tx, _sent_rx) = tokio::sync::mpsc::unbounded_channel();
+        integrations.insert_integration(&failing_id, "failing", Box::new(FailingStart), None);
+        integrations.insert_integration(
+            &recording_id,
+            "recording",
+            Box::new(Recording { sent_tx }),
+            None,
+        );
+
+        integrations.run_register_pass().await;
+        integrations.run_start_pass().await;
+
+        let health = integrations.get_health();
+        assert_eq!(
+            health[&failing_id].status,
+            IntegrationStatus::Failed {
+                reason: "Could not connect".to_string()
+            }
+        );
+        assert_eq!(health[&recording_id].status, IntegrationStatus::Running);
+
+        // Status is also published as a sensor device
+        let mut failing_status = None;
+        while let Some(event) = event_rx.try_recv() {
+            if let Event::ExternalStateUpdate { device } = event.event {
+                if device.name == "failing status" {
+                    failing_status = Some(device.data);
+                }
+            }
+        }
+        assert_eq!(
+            failing_status,
+            Some(DeviceData::Sensor(SensorDevice::Text {
+                value: "failed".to_string(),
+                meta: None
+            }))
+        );
+    }
+}
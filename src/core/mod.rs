@@ -1,11 +1,26 @@
+pub mod audit;
+pub mod backup;
+pub mod commands;
 pub mod config;
 pub mod devices;
 pub mod event;
+pub mod event_queue;
 pub mod expr;
 pub mod groups;
+pub mod history;
+pub mod integration_supervisor;
+pub mod integration_worker;
 pub mod integrations;
+pub mod mqtt_bridge;
+pub mod recording;
 pub mod routines;
+pub mod scene_export;
 pub mod scenes;
+pub mod shutdown;
+#[cfg(any(test, feature = "simulation"))]
+pub mod simulation;
 pub mod state;
+#[cfg(test)]
+pub mod test_harness;
 pub mod ui;
 pub mod websockets;
@@ -0,0 +1,594 @@
+//! Publishes homectl's own view of the world to an MQTT broker, so that other
+//! systems such as Node-RED or Home Assistant can consume it:
+//!
+//! - the expected state of every device, including the active scene and
+//!   sensor values
+//! - power and active scene of every group
+//! - actions sent by triggered routines
+//!
+//! Device and group states are retained. Messages on the `/set` topics of
+//! devices and groups, and on the action topic, are turned into [Action]s.
+
+use std::{
+    collections::HashMap,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use color_eyre::Result;
+use ordered_float::OrderedFloat;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::sync::mpsc;
+
+use crate::integrations::mqtt::client::{self, MqttClient, Notification};
+use crate::types::{
+    action::Action,
+    color::DeviceColor,
+    device::{ControllableState, Device, DeviceData, DevicesState, SensorDevice},
+    dim::DimDescriptor,
+    event::{Event, EventOrigin, TxEventChannel},
+    group::GroupId,
+    integration::IntegrationId,
+    mqtt_bridge::MqttBridgeConfig,
+    scene::{ActivateSceneDescriptor, SceneId},
+};
+use crate::utils::{cli::Cli, clock};
+
+use super::{
+    audit::action_to_json,
+    groups::{flattened_group_state, Groups},
+};
+
+const DEFAULT_DEVICE_TOPIC: &str = "homectl/devices/{integration}/{name}";
+const DEFAULT_GROUP_TOPIC: &str = "homectl/groups/{group}";
+const DEFAULT_ROUTINE_TOPIC: &str = "homectl/routines/{routine}";
+const DEFAULT_ACTION_TOPIC: &str = "homectl/actions";
+
+/// Messages that don't fit in the queue are dropped, e.g. while the broker is
+/// unreachable. Retained states are republished after reconnecting.
+const PUBLISH_QUEUE_SIZE: usize = 1024;
+
+const PLACEHOLDERS: [&str; 5] = ["{integration}", "{id}", "{name}", "{group}", "{routine}"];
+
+/// Fills in topic placeholders. Characters that have a special meaning in
+/// topics are replaced, so that every placeholder stays within one level.
+fn format_topic(pattern: &str, values: &[(&str, &str)]) -> String {
+    values
+        .iter()
+        .fold(pattern.to_string(), |topic, (key, value)| {
+            topic.replace(key, &value.replace(['/', '+', '#'], "_"))
+        })
+}
+
+/// Subscription matching the `/set` topics of all devices or groups
+fn set_topic_filter(pattern: &str) -> String {
+    let filter = PLACEHOLDERS
+        .iter()
+        .fold(pattern.to_string(), |topic, key| topic.replace(key, "+"));
+
+    format!("{filter}/set")
+}
+
+pub fn device_payload(device: &Device) -> Value {
+    let mut payload = json!({
+        "id": device.id,
+        "name": device.name,
+        "integration_id": device.integration_id,
+    });
+
+    match &device.data {
+        DeviceData::Controllable(controllable) => {
+            let state = &controllable.state;
+            payload["scene_id"] = json!(controllable.scene_id);
+            payload["power"] = json!(state.power);
+            payload["brightness"] = json!(state.brightness);
+            payload["color"] = json!(state.color);
+            payload["transition"] = json!(state.transition);
+        }
+        DeviceData::Sensor(sensor) => {
+            payload["value"] = match sensor {
+                SensorDevice::Boolean { value, .. } => json!(value),
+                SensorDevice::Text { value, .. } => json!(value),
+                SensorDevice::Number { value, .. } => json!(value),
+                SensorDevice::Color(state) => json!(state),
+            };
+
+            if let Some(meta) = sensor.meta() {
+                payload["class"] = json!(meta.class);
+                payload["unit"] = json!(meta.unit);
+            }
+        }
+    }
+
+    payload
+}
+
+/// Payload accepted on `/set` topics. Setting `scene_id` activates a scene,
+/// `dim` dims by the given step and the remaining fields change the state of
+/// a device directly.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Command {
+    scene_id: Option<SceneId>,
+    dim: Option<f32>,
+    power: Option<bool>,
+    brightness: Option<f32>,
+    color: Option<DeviceColor>,
+    transition: Option<f32>,
+}
+
+#[derive(Clone, Debug)]
+enum CommandTarget {
+    /// Last published state of the device
+    Device(Device),
+    Group(GroupId),
+}
+
+impl Command {
+    fn into_actions(self, target: &CommandTarget) -> Vec<Action> {
+        let (device_keys, group_keys) = match target {
+            CommandTarget::Device(device) => (Some(vec![device.get_device_key()]), None),
+            CommandTarget::Group(group_id) => (None, Some(vec![group_id.clone()])),
+        };
+
+        let mut actions = vec![];
+
+        if let Some(scene_id) = self.scene_id {
+            actions.push(Action::ActivateScene(ActivateSceneDescriptor {
+                scene_id,
+                device_keys: device_keys.clone(),
+                group_keys: group_keys.clone(),
+            }));
+        }
+
+        if let Some(step) = self.dim {
+            actions.push(Action::Dim(DimDescriptor {
+                device_keys,
+                group_keys,
+                step: Some(step),
+            }));
+        }
+
+        let changes_state = self.power.is_some()
+            || self.brightness.is_some()
+            || self.color.is_some()
+            || self.transition.is_some();
+
+        match target {
+            CommandTarget::Device(device) if changes_state => {
+                let Some(state) = device.get_controllable_state() else {
+                    warn!("Ignoring state change of sensor {device}");
+                    return actions;
+                };
+
+                let state = ControllableState {
+                    power: self.power.unwrap_or(state.power),
+                    brightness: self.brightness.map(OrderedFloat).or(state.brightness),
+                    color: self.color.or_else(|| state.color.clone()),
+                    transition: self.transition.map(OrderedFloat),
+                };
+
+                // Changing the state by hand deactivates the current scene
+                let mut device = device.set_controllable_state(state);
+                if let DeviceData::Controllable(ref mut controllable) = device.data {
+                    controllable.scene_id = None;
+                }
+
+                actions.push(Action::SetDeviceState(device));
+            }
+            CommandTarget::Group(group_id) if changes_state => {
+                warn!(
+                    "Groups only support scene_id and dim commands, ignoring command to {group_id}"
+                );
+            }
+            _ => {}
+        }
+
+        actions
+    }
+}
+
+struct Message {
+    topic: String,
+    payload: Vec<u8>,
+    retain: bool,
+}
+
+struct Connection {
+    config: MqttBridgeConfig,
+    cli: Cli,
+    client: MqttClient,
+    publish_tx: mpsc::Sender<Message>,
+
+    /// Last retained payload of each topic, republished after reconnecting
+    retained: Mutex<HashMap<String, Vec<u8>>>,
+
+    /// Devices and groups by their `/set` topic
+    targets: Mutex<HashMap<String, CommandTarget>>,
+}
+
+impl Connection {
+    fn device_topic(&self, device: &Device) -> String {
+        let pattern = self
+            .config
+            .device_topic
+            .as_deref()
+            .unwrap_or(DEFAULT_DEVICE_TOPIC);
+
+        format_topic(
+            pattern,
+            &[
+                ("{integration}", &device.integration_id.to_string()),
+                ("{id}", &device.id.to_string()),
+                ("{name}", &device.name),
+            ],
+        )
+    }
+
+    fn group_topic(&self, group_id: &GroupId) -> String {
+        let pattern = self
+            .config
+            .group_topic
+            .as_deref()
+            .unwrap_or(DEFAULT_GROUP_TOPIC);
+
+        format_topic(pattern, &[("{group}", &group_id.to_string())])
+    }
+
+    fn action_topic(&self) -> &str {
+        self.config
+            .action_topic
+            .as_deref()
+            .unwrap_or(DEFAULT_ACTION_TOPIC)
+    }
+
+    fn subscriptions(&self) -> Vec<String> {
+        let device_topic = self.config.device_topic.as_deref();
+        let group_topic = self.config.group_topic.as_deref();
+
+        vec![
+            set_topic_filter(device_topic.unwrap_or(DEFAULT_DEVICE_TOPIC)),
+            set_topic_filter(group_topic.unwrap_or(DEFAULT_GROUP_TOPIC)),
+            self.action_topic().to_string(),
+        ]
+    }
+
+    fn publish(&self, topic: String, payload: &Value, retain: bool) {
+        if self.cli.dry_run {
+            debug!("(dry run) would publish MQTT bridge message to {topic}");
+            return;
+        }
+
+        let payload = payload.to_string().into_bytes();
+
+        if retain {
+            let mut retained = self.retained.lock().unwrap();
+            if retained.get(&topic) == Some(&payload) {
+                return;
+            }
+            retained.insert(topic.clone(), payload.clone());
+        }
+
+        let message = Message {
+            topic,
+            payload,
+            retain,
+        };
+
+        if self.publish_tx.try_send(message).is_err() {
+            debug!("MQTT bridge publish queue is full, dropping message");
+        }
+    }
+
+    fn publish_device(&self, device: &Device) {
+        let topic = self.device_topic(device);
+
+        self.targets.lock().unwrap().insert(
+            format!("{topic}/set"),
+            CommandTarget::Device(device.clone()),
+        );
+
+        self.publish(topic, &device_payload(device), true);
+    }
+
+    fn publish_group(&self, group_id: &GroupId, groups: &Groups, devices: &DevicesState) {
+        let Some(group) = groups.get_flattened_groups().0.get(group_id) else {
+            return;
+        };
+
+        let (power, scene_id) = flattened_group_state(group, devices);
+        let payload = json!({
+            "id": group_id,
+            "name": group.name,
+            "power": power,
+            "scene_id": scene_id,
+        });
+
+        let topic = self.group_topic(group_id);
+
+        self.targets.lock().unwrap().insert(
+            format!("{topic}/set"),
+            CommandTarget::Group(group_id.clone()),
+        );
+
+        self.publish(topic, &payload, true);
+    }
+
+    /// Turns a message on one of the command topics into actions
+    fn handle_message(&self, topic: &str, payload: &[u8], event_tx: &TxEventChannel) -> Result<()> {
+        let origin = EventOrigin::Mqtt {
+            topic: topic.to_string(),
+        };
+
+        if topic == self.action_topic() {
+            let action: Action = serde_json::from_slice(payload)?;
+            event_tx.send_with_origin(Event::Action(action), origin);
+            return Ok(());
+        }
+
+        let Some(target) = self.targets.lock().unwrap().get(topic).cloned() else {
+            debug!("Ignoring MQTT bridge command to unknown device or group: {topic}");
+            return Ok(());
+        };
+
+        let command: Command = serde_json::from_slice(payload)?;
+        for action in command.into_actions(&target) {
+            event_tx.send_with_origin(Event::Action(action), origin.clone());
+        }
+
+        Ok(())
+    }
+
+    /// Subscribes to command topics and republishes retained states, runs in
+    /// its own task so that the event loop keeps being polled
+    async fn on_connected(&self) -> Result<()> {
+        if self.cli.dry_run {
+            debug!("(dry run) not subscribing to MQTT bridge command topics");
+            return Ok(());
+        }
+
+        for topic in self.subscriptions() {
+            self.client.subscribe(topic).await?;
+        }
+
+        let retained = self.retained.lock().unwrap().clone();
+        for (topic, payload) in retained {
+            let message = Message {
+                topic,
+                payload,
+                retain: true,
+            };
+            self.publish_tx.send(message).await?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Keeps other systems up to date with homectl's state via MQTT. Does nothing
+/// unless `[mqtt_bridge]` is configured.
+#[derive(Clone, Default)]
+pub struct MqttBridge {
+    connection: Option<Arc<Connection>>,
+}
+
+impl MqttBridge {
+    pub fn start(
+        config: Option<MqttBridgeConfig>,
+        cli: &Cli,
+        event_tx: TxEventChannel,
+    ) -> Result<Self> {
+        let Some(config) = config else {
+            return Ok(MqttBridge::default());
+        };
+
+        let id = IntegrationId::from_str("homectl-bridge")?;
+        let (client, mut event_loop) = client::connect(&id, &config.connection)?;
+        let (publish_tx, mut publish_rx) = mpsc::channel::<Message>(PUBLISH_QUEUE_SIZE);
+
+        let connection = Arc::new(Connection {
+            config,
+            cli: cli.clone(),
+            client,
+            publish_tx,
+            retained: Default::default(),
+            targets: Default::default(),
+        });
+
+        {
+            let connection = connection.clone();
+            tokio::spawn(async move {
+                while let Some(message) = publish_rx.recv().await {
+                    let Message {
+                        topic,
+                        payload,
+                        retain,
+                    } = message;
+
+                    if let Err(e) = connection.client.publish(topic, retain, payload).await {
+                        error!("Failed to publish MQTT bridge message: {e:?}");
+                    }
+                }
+            });
+        }
+
+        {
+            let connection = connection.clone();
+            tokio::spawn(async move {
+                loop {
+                    match event_loop.poll().await {
+                        Ok(Notification::Connected) => {
+                            info!("MQTT bridge connected");
+
+                            let connection = connection.clone();
+                            tokio::spawn(async move {
+                                if let Err(e) = connection.on_connected().await {
+                                    error!("MQTT bridge error: {e:?}");
+                                }
+                            });
+                        }
+                        Ok(Notification::Message { topic, payload }) => {
+                            if let Err(e) = connection.handle_message(&topic, &payload, &event_tx) {
+                                warn!("Invalid MQTT bridge command on {topic}: {e}");
+                            }
+                        }
+                        Ok(Notification::Disconnected) => break,
+                        Ok(Notification::Other) => {}
+                        Err(e) => {
+                            error!("MQTT bridge error: {e:?}");
+                            tokio::time::sleep(Duration::from_secs(1)).await;
+                        }
+                    }
+                }
+            });
+        }
+
+        Ok(MqttBridge {
+            connection: Some(connection),
+        })
+    }
+
+    /// Publishes the new state of a device, along with the state of every
+    /// group it belongs to
+    pub fn publish_device(&self, device: &Device, groups: &Groups, devices: &DevicesState) {
+        let Some(connection) = &self.connection else {
+            return;
+        };
+
+        connection.publish_device(device);
+
+        let device_key = device.get_device_key();
+        for (group_id, group) in &groups.get_flattened_groups().0 {
+            if group.device_keys.contains(&device_key) {
+                connection.publish_group(group_id, groups, devices);
+            }
+        }
+    }
+
+    /// Publishes the state of all devices and groups
+    pub fn publish_all(&self, groups: &Groups, devices: &DevicesState) {
+        let Some(connection) = &self.connection else {
+            return;
+        };
+
+        for device in devices.0.values() {
+            connection.publish_device(device);
+        }
+
+        for group_id in groups.get_flattened_groups().0.keys() {
+            connection.publish_group(group_id, groups, devices);
+        }
+    }
+
+    /// Publishes actions sent by triggered routines
+    pub fn publish_event(&self, event: &Event, origin: &EventOrigin) {
+        let Some(connection) = &self.connection else {
+            return;
+        };
+
+        let (Event::Action(action), EventOrigin::Routine { routine_id }) = (event, origin) else {
+            return;
+        };
+
+        let pattern = connection
+            .config
+            .routine_topic
+            .as_deref()
+            .unwrap_or(DEFAULT_ROUTINE_TOPIC);
+        let topic = format_topic(pattern, &[("{routine}", &routine_id.to_string())]);
+
+        let payload = json!({
+            "routine_id": routine_id,
+            "action": action_to_json(action),
+            "at": clock::now(),
+        });
+
+        connection.publish(topic, &payload, false);
+    }
+
+    /// Disconnects from the broker once queued messages have been sent
+    pub async fn stop(&self) -> Result<()> {
+        if let Some(connection) = &self.connection {
+            connection.client.disconnect().await?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::types::{
+        color::Capabilities,
+        device::{ControllableDevice, DeviceId, ManageKind},
+    };
+
+    use super::*;
+
+    fn mk_light() -> Device {
+        Device::new(
+            IntegrationId::from_str("hue").unwrap(),
+            DeviceId::new("1"),
+            "Desk lamp".to_string(),
+            DeviceData::Controllable(ControllableDevice::new(
+                Some(SceneId::new("evening".to_string())),
+                true,
+                Some(0.5),
+                None,
+                None,
+                Capabilities::default(),
+                ManageKind::Full,
+            )),
+            None,
+        )
+    }
+
+    #[test]
+    fn test_topics() {
+        let topic = format_topic(
+            DEFAULT_DEVICE_TOPIC,
+            &[("{integration}", "hue"), ("{name}", "Desk/lamp #1")],
+        );
+        assert_eq!(topic, "homectl/devices/hue/Desk_lamp _1");
+
+        assert_eq!(
+            set_topic_filter(DEFAULT_DEVICE_TOPIC),
+            "homectl/devices/+/+/set"
+        );
+    }
+
+    #[test]
+    fn test_device_command() {
+        let target = CommandTarget::Device(mk_light());
+        let command: Command = serde_json::from_value(json!({ "power": false })).unwrap();
+
+        let actions = command.into_actions(&target);
+        let [Action::SetDeviceState(device)] = actions.as_slice() else {
+            panic!("Expected a single SetDeviceState action, got {actions:?}");
+        };
+
+        let state = device.get_controllable_state().unwrap();
+        assert!(!state.power);
+        assert_eq!(state.brightness, Some(OrderedFloat(0.5)));
+        assert_eq!(device.get_scene_id(), None);
+    }
+
+    #[test]
+    fn test_group_command() {
+        let target = CommandTarget::Group(GroupId("living_room".to_string()));
+        let command: Command =
+            serde_json::from_value(json!({ "scene_id": "evening", "power": false })).unwrap();
+
+        let actions = command.into_actions(&target);
+        let [Action::ActivateScene(descriptor)] = actions.as_slice() else {
+            panic!("Expected a single ActivateScene action, got {actions:?}");
+        };
+
+        assert_eq!(descriptor.scene_id, SceneId::new("evening".to_string()));
+        assert_eq!(
+            descriptor.group_keys,
+            Some(vec![GroupId("living_room".to_string())])
+        );
+    }
+}
@@ -0,0 +1,97 @@
+//! Records every event entering the event queue into a JSONL file, one
+//! [RecordedEvent] per line. Recordings can be fed back into homectl with
+//! `homectl replay` to reproduce issues that depend on a particular sequence
+//! of events.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::Path,
+    sync::mpsc,
+};
+
+use color_eyre::Result;
+use eyre::Context;
+
+use crate::types::{event::OriginatedEvent, simulation::RecordedEvent};
+use crate::utils::clock;
+
+/// Appends events to a recording file. Events are serialized and written on a
+/// separate thread, so recording doesn't slow down senders.
+pub struct EventRecorder {
+    tx: mpsc::Sender<RecordedEvent>,
+}
+
+impl EventRecorder {
+    pub fn create(path: &Path) -> Result<EventRecorder> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .wrap_err_with(|| format!("Failed to open recording {}", path.display()))?;
+
+        let (tx, rx) = mpsc::channel();
+        std::thread::spawn(move || write_recording(BufWriter::new(file), rx));
+
+        info!("Recording events to {}", path.display());
+
+        Ok(EventRecorder { tx })
+    }
+
+    pub fn record(&self, event: &OriginatedEvent) {
+        let OriginatedEvent { event, origin } = event.clone();
+
+        self.tx
+            .send(RecordedEvent {
+                at: clock::now(),
+                origin,
+                event,
+            })
+            .ok();
+    }
+}
+
+fn write_event(writer: &mut BufWriter<File>, event: &RecordedEvent) -> Result<()> {
+    serde_json::to_writer(&mut *writer, event)?;
+    writer.write_all(b"\n")?;
+
+    Ok(())
+}
+
+fn write_recording(mut writer: BufWriter<File>, rx: mpsc::Receiver<RecordedEvent>) {
+    while let Ok(event) = rx.recv() {
+        // Flush once there's a pause in incoming events, so that the
+        // recording is up to date if homectl crashes
+        let result = std::iter::once(event)
+            .chain(rx.try_iter())
+            .try_for_each(|event| write_event(&mut writer, &event))
+            .and_then(|_| Ok(writer.flush()?));
+
+        if let Err(e) = result {
+            error!("Failed to write event recording, recording stopped: {e:?}");
+            return;
+        }
+    }
+}
+
+pub fn read_recording(path: &Path) -> Result<Vec<RecordedEvent>> {
+    let file = File::open(path)
+        .wrap_err_with(|| format!("Failed to open recording {}", path.display()))?;
+
+    let mut events = vec![];
+
+    for (index, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let event = serde_json::from_str(&line).wrap_err_with(|| {
+            format!("Invalid event on line {} of {}", index + 1, path.display())
+        })?;
+        events.push(event);
+    }
+
+    Ok(events)
+}
@@ -2,9 +2,9 @@ use evalexpr::HashMapContext;
 use eyre::{ContextCompat, Result};
 
 use crate::types::{
-    action::Actions,
-    device::{Device, DevicesState, SensorDevice},
-    event::{Event, TxEventChannel},
+    action::Action,
+    device::{Device, SensorDevice},
+    event::{Event, EventOrigin, TxEventChannel},
     rule::{AnyRule, DeviceRule, GroupRule, Routine, RoutineId, RoutinesConfig, Rule},
 };
 use std::collections::HashSet;
@@ -31,19 +31,18 @@ impl Routines {
     /// are triggered by this change and run actions of triggered rules.
     pub async fn handle_internal_state_update(
         &mut self,
-        old_state: &DevicesState,
-        new_state: &DevicesState,
         old: &Option<Device>,
+        new: &Device,
         devices: &Devices,
         groups: &Groups,
         expr: &Expr,
     ) {
-        if old.is_some() {
-            let matching_actions =
-                self.find_matching_actions(old_state, new_state, devices, groups, expr);
+        if let Some(old) = old {
+            let matching_actions = self.find_matching_actions(old, new, devices, groups, expr);
 
-            for action in matching_actions {
-                self.event_tx.send(Event::Action(action.clone()));
+            for (routine_id, action) in matching_actions {
+                self.event_tx
+                    .send_with_origin(Event::Action(action), EventOrigin::Routine { routine_id });
             }
         }
     }
@@ -57,24 +56,30 @@ impl Routines {
         let routine_actions = routine.actions.clone();
 
         for action in routine_actions {
-            self.event_tx.send(Event::Action(action.clone()));
+            self.event_tx.send_with_origin(
+                Event::Action(action.clone()),
+                EventOrigin::Routine {
+                    routine_id: routine_id.clone(),
+                },
+            );
         }
 
         Ok(())
     }
 
-    /// Find any rules that were triggered by transitioning from `old_state` to
-    /// `new_state`, and return all actions of those rules.
+    /// Find any rules that were triggered by a device transitioning from `old`
+    /// to `new`, and return all actions of those rules along with the id of
+    /// the routine they belong to.
     fn find_matching_actions(
         &mut self,
-        old_state: &DevicesState,
-        new_state: &DevicesState,
+        old: &Device,
+        new: &Device,
         devices: &Devices,
         groups: &Groups,
         expr: &Expr,
-    ) -> Actions {
+    ) -> Vec<(RoutineId, Action)> {
         // if states are equal we can bail out early
-        if old_state == new_state {
+        if old == new {
             return vec![];
         }
 
@@ -96,7 +101,11 @@ impl Routines {
                     .config
                     .get(id)
                     .expect("Expected triggered_routine_ids to only contain ids of routines existing in the RoutinesConfig");
-                routine.actions.clone()
+                routine
+                    .actions
+                    .iter()
+                    .map(|action| (id.clone(), action.clone()))
+                    .collect::<Vec<_>>()
             })
             .collect()
     }
@@ -159,15 +168,21 @@ fn compare_rule_device_state(rule: &Rule, device: &Device) -> Result<bool> {
         // Check for sensor value matches
         Rule::Sensor(rule) => match (&rule.state, sensor_state) {
             (
-                SensorDevice::Boolean { value: rule_value },
+                SensorDevice::Boolean {
+                    value: rule_value, ..
+                },
                 Some(SensorDevice::Boolean {
                     value: sensor_value,
+                    ..
                 }),
             ) => Ok(rule_value == sensor_value),
             (
-                SensorDevice::Text { value: rule_value },
+                SensorDevice::Text {
+                    value: rule_value, ..
+                },
                 Some(SensorDevice::Text {
                     value: sensor_value,
+                    ..
                 }),
             ) => Ok(rule_value == sensor_value),
             (rule, sensor) => Err(eyre!(
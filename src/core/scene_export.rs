@@ -0,0 +1,374 @@
+use std::{
+    fmt::Write as _,
+    io::{self, Write},
+    path::Path,
+};
+
+use color_eyre::Result;
+use eyre::Context;
+use serde::Serialize;
+
+use crate::db::actions::{
+    db_delete_scene_with_overrides, db_get_devices, db_get_scene_overrides, db_get_scenes,
+};
+use crate::types::{
+    device::{DeviceRef, DevicesState},
+    scene::{
+        SceneConfig, SceneDeviceConfig, SceneDeviceLink, SceneDevicesConfig,
+        SceneDevicesSearchConfig, SceneGroupsConfig, SceneId, ScenesConfig,
+    },
+};
+
+/// Replaces device id references in a scene device config with references by
+/// name, which is the form used in the configuration file.
+fn resolve_device_config(config: &SceneDeviceConfig, devices: &DevicesState) -> SceneDeviceConfig {
+    let SceneDeviceConfig::DeviceLink(link) = config else {
+        return config.clone();
+    };
+
+    let DeviceRef::Id(id_ref) = &link.device_ref else {
+        return config.clone();
+    };
+
+    let Some(device) = devices.0.get(&id_ref.clone().into_device_key()) else {
+        return config.clone();
+    };
+
+    SceneDeviceConfig::DeviceLink(SceneDeviceLink {
+        brightness: link.brightness,
+        device_ref: DeviceRef::new_with_name(device.integration_id.clone(), device.name.clone()),
+    })
+}
+
+/// Converts a scene stored in the database into the form used in the
+/// configuration file, optionally merging in any scene overrides. Devices are
+/// referred to by integration and name instead of by device key.
+pub fn resolve_scene_config(
+    config: &SceneConfig,
+    overrides: Option<&SceneDevicesConfig>,
+    devices: &DevicesState,
+) -> SceneConfig {
+    let mut search_config = config
+        .devices
+        .clone()
+        .map(|devices| devices.0)
+        .unwrap_or_default();
+
+    for device_configs in search_config.values_mut() {
+        for device_config in device_configs.values_mut() {
+            *device_config = resolve_device_config(device_config, devices);
+        }
+    }
+
+    for (device_key, device_config) in overrides.into_iter().flatten() {
+        let Some(device) = devices.0.get(device_key) else {
+            warn!("Could not find device {device_key} for scene override, skipping");
+            continue;
+        };
+
+        search_config
+            .entry(device.integration_id.clone())
+            .or_default()
+            .insert(
+                device.name.clone(),
+                resolve_device_config(device_config, devices),
+            );
+    }
+
+    let groups = config.groups.as_ref().map(|groups| {
+        SceneGroupsConfig(
+            groups
+                .0
+                .iter()
+                .map(|(group_id, config)| {
+                    (group_id.clone(), resolve_device_config(config, devices))
+                })
+                .collect(),
+        )
+    });
+
+    SceneConfig {
+        name: config.name.clone(),
+        devices: (!search_config.is_empty()).then_some(SceneDevicesSearchConfig(search_config)),
+        groups,
+        hidden: config.hidden,
+        expr: config.expr.clone(),
+    }
+}
+
+/// Serializes a value as an inline TOML value
+fn toml_value<T: Serialize + ?Sized>(value: &T) -> Result<String> {
+    let mut out = String::new();
+    value.serialize(toml::ser::ValueSerializer::new(&mut out))?;
+
+    Ok(out)
+}
+
+/// Formats a TOML key, quoting it only when needed
+fn toml_key(key: &str) -> Result<String> {
+    let is_bare = !key.is_empty()
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+
+    if is_bare {
+        Ok(key.to_string())
+    } else {
+        toml_value(key)
+    }
+}
+
+/// Renders scenes as `[scenes.<scene_id>]` blocks in the same layout as
+/// hand-written scenes in `Settings.toml`.
+pub fn render_scenes_toml(scenes: &ScenesConfig) -> Result<String> {
+    let mut out = String::new();
+
+    for (scene_id, scene) in scenes {
+        let table = format!("scenes.{}", toml_key(&scene_id.to_string())?);
+
+        writeln!(out, "[{table}]")?;
+        writeln!(out, "name = {}", toml_value(&scene.name)?)?;
+        if let Some(hidden) = scene.hidden {
+            writeln!(out, "hidden = {hidden}")?;
+        }
+        if let Some(expr) = &scene.expr {
+            writeln!(out, "expr = {}", toml_value(expr.source())?)?;
+        }
+
+        if let Some(groups) = scene.groups.as_ref().filter(|groups| !groups.0.is_empty()) {
+            writeln!(out, "\n  [{table}.groups]")?;
+            for (group_id, config) in &groups.0 {
+                let key = toml_key(&group_id.to_string())?;
+                writeln!(out, "  {key} = {}", toml_value(config)?)?;
+            }
+        }
+
+        for (integration_id, device_configs) in scene.devices.iter().flat_map(|devices| &devices.0)
+        {
+            let integration_key = toml_key(&integration_id.to_string())?;
+            writeln!(out, "\n  [{table}.devices.{integration_key}]")?;
+            for (name, config) in device_configs {
+                writeln!(out, "  {} = {}", toml_key(name)?, toml_value(config)?)?;
+            }
+        }
+
+        writeln!(out)?;
+    }
+
+    Ok(out)
+}
+
+/// Reads scenes from the database and converts them into the form used in the
+/// configuration file. If `scene_ids` is empty, all scenes are exported.
+pub async fn export_db_scenes(
+    scene_ids: &[SceneId],
+    include_overrides: bool,
+) -> Result<ScenesConfig> {
+    let db_scenes = db_get_scenes().await?;
+    let db_scene_overrides = db_get_scene_overrides().await?;
+    let devices = DevicesState(db_get_devices().await?.into_iter().collect());
+
+    if let Some(scene_id) = scene_ids.iter().find(|id| !db_scenes.contains_key(id)) {
+        return Err(eyre!("Scene {scene_id} not found in database"));
+    }
+
+    let scenes = db_scenes
+        .iter()
+        .filter(|(scene_id, _)| scene_ids.is_empty() || scene_ids.contains(scene_id))
+        .map(|(scene_id, config)| {
+            let overrides = db_scene_overrides
+                .get(scene_id)
+                .filter(|_| include_overrides);
+
+            (
+                scene_id.clone(),
+                resolve_scene_config(config, overrides, &devices),
+            )
+        })
+        .collect();
+
+    Ok(scenes)
+}
+
+/// Moves a scene from the database into the configuration file at `path`.
+/// Scene overrides are merged into the written scene and removed from the
+/// database along with the scene itself.
+///
+/// Returns the scene config as it was written to the file.
+pub async fn promote_db_scene(scene_id: &SceneId, path: &Path) -> Result<SceneConfig> {
+    let scenes = export_db_scenes(std::slice::from_ref(scene_id), true).await?;
+    let contents = render_scenes_toml(&scenes)?;
+
+    let existing = std::fs::read_to_string(path)
+        .wrap_err_with(|| format!("Failed to read {}", path.display()))?;
+    let existing: toml::Table = toml::from_str(&existing)?;
+    let already_configured = existing
+        .get("scenes")
+        .and_then(|scenes| scenes.get(scene_id.to_string()))
+        .is_some();
+    if already_configured {
+        return Err(eyre!(
+            "Scene {scene_id} already exists in {}",
+            path.display()
+        ));
+    }
+
+    let mut file = std::fs::OpenOptions::new()
+        .append(true)
+        .open(path)
+        .wrap_err_with(|| format!("Failed to open {}", path.display()))?;
+    let original_len = file.metadata()?.len();
+
+    // Make sure the scene has been written to the file before removing it
+    // from the database, so that it can't get lost in between
+    let appended = file
+        .write_all(format!("\n{contents}").as_bytes())
+        .and_then(|_| file.sync_all());
+
+    let result = match appended {
+        Ok(()) => db_delete_scene_with_overrides(scene_id).await,
+        Err(e) => Err(e.into()),
+    };
+
+    if let Err(e) = result {
+        // Leave the scene in the database only
+        file.set_len(original_len)
+            .and_then(|_| file.sync_all())
+            .wrap_err_with(|| {
+                format!(
+                    "Failed to promote scene {scene_id}, and failed to restore {}",
+                    path.display()
+                )
+            })?;
+
+        return Err(e.wrap_err(format!("Failed to promote scene {scene_id}")));
+    }
+
+    info!("Promoted scene {scene_id} into {}", path.display());
+
+    let config = scenes
+        .into_values()
+        .next()
+        .ok_or_else(|| eyre!("Scene {scene_id} not found in database"))?;
+
+    Ok(config)
+}
+
+/// Writes scenes stored in the database as TOML to `output`, or stdout.
+pub async fn export_scenes_to(
+    output: Option<&Path>,
+    scene_ids: &[SceneId],
+    include_overrides: bool,
+) -> Result<()> {
+    let scenes = export_db_scenes(scene_ids, include_overrides).await?;
+    let contents = render_scenes_toml(&scenes)?;
+
+    match output {
+        Some(path) => std::fs::write(path, contents)
+            .wrap_err_with(|| format!("Failed to write scenes to {}", path.display()))?,
+        None => io::stdout().write_all(contents.as_bytes())?,
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{BTreeMap, HashMap};
+    use std::str::FromStr;
+
+    use ordered_float::OrderedFloat;
+
+    use super::*;
+    use crate::types::{
+        color::Capabilities,
+        device::{ControllableDevice, Device, DeviceData, DeviceId, ManageKind},
+        integration::IntegrationId,
+        scene::SceneDeviceState,
+    };
+
+    #[derive(serde::Deserialize)]
+    struct ScenesFile {
+        scenes: ScenesConfig,
+    }
+
+    fn mk_device(device_id: &str, name: &str) -> Device {
+        Device::new(
+            IntegrationId::from_str("dummy").unwrap(),
+            DeviceId::new(device_id),
+            name.to_string(),
+            DeviceData::Controllable(ControllableDevice::new(
+                None,
+                true,
+                Some(0.5),
+                None,
+                None,
+                Capabilities::default(),
+                ManageKind::Full,
+            )),
+            None,
+        )
+    }
+
+    #[test]
+    fn test_export_resolves_device_keys() {
+        let kitchen = mk_device("1", "Kitchen");
+        let living_room = mk_device("2", "Living room");
+        let devices = DevicesState(BTreeMap::from([
+            (kitchen.get_device_key(), kitchen.clone()),
+            (living_room.get_device_key(), living_room.clone()),
+        ]));
+
+        let scene = SceneConfig {
+            name: "Evening".to_string(),
+            devices: Some(SceneDevicesSearchConfig(BTreeMap::from([(
+                IntegrationId::from_str("dummy").unwrap(),
+                BTreeMap::from([(
+                    "Kitchen".to_string(),
+                    SceneDeviceConfig::DeviceLink(SceneDeviceLink {
+                        brightness: None,
+                        device_ref: DeviceRef::from(&living_room.get_device_key()),
+                    }),
+                )]),
+            )]))),
+            groups: None,
+            hidden: None,
+            expr: Some("1 + 1".parse().unwrap()),
+        };
+
+        let overrides = HashMap::from([(
+            living_room.get_device_key(),
+            SceneDeviceConfig::DeviceState(SceneDeviceState {
+                power: Some(true),
+                color: None,
+                brightness: Some(OrderedFloat(0.35)),
+                transition: None,
+            }),
+        )]);
+
+        let resolved = resolve_scene_config(&scene, Some(&overrides), &devices);
+        let scene_id = SceneId::new("evening".to_string());
+        let scenes = BTreeMap::from([(scene_id.clone(), resolved.clone())]);
+
+        let rendered = render_scenes_toml(&scenes).unwrap();
+        assert!(rendered.contains("[scenes.evening.devices.dummy]"));
+        assert!(rendered.contains("\"Living room\" = { power = true, brightness = 0.35 }"));
+        assert!(rendered.contains("expr = \"1 + 1\""));
+
+        let parsed: ScenesFile = toml::from_str(&rendered).unwrap();
+        assert_eq!(parsed.scenes.get(&scene_id), Some(&resolved));
+
+        let kitchen_config =
+            &resolved.devices.unwrap().0[&IntegrationId::from_str("dummy").unwrap()]["Kitchen"];
+        assert_eq!(
+            kitchen_config,
+            &SceneDeviceConfig::DeviceLink(SceneDeviceLink {
+                brightness: None,
+                device_ref: DeviceRef::new_with_name(
+                    IntegrationId::from_str("dummy").unwrap(),
+                    "Living room".to_string()
+                ),
+            })
+        );
+    }
+}
@@ -1,9 +1,7 @@
 use crate::{
     db::actions::{db_get_scene_overrides, db_store_scene_overrides},
     types::{
-        device::{
-            ControllableState, Device, DeviceData, DeviceKey, DeviceRef, DevicesState, SensorDevice,
-        },
+        device::{ControllableState, Device, DeviceData, DeviceKey, DeviceRef, SensorDevice},
         group::GroupId,
         scene::{
             ActivateSceneDescriptor, FlattenedSceneConfig, FlattenedScenesConfig, SceneConfig,
@@ -251,6 +249,12 @@ impl Scenes {
         self.db_scene_overrides = scene_overrides
     }
 
+    /// Adds a scene to the scenes read from the configuration file, e.g. after
+    /// it has been promoted from the database.
+    pub fn insert_config_scene(&mut self, scene_id: SceneId, config: SceneConfig) {
+        self.config.insert(scene_id, config);
+    }
+
     pub async fn store_scene_override(
         &mut self,
         device: &Device,
@@ -618,16 +622,13 @@ impl Scenes {
 
     pub fn invalidate(
         &mut self,
-        old_state: &DevicesState,
-        _new_state: &DevicesState,
+        old: &Option<Device>,
         invalidated_device: &Device,
         devices: &Devices,
         groups: &Groups,
         eval_context: &EvalContext,
     ) -> HashSet<SceneId> {
-        let is_new_device = !old_state
-            .0
-            .contains_key(&invalidated_device.get_device_key());
+        let is_new_device = old.is_none();
 
         let invalidated_scenes = self
             .device_invalidation_map
@@ -0,0 +1,121 @@
+use std::{sync::Arc, time::Duration};
+
+use color_eyre::Result;
+use tokio::{sync::RwLock, time::Instant};
+
+use crate::db::wait_for_pending_writes;
+use crate::types::event::RxEventChannel;
+
+use super::{event::process_event, state::AppState};
+
+/// Default time allowed for each shutdown step, see
+/// [crate::core::config::CoreConfig::shutdown_timeout_seconds]
+pub const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Waits until the process receives SIGINT or SIGTERM, and returns the name of
+/// the received signal.
+pub async fn wait_for_signal() -> &'static str {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut sigterm =
+            signal(SignalKind::terminate()).expect("Failed to install SIGTERM handler");
+
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => "SIGINT",
+            _ = sigterm.recv() => "SIGTERM",
+        }
+    }
+
+    #[cfg(not(unix))]
+    {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("Failed to install Ctrl+C handler");
+        "SIGINT"
+    }
+}
+
+/// Handles events that are still queued. Handling an event may queue more
+/// events, so this gives up after `timeout`. Returns the number of handled
+/// events, or an error if the queue could not be drained in time.
+async fn drain_events(
+    state: &Arc<RwLock<AppState>>,
+    event_rx: &mut RxEventChannel,
+    timeout: Duration,
+) -> Result<usize> {
+    let deadline = Instant::now() + timeout;
+    let mut handled = 0;
+
+    while let Some(event) = event_rx.try_recv() {
+        if Instant::now() >= deadline {
+            return Err(eyre!(
+                "Timed out while draining event queue after handling {handled} events"
+            ));
+        }
+
+        process_event(state, event).await;
+        handled += 1;
+    }
+
+    Ok(handled)
+}
+
+/// Shuts down homectl after the event loop has stopped:
+///
+/// 1. Handles events that are still queued
+/// 2. Closes WebSocket connections
+/// 3. Sends pending device states to integrations and stops them
+/// 4. Disconnects the MQTT bridge
+/// 5. Waits for pending DB writes
+///
+/// Every step runs even if a previous one failed. Returns an error if any step
+/// failed or timed out.
+pub async fn shutdown(
+    state: &Arc<RwLock<AppState>>,
+    mut event_rx: RxEventChannel,
+    timeout: Duration,
+) -> Result<()> {
+    let mut errors = vec![];
+
+    match drain_events(state, &mut event_rx, timeout).await {
+        Ok(handled) => debug!("Handled {handled} queued events"),
+        Err(e) => errors.push(e),
+    }
+
+    // Stop accepting new events
+    drop(event_rx);
+
+    let state = state.read().await.clone();
+
+    state.ws.close_all().await;
+
+    if let Err(e) = state.integrations.run_stop_pass(timeout).await {
+        errors.push(e);
+    }
+
+    match tokio::time::timeout(timeout, state.mqtt_bridge.stop()).await {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => errors.push(e),
+        Err(_) => errors.push(eyre!("Timed out while disconnecting MQTT bridge")),
+    }
+
+    if tokio::time::timeout(timeout, wait_for_pending_writes())
+        .await
+        .is_err()
+    {
+        errors.push(eyre!("Timed out while waiting for pending DB writes"));
+    }
+
+    match errors.as_slice() {
+        [] => Ok(()),
+        _ => {
+            for e in &errors {
+                error!("{e}");
+            }
+
+            Err(eyre!("Shutdown did not complete cleanly"))
+        }
+    }
+}
@@ -0,0 +1,781 @@
+//! Runs homectl against a virtual clock, either to replay a [Timeline] of
+//! device state reports (`homectl simulate`), a recording of the event queue
+//! (`homectl replay`), or in tests (see [super::test_harness]).
+//!
+//! Time only advances when nothing else is left to do, so hours of simulated
+//! time with cron schedules, timers etc. pass in an instant.
+//!
+//! Paused time requires tokio's `test-util`, so this is only built in tests and
+//! with the `simulation` feature.
+
+use std::{
+    collections::HashSet,
+    fmt::Write as _,
+    future::Future,
+    io::{self, Write},
+    path::Path,
+    sync::Arc,
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use color_eyre::Result;
+use eyre::Context;
+use tokio::sync::RwLock;
+
+use crate::types::{
+    action::Action,
+    event::{
+        mk_event_channel, Event, EventOrigin, OriginatedEvent, RxEventChannel, TxEventChannel,
+    },
+    integration::IntegrationId,
+    scene::ActivateSceneDescriptor,
+    simulation::{
+        ActivatedScene, FiredRoutine, RecordedEvent, ReportFormat, SentCommand, SimulationReport,
+        Timeline, TimelineEvent,
+    },
+};
+use crate::utils::{cli::Cli, clock};
+
+use super::{
+    audit::AuditLog,
+    config::{read_config, Config, OpaqueIntegrationsConfigs},
+    devices::Devices,
+    event::process_event,
+    expr::Expr,
+    groups::Groups,
+    history::History,
+    integrations::Integrations,
+    recording::read_recording,
+    routines::Routines,
+    scenes::Scenes,
+    state::AppState,
+    ui::Ui,
+};
+
+/// Integrations that only depend on time and homectl itself, these keep
+/// running as configured in simulations. All others are replaced with dummy
+/// integrations.
+const SIMULATED_PLUGINS: &[&str] = &["circadian", "cron", "dummy", "random", "timer"];
+
+/// How many times background tasks get to run without producing new events
+/// before the event loop is considered idle
+const IDLE_ROUNDS: usize = 10;
+
+/// An event that has been handled, along with the virtual time it was handled
+/// at.
+pub struct HandledEvent {
+    pub at: DateTime<Utc>,
+    pub event: OriginatedEvent,
+}
+
+pub struct Simulation {
+    pub state: Arc<RwLock<AppState>>,
+    event_rx: RxEventChannel,
+    handled: Vec<HandledEvent>,
+}
+
+impl Simulation {
+    /// Starts homectl with the given config in dry run mode, with the virtual
+    /// clock set to `start`. Must run on a current thread runtime with paused
+    /// time. Returns once all integrations have started and startup has
+    /// completed.
+    pub async fn new(
+        config: Config,
+        opaque_integrations_configs: &OpaqueIntegrationsConfigs,
+        start: DateTime<Utc>,
+    ) -> Simulation {
+        let mut simulation = Simulation::load(config, opaque_integrations_configs, start).await;
+        simulation.complete_startup().await;
+
+        simulation
+    }
+
+    /// Like [Simulation::new], but returns while still warming up, see
+    /// [Simulation::complete_startup].
+    pub async fn load(
+        config: Config,
+        opaque_integrations_configs: &OpaqueIntegrationsConfigs,
+        start: DateTime<Utc>,
+    ) -> Simulation {
+        clock::set_virtual(start);
+
+        let cli = Cli {
+            dry_run: true,
+            record: None,
+            command: None,
+        };
+
+        let (event_tx, event_rx) = mk_event_channel();
+
+        let mut integrations = Integrations::new(event_tx.clone());
+        for (id, integration_config) in &config.integrations.unwrap_or_default() {
+            integrations.load_integration(
+                integration_config,
+                id,
+                &opaque_integrations_configs[id],
+                &cli,
+            );
+        }
+        integrations.run_register_pass().await;
+        integrations.run_start_pass().await;
+
+        let state = AppState {
+            warming_up: true,
+            integrations,
+            groups: Groups::new(config.groups.unwrap_or_default()),
+            scenes: Scenes::new(config.scenes.unwrap_or_default()),
+            devices: Devices::new(event_tx.clone(), &cli),
+            rules: Routines::new(config.routines.unwrap_or_default(), event_tx.clone()),
+            event_tx,
+            expr: Expr::new(),
+            ui: Ui::new(),
+            history: History::new(config.history.unwrap_or_default(), &cli),
+            audit: AuditLog::new(config.audit.unwrap_or_default(), &cli),
+            mqtt_bridge: Default::default(),
+            ws: Default::default(),
+        };
+
+        Simulation {
+            state: Arc::new(RwLock::new(state)),
+            event_rx,
+            handled: vec![],
+        }
+    }
+
+    /// Handles devices discovered while warming up, then completes startup.
+    pub async fn complete_startup(&mut self) {
+        self.run_until_idle().await;
+        self.state.write().await.warming_up = false;
+        self.send(Event::StartupCompleted).await;
+        self.take_handled();
+    }
+
+    pub async fn event_tx(&self) -> TxEventChannel {
+        self.state.read().await.event_tx.clone()
+    }
+
+    /// Sends an event and handles it along with any events that follow from
+    /// it.
+    pub async fn send(&mut self, event: Event) {
+        self.event_tx().await.send(event);
+        self.run_until_idle().await;
+    }
+
+    async fn handle(&mut self, event: OriginatedEvent) {
+        self.handled.push(HandledEvent {
+            at: clock::now(),
+            event: event.clone(),
+        });
+
+        process_event(&self.state, event).await;
+    }
+
+    /// Handles queued events until background tasks (integration workers,
+    /// timers that are due, etc.) stop producing new ones.
+    pub async fn run_until_idle(&mut self) {
+        let mut idle_rounds = 0;
+
+        while idle_rounds < IDLE_ROUNDS {
+            match self.event_rx.try_recv() {
+                Some(event) => {
+                    idle_rounds = 0;
+                    self.handle(event).await;
+                }
+                None => {
+                    idle_rounds += 1;
+                    tokio::task::yield_now().await;
+                }
+            }
+        }
+    }
+
+    /// Runs the event loop while the virtual clock moves forward by
+    /// `duration`. With paused tokio time, the clock jumps straight to the
+    /// next due timer whenever there's nothing else to do, so this is fast
+    /// even for long durations.
+    pub async fn advance(&mut self, duration: Duration) {
+        let deadline = tokio::time::sleep(duration);
+        tokio::pin!(deadline);
+
+        loop {
+            tokio::select! {
+                biased;
+                event = self.event_rx.recv() => self.handle(event).await,
+                _ = &mut deadline => break,
+            }
+        }
+
+        self.run_until_idle().await;
+    }
+
+    /// Runs the event loop until the virtual clock reaches `time`.
+    pub async fn advance_to(&mut self, time: DateTime<Utc>) {
+        let duration = (time - clock::now()).to_std().unwrap_or_default();
+        self.advance(duration).await;
+    }
+
+    /// Returns events handled since the last call
+    pub fn take_handled(&mut self) -> Vec<HandledEvent> {
+        std::mem::take(&mut self.handled)
+    }
+}
+
+/// Replaces integrations that talk to the outside world with dummy
+/// integrations, which accept device states without doing anything. Returns
+/// the ids of the replaced integrations.
+fn stand_in_integrations(
+    config: &mut Config,
+    opaque_integrations_configs: &mut OpaqueIntegrationsConfigs,
+) -> HashSet<IntegrationId> {
+    let mut replaced = HashSet::new();
+
+    for (id, integration_config) in config.integrations.iter_mut().flatten() {
+        if SIMULATED_PLUGINS.contains(&integration_config.plugin.as_str()) {
+            continue;
+        }
+
+        info!(
+            "Replacing integration {id} ({plugin}) with a dummy integration",
+            plugin = integration_config.plugin
+        );
+
+        integration_config.plugin = "dummy".to_string();
+        integration_config.max_commands_per_second = None;
+        opaque_integrations_configs.insert(
+            id.clone(),
+            config::Value::from(config::Map::from([(
+                "plugin".to_string(),
+                config::Value::from("dummy"),
+            )])),
+        );
+        replaced.insert(id.clone());
+    }
+
+    replaced
+}
+
+fn record(report: &mut SimulationReport, handled: Vec<HandledEvent>) {
+    for HandledEvent { at, event } in handled {
+        let OriginatedEvent { event, origin } = event;
+
+        let action = match event {
+            Event::SetExternalState { device } => {
+                report.commands.push(SentCommand { at, device });
+                continue;
+            }
+            Event::Action(action) => action,
+            _ => continue,
+        };
+
+        if let Action::ActivateScene(ActivateSceneDescriptor { scene_id, .. }) = &action {
+            report.scenes.push(ActivatedScene {
+                at,
+                scene_id: scene_id.clone(),
+                origin: origin.clone(),
+            });
+        }
+
+        if let EventOrigin::Routine { routine_id } = origin {
+            report.routines.push(FiredRoutine {
+                at,
+                routine_id,
+                action,
+            });
+        }
+    }
+}
+
+/// Sends each event at its time, and keeps running until `end` if given.
+async fn run(
+    mut simulation: Simulation,
+    start: DateTime<Utc>,
+    events: Vec<RecordedEvent>,
+    end: Option<DateTime<Utc>>,
+) -> SimulationReport {
+    let mut report = SimulationReport {
+        start,
+        end: start,
+        routines: vec![],
+        scenes: vec![],
+        commands: vec![],
+        devices: vec![],
+    };
+
+    for RecordedEvent { at, origin, event } in events {
+        simulation.advance_to(at).await;
+        simulation.event_tx().await.send_with_origin(event, origin);
+        simulation.run_until_idle().await;
+
+        record(&mut report, simulation.take_handled());
+    }
+
+    if let Some(end) = end {
+        simulation.advance_to(end).await;
+        record(&mut report, simulation.take_handled());
+    }
+
+    report.end = clock::now();
+    report.devices = simulation
+        .state
+        .read()
+        .await
+        .devices
+        .get_state()
+        .0
+        .values()
+        .cloned()
+        .collect();
+
+    report
+}
+
+/// Replays the timeline against the given config and reports what happened.
+/// Must run on a current thread runtime with paused time.
+pub async fn simulate(
+    config: Config,
+    opaque_integrations_configs: &OpaqueIntegrationsConfigs,
+    mut timeline: Timeline,
+) -> SimulationReport {
+    timeline.events.sort_by_key(|event| event.at);
+
+    let start = timeline
+        .start
+        .or(timeline.events.first().map(|event| event.at))
+        .unwrap_or_else(Utc::now);
+
+    let simulation = Simulation::new(config, opaque_integrations_configs, start).await;
+
+    let events = timeline
+        .events
+        .into_iter()
+        .map(|TimelineEvent { at, device }| RecordedEvent {
+            at,
+            origin: EventOrigin::Integration {
+                integration_id: device.integration_id.clone(),
+            },
+            event: Event::ExternalStateUpdate { device },
+        })
+        .collect();
+
+    run(simulation, start, events, timeline.end).await
+}
+
+/// Whether a recorded event needs to be sent again during a replay. Events
+/// sent by homectl itself or by integrations that keep running in the
+/// simulation would be sent again anyway, and so would events that follow
+/// from state changes, which carry the origin of the event that caused them.
+fn is_replayed(event: &Event, origin: &EventOrigin, stubbed: &HashSet<IntegrationId>) -> bool {
+    if matches!(
+        event,
+        Event::InternalStateUpdate { .. }
+            | Event::SetExternalState { .. }
+            | Event::WsBroadcastState
+    ) {
+        return false;
+    }
+
+    match origin {
+        EventOrigin::Integration { integration_id } => stubbed.contains(integration_id),
+        EventOrigin::WebSocket { .. } | EventOrigin::Api { .. } | EventOrigin::Mqtt { .. } => true,
+        EventOrigin::Internal
+        | EventOrigin::Cron { .. }
+        | EventOrigin::Routine { .. }
+        | EventOrigin::Expr { .. }
+        | EventOrigin::DriftCorrection => false,
+    }
+}
+
+/// Feeds a recording made with [super::recording::EventRecorder] back into
+/// homectl. Events recorded before startup completed are sent while the
+/// simulation is warming up, like they were originally. Must run on a current
+/// thread runtime with paused time.
+pub async fn replay(
+    config: Config,
+    opaque_integrations_configs: &OpaqueIntegrationsConfigs,
+    stubbed: &HashSet<IntegrationId>,
+    recording: Vec<RecordedEvent>,
+) -> SimulationReport {
+    let start = recording
+        .first()
+        .map(|event| event.at)
+        .unwrap_or_else(Utc::now);
+
+    let startup_index = recording
+        .iter()
+        .position(|event| matches!(event.event, Event::StartupCompleted))
+        .unwrap_or(0);
+    let startup_at = recording
+        .get(startup_index)
+        .map(|event| event.at)
+        .unwrap_or(start);
+
+    let (warmup, events): (Vec<_>, Vec<_>) = recording
+        .into_iter()
+        .enumerate()
+        .filter(|(_, event)| is_replayed(&event.event, &event.origin, stubbed))
+        .partition(|(index, _)| *index < startup_index);
+
+    let mut simulation = Simulation::load(config, opaque_integrations_configs, start).await;
+
+    for (_, RecordedEvent { at, origin, event }) in warmup {
+        simulation.advance_to(at).await;
+        simulation.event_tx().await.send_with_origin(event, origin);
+    }
+    simulation.advance_to(startup_at).await;
+    simulation.complete_startup().await;
+
+    let events = events.into_iter().map(|(_, event)| event).collect();
+
+    run(simulation, start, events, None).await
+}
+
+pub fn format_report(report: &SimulationReport, format: ReportFormat) -> Result<String> {
+    if format == ReportFormat::Json {
+        return Ok(serde_json::to_string_pretty(report)?);
+    }
+
+    let mut out = String::new();
+
+    writeln!(out, "Simulated {} to {}", report.start, report.end)?;
+
+    writeln!(out, "\nRoutines fired ({}):", report.routines.len())?;
+    for FiredRoutine {
+        at,
+        routine_id,
+        action,
+    } in &report.routines
+    {
+        writeln!(out, "  {at}  {routine_id}: {action:?}")?;
+    }
+
+    writeln!(out, "\nScenes activated ({}):", report.scenes.len())?;
+    for ActivatedScene {
+        at,
+        scene_id,
+        origin,
+    } in &report.scenes
+    {
+        writeln!(out, "  {at}  {scene_id} ({origin:?})")?;
+    }
+
+    writeln!(out, "\nDevice states sent ({}):", report.commands.len())?;
+    for SentCommand { at, device } in &report.commands {
+        writeln!(out, "  {at}  {device}")?;
+    }
+
+    writeln!(out, "\nDevice states ({}):", report.devices.len())?;
+    for device in &report.devices {
+        writeln!(out, "  {device}")?;
+    }
+
+    Ok(out)
+}
+
+/// Runs a simulation on its own thread and runtime, since the virtual clock
+/// only works with paused time on a current thread runtime. Then writes the
+/// report to `output`, or stdout.
+async fn run_isolated<F, Fut>(
+    simulate: F,
+    format: ReportFormat,
+    output: Option<&Path>,
+) -> Result<()>
+where
+    F: FnOnce() -> Fut + Send + 'static,
+    Fut: Future<Output = SimulationReport>,
+{
+    let simulation = std::thread::spawn(move || -> Result<SimulationReport> {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .start_paused(true)
+            .build()?;
+
+        Ok(runtime.block_on(simulate()))
+    });
+
+    let report = tokio::task::spawn_blocking(move || simulation.join())
+        .await?
+        .map_err(|_| eyre!("Simulation panicked"))??;
+
+    let contents = format_report(&report, format)?;
+
+    match output {
+        Some(path) => std::fs::write(path, contents)
+            .wrap_err_with(|| format!("Failed to write report to {}", path.display()))?,
+        None => io::stdout().write_all(contents.as_bytes())?,
+    }
+
+    Ok(())
+}
+
+/// Parses a timeline as TOML or YAML based on the file extension, and as JSON
+/// otherwise
+fn parse_timeline(path: &Path, contents: &str) -> Result<Timeline> {
+    let timeline = match path.extension().and_then(|ext| ext.to_str()) {
+        Some("toml") => toml::from_str(contents)?,
+        Some("yaml" | "yml") => serde_yaml::from_str(contents)?,
+        _ => serde_json::from_str(contents)?,
+    };
+
+    Ok(timeline)
+}
+
+/// Runs `homectl simulate`
+pub async fn run_simulation(
+    timeline_path: &Path,
+    format: ReportFormat,
+    output: Option<&Path>,
+) -> Result<()> {
+    let contents = std::fs::read_to_string(timeline_path)
+        .wrap_err_with(|| format!("Failed to read timeline from {}", timeline_path.display()))?;
+    let timeline = parse_timeline(timeline_path, &contents)
+        .wrap_err_with(|| format!("Failed to parse timeline {}", timeline_path.display()))?;
+
+    let (mut config, mut opaque_integrations_configs) = read_config()?;
+    stand_in_integrations(&mut config, &mut opaque_integrations_configs);
+
+    run_isolated(
+        move || async move { simulate(config, &opaque_integrations_configs, timeline).await },
+        format,
+        output,
+    )
+    .await
+}
+
+/// Runs `homectl replay`
+pub async fn run_replay(
+    recording_path: &Path,
+    format: ReportFormat,
+    output: Option<&Path>,
+) -> Result<()> {
+    let recording = read_recording(recording_path)?;
+
+    let (mut config, mut opaque_integrations_configs) = read_config()?;
+    let stubbed = stand_in_integrations(&mut config, &mut opaque_integrations_configs);
+
+    run_isolated(
+        move || async move {
+            replay(config, &opaque_integrations_configs, &stubbed, recording).await
+        },
+        format,
+        output,
+    )
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use chrono::TimeZone;
+
+    use super::*;
+    use crate::types::{
+        device::{Device, DeviceData, DeviceId, SensorDevice},
+        integration::IntegrationId,
+        rule::RoutineId,
+        scene::SceneId,
+    };
+
+    const CONFIG: &str = r#"
+[integrations.zigbee]
+plugin = "mqtt"
+host = "localhost"
+port = 1883
+topic = "zigbee2mqtt/{id}"
+topic_set = "zigbee2mqtt/{id}/set"
+
+[integrations.lights]
+plugin = "dummy"
+
+  [integrations.lights.devices.hallway]
+  name = "Hallway"
+
+[scenes.bright]
+name = "Bright"
+
+  [scenes.bright.devices.lights]
+  "Hallway" = { power = true, brightness = 1.0 }
+
+[routines.motion]
+name = "Motion in hallway"
+rules = [{ integration_id = "zigbee", name = "Hallway motion", state = { value = true } }]
+actions = [{ action = "ActivateScene", scene_id = "bright" }]
+"#;
+
+    fn motion(at: DateTime<Utc>, value: bool) -> TimelineEvent {
+        TimelineEvent {
+            at,
+            device: Device::new(
+                IntegrationId::from_str("zigbee").unwrap(),
+                DeviceId::new("motion"),
+                "Hallway motion".to_string(),
+                DeviceData::Sensor(SensorDevice::Boolean { value, meta: None }),
+                None,
+            ),
+        }
+    }
+
+    fn load_config() -> (Config, OpaqueIntegrationsConfigs, HashSet<IntegrationId>) {
+        let mut config: Config = toml::from_str(CONFIG).unwrap();
+        let mut opaque_integrations_configs: OpaqueIntegrationsConfigs = config::Config::builder()
+            .add_source(config::File::from_str(CONFIG, config::FileFormat::Toml))
+            .build()
+            .unwrap()
+            .get("integrations")
+            .unwrap();
+        let stubbed = stand_in_integrations(&mut config, &mut opaque_integrations_configs);
+
+        (config, opaque_integrations_configs, stubbed)
+    }
+
+    fn recorded(event: TimelineEvent) -> RecordedEvent {
+        RecordedEvent {
+            at: event.at,
+            origin: EventOrigin::Integration {
+                integration_id: event.device.integration_id.clone(),
+            },
+            event: Event::ExternalStateUpdate {
+                device: event.device,
+            },
+        }
+    }
+
+    #[test]
+    fn test_parse_timeline_formats() {
+        let json = r#"{
+            "end": "2024-06-01T23:00:00Z",
+            "events": [
+                {
+                    "at": "2024-06-01T18:30:00Z",
+                    "device": {
+                        "integration_id": "zigbee",
+                        "id": "motion",
+                        "name": "Hallway motion",
+                        "data": { "Sensor": { "value": true } }
+                    }
+                }
+            ]
+        }"#;
+        let yaml = r#"
+end: 2024-06-01T23:00:00Z
+events:
+  - at: 2024-06-01T18:30:00Z
+    device:
+      integration_id: zigbee
+      id: motion
+      name: Hallway motion
+      data:
+        Sensor:
+          value: true
+"#;
+
+        let from_json = parse_timeline(Path::new("timeline.json"), json).unwrap();
+        let from_yaml = parse_timeline(Path::new("timeline.yaml"), yaml).unwrap();
+
+        assert_eq!(
+            serde_json::to_value(&from_yaml).unwrap(),
+            serde_json::to_value(&from_json).unwrap()
+        );
+        assert_eq!(from_yaml.events.len(), 1);
+        assert_eq!(from_yaml.events[0].device.name, "Hallway motion");
+        assert!(parse_timeline(Path::new("timeline.yml"), json).is_ok());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_simulate_timeline() {
+        let (config, opaque_integrations_configs, _) = load_config();
+
+        let start = Utc.with_ymd_and_hms(2024, 6, 1, 12, 0, 0).unwrap();
+        let timeline = Timeline {
+            start: None,
+            end: Some(start + chrono::Duration::hours(1)),
+            events: vec![
+                motion(start + chrono::Duration::minutes(30), true),
+                motion(start, false),
+            ],
+        };
+
+        let report = simulate(config, &opaque_integrations_configs, timeline).await;
+
+        assert_eq!(report.start, start);
+        assert_eq!(report.end, start + chrono::Duration::hours(1));
+
+        assert_eq!(report.routines.len(), 1);
+        assert_eq!(
+            report.routines[0].routine_id,
+            RoutineId("motion".to_string())
+        );
+        assert_eq!(report.routines[0].at, start + chrono::Duration::minutes(30));
+
+        assert_eq!(report.scenes.len(), 1);
+        assert_eq!(
+            report.scenes[0].scene_id,
+            SceneId::from_str("bright").unwrap()
+        );
+
+        let hallway = report
+            .devices
+            .iter()
+            .find(|device| device.name == "Hallway")
+            .unwrap();
+        assert_eq!(hallway.is_powered_on(), Some(true));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_replay_skips_derived_events() {
+        let (config, opaque_integrations_configs, stubbed) = load_config();
+        assert_eq!(
+            stubbed,
+            HashSet::from([IntegrationId::from_str("zigbee").unwrap()])
+        );
+
+        let start = Utc.with_ymd_and_hms(2024, 6, 1, 3, 0, 0).unwrap();
+        let motion_at = start + chrono::Duration::minutes(10);
+
+        let recording = vec![
+            recorded(motion(start, false)),
+            RecordedEvent {
+                at: start + chrono::Duration::seconds(1),
+                origin: EventOrigin::Internal,
+                event: Event::StartupCompleted,
+            },
+            recorded(motion(motion_at, true)),
+            // Follows from the motion sensor update during the replay
+            RecordedEvent {
+                at: motion_at,
+                origin: EventOrigin::Integration {
+                    integration_id: IntegrationId::from_str("zigbee").unwrap(),
+                },
+                event: Event::InternalStateUpdate {
+                    old: Some(motion(start, false).device),
+                    new: motion(motion_at, true).device,
+                },
+            },
+            // Sent again by the routine during the replay
+            RecordedEvent {
+                at: motion_at,
+                origin: EventOrigin::Routine {
+                    routine_id: RoutineId("motion".to_string()),
+                },
+                event: Event::Action(Action::ActivateScene(ActivateSceneDescriptor {
+                    scene_id: SceneId::from_str("bright").unwrap(),
+                    device_keys: None,
+                    group_keys: None,
+                })),
+            },
+        ];
+
+        let report = replay(config, &opaque_integrations_configs, &stubbed, recording).await;
+
+        assert_eq!(report.start, start);
+        assert_eq!(report.routines.len(), 1);
+        assert_eq!(report.routines[0].at, motion_at);
+        assert_eq!(report.scenes.len(), 1);
+        assert!(!report.commands.is_empty());
+        assert!(report
+            .commands
+            .iter()
+            .all(|command| command.device.name == "Hallway"));
+    }
+}
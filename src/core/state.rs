@@ -6,8 +6,9 @@ use crate::types::{
 };
 
 use super::{
-    devices::Devices, expr::Expr, groups::Groups, integrations::Integrations, routines::Routines,
-    scenes::Scenes, ui::Ui, websockets::WebSockets,
+    audit::AuditLog, devices::Devices, expr::Expr, groups::Groups, history::History,
+    integrations::Integrations, mqtt_bridge::MqttBridge, routines::Routines, scenes::Scenes,
+    ui::Ui, websockets::WebSockets,
 };
 
 #[derive(Clone)]
@@ -22,9 +23,22 @@ pub struct AppState {
     pub expr: Expr,
     pub ws: WebSockets,
     pub ui: Ui,
+    pub history: History,
+    pub audit: AuditLog,
+    pub mqtt_bridge: MqttBridge,
 }
 
 impl AppState {
+    /// Reloads all state stored in the database, e.g. after restoring a backup.
+    pub async fn reload_db_state(&mut self) {
+        self.scenes.refresh_db_scenes().await;
+        self.ui.refresh_db_state().await;
+        self.devices.refresh_db_devices(&self.scenes).await;
+        self.scenes
+            .force_invalidate(&self.devices, &self.groups, self.expr.get_context());
+        self.send_state_ws(None).await;
+    }
+
     /// Sends current state over WebSockets. If user_id is omitted, the message
     /// is broadcast to all connected peers.
     pub async fn send_state_ws(&self, user_id: Option<usize>) {
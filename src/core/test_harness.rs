@@ -0,0 +1,306 @@
+//! Runs homectl in-process for tests: builds [AppState] from an inline TOML
+//! config, drives events through the real event loop in a [Simulation], and
+//! records outgoing device state commands and WebSocket messages.
+//!
+//! Tests should use `#[tokio::test(start_paused = true)]`. The harness sets up
+//! a virtual clock (see [crate::utils::clock]), so time only moves when
+//! calling [TestHarness::advance].
+
+use std::{str::FromStr, sync::Arc, time::Duration};
+
+use chrono::{DateTime, Utc};
+use tokio::sync::{mpsc, RwLock};
+
+use crate::types::{
+    device::Device,
+    event::{Event, TxEventChannel},
+    integration::IntegrationId,
+};
+
+use super::{
+    config::{Config, OpaqueIntegrationsConfigs},
+    simulation::{HandledEvent, Simulation},
+    state::AppState,
+};
+
+/// User id of the WebSocket client connected by the harness
+const WS_USER_ID: usize = 0;
+
+pub struct TestHarness {
+    pub state: Arc<RwLock<AppState>>,
+    simulation: Simulation,
+    ws_rx: mpsc::UnboundedReceiver<warp::ws::Message>,
+}
+
+impl TestHarness {
+    /// Starts homectl with the given config, with the virtual clock set to
+    /// `now`. Returns once all integrations have started and startup has
+    /// completed.
+    pub async fn new(config: &str, now: DateTime<Utc>) -> TestHarness {
+        let parsed: Config = toml::from_str(config).expect("Invalid test config");
+        let opaque_integrations_configs: OpaqueIntegrationsConfigs = config::Config::builder()
+            .add_source(config::File::from_str(config, config::FileFormat::Toml))
+            .build()
+            .unwrap()
+            .get("integrations")
+            .unwrap_or_default();
+
+        let simulation = Simulation::new(parsed, &opaque_integrations_configs, now).await;
+
+        let (ws_tx, ws_rx) = mpsc::unbounded_channel();
+        simulation
+            .state
+            .read()
+            .await
+            .ws
+            .user_connected(WS_USER_ID, ws_tx)
+            .await;
+
+        TestHarness {
+            state: simulation.state.clone(),
+            simulation,
+            ws_rx,
+        }
+    }
+
+    pub async fn event_tx(&self) -> TxEventChannel {
+        self.simulation.event_tx().await
+    }
+
+    /// Sends an event and handles it along with any events that follow from
+    /// it.
+    pub async fn send(&mut self, event: Event) {
+        self.simulation.send(event).await;
+    }
+
+    /// Handles queued events until background tasks stop producing new ones.
+    pub async fn run_until_idle(&mut self) {
+        self.simulation.run_until_idle().await;
+    }
+
+    /// Runs the event loop while the virtual clock moves forward by
+    /// `duration`.
+    pub async fn advance(&mut self, duration: Duration) {
+        self.simulation.advance(duration).await;
+    }
+
+    /// Runs the event loop until the virtual clock reaches `time`.
+    pub async fn advance_to(&mut self, time: DateTime<Utc>) {
+        self.simulation.advance_to(time).await;
+    }
+
+    /// Returns device states that were sent to integrations since the last
+    /// call
+    pub fn take_external_states(&mut self) -> Vec<Device> {
+        self.simulation
+            .take_handled()
+            .into_iter()
+            .filter_map(|HandledEvent { event, .. }| match event.event {
+                Event::SetExternalState { device } => Some(device),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Returns WebSocket messages broadcast since the last call
+    pub fn take_ws_messages(&mut self) -> Vec<serde_json::Value> {
+        let mut messages = vec![];
+
+        while let Ok(message) = self.ws_rx.try_recv() {
+            if let Ok(text) = message.to_str() {
+                messages.push(serde_json::from_str(text).unwrap());
+            }
+        }
+
+        messages
+    }
+
+    pub async fn get_device(&self, integration_id: &str, name: &str) -> Option<Device> {
+        let state = self.state.read().await;
+
+        state
+            .devices
+            .get_state()
+            .0
+            .values()
+            .find(|device| {
+                device.integration_id == IntegrationId::from_str(integration_id).unwrap()
+                    && device.name == name
+            })
+            .cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Local, TimeZone};
+    use ordered_float::OrderedFloat;
+
+    use super::*;
+    use crate::types::{
+        action::Action,
+        device::{ControllableState, DeviceData, SensorDevice},
+        integration::{CustomActionDescriptor, IntegrationActionPayload},
+        scene::{ActivateSceneDescriptor, SceneId},
+    };
+
+    const CONFIG: &str = r#"
+[integrations.dummy]
+plugin = "dummy"
+
+  [integrations.dummy.devices.light]
+  name = "Light"
+
+[integrations.timer]
+plugin = "timer"
+device_name = "Timer"
+
+[integrations.cron]
+plugin = "cron"
+
+  [integrations.cron.schedules.evening]
+  name = "Evening"
+  schedule = "0 18 * * *"
+  action = { action = "ActivateScene", scene_id = "bright" }
+
+[integrations.circadian]
+plugin = "circadian"
+device_name = "Circadian"
+day_color = { h = 25, s = 0.35 }
+day_fade_start = "06:00"
+day_fade_duration_hours = 2
+day_brightness = 1.0
+night_color = { h = 20, s = 0.95 }
+night_fade_start = "19:00"
+night_fade_duration_hours = 2
+night_brightness = 0.5
+
+[scenes.bright]
+name = "Bright"
+
+  [scenes.bright.devices.dummy]
+  "Light" = { power = true, brightness = 0.8 }
+"#;
+
+    fn noon() -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 6, 1, 12, 0, 0).unwrap()
+    }
+
+    /// Cron schedules and circadian rhythms use local time, so tests pin
+    /// virtual times to the local timezone of the machine running them.
+    fn local(hour: u32, min: u32, sec: u32) -> DateTime<Utc> {
+        Local
+            .with_ymd_and_hms(2024, 6, 1, hour, min, sec)
+            .unwrap()
+            .with_timezone(&Utc)
+    }
+
+    fn circadian_state(device: &Device) -> &ControllableState {
+        match &device.data {
+            DeviceData::Sensor(SensorDevice::Color(state)) => state,
+            data => panic!("Expected color sensor, got {data:?}"),
+        }
+    }
+
+    fn timer_value(device: &Device) -> bool {
+        match &device.data {
+            DeviceData::Sensor(SensorDevice::Boolean { value, .. }) => *value,
+            data => panic!("Expected boolean sensor, got {data:?}"),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_activate_scene_sends_device_states() {
+        let mut harness = TestHarness::new(CONFIG, noon()).await;
+
+        harness
+            .send(Event::Action(Action::ActivateScene(
+                ActivateSceneDescriptor {
+                    scene_id: SceneId::from_str("bright").unwrap(),
+                    device_keys: None,
+                    group_keys: None,
+                },
+            )))
+            .await;
+
+        let sent = harness.take_external_states();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].name, "Light");
+        assert_eq!(sent[0].is_powered_on(), Some(true));
+
+        let light = harness.get_device("dummy", "Light").await.unwrap();
+        assert_eq!(
+            light.get_scene_id(),
+            Some(SceneId::from_str("bright").unwrap())
+        );
+        assert!(!harness.take_ws_messages().is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_timer_expires_on_virtual_clock() {
+        let mut harness = TestHarness::new(CONFIG, noon()).await;
+
+        harness
+            .send(Event::Action(Action::Custom(CustomActionDescriptor {
+                integration_id: IntegrationId::from_str("timer").unwrap(),
+                payload: IntegrationActionPayload::from("60000".to_string()),
+            })))
+            .await;
+
+        let timer = harness.get_device("timer", "Timer").await.unwrap();
+        assert!(timer_value(&timer));
+        assert_eq!(timer.raw.unwrap()["started_at"], noon().timestamp_millis());
+
+        harness.advance(Duration::from_secs(59)).await;
+        let timer = harness.get_device("timer", "Timer").await.unwrap();
+        assert!(timer_value(&timer));
+
+        harness.advance(Duration::from_secs(1)).await;
+        let timer = harness.get_device("timer", "Timer").await.unwrap();
+        assert!(!timer_value(&timer));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_cron_runs_at_scheduled_time() {
+        let mut harness = TestHarness::new(CONFIG, local(17, 59, 0)).await;
+
+        harness.advance(Duration::from_secs(59)).await;
+        let light = harness.get_device("dummy", "Light").await.unwrap();
+        assert_eq!(light.get_scene_id(), None);
+
+        harness.advance(Duration::from_secs(1)).await;
+        let light = harness.get_device("dummy", "Light").await.unwrap();
+        assert_eq!(
+            light.get_scene_id(),
+            Some(SceneId::from_str("bright").unwrap())
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_circadian_follows_virtual_clock() {
+        let mut harness = TestHarness::new(CONFIG, local(12, 0, 0)).await;
+
+        // Day
+        let circadian = harness.get_device("circadian", "Circadian").await.unwrap();
+        assert_eq!(
+            circadian_state(&circadian).brightness,
+            Some(OrderedFloat(1.0))
+        );
+
+        // Halfway through the night fade, which eases in with a sine curve.
+        // The sensor is polled every minute, so stop just past 20:00.
+        harness.advance_to(local(20, 0, 30)).await;
+        let circadian = harness.get_device("circadian", "Circadian").await.unwrap();
+        let brightness = circadian_state(&circadian).brightness.unwrap();
+        let expected = 1.0 - 0.5 * f32::sin(std::f32::consts::PI / 4.0);
+        assert!((*brightness - expected).abs() < 1e-3, "{brightness}");
+
+        // Night
+        harness.advance_to(local(23, 0, 30)).await;
+        let circadian = harness.get_device("circadian", "Circadian").await.unwrap();
+        assert_eq!(
+            circadian_state(&circadian).brightness,
+            Some(OrderedFloat(0.5))
+        );
+    }
+}
@@ -22,6 +22,20 @@ impl WebSockets {
         self.users.write().await.remove(&user_id);
     }
 
+    /// Sends a close frame to every connected user and forgets about them,
+    /// which ends their message forwarding tasks.
+    pub async fn close_all(&self) {
+        let mut users = self.users.write().await;
+
+        for (_, user) in users.drain() {
+            user.send(warp::ws::Message::close_with(
+                1001u16,
+                "Server shutting down",
+            ))
+            .ok();
+        }
+    }
+
     pub async fn num_users(&self) -> usize {
         self.users.read().await.len()
     }
@@ -1,15 +1,24 @@
 use std::collections::HashMap;
 
 use super::get_db_connection;
+use crate::types::audit::AuditLogEntry;
+use crate::types::backup::{Backup, RestoreMode};
 use crate::types::device::{Device, DeviceData, DeviceKey, DeviceRow};
+use crate::types::event::EventOrigin;
+use crate::types::history::DeviceHistoryEntry;
 use crate::types::scene::{SceneConfig, SceneId};
 use crate::types::scene::{SceneDevicesConfig, SceneOverridesConfig, ScenesConfig};
+use chrono::{DateTime, Utc};
 use color_eyre::Result;
-use sqlx::types::Json;
+use sqlx::{types::Json, PgExecutor};
 
 pub async fn db_update_device(device: &Device) -> Result<Device> {
     let db = get_db_connection().await?;
 
+    update_device(db, device).await
+}
+
+async fn update_device<'e>(db: impl PgExecutor<'e>, device: &Device) -> Result<Device> {
     let row = sqlx::query_as!(
         DeviceRow,
         r#"
@@ -130,6 +139,14 @@ pub async fn db_get_scenes() -> Result<ScenesConfig> {
 pub async fn db_store_scene(scene_id: &SceneId, config: &SceneConfig) -> Result<()> {
     let db = get_db_connection().await?;
 
+    store_scene(db, scene_id, config).await
+}
+
+async fn store_scene<'e>(
+    db: impl PgExecutor<'e>,
+    scene_id: &SceneId,
+    config: &SceneConfig,
+) -> Result<()> {
     sqlx::query!(
         r#"
             insert into scenes (scene_id, config)
@@ -154,6 +171,14 @@ pub async fn db_store_scene_overrides(
 ) -> Result<()> {
     let db = get_db_connection().await?;
 
+    store_scene_overrides(db, scene_id, overrides).await
+}
+
+async fn store_scene_overrides<'e>(
+    db: impl PgExecutor<'e>,
+    scene_id: &SceneId,
+    overrides: &SceneDevicesConfig,
+) -> Result<()> {
     sqlx::query!(
         r#"
             insert into scene_overrides (scene_id, overrides)
@@ -195,6 +220,10 @@ pub async fn db_get_scene_overrides() -> Result<SceneOverridesConfig> {
 pub async fn db_delete_scene(scene_id: &SceneId) -> Result<()> {
     let db = get_db_connection().await?;
 
+    delete_scene(db, scene_id).await
+}
+
+async fn delete_scene<'e>(db: impl PgExecutor<'e>, scene_id: &SceneId) -> Result<()> {
     sqlx::query!(
         r#"
             delete from scenes
@@ -208,6 +237,29 @@ pub async fn db_delete_scene(scene_id: &SceneId) -> Result<()> {
     Ok(())
 }
 
+/// Deletes a scene along with its scene overrides, or nothing at all if
+/// either fails.
+pub async fn db_delete_scene_with_overrides(scene_id: &SceneId) -> Result<()> {
+    let db = get_db_connection().await?;
+    let mut tx = db.begin().await?;
+
+    delete_scene(&mut *tx, scene_id).await?;
+
+    sqlx::query!(
+        r#"
+            delete from scene_overrides
+            where scene_id = $1
+        "#,
+        scene_id.to_string(),
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+
+    Ok(())
+}
+
 pub async fn db_edit_scene(scene_id: &SceneId, name: &String) -> Result<()> {
     let db = get_db_connection().await?;
 
@@ -231,6 +283,14 @@ pub async fn db_edit_scene(scene_id: &SceneId, name: &String) -> Result<()> {
 pub async fn db_store_ui_state(key: &String, value: &serde_json::Value) -> Result<()> {
     let db = get_db_connection().await?;
 
+    store_ui_state(db, key, value).await
+}
+
+async fn store_ui_state<'e>(
+    db: impl PgExecutor<'e>,
+    key: &String,
+    value: &serde_json::Value,
+) -> Result<()> {
     sqlx::query!(
         r#"
             insert into ui_state (key, value)
@@ -268,3 +328,341 @@ pub async fn db_get_ui_state() -> Result<HashMap<String, serde_json::Value>> {
         .map(|row| (row.key, row.value.0))
         .collect())
 }
+
+pub async fn db_insert_device_history(device: &Device) -> Result<()> {
+    let db = get_db_connection().await?;
+
+    sqlx::query!(
+        r#"
+            insert into device_history (integration_id, device_id, state)
+            values ($1, $2, $3)
+        "#,
+        &device.integration_id.to_string(),
+        &device.id.to_string(),
+        Json(device.data.clone()) as _
+    )
+    .execute(db)
+    .await?;
+
+    Ok(())
+}
+
+/// Fetches recorded states of a device between `from` and `to`, keeping only
+/// the latest entry within each `resolution_seconds` long time bucket.
+pub async fn db_get_device_history(
+    key: &DeviceKey,
+    from: &DateTime<Utc>,
+    to: &DateTime<Utc>,
+    resolution_seconds: f64,
+) -> Result<Vec<DeviceHistoryEntry>> {
+    let db = get_db_connection().await?;
+
+    let result = sqlx::query!(
+        r#"
+            select
+                recorded_at as "recorded_at!",
+                state as "state!: Json<DeviceData>"
+            from (
+                select distinct on (floor(extract(epoch from recorded_at)::float8 / $5::float8))
+                    recorded_at,
+                    state
+                from device_history
+                where integration_id = $1
+                  and device_id = $2
+                  and recorded_at >= $3
+                  and recorded_at <= $4
+                order by floor(extract(epoch from recorded_at)::float8 / $5::float8), recorded_at desc
+            ) as buckets
+            order by recorded_at
+        "#,
+        &key.integration_id.to_string(),
+        &key.device_id.to_string(),
+        from,
+        to,
+        resolution_seconds
+    )
+    .fetch_all(db)
+    .await?;
+
+    Ok(result
+        .into_iter()
+        .map(|row| DeviceHistoryEntry {
+            recorded_at: row.recorded_at,
+            state: row.state.0,
+        })
+        .collect())
+}
+
+pub async fn db_prune_device_history(before: DateTime<Utc>) -> Result<u64> {
+    let db = get_db_connection().await?;
+
+    let result = sqlx::query!(
+        r#"
+            delete from device_history
+            where recorded_at < $1
+        "#,
+        before
+    )
+    .execute(db)
+    .await?;
+
+    Ok(result.rows_affected())
+}
+
+/// Thins out entries older than `before` so that at most one entry per device
+/// remains within each `resolution_seconds` long time bucket.
+pub async fn db_downsample_device_history(
+    before: DateTime<Utc>,
+    resolution_seconds: f64,
+) -> Result<u64> {
+    let db = get_db_connection().await?;
+
+    let result = sqlx::query!(
+        r#"
+            delete from device_history
+            where recorded_at < $1
+              and id not in (
+                select distinct on (
+                    integration_id,
+                    device_id,
+                    floor(extract(epoch from recorded_at)::float8 / $2::float8)
+                )
+                    id
+                from device_history
+                where recorded_at < $1
+                order by
+                    integration_id,
+                    device_id,
+                    floor(extract(epoch from recorded_at)::float8 / $2::float8),
+                    recorded_at desc
+              )
+        "#,
+        before,
+        resolution_seconds
+    )
+    .execute(db)
+    .await?;
+
+    Ok(result.rows_affected())
+}
+
+pub async fn db_insert_audit_log_entry(entry: &AuditLogEntry) -> Result<()> {
+    let db = get_db_connection().await?;
+
+    sqlx::query!(
+        r#"
+            insert into audit_log (event, origin, recorded_at)
+            values ($1, $2, $3)
+        "#,
+        Json(&entry.event) as _,
+        Json(&entry.origin) as _,
+        entry.recorded_at
+    )
+    .execute(db)
+    .await?;
+
+    Ok(())
+}
+
+pub async fn db_prune_audit_log(before: DateTime<Utc>) -> Result<u64> {
+    let db = get_db_connection().await?;
+
+    let result = sqlx::query("delete from audit_log where recorded_at < $1")
+        .bind(before)
+        .execute(db)
+        .await?;
+
+    Ok(result.rows_affected())
+}
+
+/// Fetches at most `limit` audit log entries between `from` and `to`, newest
+/// entries first.
+pub async fn db_get_audit_log(
+    from: &DateTime<Utc>,
+    to: &DateTime<Utc>,
+    limit: i64,
+) -> Result<Vec<AuditLogEntry>> {
+    let db = get_db_connection().await?;
+
+    let result = sqlx::query!(
+        r#"
+            select
+                recorded_at,
+                event as "event: Json<serde_json::Value>",
+                origin as "origin: Json<EventOrigin>"
+            from audit_log
+            where recorded_at >= $1
+              and recorded_at <= $2
+            order by recorded_at desc
+            limit $3
+        "#,
+        from,
+        to,
+        limit
+    )
+    .fetch_all(db)
+    .await?;
+
+    Ok(result
+        .into_iter()
+        .map(|row| AuditLogEntry {
+            recorded_at: row.recorded_at,
+            event: row.event.0,
+            origin: row.origin.0,
+        })
+        .collect())
+}
+
+/// Restores the contents of a backup within a single transaction.
+pub async fn db_restore_backup(backup: &Backup, mode: RestoreMode) -> Result<()> {
+    let db = get_db_connection().await?;
+    let mut tx = db.begin().await?;
+
+    if mode == RestoreMode::Replace {
+        sqlx::query!("delete from scenes").execute(&mut *tx).await?;
+        sqlx::query!("delete from scene_overrides")
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query!("delete from ui_state")
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query!("delete from devices")
+            .execute(&mut *tx)
+            .await?;
+    }
+
+    for (scene_id, config) in &backup.scenes {
+        store_scene(&mut *tx, scene_id, config).await?;
+    }
+
+    for (scene_id, overrides) in &backup.scene_overrides {
+        store_scene_overrides(&mut *tx, scene_id, overrides).await?;
+    }
+
+    for (key, value) in &backup.ui_state {
+        store_ui_state(&mut *tx, key, value).await?;
+    }
+
+    for device in backup.devices.0.values() {
+        update_device(&mut *tx, device).await?;
+    }
+
+    tx.commit().await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::init_db;
+    use chrono::TimeZone;
+
+    const INTEGRATION_ID: &str = "test_device_history";
+
+    async fn insert_history(device_id: &str, recorded_at: DateTime<Utc>) -> i64 {
+        let db = get_db_connection().await.unwrap();
+
+        sqlx::query_scalar(
+            r#"
+                insert into device_history (integration_id, device_id, state, recorded_at)
+                values ($1, $2, '{}', $3)
+                returning id
+            "#,
+        )
+        .bind(INTEGRATION_ID)
+        .bind(device_id)
+        .bind(recorded_at)
+        .fetch_one(db)
+        .await
+        .unwrap()
+    }
+
+    async fn history_ids() -> Vec<i64> {
+        let db = get_db_connection().await.unwrap();
+
+        sqlx::query_scalar("select id from device_history where integration_id = $1 order by id")
+            .bind(INTEGRATION_ID)
+            .fetch_all(db)
+            .await
+            .unwrap()
+    }
+
+    async fn clear_history() {
+        let db = get_db_connection().await.unwrap();
+
+        sqlx::query("delete from device_history where integration_id = $1")
+            .bind(INTEGRATION_ID)
+            .execute(db)
+            .await
+            .unwrap();
+    }
+
+    /// Requires a migrated database at DATABASE_URL. Entries are recorded in
+    /// the year 2000, so that other history entries are left alone.
+    #[tokio::test]
+    #[ignore]
+    async fn test_device_history_maintenance() {
+        init_db().await.expect("DATABASE_URL is not set");
+        clear_history().await;
+
+        let t0 = Utc.with_ymd_and_hms(2000, 1, 1, 0, 0, 0).unwrap();
+        let secs = chrono::Duration::seconds;
+
+        // First 5 minute bucket, only the latest entry of each device is kept
+        insert_history("a", t0).await;
+        insert_history("a", t0 + secs(100)).await;
+        let a2 = insert_history("a", t0 + secs(299)).await;
+        let b0 = insert_history("b", t0 + secs(50)).await;
+
+        // Entries alone in their buckets
+        let a3 = insert_history("a", t0 + secs(300)).await;
+        let a4 = insert_history("a", t0 + secs(900)).await;
+
+        // Newer than the downsampling threshold
+        let a5 = insert_history("a", t0 + chrono::Duration::days(2)).await;
+        let a6 = insert_history("a", t0 + chrono::Duration::days(2) + secs(1)).await;
+
+        let before = t0 + chrono::Duration::days(1);
+        let downsampled = db_downsample_device_history(before, 300.0).await.unwrap();
+
+        assert_eq!(downsampled, 2);
+        assert_eq!(history_ids().await, vec![a2, b0, a3, a4, a5, a6]);
+
+        let pruned = db_prune_device_history(t0 + secs(600)).await.unwrap();
+
+        assert_eq!(pruned, 3);
+        assert_eq!(history_ids().await, vec![a4, a5, a6]);
+
+        clear_history().await;
+    }
+
+    /// Requires a migrated database at DATABASE_URL. Entries are recorded in
+    /// the year 1990, so that other audit log entries are left alone.
+    #[tokio::test]
+    #[ignore]
+    async fn test_prune_audit_log() {
+        init_db().await.expect("DATABASE_URL is not set");
+
+        let t0 = Utc.with_ymd_and_hms(1990, 1, 1, 0, 0, 0).unwrap();
+        let secs = chrono::Duration::seconds;
+
+        for recorded_at in [t0, t0 + secs(60), t0 + secs(600)] {
+            let entry = AuditLogEntry {
+                recorded_at,
+                event: serde_json::json!({}),
+                origin: EventOrigin::Internal,
+            };
+            db_insert_audit_log_entry(&entry).await.unwrap();
+        }
+
+        let pruned = db_prune_audit_log(t0 + secs(300)).await.unwrap();
+        assert_eq!(pruned, 2);
+
+        let remaining = db_get_audit_log(&t0, &(t0 + secs(3600)), 10).await.unwrap();
+        assert_eq!(remaining.len(), 1);
+        assert_eq!(remaining[0].recorded_at, t0 + secs(600));
+
+        db_prune_audit_log(t0 + secs(3600)).await.unwrap();
+    }
+}
@@ -2,12 +2,22 @@ use color_eyre::Result;
 use eyre::eyre;
 use once_cell::sync::OnceCell;
 use sqlx::{pool::PoolOptions, PgPool};
-use std::{env, time::Duration};
+use std::{
+    env,
+    future::Future,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+use tokio::sync::Notify;
 
 pub mod actions;
 
 static DB_CONNECTION: OnceCell<PgPool> = OnceCell::new();
 
+/// Number of DB writes spawned with [spawn_db_write] that haven't finished yet
+static PENDING_WRITES: AtomicUsize = AtomicUsize::new(0);
+static WRITES_FINISHED: Notify = Notify::const_new();
+
 pub async fn init_db() -> Option<()> {
     let database_url = env::var("DATABASE_URL").ok();
 
@@ -35,3 +45,37 @@ pub async fn get_db_connection<'a>() -> Result<&'a PgPool> {
         .get()
         .ok_or_else(|| eyre!("Not connected to database"))
 }
+
+/// Runs a DB write in the background without blocking the caller. Pending
+/// writes can be awaited with [wait_for_pending_writes], e.g. before shutting
+/// down.
+pub fn spawn_db_write<F, T>(write: F)
+where
+    F: Future<Output = Result<T>> + Send + 'static,
+    T: Send + 'static,
+{
+    PENDING_WRITES.fetch_add(1, Ordering::SeqCst);
+
+    tokio::spawn(async move {
+        write.await.ok();
+
+        if PENDING_WRITES.fetch_sub(1, Ordering::SeqCst) == 1 {
+            WRITES_FINISHED.notify_waiters();
+        }
+    });
+}
+
+/// Waits until all DB writes spawned with [spawn_db_write] have finished.
+pub async fn wait_for_pending_writes() {
+    loop {
+        let finished = WRITES_FINISHED.notified();
+
+        let pending = PENDING_WRITES.load(Ordering::SeqCst);
+        if pending == 0 {
+            return;
+        }
+
+        debug!("Waiting for {pending} pending DB writes");
+        finished.await;
+    }
+}
@@ -6,7 +6,7 @@ use crate::{
         event::{Event, TxEventChannel},
         integration::{Integration, IntegrationId},
     },
-    utils::cli::Cli,
+    utils::{cli::Cli, clock},
 };
 use async_trait::async_trait;
 use color_eyre::Result;
@@ -85,7 +85,7 @@ impl Integration for Circadian {
 }
 
 fn get_night_fade(circadian: &Circadian) -> f32 {
-    let local = chrono::Local::now().naive_local().time();
+    let local = clock::local_now().naive_local().time();
 
     let day_fade_start = circadian.config.day_fade_start;
     let day_fade_duration = chrono::Duration::hours(circadian.config.day_fade_duration_hours);
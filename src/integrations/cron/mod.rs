@@ -3,19 +3,19 @@ use crate::{
         action::Action,
         color::Capabilities,
         device::{ControllableDevice, Device, DeviceData, DeviceId, ManageKind},
-        event::{Event, TxEventChannel},
+        event::{Event, EventOrigin, TxEventChannel},
         integration::{Integration, IntegrationActionPayload, IntegrationId},
     },
-    utils::cli::Cli,
+    utils::{cli::Cli, clock},
 };
 use async_trait::async_trait;
-use chrono::Local;
 use color_eyre::Result;
 use eyre::Context;
 use serde::Deserialize;
 use std::{collections::HashMap, sync::Arc};
 use tokio::{
     sync::RwLock,
+    task::JoinHandle,
     time::{sleep_until, Instant},
 };
 
@@ -37,6 +37,7 @@ pub struct Cron {
     event_tx: TxEventChannel,
     config: CronConfig,
     devices: Arc<RwLock<HashMap<DeviceId, Device>>>,
+    tasks: HashMap<DeviceId, JoinHandle<()>>,
 }
 
 #[async_trait]
@@ -57,6 +58,7 @@ impl Integration for Cron {
             config,
             event_tx,
             devices: Default::default(),
+            tasks: Default::default(),
         })
     }
 
@@ -95,26 +97,41 @@ impl Integration for Cron {
             let event_tx = self.event_tx.clone();
             let action = config.action.clone();
             let id = id.clone();
+            let integration_id = self.id.clone();
 
             let cron = croner::Cron::new(&config.schedule).parse()?;
 
-            tokio::spawn(async move {
-                loop {
-                    let next = cron.find_next_occurrence(&Local::now(), false).unwrap();
-
-                    let duration = next - Local::now();
-                    trace!("Sleeping for {duration:?}");
-                    sleep_until(Instant::now() + duration.to_std().unwrap()).await;
-
-                    debug!("Running cron job for device {id}");
-
-                    let devices = devices.read().await;
-                    let device = devices.get(&id).unwrap();
-                    if device.is_powered_on() == Some(true) {
-                        event_tx.send(Event::Action(action.clone()));
+            let task = tokio::spawn({
+                let id = id.clone();
+
+                async move {
+                    loop {
+                        let next = cron
+                            .find_next_occurrence(&clock::local_now(), false)
+                            .unwrap();
+
+                        let duration = next - clock::local_now();
+                        trace!("Sleeping for {duration:?}");
+                        sleep_until(Instant::now() + duration.to_std().unwrap()).await;
+
+                        debug!("Running cron job for device {id}");
+
+                        let devices = devices.read().await;
+                        let device = devices.get(&id).unwrap();
+                        if device.is_powered_on() == Some(true) {
+                            event_tx.send_with_origin(
+                                Event::Action(action.clone()),
+                                EventOrigin::Cron {
+                                    integration_id: integration_id.clone(),
+                                    device_id: id.clone(),
+                                },
+                            );
+                        }
                     }
                 }
             });
+
+            self.tasks.insert(id, task);
         }
 
         Ok(())
@@ -133,4 +150,19 @@ impl Integration for Cron {
         // do nothing
         Ok(())
     }
+
+    async fn stop(&mut self) -> Result<()> {
+        for (_, task) in self.tasks.drain() {
+            task.abort();
+        }
+
+        Ok(())
+    }
+
+    async fn health_check(&mut self) -> Result<()> {
+        match self.tasks.iter().find(|(_, task)| task.is_finished()) {
+            Some((id, _)) => Err(eyre!("Cron job for device {id} has stopped")),
+            None => Ok(()),
+        }
+    }
 }
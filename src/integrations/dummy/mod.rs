@@ -21,6 +21,7 @@ pub struct DummyDeviceConfig {
 
 #[derive(Debug, Deserialize)]
 pub struct DummyConfig {
+    #[serde(default)]
     devices: HashMap<DeviceId, DummyDeviceConfig>,
 }
 
@@ -0,0 +1,477 @@
+mod protocol;
+
+use crate::{
+    types::{
+        device::Device,
+        event::{Event, TxEventChannel},
+        integration::{Integration, IntegrationActionPayload, IntegrationId},
+    },
+    utils::cli::Cli,
+};
+use async_trait::async_trait;
+use color_eyre::Result;
+use eyre::Context;
+use serde::Deserialize;
+use serde_json::Value;
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    process::Stdio,
+    sync::{Arc, Mutex as StdMutex},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    process::{Child, ChildStdin, ChildStdout, Command},
+    sync::oneshot,
+    task::JoinHandle,
+};
+
+use self::protocol::*;
+
+const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a plugin is given to exit after being asked to stop, before it is
+/// killed
+const EXIT_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Deserialize)]
+pub struct ExecConfig {
+    /// Executable that implements the plugin, e.g. `python3`
+    command: String,
+    #[serde(default)]
+    args: Vec<String>,
+    #[serde(default)]
+    env: HashMap<String, String>,
+    cwd: Option<PathBuf>,
+
+    /// How long to wait for the plugin to respond to each request, defaults to
+    /// 10 seconds
+    request_timeout_seconds: Option<u64>,
+
+    /// Passed as-is to the plugin in the `initialize` request
+    #[serde(default)]
+    config: Value,
+}
+
+type PendingRequests = Arc<StdMutex<HashMap<u64, oneshot::Sender<Result<Value, RpcError>>>>>;
+
+/// A running plugin process
+struct PluginProcess {
+    child: Child,
+    stdin: ChildStdin,
+    pending: PendingRequests,
+    next_request_id: u64,
+    request_timeout: Duration,
+    reader: JoinHandle<()>,
+}
+
+impl PluginProcess {
+    fn spawn(id: &IntegrationId, config: &ExecConfig, event_tx: TxEventChannel) -> Result<Self> {
+        let mut command = Command::new(&config.command);
+        command
+            .args(&config.args)
+            .envs(&config.env)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true);
+
+        if let Some(cwd) = &config.cwd {
+            command.current_dir(cwd);
+        }
+
+        let mut child = command
+            .spawn()
+            .wrap_err_with(|| format!("Failed to spawn plugin command {}", config.command))?;
+
+        let stdin = child.stdin.take().expect("Expected stdin to be piped");
+        let stdout = child.stdout.take().expect("Expected stdout to be piped");
+        let stderr = child.stderr.take().expect("Expected stderr to be piped");
+
+        let target = format!("homectl_server::integrations::exec::{id}");
+
+        {
+            let target = target.clone();
+            tokio::spawn(async move {
+                let mut lines = BufReader::new(stderr).lines();
+                while let Ok(Some(line)) = lines.next_line().await {
+                    info!(target: &target, "{line}");
+                }
+            });
+        }
+
+        let pending: PendingRequests = Default::default();
+        let reader = tokio::spawn(read_messages(
+            id.clone(),
+            stdout,
+            pending.clone(),
+            event_tx,
+            target,
+        ));
+
+        Ok(PluginProcess {
+            child,
+            stdin,
+            pending,
+            next_request_id: 1,
+            request_timeout: config
+                .request_timeout_seconds
+                .map(Duration::from_secs)
+                .unwrap_or(DEFAULT_REQUEST_TIMEOUT),
+            reader,
+        })
+    }
+
+    /// Sends a request to the plugin and waits for its response
+    async fn request(&mut self, method: &str, params: Value) -> Result<Value> {
+        let id = self.next_request_id;
+        self.next_request_id += 1;
+
+        let (response_tx, response_rx) = oneshot::channel();
+        self.pending.lock().unwrap().insert(id, response_tx);
+
+        let request = Request {
+            jsonrpc: "2.0",
+            method,
+            params,
+            id,
+        };
+        let mut line = serde_json::to_string(&request)?;
+        line.push('\n');
+
+        let sent = async {
+            self.stdin.write_all(line.as_bytes()).await?;
+            self.stdin.flush().await
+        };
+        if let Err(e) = sent.await {
+            self.pending.lock().unwrap().remove(&id);
+            return Err(e).wrap_err_with(|| format!("Failed to send {method} request to plugin"));
+        }
+
+        match tokio::time::timeout(self.request_timeout, response_rx).await {
+            Ok(Ok(Ok(result))) => Ok(result),
+            Ok(Ok(Err(e))) => Err(eyre!(
+                "Plugin returned error for {method}: {message} (code {code})",
+                message = e.message,
+                code = e.code
+            )),
+            Ok(Err(_)) => Err(eyre!("Plugin exited before responding to {method}")),
+            Err(_) => {
+                self.pending.lock().unwrap().remove(&id);
+                Err(eyre!(
+                    "Plugin did not respond to {method} within {timeout:?}",
+                    timeout = self.request_timeout
+                ))
+            }
+        }
+    }
+
+    /// Asks the plugin to stop and waits for it to exit, killing it if it
+    /// doesn't exit in time
+    async fn stop(mut self) -> Result<()> {
+        let result = self.request(methods::STOP, Value::Null).await;
+
+        // Closing stdin signals the plugin to exit
+        drop(self.stdin);
+
+        match tokio::time::timeout(EXIT_TIMEOUT, self.child.wait()).await {
+            Ok(status) => {
+                debug!("Plugin exited with {}", status?);
+            }
+            Err(_) => {
+                warn!("Plugin did not exit within {EXIT_TIMEOUT:?}, killing it");
+                self.child.kill().await?;
+            }
+        }
+
+        result.map(|_| ())
+    }
+}
+
+/// Reads responses and notifications from the plugin until it closes stdout
+async fn read_messages(
+    integration_id: IntegrationId,
+    stdout: ChildStdout,
+    pending: PendingRequests,
+    event_tx: TxEventChannel,
+    target: String,
+) {
+    let mut lines = BufReader::new(stdout).lines();
+
+    loop {
+        let line = match lines.next_line().await {
+            Ok(Some(line)) => line,
+            Ok(None) => break,
+            Err(e) => {
+                error!(target: &target, "Failed to read from plugin: {e}");
+                break;
+            }
+        };
+
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let message: Incoming = match serde_json::from_str(&line) {
+            Ok(message) => message,
+            Err(e) => {
+                warn!(target: &target, "Ignoring invalid message from plugin: {e}: {line}");
+                continue;
+            }
+        };
+
+        match (message.id, message.method) {
+            (Some(id), _) => {
+                let response_tx = pending.lock().unwrap().remove(&id);
+                let Some(response_tx) = response_tx else {
+                    warn!(target: &target, "Ignoring response to unknown request {id}");
+                    continue;
+                };
+
+                let response = match message.error {
+                    Some(error) => Err(error),
+                    None => Ok(message.result),
+                };
+                response_tx.send(response).ok();
+            }
+            (None, Some(method)) => {
+                if let Err(e) =
+                    handle_notification(&integration_id, &method, message.params, &event_tx)
+                {
+                    warn!(target: &target, "Invalid {method} notification from plugin: {e}");
+                }
+            }
+            (None, None) => {
+                warn!(target: &target, "Ignoring message without id or method: {line}");
+            }
+        }
+    }
+
+    warn!(target: &target, "Plugin closed its stdout");
+
+    // Fail all requests that are still waiting for a response
+    pending.lock().unwrap().clear();
+}
+
+fn handle_notification(
+    integration_id: &IntegrationId,
+    method: &str,
+    params: Value,
+    event_tx: &TxEventChannel,
+) -> Result<()> {
+    let target = format!("homectl_server::integrations::exec::{integration_id}");
+
+    match method {
+        notifications::DEVICE_UPDATE => {
+            let DeviceUpdateParams { device } = serde_json::from_value(params)?;
+
+            let device = Device::new(
+                integration_id.clone(),
+                device.id,
+                device.name,
+                device.data,
+                device.raw,
+            );
+            event_tx.send(Event::ExternalStateUpdate { device });
+        }
+        notifications::LOG => {
+            let LogParams { level, message } = serde_json::from_value(params)?;
+
+            match level {
+                LogLevel::Error => error!(target: &target, "{message}"),
+                LogLevel::Warn => warn!(target: &target, "{message}"),
+                LogLevel::Info => info!(target: &target, "{message}"),
+                LogLevel::Debug => debug!(target: &target, "{message}"),
+                LogLevel::Trace => trace!(target: &target, "{message}"),
+            }
+        }
+        _ => return Err(eyre!("Unknown notification")),
+    }
+
+    Ok(())
+}
+
+/// Runs an integration implemented by an external process, which homectl
+/// talks to using JSON-RPC over stdin and stdout.
+pub struct Exec {
+    id: IntegrationId,
+    config: ExecConfig,
+    cli: Cli,
+    event_tx: TxEventChannel,
+    process: Option<PluginProcess>,
+}
+
+impl Exec {
+    fn process(&mut self) -> Result<&mut PluginProcess> {
+        self.process
+            .as_mut()
+            .ok_or_else(|| eyre!("Plugin process of integration {} is not running", self.id))
+    }
+}
+
+#[async_trait]
+impl Integration for Exec {
+    fn new(
+        id: &IntegrationId,
+        config: &config::Value,
+        cli: &Cli,
+        event_tx: TxEventChannel,
+    ) -> Result<Self> {
+        let config = config
+            .clone()
+            .try_deserialize()
+            .wrap_err("Failed to deserialize config of Exec integration")?;
+
+        Ok(Exec {
+            id: id.clone(),
+            config,
+            cli: cli.clone(),
+            event_tx,
+            process: None,
+        })
+    }
+
+    async fn register(&mut self) -> Result<()> {
+        let mut process = PluginProcess::spawn(&self.id, &self.config, self.event_tx.clone())?;
+
+        let params = serde_json::to_value(InitializeParams {
+            protocol_version: PROTOCOL_VERSION,
+            integration_id: &self.id,
+            config: &self.config.config,
+            dry_run: self.cli.dry_run,
+        })?;
+        process.request(methods::INITIALIZE, params).await?;
+        process.request(methods::REGISTER, Value::Null).await?;
+
+        self.process = Some(process);
+
+        Ok(())
+    }
+
+    async fn start(&mut self) -> Result<()> {
+        self.process()?.request(methods::START, Value::Null).await?;
+
+        Ok(())
+    }
+
+    async fn set_integration_device_state(&mut self, device: &Device) -> Result<()> {
+        let params = serde_json::to_value(SetDeviceStateParams { device })?;
+        self.process()?
+            .request(methods::SET_DEVICE_STATE, params)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn run_integration_action(&mut self, payload: &IntegrationActionPayload) -> Result<()> {
+        let params = serde_json::to_value(RunActionParams { payload })?;
+        self.process()?.request(methods::RUN_ACTION, params).await?;
+
+        Ok(())
+    }
+
+    async fn health_check(&mut self) -> Result<()> {
+        let process = self.process()?;
+
+        if process.reader.is_finished() {
+            return Err(eyre!("Plugin process has exited"));
+        }
+
+        process.request(methods::HEALTH_CHECK, Value::Null).await?;
+
+        Ok(())
+    }
+
+    async fn stop(&mut self) -> Result<()> {
+        match self.process.take() {
+            Some(process) => process.stop().await,
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+    use crate::types::{
+        device::{DeviceData, SensorDevice},
+        event::mk_event_channel,
+    };
+
+    /// Minimal plugin that acknowledges every request, reports a sensor when
+    /// registering and fails actions
+    const PLUGIN_SCRIPT: &str = r#"
+while read -r line; do
+  id=$(echo "$line" | sed -n 's/.*"id":\([0-9]*\)}$/\1/p')
+  case "$line" in
+    *'"method":"register"'*)
+      echo '{"jsonrpc":"2.0","method":"device_update","params":{"device":{"id":"button","name":"Button","data":{"Sensor":{"value":true}}}}}'
+      echo "{\"jsonrpc\":\"2.0\",\"id\":$id,\"result\":null}" ;;
+    *'"method":"run_action"'*)
+      echo "{\"jsonrpc\":\"2.0\",\"id\":$id,\"error\":{\"code\":1,\"message\":\"not supported\"}}" ;;
+    *)
+      echo "{\"jsonrpc\":\"2.0\",\"id\":$id,\"result\":null}" ;;
+  esac
+done
+"#;
+
+    fn mk_exec(event_tx: TxEventChannel) -> Exec {
+        let config = config::Value::from(config::Map::from([
+            ("command".to_string(), config::Value::from("sh")),
+            (
+                "args".to_string(),
+                config::Value::from(vec!["-c", PLUGIN_SCRIPT]),
+            ),
+        ]));
+
+        let cli = Cli {
+            dry_run: false,
+            record: None,
+            command: None,
+        };
+
+        Exec::new(
+            &IntegrationId::from_str("plugin").unwrap(),
+            &config,
+            &cli,
+            event_tx,
+        )
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_exec_plugin_lifecycle() {
+        let (event_tx, mut event_rx) = mk_event_channel();
+        let mut exec = mk_exec(event_tx);
+
+        exec.register().await.unwrap();
+        exec.start().await.unwrap();
+
+        let event = event_rx.recv().await;
+        let Event::ExternalStateUpdate { device } = event.event else {
+            panic!("Expected ExternalStateUpdate, got {:?}", event.event);
+        };
+        assert_eq!(device.integration_id, exec.id);
+        assert_eq!(
+            device.data,
+            DeviceData::Sensor(SensorDevice::Boolean {
+                value: true,
+                meta: None
+            })
+        );
+
+        exec.set_integration_device_state(&device).await.unwrap();
+        exec.health_check().await.unwrap();
+
+        let result = exec
+            .run_integration_action(&IntegrationActionPayload::from("test".to_string()))
+            .await;
+        assert!(result.unwrap_err().to_string().contains("not supported"));
+
+        exec.stop().await.unwrap();
+        assert!(exec.health_check().await.is_err());
+    }
+}
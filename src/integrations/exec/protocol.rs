@@ -0,0 +1,106 @@
+//! JSON-RPC 2.0 messages exchanged with `exec` plugins, one JSON object per
+//! line over the plugin's stdin and stdout. See `docs/exec-plugins.md`.
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::types::{
+    device::{Device, DeviceData, DeviceId},
+    integration::{IntegrationActionPayload, IntegrationId},
+};
+
+/// Bumped whenever a breaking change is made to the protocol
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Methods called by homectl, mirroring the `Integration` trait
+pub mod methods {
+    pub const INITIALIZE: &str = "initialize";
+    pub const REGISTER: &str = "register";
+    pub const START: &str = "start";
+    pub const SET_DEVICE_STATE: &str = "set_device_state";
+    pub const RUN_ACTION: &str = "run_action";
+    pub const HEALTH_CHECK: &str = "health_check";
+    pub const STOP: &str = "stop";
+}
+
+/// Notifications sent by plugins
+pub mod notifications {
+    pub const DEVICE_UPDATE: &str = "device_update";
+    pub const LOG: &str = "log";
+}
+
+#[derive(Debug, Serialize)]
+pub struct Request<'a> {
+    pub jsonrpc: &'static str,
+    pub method: &'a str,
+    pub params: Value,
+    pub id: u64,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+}
+
+/// Any message sent by a plugin. Responses have an `id` and either a `result`
+/// or an `error`, notifications have a `method` and no `id`.
+#[derive(Debug, Deserialize)]
+pub struct Incoming {
+    pub id: Option<u64>,
+    pub method: Option<String>,
+    #[serde(default)]
+    pub params: Value,
+    #[serde(default)]
+    pub result: Value,
+    pub error: Option<RpcError>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct InitializeParams<'a> {
+    pub protocol_version: u32,
+    pub integration_id: &'a IntegrationId,
+    pub config: &'a Value,
+    pub dry_run: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SetDeviceStateParams<'a> {
+    pub device: &'a Device,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RunActionParams<'a> {
+    pub payload: &'a IntegrationActionPayload,
+}
+
+/// Device reported by a plugin. The integration id is filled in by homectl.
+#[derive(Debug, Deserialize)]
+pub struct PluginDevice {
+    pub id: DeviceId,
+    pub name: String,
+    pub data: DeviceData,
+    #[serde(default)]
+    pub raw: Option<Value>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DeviceUpdateParams {
+    pub device: PluginDevice,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LogParams {
+    pub level: LogLevel,
+    pub message: String,
+}
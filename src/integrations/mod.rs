@@ -1,6 +1,12 @@
 pub mod circadian;
 pub mod cron;
 pub mod dummy;
+pub mod exec;
 pub mod mqtt;
 pub mod random;
+pub mod shelly;
 pub mod timer;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+pub mod wled;
+pub mod zigbee2mqtt;
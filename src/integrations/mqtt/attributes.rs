@@ -0,0 +1,219 @@
+//! Support for devices that publish each attribute on a separate topic, e.g.
+//! `shellies/<id>/relay/0` → `on`.
+//!
+//! Messages received on attribute topics are accumulated into one message per
+//! device, which is then converted with [json_to_homectl] like any other
+//! message. Outgoing states are produced by [homectl_to_mqtt] and each
+//! attribute with a `topic_set` is published to its own topic.
+
+use std::collections::{BTreeMap, HashMap};
+
+use color_eyre::Result;
+use jsonptr::{Assign, Pointer, PointerBuf};
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::types::{device::Device, integration::IntegrationId};
+
+use super::{
+    utils::{
+        device_topic, encode_payload, homectl_to_mqtt, insert_topic_captures, json_to_homectl,
+        match_topic, parse_payload, TopicCaptures,
+    },
+    MqttConfig,
+};
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct AttributeTopic {
+    /// Topic pattern the attribute is read from, must contain `{id}`
+    topic: String,
+
+    /// Topic pattern the attribute is written to when setting device states
+    topic_set: Option<String>,
+}
+
+/// Attribute topics by the JSON pointer their values are stored at
+pub type AttributeTopics = BTreeMap<PointerBuf, AttributeTopic>;
+
+pub fn validate(attributes: &AttributeTopics) -> Result<()> {
+    for (field, attribute) in attributes {
+        if !attribute.topic.contains("{id}") {
+            return Err(eyre!(
+                "Topic of attribute '{field}' must contain {{id}}: {}",
+                attribute.topic
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Topics to subscribe to for receiving attribute values
+pub fn subscriptions(attributes: &AttributeTopics) -> impl Iterator<Item = &str> {
+    attributes
+        .values()
+        .map(|attribute| attribute.topic.as_str())
+}
+
+/// Finds the attribute that the topic belongs to
+pub fn match_attribute<'a>(
+    attributes: &'a AttributeTopics,
+    topic: &str,
+) -> Option<(&'a Pointer, TopicCaptures)> {
+    attributes.iter().find_map(|(field, attribute)| {
+        let captures = match_topic(&attribute.topic, topic)?;
+        Some((field.as_ref(), captures))
+    })
+}
+
+/// Builds the messages for setting the device's attributes, as (topic,
+/// payload) pairs
+pub fn attribute_commands(device: &Device, config: &MqttConfig) -> Result<Vec<(String, Vec<u8>)>> {
+    if config.attributes.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let state = homectl_to_mqtt(device.clone(), config)?;
+
+    let commands = config
+        .attributes
+        .iter()
+        .filter_map(|(field, attribute)| {
+            let topic_set = attribute.topic_set.as_deref()?;
+            let value = field.resolve(&state).ok()?;
+
+            Some((device_topic(topic_set, device), encode_payload(value)))
+        })
+        .collect();
+
+    Ok(commands)
+}
+
+/// Latest attribute values of each device, by device id
+#[derive(Debug, Default)]
+pub struct AttributeStates {
+    messages: HashMap<String, Value>,
+}
+
+impl AttributeStates {
+    /// Stores the value received on an attribute topic and converts the
+    /// device's accumulated message into a device
+    pub fn handle_message(
+        &mut self,
+        field: &Pointer,
+        captures: TopicCaptures,
+        payload: &[u8],
+        topic: &str,
+        integration_id: IntegrationId,
+        config: &MqttConfig,
+    ) -> Result<Option<Device>> {
+        let id = captures
+            .id
+            .clone()
+            .ok_or_else(|| eyre!("No device id found in topic {topic}"))?;
+
+        let message = self
+            .messages
+            .entry(id)
+            .or_insert_with(|| Value::Object(Default::default()));
+
+        insert_topic_captures(message, captures, config)?;
+        message.assign(field, parse_payload(payload))?;
+
+        Ok(json_to_homectl(message, topic, integration_id, config))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use serde_json::json;
+
+    use crate::types::device::{DeviceData, ManageKind};
+
+    use super::*;
+
+    fn config() -> MqttConfig {
+        MqttConfig {
+            managed: Some(ManageKind::Unmanaged),
+            attributes: serde_json::from_value(json!({
+                "/power": {
+                    "topic": "shellies/{id}/light/0",
+                    "topic_set": "shellies/{id}/light/0/command"
+                },
+                "/brightness": {
+                    "topic": "shellies/{id}/light/0/brightness",
+                    "topic_set": "shellies/{id}/light/0/brightness/set"
+                }
+            }))
+            .unwrap(),
+            transforms: serde_json::from_value(json!({
+                "/power": [{ "type": "map", "values": [["on", true], ["off", false]] }],
+                "/brightness": [{ "type": "convert", "from": "percent", "to": "ratio" }]
+            }))
+            .unwrap(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_assemble_device() {
+        let config = config();
+        let integration_id = IntegrationId::from_str("mqtt").unwrap();
+        let mut states = AttributeStates::default();
+
+        let mut receive = |topic: &str, payload: &[u8]| {
+            let (field, captures) = match_attribute(&config.attributes, topic).unwrap();
+            states
+                .handle_message(
+                    field,
+                    captures,
+                    payload,
+                    topic,
+                    integration_id.clone(),
+                    &config,
+                )
+                .unwrap()
+                .unwrap()
+        };
+
+        receive("shellies/dimmer-1/light/0", b"on");
+        let device = receive("shellies/dimmer-1/light/0/brightness", b"40");
+
+        assert_eq!(device.id.to_string(), "dimmer-1");
+        assert_eq!(device.name, "dimmer-1");
+
+        let DeviceData::Controllable(controllable) = &device.data else {
+            panic!("Expected a controllable device");
+        };
+        assert!(controllable.state.power);
+        assert!(controllable.state.brightness.is_some());
+
+        assert!(match_attribute(&config.attributes, "shellies/dimmer-1/relay/0").is_none());
+    }
+
+    #[test]
+    fn test_attribute_commands() {
+        let config = config();
+        let integration_id = IntegrationId::from_str("mqtt").unwrap();
+        let mut states = AttributeStates::default();
+
+        let topic = "shellies/dimmer-1/light/0";
+        let (field, captures) = match_attribute(&config.attributes, topic).unwrap();
+        let device = states
+            .handle_message(field, captures, b"off", topic, integration_id, &config)
+            .unwrap()
+            .unwrap();
+
+        let commands = attribute_commands(&device, &config).unwrap();
+
+        assert_eq!(
+            commands,
+            vec![(
+                "shellies/dimmer-1/light/0/command".to_string(),
+                b"off".to_vec()
+            )]
+        );
+    }
+}
@@ -0,0 +1,445 @@
+//! Wraps the MQTT 3.1.1 and MQTT 5 clients of rumqttc, so that the rest of the
+//! integration doesn't need to care about which protocol version is used.
+
+use std::{path::Path, sync::Arc, time::Duration};
+
+use bytes::Bytes;
+use color_eyre::Result;
+use eyre::Context;
+use rand::{distributions::Alphanumeric, Rng};
+use rumqttc::{v5, TlsConfiguration, Transport};
+use rustls::{
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    pki_types::{CertificateDer, ServerName, UnixTime},
+    DigitallySignedStruct, SignatureScheme,
+};
+use serde::Deserialize;
+
+use crate::types::integration::IntegrationId;
+
+const DEFAULT_KEEP_ALIVE: Duration = Duration::from_secs(5);
+const DEFAULT_WEBSOCKET_PATH: &str = "/mqtt";
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum MqttTransport {
+    #[default]
+    Tcp,
+    Websocket,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct MqttTlsConfig {
+    /// CA certificate used to verify the broker in PEM format, defaults to the
+    /// system's root certificates
+    ca_file: Option<String>,
+
+    /// Client certificate and private key in PEM format, for brokers that
+    /// require certificate authentication
+    client_cert_file: Option<String>,
+    client_key_file: Option<String>,
+
+    /// Accept any certificate from the broker. Only meant for testing!
+    #[serde(default)]
+    insecure_skip_verify: bool,
+}
+
+/// Broker connection settings shared by everything that connects to an MQTT
+/// broker, i.e. the `mqtt` and `zigbee2mqtt` integrations and the MQTT bridge
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct MqttConnectionConfig {
+    pub host: String,
+    pub port: u16,
+    username: Option<String>,
+    password: Option<String>,
+
+    /// MQTT protocol version, 3 (for 3.1.1, default) or 5
+    protocol_version: Option<u8>,
+
+    #[serde(default)]
+    transport: MqttTransport,
+
+    /// Path of the broker's WebSocket endpoint, defaults to /mqtt
+    websocket_path: Option<String>,
+
+    /// Connect using TLS, see [MqttTlsConfig]
+    tls: Option<MqttTlsConfig>,
+
+    /// Defaults to the integration id followed by a random suffix
+    client_id: Option<String>,
+
+    keep_alive_seconds: Option<u64>,
+
+    /// Whether the broker should discard the session when disconnecting,
+    /// defaults to true
+    clean_session: Option<bool>,
+}
+
+pub enum MqttClient {
+    V4(rumqttc::AsyncClient),
+    V5(v5::AsyncClient),
+}
+
+pub enum MqttEventLoop {
+    V4(rumqttc::EventLoop),
+    V5(v5::EventLoop),
+}
+
+/// The parts of MQTT events that the integration cares about
+pub enum Notification {
+    Connected,
+    Message {
+        topic: String,
+        payload: Bytes,
+    },
+
+    /// We have disconnected from the broker, see [MqttClient::disconnect]
+    Disconnected,
+    Other,
+}
+
+impl MqttClient {
+    pub async fn subscribe(&self, topic: String) -> Result<()> {
+        match self {
+            MqttClient::V4(client) => {
+                client.subscribe(topic, rumqttc::QoS::AtMostOnce).await?;
+            }
+            MqttClient::V5(client) => {
+                client
+                    .subscribe(topic, v5::mqttbytes::QoS::AtMostOnce)
+                    .await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub async fn publish(&self, topic: String, retain: bool, payload: Vec<u8>) -> Result<()> {
+        match self {
+            MqttClient::V4(client) => {
+                client
+                    .publish(topic, rumqttc::QoS::AtLeastOnce, retain, payload)
+                    .await?;
+            }
+            MqttClient::V5(client) => {
+                client
+                    .publish(topic, v5::mqttbytes::QoS::AtLeastOnce, retain, payload)
+                    .await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Disconnects once queued publishes have been sent
+    pub async fn disconnect(&self) -> Result<()> {
+        match self {
+            MqttClient::V4(client) => client.disconnect().await?,
+            MqttClient::V5(client) => client.disconnect().await?,
+        }
+
+        Ok(())
+    }
+}
+
+impl MqttEventLoop {
+    /// Drives the connection, reconnecting if the previous call returned an
+    /// error.
+    pub async fn poll(&mut self) -> Result<Notification> {
+        let notification = match self {
+            MqttEventLoop::V4(event_loop) => match event_loop.poll().await? {
+                rumqttc::Event::Incoming(rumqttc::Packet::ConnAck(_)) => Notification::Connected,
+                rumqttc::Event::Incoming(rumqttc::Packet::Publish(msg)) => Notification::Message {
+                    topic: msg.topic,
+                    payload: msg.payload,
+                },
+                rumqttc::Event::Outgoing(rumqttc::Outgoing::Disconnect) => {
+                    Notification::Disconnected
+                }
+                _ => Notification::Other,
+            },
+            MqttEventLoop::V5(event_loop) => match event_loop.poll().await? {
+                v5::Event::Incoming(v5::Incoming::ConnAck(_)) => Notification::Connected,
+                v5::Event::Incoming(v5::Incoming::Publish(msg)) => Notification::Message {
+                    topic: String::from_utf8_lossy(&msg.topic).into_owned(),
+                    payload: msg.payload,
+                },
+                v5::Event::Outgoing(rumqttc::Outgoing::Disconnect) => Notification::Disconnected,
+                _ => Notification::Other,
+            },
+        };
+
+        Ok(notification)
+    }
+}
+
+fn read_file(path: &str) -> Result<Vec<u8>> {
+    std::fs::read(Path::new(path)).wrap_err_with(|| format!("Failed to read {path}"))
+}
+
+/// Accepts any certificate, used with [MqttTlsConfig::insecure_skip_verify]
+#[derive(Debug)]
+struct NoVerification;
+
+impl ServerCertVerifier for NoVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        _message: &[u8],
+        _cert: &CertificateDer<'_>,
+        _dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        Ok(HandshakeSignatureValid::assertion())
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        _message: &[u8],
+        _cert: &CertificateDer<'_>,
+        _dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        Ok(HandshakeSignatureValid::assertion())
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        rustls::crypto::ring::default_provider()
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+fn insecure_tls_configuration(client_auth: Option<(Vec<u8>, Vec<u8>)>) -> Result<TlsConfiguration> {
+    let builder = rustls::ClientConfig::builder()
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(NoVerification));
+
+    let config = match client_auth {
+        Some((cert, key)) => {
+            let certs = rustls_pemfile::certs(&mut cert.as_slice()).collect::<Result<_, _>>()?;
+            let key = rustls_pemfile::private_key(&mut key.as_slice())?
+                .ok_or_else(|| eyre!("No private key found in client_key_file"))?;
+
+            builder.with_client_auth_cert(certs, key)?
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    Ok(TlsConfiguration::Rustls(Arc::new(config)))
+}
+
+fn tls_configuration(tls: &MqttTlsConfig) -> Result<TlsConfiguration> {
+    let client_auth = match (&tls.client_cert_file, &tls.client_key_file) {
+        (Some(cert), Some(key)) => Some((read_file(cert)?, read_file(key)?)),
+        (None, None) => None,
+        _ => {
+            return Err(eyre!(
+                "Both client_cert_file and client_key_file are needed for client certificate authentication"
+            ))
+        }
+    };
+
+    if tls.insecure_skip_verify {
+        warn!("Certificate verification of the MQTT broker is disabled");
+        return insecure_tls_configuration(client_auth);
+    }
+
+    match (&tls.ca_file, client_auth) {
+        (Some(ca), client_auth) => Ok(TlsConfiguration::Simple {
+            ca: read_file(ca)?,
+            alpn: None,
+            client_auth,
+        }),
+        (None, None) => Ok(TlsConfiguration::default()),
+        (None, Some(_)) => Err(eyre!(
+            "ca_file is needed for client certificate authentication"
+        )),
+    }
+}
+
+fn transport(config: &MqttConnectionConfig) -> Result<Transport> {
+    let tls = config.tls.as_ref().map(tls_configuration).transpose()?;
+
+    let transport = match (config.transport, tls) {
+        (MqttTransport::Tcp, None) => Transport::Tcp,
+        (MqttTransport::Tcp, Some(tls)) => Transport::Tls(tls),
+        (MqttTransport::Websocket, None) => Transport::Ws,
+        (MqttTransport::Websocket, Some(tls)) => Transport::Wss(tls),
+    };
+
+    Ok(transport)
+}
+
+/// Address of the broker as expected by rumqttc, which takes a URL instead of
+/// a host name for WebSocket connections
+fn broker_addr(config: &MqttConnectionConfig) -> String {
+    match config.transport {
+        MqttTransport::Tcp => config.host.clone(),
+        MqttTransport::Websocket => {
+            let scheme = if config.tls.is_some() { "wss" } else { "ws" };
+            let path = config
+                .websocket_path
+                .as_deref()
+                .unwrap_or(DEFAULT_WEBSOCKET_PATH);
+
+            format!("{scheme}://{}:{}{path}", config.host, config.port)
+        }
+    }
+}
+
+/// Creates a client for the broker in `config`. The connection is
+/// established once the event loop is polled.
+pub fn connect(
+    id: &IntegrationId,
+    config: &MqttConnectionConfig,
+) -> Result<(MqttClient, MqttEventLoop)> {
+    let client_id = config.client_id.clone().unwrap_or_else(|| {
+        let random_string: String = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(8)
+            .map(char::from)
+            .collect();
+
+        format!("{id}-{random_string}")
+    });
+
+    let transport = transport(config)?;
+    let broker_addr = broker_addr(config);
+    let keep_alive = config
+        .keep_alive_seconds
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_KEEP_ALIVE);
+
+    match config.protocol_version.unwrap_or(4) {
+        3 | 4 => {
+            let mut options = rumqttc::MqttOptions::new(client_id, broker_addr, config.port);
+            options.set_keep_alive(keep_alive);
+            options.set_transport(transport);
+
+            if let Some(clean_session) = config.clean_session {
+                options.set_clean_session(clean_session);
+            }
+
+            if let Some(username) = &config.username {
+                options.set_credentials(username, config.password.as_deref().unwrap_or(""));
+            }
+
+            let (client, event_loop) = rumqttc::AsyncClient::new(options, 10);
+            Ok((MqttClient::V4(client), MqttEventLoop::V4(event_loop)))
+        }
+        5 => {
+            let mut options = v5::MqttOptions::new(client_id, broker_addr, config.port);
+            options.set_keep_alive(keep_alive);
+            options.set_transport(transport);
+
+            if let Some(clean_session) = config.clean_session {
+                options.set_clean_start(clean_session);
+            }
+
+            if let Some(username) = &config.username {
+                options.set_credentials(username, config.password.as_deref().unwrap_or(""));
+            }
+
+            let (client, event_loop) = v5::AsyncClient::new(options, 10);
+            Ok((MqttClient::V5(client), MqttEventLoop::V5(event_loop)))
+        }
+        version => Err(eyre!(
+            "Unsupported MQTT protocol version {version}, expected 3 (for 3.1.1) or 5"
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+
+    const TOPIC: &str = "homectl-test/roundtrip";
+
+    fn mk_config(
+        protocol_version: u8,
+        transport: MqttTransport,
+        port: u16,
+    ) -> MqttConnectionConfig {
+        MqttConnectionConfig {
+            host: std::env::var("MQTT_TEST_HOST").unwrap_or("localhost".to_string()),
+            port,
+            protocol_version: Some(protocol_version),
+            transport,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_client_cert_requires_key() {
+        let config = MqttConnectionConfig {
+            tls: Some(MqttTlsConfig {
+                client_cert_file: Some("client.pem".to_string()),
+                ..Default::default()
+            }),
+            ..mk_config(4, MqttTransport::Tcp, 8883)
+        };
+
+        let id = IntegrationId::from_str("mqtt").unwrap();
+        assert!(connect(&id, &config).is_err());
+    }
+
+    /// Publishes a message and waits for it to be echoed back by the broker
+    async fn roundtrip(config: MqttConnectionConfig) {
+        let id = IntegrationId::from_str("mqtt").unwrap();
+        let (client, mut event_loop) = connect(&id, &config).unwrap();
+        let topic = TOPIC.to_string();
+
+        let result = tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                match event_loop.poll().await.unwrap() {
+                    Notification::Connected => {
+                        client.subscribe(topic.clone()).await.unwrap();
+                        client
+                            .publish(topic.clone(), false, b"hello".to_vec())
+                            .await
+                            .unwrap();
+                    }
+                    Notification::Message { topic: t, payload } if t == topic => {
+                        return payload;
+                    }
+                    _ => {}
+                }
+            }
+        })
+        .await;
+
+        assert_eq!(result.unwrap(), Bytes::from_static(b"hello"));
+    }
+
+    /// Needs a broker with the listeners in script/mqtt-test-broker.sh
+    #[tokio::test]
+    #[ignore]
+    async fn test_broker_roundtrip() {
+        for protocol_version in [4, 5] {
+            roundtrip(mk_config(protocol_version, MqttTransport::Tcp, 1883)).await;
+            roundtrip(mk_config(protocol_version, MqttTransport::Websocket, 8080)).await;
+
+            if let Ok(ca_file) = std::env::var("MQTT_TEST_CA_FILE") {
+                roundtrip(MqttConnectionConfig {
+                    tls: Some(MqttTlsConfig {
+                        ca_file: Some(ca_file),
+                        ..Default::default()
+                    }),
+                    ..mk_config(protocol_version, MqttTransport::Tcp, 8883)
+                })
+                .await;
+            }
+        }
+    }
+}
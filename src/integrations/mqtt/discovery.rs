@@ -0,0 +1,760 @@
+//! Support for [Home Assistant MQTT
+//! discovery](https://www.home-assistant.io/integrations/mqtt/#mqtt-discovery).
+//!
+//! Devices such as Tasmota, ESPHome and Zigbee2MQTT announce their entities by
+//! publishing a config message to `homeassistant/<component>/[<node_id>/]<object_id>/config`.
+//! Each announced entity gets its own mapping: incoming state messages are
+//! converted into the default homectl message shape and passed to
+//! [json_to_homectl], and outgoing states are produced by [homectl_to_mqtt]
+//! and then converted into whatever the entity's command topic expects.
+
+use std::collections::HashMap;
+
+use color_eyre::Result;
+use jsonptr::PointerBuf;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::types::{
+    color::{Capabilities, DeviceColor},
+    device::{Device, DeviceId},
+    integration::IntegrationId,
+};
+
+use super::{
+    utils::{homectl_to_mqtt, json_to_homectl},
+    MqttConfig,
+};
+
+pub const DEFAULT_DISCOVERY_PREFIX: &str = "homeassistant";
+
+/// Default color temperature range of Home Assistant lights, in mireds
+const DEFAULT_MIN_MIREDS: u16 = 153;
+const DEFAULT_MAX_MIREDS: u16 = 500;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Component {
+    Light,
+    Switch,
+    BinarySensor,
+    Sensor,
+}
+
+impl Component {
+    fn from_topic_segment(segment: &str) -> Option<Component> {
+        match segment {
+            "light" => Some(Component::Light),
+            "switch" => Some(Component::Switch),
+            "binary_sensor" => Some(Component::BinarySensor),
+            "sensor" => Some(Component::Sensor),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+struct DiscoveryDevice {
+    name: Option<String>,
+}
+
+/// Discovery config message, abbreviated keys are accepted as aliases.
+#[derive(Clone, Debug, Default, Deserialize)]
+struct DiscoveryPayload {
+    #[serde(rename = "~")]
+    base_topic: Option<String>,
+
+    name: Option<String>,
+
+    #[serde(alias = "uniq_id")]
+    unique_id: Option<String>,
+
+    #[serde(alias = "dev")]
+    device: Option<DiscoveryDevice>,
+
+    #[serde(alias = "stat_t")]
+    state_topic: Option<String>,
+
+    #[serde(alias = "cmd_t")]
+    command_topic: Option<String>,
+
+    /// Lights use the "default" schema unless this is set to "json"
+    schema: Option<String>,
+
+    #[serde(alias = "val_tpl")]
+    value_template: Option<String>,
+
+    #[serde(alias = "stat_val_tpl")]
+    state_value_template: Option<String>,
+
+    #[serde(alias = "pl_on")]
+    payload_on: Option<Value>,
+
+    #[serde(alias = "pl_off")]
+    payload_off: Option<Value>,
+
+    #[serde(alias = "stat_on")]
+    state_on: Option<Value>,
+
+    #[serde(alias = "stat_off")]
+    state_off: Option<Value>,
+
+    #[serde(alias = "bri")]
+    brightness: Option<bool>,
+
+    #[serde(alias = "bri_scl")]
+    brightness_scale: Option<f32>,
+
+    #[serde(alias = "sup_clrm")]
+    supported_color_modes: Option<Vec<String>>,
+
+    #[serde(alias = "min_mirs")]
+    min_mireds: Option<u16>,
+
+    #[serde(alias = "max_mirs")]
+    max_mireds: Option<u16>,
+
+    #[serde(alias = "min_klv")]
+    min_kelvin: Option<u16>,
+
+    #[serde(alias = "max_klv")]
+    max_kelvin: Option<u16>,
+}
+
+/// Expands the `~` abbreviation at the start or end of a topic
+fn expand_topic(topic: String, base_topic: Option<&str>) -> String {
+    let Some(base_topic) = base_topic else {
+        return topic;
+    };
+
+    if let Some(rest) = topic.strip_prefix('~') {
+        format!("{base_topic}{rest}")
+    } else if let Some(rest) = topic.strip_suffix('~') {
+        format!("{rest}{base_topic}")
+    } else {
+        topic
+    }
+}
+
+/// Converts simple value templates such as `{{ value_json.state }}` or
+/// `{{ value_json['temperature'] }}` into a JSON pointer. Templates using
+/// filters or any other Jinja features are not supported.
+fn template_to_pointer(template: &str) -> Option<PointerBuf> {
+    let expr = template
+        .trim()
+        .strip_prefix("{{")?
+        .strip_suffix("}}")?
+        .trim();
+
+    if expr == "value" {
+        return Some(PointerBuf::new());
+    }
+
+    let mut rest = expr.strip_prefix("value_json")?;
+    let mut tokens = vec![];
+
+    while !rest.is_empty() {
+        if let Some(r) = rest.strip_prefix('.') {
+            let end = r.find(['.', '[']).unwrap_or(r.len());
+            tokens.push(r[..end].to_string());
+            rest = &r[end..];
+        } else if let Some(r) = rest.strip_prefix("['").or(rest.strip_prefix("[\"")) {
+            let end = r.find(['\'', '"'])?;
+            tokens.push(r[..end].to_string());
+            rest = r[end + 1..].strip_prefix(']')?;
+        } else {
+            return None;
+        }
+    }
+
+    Some(PointerBuf::from_tokens(tokens))
+}
+
+/// Compares payloads the way they appear on the wire, so that e.g. a plain
+/// `1` payload matches `payload_on = "1"`.
+fn payload_matches(value: &Value, expected: &Value) -> bool {
+    payload_text(value) == payload_text(expected)
+}
+
+fn payload_text(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        value => value.to_string(),
+    }
+}
+
+fn mireds_to_kelvin(mireds: f64) -> u16 {
+    (1_000_000.0 / mireds).round() as u16
+}
+
+fn kelvin_to_mireds(kelvin: u64) -> u64 {
+    (1_000_000.0 / kelvin as f64).round() as u64
+}
+
+fn color_temp(value: &Value) -> Option<DeviceColor> {
+    let mireds = value.get("color_temp")?.as_f64()?;
+    Some(DeviceColor::new_from_ct(mireds_to_kelvin(mireds)))
+}
+
+fn xy_color(color: &Value) -> Option<DeviceColor> {
+    let x = color.get("x")?.as_f64()?;
+    let y = color.get("y")?.as_f64()?;
+    Some(DeviceColor::new_from_xy(x as f32, y as f32))
+}
+
+/// Home Assistant uses saturation values between 0 and 100
+fn hs_color(color: &Value) -> Option<DeviceColor> {
+    let h = color.get("h")?.as_f64()?;
+    let s = color.get("s")?.as_f64()?;
+    Some(DeviceColor::new_from_hs(h.round() as u16, s as f32 / 100.0))
+}
+
+fn rgb_color(color: &Value) -> Option<DeviceColor> {
+    let r = color.get("r")?.as_u64()?;
+    let g = color.get("g")?.as_u64()?;
+    let b = color.get("b")?.as_u64()?;
+    Some(DeviceColor::new_from_rgb(r as u8, g as u8, b as u8))
+}
+
+/// Reads the current color of a JSON schema light state message
+fn light_color(value: &Value) -> Option<DeviceColor> {
+    let color = value.get("color");
+
+    match value.get("color_mode").and_then(Value::as_str) {
+        Some("color_temp") => color_temp(value),
+        Some("xy") => color.and_then(xy_color),
+        Some("hs") => color.and_then(hs_color),
+        Some("rgb" | "rgbw" | "rgbww") => color.and_then(rgb_color),
+        _ => color
+            .and_then(|color| xy_color(color).or(hs_color(color)).or(rgb_color(color)))
+            .or_else(|| color_temp(value)),
+    }
+}
+
+fn light_capabilities(payload: &DiscoveryPayload) -> Capabilities {
+    let modes = payload.supported_color_modes.as_deref().unwrap_or_default();
+    let has_mode = |mode: &str| modes.iter().any(|m| m == mode);
+
+    let ct = has_mode("color_temp").then(|| {
+        let start = payload.min_kelvin.unwrap_or_else(|| {
+            mireds_to_kelvin(payload.max_mireds.unwrap_or(DEFAULT_MAX_MIREDS) as f64)
+        });
+        let end = payload.max_kelvin.unwrap_or_else(|| {
+            mireds_to_kelvin(payload.min_mireds.unwrap_or(DEFAULT_MIN_MIREDS) as f64)
+        });
+        start..end
+    });
+
+    Capabilities {
+        xy: has_mode("xy"),
+        hs: has_mode("hs"),
+        rgb: has_mode("rgb") || has_mode("rgbw") || has_mode("rgbww"),
+        ct,
+    }
+}
+
+/// An entity announced through discovery, along with the mapping used to
+/// translate its messages.
+#[derive(Clone, Debug)]
+pub struct Entity {
+    component: Component,
+    pub id: DeviceId,
+    name: String,
+    pub state_topic: Option<String>,
+    command_topic: Option<String>,
+
+    /// Whether the light uses the JSON schema
+    json_schema: bool,
+
+    /// Where the state is found in state messages of non-JSON schema entities
+    value_field: PointerBuf,
+
+    payload_on: Value,
+    payload_off: Value,
+    state_on: Value,
+    state_off: Value,
+
+    /// Maximum brightness value, or None if brightness is not supported
+    brightness_scale: Option<f32>,
+
+    /// Mapping used with [json_to_homectl] and [homectl_to_mqtt]
+    config: MqttConfig,
+}
+
+impl Entity {
+    fn new(
+        component: Component,
+        object_path: &str,
+        payload: DiscoveryPayload,
+        base_config: &MqttConfig,
+    ) -> Entity {
+        let base_topic = payload.base_topic.as_deref();
+        let state_topic = payload
+            .state_topic
+            .clone()
+            .map(|topic| expand_topic(topic, base_topic));
+        let command_topic = payload
+            .command_topic
+            .clone()
+            .map(|topic| expand_topic(topic, base_topic));
+
+        let device_name = payload.device.as_ref().and_then(|d| d.name.clone());
+        let name = match (device_name, payload.name.clone()) {
+            (Some(device), Some(name)) if !name.starts_with(&device) => {
+                format!("{device} {name}")
+            }
+            (_, Some(name)) => name,
+            (Some(device), None) => device,
+            (None, None) => object_path.to_string(),
+        };
+
+        let id = DeviceId::new(payload.unique_id.as_deref().unwrap_or(object_path));
+
+        let template = payload
+            .state_value_template
+            .as_deref()
+            .or(payload.value_template.as_deref());
+        let value_field = match template.map(|t| (t, template_to_pointer(t))) {
+            Some((_, Some(pointer))) => pointer,
+            Some((template, None)) => {
+                warn!("Unsupported value template for {name}, using the raw payload: {template}");
+                PointerBuf::new()
+            }
+            None => PointerBuf::new(),
+        };
+
+        let payload_on = payload.payload_on.clone().unwrap_or(json!("ON"));
+        let payload_off = payload.payload_off.clone().unwrap_or(json!("OFF"));
+        let state_on = payload.state_on.clone().unwrap_or(payload_on.clone());
+        let state_off = payload.state_off.clone().unwrap_or(payload_off.clone());
+
+        let json_schema =
+            component == Component::Light && payload.schema.as_deref() == Some("json");
+
+        let (brightness_scale, capabilities) = if json_schema {
+            let supports_brightness = payload.brightness.unwrap_or_default()
+                || payload
+                    .supported_color_modes
+                    .as_ref()
+                    .is_some_and(|modes| modes.iter().any(|mode| mode != "onoff"));
+
+            (
+                supports_brightness.then(|| payload.brightness_scale.unwrap_or(255.0)),
+                Some(light_capabilities(&payload)),
+            )
+        } else {
+            (None, None)
+        };
+
+        let config = MqttConfig {
+            managed: base_config.managed.clone(),
+            capabilities_override: capabilities,
+            default_transition: base_config.default_transition,
+            ..Default::default()
+        };
+
+        Entity {
+            component,
+            id,
+            name,
+            state_topic,
+            command_topic,
+            json_schema,
+            value_field,
+            payload_on,
+            payload_off,
+            state_on,
+            state_off,
+            brightness_scale,
+            config,
+        }
+    }
+
+    /// Converts a state message of this entity into the default homectl
+    /// message shape.
+    fn normalize(&self, value: &Value) -> Option<Value> {
+        let mut normalized = json!({
+            "id": self.id.to_string(),
+            "name": self.name,
+        });
+
+        if self.json_schema {
+            let state = value.get("state")?;
+            normalized["power"] = json!(payload_matches(state, &json!("ON")));
+
+            if let Some(scale) = self.brightness_scale {
+                if let Some(brightness) = value.get("brightness").and_then(Value::as_f64) {
+                    normalized["brightness"] = json!(brightness / scale as f64);
+                }
+            }
+
+            if let Some(color) = light_color(value) {
+                normalized["color"] = serde_json::to_value(color).ok()?;
+            }
+
+            return Some(normalized);
+        }
+
+        let state = self
+            .value_field
+            .resolve(value)
+            .ok()
+            .filter(|v| !v.is_null())?;
+
+        match self.component {
+            Component::Light | Component::Switch => {
+                if payload_matches(state, &self.state_on) {
+                    normalized["power"] = json!(true);
+                } else if payload_matches(state, &self.state_off) {
+                    normalized["power"] = json!(false);
+                } else {
+                    return None;
+                }
+            }
+            Component::BinarySensor => {
+                if payload_matches(state, &self.payload_on) {
+                    normalized["sensor_value"] = json!(true);
+                } else if payload_matches(state, &self.payload_off) {
+                    normalized["sensor_value"] = json!(false);
+                } else {
+                    return None;
+                }
+            }
+            Component::Sensor => {
+                normalized["sensor_value"] = state.clone();
+            }
+        }
+
+        Some(normalized)
+    }
+
+    /// Converts a message received on the entity's state topic into a device
+    pub fn to_homectl(&self, payload: &[u8], integration_id: IntegrationId) -> Option<Device> {
+        // Payloads that aren't valid JSON are treated as plain strings
+        let value = serde_json::from_slice(payload)
+            .unwrap_or_else(|_| Value::String(String::from_utf8_lossy(payload).into_owned()));
+
+        let normalized = self.normalize(&value)?;
+        let topic = self.state_topic.as_deref().unwrap_or_default();
+
+        json_to_homectl(&normalized, topic, integration_id, &self.config)
+    }
+
+    /// Builds the command message for setting the entity to the given state,
+    /// or None if the entity can't be controlled.
+    pub fn to_command(&self, device: &Device) -> Result<Option<(String, Vec<u8>)>> {
+        let Some(topic) = &self.command_topic else {
+            return Ok(None);
+        };
+
+        let state = homectl_to_mqtt(device.clone(), &self.config)?;
+        let power = state.get("power") == Some(&Value::Bool(true));
+
+        let payload = match self.component {
+            Component::Light if self.json_schema => {
+                serde_json::to_vec(&self.light_command(&state))?
+            }
+            Component::Light | Component::Switch => {
+                let payload = if power {
+                    &self.payload_on
+                } else {
+                    &self.payload_off
+                };
+                payload_text(payload).into_bytes()
+            }
+            Component::BinarySensor | Component::Sensor => return Ok(None),
+        };
+
+        Ok(Some((topic.clone(), payload)))
+    }
+
+    fn light_command(&self, state: &Value) -> Value {
+        let power = state.get("power") == Some(&Value::Bool(true));
+        let mut command = json!({ "state": if power { "ON" } else { "OFF" } });
+
+        if let Some(transition) = state.get("transition") {
+            command["transition"] = transition.clone();
+        }
+
+        if !power {
+            return command;
+        }
+
+        let brightness = state.get("brightness").and_then(Value::as_f64);
+        if let (Some(scale), Some(brightness)) = (self.brightness_scale, brightness) {
+            command["brightness"] = json!((brightness * scale as f64).round());
+        }
+
+        let color = state
+            .get("color")
+            .and_then(|color| serde_json::from_value::<DeviceColor>(color.clone()).ok());
+
+        match color {
+            Some(DeviceColor::Xy(xy)) => command["color"] = json!({ "x": xy.x, "y": xy.y }),
+            Some(DeviceColor::Hs(hs)) => {
+                command["color"] = json!({ "h": hs.h, "s": *hs.s * 100.0 })
+            }
+            Some(DeviceColor::Rgb(rgb)) => {
+                command["color"] = json!({ "r": rgb.r, "g": rgb.g, "b": rgb.b })
+            }
+            Some(DeviceColor::Ct(ct)) => command["color_temp"] = json!(kelvin_to_mireds(ct.ct)),
+            None => {}
+        }
+
+        command
+    }
+}
+
+/// Entities discovered so far, keyed by their discovery config topic
+#[derive(Debug)]
+pub struct Discovery {
+    prefix: String,
+    entities: HashMap<String, Entity>,
+}
+
+impl Discovery {
+    pub fn new(prefix: Option<&str>) -> Discovery {
+        Discovery {
+            prefix: prefix.unwrap_or(DEFAULT_DISCOVERY_PREFIX).to_string(),
+            entities: HashMap::new(),
+        }
+    }
+
+    /// Topics to subscribe to for receiving discovery config messages
+    pub fn config_topics(&self) -> Vec<String> {
+        let prefix = &self.prefix;
+        vec![
+            format!("{prefix}/+/+/config"),
+            format!("{prefix}/+/+/+/config"),
+        ]
+    }
+
+    /// Returns `<component>/[<node_id>/]<object_id>` if the topic is a
+    /// discovery config topic
+    fn object_path<'a>(&self, topic: &'a str) -> Option<&'a str> {
+        topic
+            .strip_prefix(&self.prefix)?
+            .strip_prefix('/')?
+            .strip_suffix("/config")
+    }
+
+    pub fn is_config_topic(&self, topic: &str) -> bool {
+        self.object_path(topic).is_some()
+    }
+
+    /// Adds, updates or (for empty payloads) removes the entity announced on
+    /// the given config topic. Returns the state topic of added entities.
+    pub fn handle_config(
+        &mut self,
+        topic: &str,
+        payload: &[u8],
+        base_config: &MqttConfig,
+    ) -> Result<Option<String>> {
+        let Some(object_path) = self.object_path(topic) else {
+            return Ok(None);
+        };
+
+        if payload.is_empty() {
+            if let Some(entity) = self.entities.remove(topic) {
+                info!("Removed discovered MQTT entity {}", entity.name);
+            }
+            return Ok(None);
+        }
+
+        let component = object_path.split('/').next().unwrap_or_default();
+        let Some(component) = Component::from_topic_segment(component) else {
+            debug!("Ignoring discovered MQTT entity of unsupported type: {topic}");
+            return Ok(None);
+        };
+
+        let payload: DiscoveryPayload = serde_json::from_slice(payload)?;
+        let entity = Entity::new(component, object_path, payload, base_config);
+        let state_topic = entity.state_topic.clone();
+
+        debug!("Discovered MQTT entity {} ({})", entity.name, entity.id);
+        self.entities.insert(topic.to_string(), entity);
+
+        Ok(state_topic)
+    }
+
+    pub fn state_topics(&self) -> Vec<String> {
+        self.entities
+            .values()
+            .filter_map(|entity| entity.state_topic.clone())
+            .collect()
+    }
+
+    /// Entities that publish their state to the given topic
+    pub fn entities_by_state_topic<'a>(
+        &'a self,
+        topic: &'a str,
+    ) -> impl Iterator<Item = &'a Entity> + 'a {
+        self.entities
+            .values()
+            .filter(move |entity| entity.state_topic.as_deref() == Some(topic))
+    }
+
+    pub fn entity(&self, id: &DeviceId) -> Option<&Entity> {
+        self.entities.values().find(|entity| &entity.id == id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use ordered_float::OrderedFloat;
+
+    use crate::types::device::{DeviceData, SensorDevice};
+
+    use super::*;
+
+    fn discover(discovery: &mut Discovery, topic: &str, config: Value) -> Entity {
+        discovery
+            .handle_config(topic, config.to_string().as_bytes(), &MqttConfig::default())
+            .unwrap();
+
+        discovery.entities.get(topic).unwrap().clone()
+    }
+
+    #[test]
+    fn test_json_schema_light() {
+        let mut discovery = Discovery::new(None);
+        let entity = discover(
+            &mut discovery,
+            "homeassistant/light/0x0017880104b0c3ea/light/config",
+            json!({
+                "~": "zigbee2mqtt/Kitchen table",
+                "name": null,
+                "uniq_id": "0x0017880104b0c3ea_light",
+                "dev": { "name": "Kitchen table" },
+                "schema": "json",
+                "stat_t": "~",
+                "cmd_t": "~/set",
+                "brightness": true,
+                "brightness_scale": 254,
+                "supported_color_modes": ["xy", "color_temp"],
+                "min_mireds": 150,
+                "max_mireds": 500
+            }),
+        );
+
+        assert_eq!(
+            entity.state_topic.as_deref(),
+            Some("zigbee2mqtt/Kitchen table")
+        );
+
+        let integration_id = IntegrationId::from_str("mqtt").unwrap();
+        let state = json!({
+            "state": "ON",
+            "brightness": 127,
+            "color_mode": "color_temp",
+            "color_temp": 370,
+            "color": { "x": 0.46, "y": 0.41 }
+        });
+        let device = entity
+            .to_homectl(state.to_string().as_bytes(), integration_id)
+            .unwrap();
+
+        assert_eq!(device.name, "Kitchen table");
+        assert_eq!(device.id, DeviceId::new("0x0017880104b0c3ea_light"));
+
+        let DeviceData::Controllable(light) = &device.data else {
+            panic!("Expected a controllable device");
+        };
+        assert!(light.state.power);
+        assert_eq!(light.state.brightness, Some(OrderedFloat(0.5)));
+        assert_eq!(light.state.color, Some(DeviceColor::new_from_ct(2703)));
+        assert_eq!(light.capabilities.ct, Some(2000..6667));
+
+        let (topic, payload) = entity.to_command(&device).unwrap().unwrap();
+        let command: Value = serde_json::from_slice(&payload).unwrap();
+
+        assert_eq!(topic, "zigbee2mqtt/Kitchen table/set");
+        assert_eq!(
+            command,
+            json!({ "state": "ON", "brightness": 127.0, "color_temp": 370 })
+        );
+    }
+
+    #[test]
+    fn test_sensors_sharing_state_topic() {
+        let mut discovery = Discovery::new(None);
+        discover(
+            &mut discovery,
+            "homeassistant/sensor/0x00158d0001/temperature/config",
+            json!({
+                "name": "Temperature",
+                "dev": { "name": "Bathroom sensor" },
+                "stat_t": "zigbee2mqtt/Bathroom sensor",
+                "val_tpl": "{{ value_json.temperature }}"
+            }),
+        );
+        discover(
+            &mut discovery,
+            "homeassistant/binary_sensor/0x00158d0001/occupancy/config",
+            json!({
+                "name": "Occupancy",
+                "dev": { "name": "Bathroom sensor" },
+                "stat_t": "zigbee2mqtt/Bathroom sensor",
+                "val_tpl": "{{ value_json['occupancy'] }}",
+                "pl_on": true,
+                "pl_off": false
+            }),
+        );
+
+        let integration_id = IntegrationId::from_str("mqtt").unwrap();
+        let state = json!({ "temperature": 21.5, "occupancy": true }).to_string();
+
+        let mut devices: Vec<Device> = discovery
+            .entities_by_state_topic("zigbee2mqtt/Bathroom sensor")
+            .filter_map(|entity| entity.to_homectl(state.as_bytes(), integration_id.clone()))
+            .collect();
+        devices.sort_by(|a, b| a.name.cmp(&b.name));
+
+        assert_eq!(devices.len(), 2);
+        assert_eq!(devices[0].name, "Bathroom sensor Occupancy");
+        assert_eq!(
+            devices[0].data,
+            DeviceData::Sensor(SensorDevice::Boolean {
+                value: true,
+                meta: None
+            })
+        );
+        assert_eq!(devices[1].name, "Bathroom sensor Temperature");
+        assert_eq!(
+            devices[1].data,
+            DeviceData::Sensor(SensorDevice::Number {
+                value: 21.5,
+                meta: None
+            })
+        );
+    }
+
+    #[test]
+    fn test_plain_switch() {
+        let mut discovery = Discovery::new(None);
+        let entity = discover(
+            &mut discovery,
+            "homeassistant/switch/tasmota_1A2B3C/relay/config",
+            json!({
+                "name": "Coffee maker",
+                "stat_t": "stat/tasmota_1A2B3C/POWER",
+                "cmd_t": "cmnd/tasmota_1A2B3C/POWER",
+                "pl_on": "ON",
+                "pl_off": "OFF"
+            }),
+        );
+
+        let integration_id = IntegrationId::from_str("mqtt").unwrap();
+        let device = entity.to_homectl(b"ON", integration_id).unwrap();
+        assert_eq!(device.id, DeviceId::new("switch/tasmota_1A2B3C/relay"));
+
+        let mut state = device.get_controllable_state().unwrap().clone();
+        state.power = false;
+        let device = device.set_controllable_state(state);
+        let (topic, payload) = entity.to_command(&device).unwrap().unwrap();
+
+        assert_eq!(topic, "cmnd/tasmota_1A2B3C/POWER");
+        assert_eq!(payload, b"OFF");
+    }
+}
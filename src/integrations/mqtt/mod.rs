@@ -1,11 +1,13 @@
-#![allow(clippy::redundant_closure_call)]
-
+mod attributes;
+pub mod client;
+mod discovery;
+mod transform;
 mod utils;
 
 use crate::{
     types::{
         color::Capabilities,
-        device::{Device, ManageKind},
+        device::{Device, DeviceId, ManageKind, SensorClass},
         event::{Event, TxEventChannel},
         integration::{Integration, IntegrationActionPayload, IntegrationId},
     },
@@ -14,26 +16,46 @@ use crate::{
 use async_trait::async_trait;
 use color_eyre::Result;
 use eyre::Context;
-use rand::{distributions::Alphanumeric, Rng};
-use rumqttc::{AsyncClient, MqttOptions, QoS};
 use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
-use tokio::task;
+use tokio::sync::RwLock;
+use tokio::task::{self, JoinHandle};
 
 use crate::integrations::mqtt::utils::mqtt_to_homectl;
 
-use self::utils::homectl_to_mqtt;
+use self::attributes::{AttributeStates, AttributeTopics};
+
+use self::client::{MqttClient, MqttConnectionConfig, Notification};
+use self::discovery::Discovery;
+use self::transform::Transforms;
+use self::utils::{device_topic, homectl_to_mqtt, topic_filter};
 
 #[derive(Default, Debug, Deserialize, Clone)]
 pub struct MqttConfig {
-    host: String,
-    port: u16,
-    username: Option<String>,
-    password: Option<String>,
+    #[serde(flatten)]
+    connection: MqttConnectionConfig,
+
+    /// Topic pattern to subscribe to, `{id}` and `{name}` placeholders are
+    /// used for devices that don't include their id or name in messages. Only
+    /// optional when devices are found via `attributes` or `discovery`, see
+    /// [mapping_config].
     topic: String,
     topic_set: String,
 
+    /// Topics of devices that publish each attribute on a separate topic,
+    /// keyed by the JSON pointer the attribute's value is stored at
+    #[serde(default)]
+    attributes: AttributeTopics,
+
+    /// Discover devices announced via Home Assistant MQTT discovery
+    discovery: Option<bool>,
+
+    /// Topic prefix of discovery messages, defaults to "homeassistant"
+    discovery_prefix: Option<String>,
+
     /// Can be used to control whether the devices published by this integration
     /// are "managed" or not, i.e.  whether homectl should keep track of the
     /// devices' expected states or not.
@@ -48,21 +70,106 @@ pub struct MqttConfig {
     brightness_field: Option<jsonptr::PointerBuf>,
     brightness_range: Option<(f32, f32)>,
     sensor_value_fields: Option<Vec<jsonptr::PointerBuf>>,
+
+    /// What sensor values measure, values are parsed as booleans for motion
+    /// and contact sensors and as numbers otherwise
+    sensor_class: Option<SensorClass>,
+
+    /// Unit of sensor values, defaults to the usual unit of the sensor class
+    sensor_unit: Option<String>,
+
     transition_field: Option<jsonptr::PointerBuf>,
     transition_range: Option<(f32, f32)>,
     default_transition: Option<f32>,
     capabilities_field: Option<jsonptr::PointerBuf>,
     capabilities_override: Option<Capabilities>,
     raw_field: Option<jsonptr::PointerBuf>,
+
+    /// Where payloads that aren't JSON objects, such as `on` or `21.5`, are
+    /// stored, defaults to /sensor_value
+    value_field: Option<jsonptr::PointerBuf>,
+
     include_id_name_in_set_payload: Option<bool>,
+
+    /// Transforms applied to fields of incoming messages, keyed by JSON
+    /// pointer. Outgoing messages use the inverse transforms.
+    #[serde(default)]
+    transforms: Transforms,
+
+    /// Additional topic mappings sharing this connection, see
+    /// [subscription_config]
+    #[serde(default)]
+    subscriptions: Vec<config::Value>,
+}
+
+/// Fields that each subscription sets on its own instead of inheriting them
+/// from the integration's config
+const SUBSCRIPTION_OWN_FIELDS: [&str; 4] = ["topic", "topic_set", "attributes", "subscriptions"];
+
+/// Deserializes the config of a topic mapping. `topic` and `topic_set` are
+/// required unless `topics_optional` is set, in which case missing topics are
+/// left empty and are neither subscribed nor published to.
+fn mapping_config(
+    mut table: config::Map<String, config::Value>,
+    topics_optional: bool,
+) -> Result<MqttConfig> {
+    if topics_optional {
+        for key in ["topic", "topic_set"] {
+            table
+                .entry(key.to_string())
+                .or_insert_with(|| config::Value::from(""));
+        }
+    }
+
+    let config: MqttConfig = config::Value::from(table).try_deserialize()?;
+    attributes::validate(&config.attributes)?;
+
+    Ok(config)
+}
+
+/// Builds the config of the integration's own topic mapping. Topics may be
+/// left out if devices are found via `attributes` or `discovery` instead.
+fn integration_config(config: &config::Value) -> Result<MqttConfig> {
+    let table = config.clone().into_table()?;
+    let topics_optional = table.contains_key("attributes") || table.contains_key("discovery");
+
+    mapping_config(table, topics_optional)
+}
+
+/// Builds the config of a subscription. Fields missing from the subscription
+/// default to the values of the integration's config, except for topics which
+/// are optional.
+fn subscription_config(base: &config::Value, subscription: &config::Value) -> Result<MqttConfig> {
+    let mut table = base.clone().into_table()?;
+    table.retain(|key, _| !SUBSCRIPTION_OWN_FIELDS.contains(&key.as_str()));
+    table.extend(subscription.clone().into_table()?);
+
+    mapping_config(table, true)
+}
+
+/// Whether messages on the topic are handled by the mapping
+fn is_mapping_topic(mapping: &MqttConfig, topic: &str) -> bool {
+    utils::match_topic(&mapping.topic, topic).is_some()
+        || attributes::match_attribute(&mapping.attributes, topic).is_some()
 }
 
 pub struct Mqtt {
     id: IntegrationId,
     event_tx: TxEventChannel,
     config: MqttConfig,
+
+    /// The integration's own topic mapping followed by its subscriptions
+    mappings: Arc<Vec<MqttConfig>>,
+
+    /// Index of the mapping that each device was last seen on
+    device_mappings: Arc<RwLock<HashMap<DeviceId, usize>>>,
+
     cli: Cli,
-    client: Option<AsyncClient>,
+    client: Option<Arc<MqttClient>>,
+    event_loop: Option<JoinHandle<()>>,
+    connected: Arc<AtomicBool>,
+    discovery: Option<Arc<RwLock<Discovery>>>,
+    attribute_states: Arc<RwLock<AttributeStates>>,
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -71,6 +178,147 @@ pub struct CustomMqttAction {
     json: String,
 }
 
+/// Handles notifications on the integration's MQTT event loop task.
+///
+/// The client's request queue is only drained while the event loop is polled,
+/// so client requests must not be awaited here. Otherwise the event loop stops
+/// once the queue fills up, e.g. when subscribing to many discovered entities.
+struct Handler {
+    id: IntegrationId,
+    event_tx: TxEventChannel,
+    client: Arc<MqttClient>,
+    mappings: Arc<Vec<MqttConfig>>,
+    device_mappings: Arc<RwLock<HashMap<DeviceId, usize>>>,
+    connected: Arc<AtomicBool>,
+    discovery: Option<Arc<RwLock<Discovery>>>,
+    attribute_states: Arc<RwLock<AttributeStates>>,
+}
+
+impl Handler {
+    fn log_target(&self) -> String {
+        format!("homectl_server::integrations::mqtt::{}", self.id)
+    }
+
+    /// Subscribes to the topics on a separate task
+    fn subscribe(&self, topics: Vec<String>) {
+        let client = self.client.clone();
+        let target = self.log_target();
+
+        task::spawn(async move {
+            for topic in topics {
+                if let Err(e) = client.subscribe(topic.clone()).await {
+                    error!(target: &target, "Failed to subscribe to {topic}: {e:?}");
+                }
+            }
+        });
+    }
+
+    async fn handle(&self, notification: Notification) -> Result<()> {
+        match notification {
+            Notification::Connected => {
+                self.connected.store(true, Ordering::Relaxed);
+
+                let mut topics = vec![];
+
+                for mapping in self.mappings.iter() {
+                    if !mapping.topic.is_empty() {
+                        topics.push(topic_filter(&mapping.topic));
+                    }
+
+                    for topic in attributes::subscriptions(&mapping.attributes) {
+                        topics.push(topic_filter(topic));
+                    }
+                }
+
+                if let Some(discovery) = &self.discovery {
+                    let discovery = discovery.read().await;
+                    // Resubscribe to state topics of entities discovered
+                    // before reconnecting
+                    topics.extend(discovery.config_topics());
+                    topics.extend(discovery.state_topics());
+                }
+
+                self.subscribe(topics);
+            }
+
+            Notification::Message { topic, payload } => {
+                self.handle_message(&topic, &payload).await?;
+            }
+
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    async fn handle_message(&self, topic: &str, payload: &[u8]) -> Result<()> {
+        if let Some(discovery) = &self.discovery {
+            if discovery.read().await.is_config_topic(topic) {
+                let state_topic = discovery
+                    .write()
+                    .await
+                    .handle_config(topic, payload, &self.mappings[0])
+                    .wrap_err_with(|| format!("Invalid discovery message on {topic}"))?;
+
+                if let Some(state_topic) = state_topic {
+                    self.subscribe(vec![state_topic]);
+                }
+
+                return Ok(());
+            }
+
+            let discovery = discovery.read().await;
+            let entities: Vec<_> = discovery.entities_by_state_topic(topic).collect();
+
+            if !entities.is_empty() {
+                for entity in entities {
+                    if let Some(device) = entity.to_homectl(payload, self.id.clone()) {
+                        self.event_tx.send(Event::ExternalStateUpdate { device });
+                    }
+                }
+
+                return Ok(());
+            }
+        }
+
+        // Messages matching none of the mappings are handled by the
+        // integration's own mapping
+        let index = self
+            .mappings
+            .iter()
+            .position(|mapping| is_mapping_topic(mapping, topic))
+            .unwrap_or_default();
+        let mapping = &self.mappings[index];
+
+        let device = if let Some((field, captures)) =
+            attributes::match_attribute(&mapping.attributes, topic)
+        {
+            self.attribute_states.write().await.handle_message(
+                field,
+                captures,
+                payload,
+                topic,
+                self.id.clone(),
+                mapping,
+            )?
+        } else {
+            mqtt_to_homectl(payload, topic, self.id.clone(), mapping)
+        };
+
+        if let Some(device) = device {
+            self.device_mappings
+                .write()
+                .await
+                .insert(device.id.clone(), index);
+
+            let event = Event::ExternalStateUpdate { device };
+            self.event_tx.send(event);
+        }
+
+        Ok(())
+    }
+}
+
 #[async_trait]
 impl Integration for Mqtt {
     fn new(
@@ -79,89 +327,78 @@ impl Integration for Mqtt {
         cli: &Cli,
         event_tx: TxEventChannel,
     ) -> Result<Self> {
-        let config = config
-            .clone()
-            .try_deserialize()
+        let raw_config = config;
+        let config = integration_config(raw_config)
             .wrap_err("Failed to deserialize config of Mqtt integration")?;
 
+        let mut mappings = vec![config.clone()];
+        for (index, subscription) in config.subscriptions.iter().enumerate() {
+            let mapping = subscription_config(raw_config, subscription)
+                .wrap_err_with(|| format!("Invalid subscription {index} of Mqtt integration"))?;
+            mappings.push(mapping);
+        }
+
+        let discovery = config.discovery.unwrap_or_default().then(|| {
+            let discovery = Discovery::new(config.discovery_prefix.as_deref());
+            Arc::new(RwLock::new(discovery))
+        });
+
         Ok(Mqtt {
             id: id.clone(),
             config,
+            mappings: Arc::new(mappings),
+            device_mappings: Default::default(),
             cli: cli.clone(),
             event_tx,
             client: None,
+            event_loop: None,
+            connected: Default::default(),
+            discovery,
+            attribute_states: Default::default(),
         })
     }
 
     async fn start(&mut self) -> Result<()> {
-        let random_string: String = rand::thread_rng()
-            .sample_iter(&Alphanumeric)
-            .take(8)
-            .map(char::from)
-            .collect();
-
-        let mut options = MqttOptions::new(
-            format!("{}-{}", self.id, random_string),
-            self.config.host.clone(),
-            self.config.port,
-        );
-        options.set_keep_alive(Duration::from_secs(5));
-
-        // Set credentials if provided
-        if let Some(username) = &self.config.username {
-            options.set_credentials(username, self.config.password.as_deref().unwrap_or(""));
-        }
-
-        let (client, mut eventloop) = AsyncClient::new(options, 10);
+        let (client, mut eventloop) = client::connect(&self.id, &self.config.connection)?;
+        let client = Arc::new(client);
 
         self.client = Some(client.clone());
 
-        let id = self.id.clone();
-        let event_tx = self.event_tx.clone();
-        let config = Arc::new(self.config.clone());
-
-        task::spawn(async move {
+        let handler = Handler {
+            id: self.id.clone(),
+            event_tx: self.event_tx.clone(),
+            client,
+            mappings: self.mappings.clone(),
+            device_mappings: self.device_mappings.clone(),
+            connected: self.connected.clone(),
+            discovery: self.discovery.clone(),
+            attribute_states: self.attribute_states.clone(),
+        };
+
+        let event_loop = task::spawn(async move {
             loop {
                 let notification = eventloop.poll().await;
 
-                let id = id.clone();
-                let event_tx = event_tx.clone();
-                let config = Arc::clone(&config);
-
-                let res = (|| async {
-                    match notification? {
-                        rumqttc::Event::Incoming(rumqttc::Packet::ConnAck(_)) => {
-                            client
-                                .subscribe(config.topic.replace("{id}", "+"), QoS::AtMostOnce)
-                                .await?;
-                        }
-
-                        rumqttc::Event::Incoming(rumqttc::Packet::Publish(msg)) => {
-                            let device =
-                                mqtt_to_homectl(&msg.payload, &msg.topic, id.clone(), &config);
-
-                            if let Some(device) = device {
-                                let event = Event::ExternalStateUpdate { device };
-                                event_tx.send(event);
-                            }
-                        }
-                        _ => {}
-                    }
+                if let Ok(Notification::Disconnected) = notification {
+                    debug!(target: &handler.log_target(), "Disconnected from MQTT broker");
+                    break;
+                }
 
-                    Ok::<(), Box<dyn std::error::Error + Sync + Send>>(())
-                })()
-                .await;
+                let res = match notification {
+                    Ok(notification) => handler.handle(notification).await,
+                    Err(e) => Err(e),
+                };
 
                 if let Err(e) = res {
-                    error!(
-                        target: &format!("homectl_server::integrations::mqtt::{id}"),
-                        "MQTT error: {e:?}"
-                    );
+                    handler.connected.store(false, Ordering::Relaxed);
+                    error!(target: &handler.log_target(), "MQTT error: {e:?}");
                     tokio::time::sleep(Duration::from_secs(1)).await;
                 }
             }
         });
 
+        self.event_loop = Some(event_loop);
+
         Ok(())
     }
 
@@ -171,17 +408,59 @@ impl Integration for Mqtt {
             .as_ref()
             .expect("Expected self.client to be set in start phase");
 
-        let topic = self
-            .config
-            .topic_set
-            .replace("{id}", &device.id.to_string())
-            .replace("{name}", &device.name.to_string());
+        if let Some(discovery) = &self.discovery {
+            let discovery = discovery.read().await;
+
+            if let Some(entity) = discovery.entity(&device.id) {
+                let Some((topic, payload)) = entity.to_command(device)? else {
+                    return Ok(());
+                };
+
+                if !self.cli.dry_run {
+                    client.publish(topic, false, payload).await?;
+                } else {
+                    debug!("(dry run) would publish device state: {device}");
+                }
+
+                return Ok(());
+            }
+        }
 
-        let mqtt_device = homectl_to_mqtt(device.clone(), &self.config)?;
+        let index = self.device_mappings.read().await.get(&device.id).copied();
+        let index = match index {
+            Some(index) => index,
+            None if self.mappings.len() == 1 => 0,
+            // Commands are only sent to devices that have reported their state
+            // since startup, so that they don't end up on the wrong mapping's
+            // topics
+            None => {
+                warn!("MQTT device {device} has not reported its state yet, ignoring state update");
+                return Ok(());
+            }
+        };
+        let mapping = &self.mappings[index];
+
+        // Commands to attribute topics aren't retained, so that devices don't
+        // act on them again after reconnecting
+        for (topic, payload) in attributes::attribute_commands(device, mapping)? {
+            if !self.cli.dry_run {
+                client.publish(topic, false, payload).await?;
+            } else {
+                debug!("(dry run) would publish {device} attribute to {topic}");
+            }
+        }
+
+        if mapping.topic_set.is_empty() {
+            return Ok(());
+        }
+
+        let topic = device_topic(&mapping.topic_set, device);
+
+        let mqtt_device = homectl_to_mqtt(device.clone(), mapping)?;
         let json = serde_json::to_string(&mqtt_device)?;
 
         if !self.cli.dry_run {
-            client.publish(topic, QoS::AtLeastOnce, true, json).await?;
+            client.publish(topic, true, json.into_bytes()).await?;
         } else {
             debug!("(dry run) would publish device state: {device}");
         }
@@ -199,9 +478,206 @@ impl Integration for Mqtt {
             .expect("Expected self.client to be set in start phase");
 
         client
-            .publish(action.topic, QoS::AtLeastOnce, true, action.json)
+            .publish(action.topic, true, action.json.into_bytes())
             .await?;
 
         Ok(())
     }
+
+    /// Disconnects from the broker once queued publishes have been sent
+    async fn stop(&mut self) -> Result<()> {
+        if let Some(client) = self.client.take() {
+            client.disconnect().await?;
+        }
+
+        Ok(())
+    }
+
+    async fn health_check(&mut self) -> Result<()> {
+        if self
+            .event_loop
+            .as_ref()
+            .is_some_and(|event_loop| event_loop.is_finished())
+        {
+            return Err(eyre!("MQTT event loop has stopped"));
+        }
+
+        if !self.connected.load(Ordering::Relaxed) {
+            return Err(eyre!(
+                "Not connected to MQTT broker at {}:{}",
+                self.config.connection.host,
+                self.config.connection.port
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use bytes::Bytes;
+    use serde_json::json;
+
+    use super::*;
+    use crate::types::event::mk_event_channel;
+
+    fn from_toml(toml: &str) -> config::Value {
+        config::Config::builder()
+            .add_source(config::File::from_str(toml, config::FileFormat::Toml))
+            .build()
+            .unwrap()
+            .try_deserialize()
+            .unwrap()
+    }
+
+    fn raw_config() -> config::Value {
+        from_toml(
+            r#"
+            host = "localhost"
+            port = 1883
+            topic = "homectl/devices/{id}"
+            topic_set = "homectl/set/{id}"
+            managed = "Unmanaged"
+
+            [[subscriptions]]
+            topic = "tele/{id}/SENSOR"
+            value_field = "/temperature"
+
+            [[subscriptions]]
+            managed = "Full"
+            attributes."/power" = { topic = "shellies/{id}/relay/0", topic_set = "shellies/{id}/relay/0/command" }
+        "#,
+        )
+    }
+
+    #[test]
+    fn test_topics_required_without_attributes_or_discovery() {
+        let missing_topics = from_toml(
+            r#"
+            host = "localhost"
+            port = 1883
+            "#,
+        );
+        assert!(integration_config(&missing_topics).is_err());
+
+        let discovery = from_toml(
+            r#"
+            host = "localhost"
+            port = 1883
+            discovery = true
+            "#,
+        );
+        let config = integration_config(&discovery).unwrap();
+        assert_eq!(config.topic, "");
+        assert_eq!(config.connection.port, 1883);
+    }
+
+    #[test]
+    fn test_subscription_config() {
+        let raw_config = raw_config();
+        let config: MqttConfig = raw_config.clone().try_deserialize().unwrap();
+        assert_eq!(config.subscriptions.len(), 2);
+
+        let sensors = subscription_config(&raw_config, &config.subscriptions[0]).unwrap();
+        assert_eq!(sensors.connection.host, "localhost");
+        assert_eq!(sensors.topic, "tele/{id}/SENSOR");
+        assert_eq!(sensors.topic_set, "");
+        assert_eq!(sensors.managed, Some(ManageKind::Unmanaged));
+        assert!(sensors.subscriptions.is_empty());
+
+        let shellies = subscription_config(&raw_config, &config.subscriptions[1]).unwrap();
+        assert_eq!(shellies.topic, "");
+        assert_eq!(shellies.managed, Some(ManageKind::Full));
+
+        assert!(is_mapping_topic(&config, "homectl/devices/lamp"));
+        assert!(is_mapping_topic(&sensors, "tele/bedroom/SENSOR"));
+        assert!(!is_mapping_topic(&sensors, "shellies/plug/relay/0"));
+        assert!(is_mapping_topic(&shellies, "shellies/plug/relay/0"));
+    }
+
+    /// Creates a handler whose client is never connected. The request queue
+    /// of the client is not drained as long as the event loop isn't polled.
+    fn mk_handler() -> (Handler, client::MqttEventLoop) {
+        let config = MqttConfig {
+            connection: MqttConnectionConfig {
+                host: "localhost".to_string(),
+                port: 1883,
+                ..Default::default()
+            },
+            discovery: Some(true),
+            ..Default::default()
+        };
+
+        let id = IntegrationId::from_str("mqtt").unwrap();
+        let (client, event_loop) = client::connect(&id, &config.connection).unwrap();
+        let (event_tx, _event_rx) = mk_event_channel();
+
+        let handler = Handler {
+            id,
+            event_tx,
+            client: Arc::new(client),
+            mappings: Arc::new(vec![config]),
+            device_mappings: Default::default(),
+            connected: Default::default(),
+            discovery: Some(Arc::new(RwLock::new(Discovery::new(None)))),
+            attribute_states: Default::default(),
+        };
+
+        (handler, event_loop)
+    }
+
+    #[tokio::test]
+    async fn test_many_discovered_entities_do_not_block_event_loop() {
+        let (handler, _event_loop) = mk_handler();
+
+        let handle_all = async {
+            for i in 0..25 {
+                let config = json!({
+                    "name": format!("Sensor {i}"),
+                    "stat_t": format!("tele/sensor{i}/SENSOR"),
+                });
+
+                handler
+                    .handle(Notification::Message {
+                        topic: format!("homeassistant/sensor/sensor{i}/config"),
+                        payload: Bytes::from(config.to_string()),
+                    })
+                    .await
+                    .unwrap();
+            }
+
+            handler.handle(Notification::Connected).await.unwrap();
+        };
+
+        tokio::time::timeout(Duration::from_secs(1), handle_all)
+            .await
+            .expect("Event loop blocked on MQTT client requests");
+
+        let discovery = handler.discovery.as_ref().unwrap().read().await;
+        assert_eq!(discovery.state_topics().len(), 25);
+    }
+
+    #[tokio::test]
+    async fn test_many_mapping_topics_do_not_block_event_loop() {
+        let (mut handler, _event_loop) = mk_handler();
+
+        let mappings = (0..25)
+            .map(|i| MqttConfig {
+                topic: format!("sensors/{i}/{{id}}"),
+                ..Default::default()
+            })
+            .collect();
+        handler.mappings = Arc::new(mappings);
+
+        tokio::time::timeout(
+            Duration::from_secs(1),
+            handler.handle(Notification::Connected),
+        )
+        .await
+        .expect("Event loop blocked on MQTT client requests")
+        .unwrap();
+    }
 }
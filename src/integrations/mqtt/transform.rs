@@ -0,0 +1,320 @@
+//! Value transforms for converting between the values found in MQTT messages
+//! and the values homectl expects, e.g. `"ON"` / `"OFF"` instead of booleans
+//! or color temperatures in mireds instead of kelvin.
+//!
+//! Transforms are configured per JSON pointer and applied in order to
+//! incoming messages, and in reverse order (using the inverse of each
+//! transform) to outgoing messages.
+
+use std::collections::BTreeMap;
+
+use color_eyre::Result;
+use jsonptr::PointerBuf;
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::core::expr::{evalexpr_value_to_serde, serde_value_to_evalexpr};
+
+/// Transform pipelines by the JSON pointer of the value they apply to
+pub type Transforms = BTreeMap<PointerBuf, Vec<Transform>>;
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Unit {
+    /// Values between 0 and 1
+    Ratio,
+    Percent,
+    Seconds,
+    Deciseconds,
+    Milliseconds,
+    Celsius,
+    Fahrenheit,
+    /// Color temperature
+    Kelvin,
+    /// Color temperature, 1 000 000 / kelvin
+    Mired,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Dimension {
+    Fraction,
+    Duration,
+    Temperature,
+}
+
+impl Unit {
+    /// Dimension, scale and offset used to convert the unit to the base unit
+    /// of its dimension. Color temperatures aren't linear and are handled
+    /// separately.
+    fn linear(self) -> Option<(Dimension, f64, f64)> {
+        match self {
+            Unit::Ratio => Some((Dimension::Fraction, 1.0, 0.0)),
+            Unit::Percent => Some((Dimension::Fraction, 0.01, 0.0)),
+            Unit::Seconds => Some((Dimension::Duration, 1.0, 0.0)),
+            Unit::Deciseconds => Some((Dimension::Duration, 0.1, 0.0)),
+            Unit::Milliseconds => Some((Dimension::Duration, 0.001, 0.0)),
+            Unit::Celsius => Some((Dimension::Temperature, 1.0, 0.0)),
+            Unit::Fahrenheit => Some((Dimension::Temperature, 5.0 / 9.0, -160.0 / 9.0)),
+            Unit::Kelvin | Unit::Mired => None,
+        }
+    }
+}
+
+fn convert(value: f64, from: Unit, to: Unit) -> Result<f64> {
+    if from == to {
+        return Ok(value);
+    }
+
+    match (from.linear(), to.linear()) {
+        (
+            Some((from_dimension, from_scale, from_offset)),
+            Some((to_dimension, to_scale, to_offset)),
+        ) if from_dimension == to_dimension => {
+            let base = value * from_scale + from_offset;
+            Ok((base - to_offset) / to_scale)
+        }
+        (None, None) if value != 0.0 => Ok(1_000_000.0 / value),
+        (None, None) => Err(eyre!("Cannot convert color temperature of 0")),
+        _ => Err(eyre!("Cannot convert from {from:?} to {to:?}")),
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Transform {
+    /// Replaces values using a table of `[mqtt value, homectl value]` pairs,
+    /// values missing from the table are left as is
+    Map { values: Vec<(Value, Value)> },
+
+    /// Converts numbers between units
+    Convert { from: Unit, to: Unit },
+
+    /// Limits numbers to the given range, in both directions
+    Clamp { min: Option<f64>, max: Option<f64> },
+
+    /// Negates booleans, and mirrors numbers within the range from `min`
+    /// (defaults to 0) to `max` (defaults to 1)
+    Invert { min: Option<f64>, max: Option<f64> },
+
+    /// Parses numbers sent as strings, e.g. `"42"` or `"42 %"`. Numbers are
+    /// sent back as strings.
+    ParseNumber,
+
+    /// Evaluates an expression where `value` is the incoming value. As
+    /// expressions can't be inverted automatically, an `inverse` expression
+    /// is needed for sending values.
+    Expr {
+        expr: evalexpr::Node,
+        inverse: Option<evalexpr::Node>,
+    },
+}
+
+/// JSON numbers compare unequal if one of them is an integer and the other
+/// one is a float, even if they have the same value
+fn values_eq(a: &Value, b: &Value) -> bool {
+    match (a.as_f64(), b.as_f64()) {
+        (Some(a), Some(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+fn as_number(value: &Value) -> Result<f64> {
+    value
+        .as_f64()
+        .ok_or_else(|| eyre!("Expected a number, got {value}"))
+}
+
+fn number(value: f64) -> Result<Value> {
+    serde_json::Number::from_f64(value)
+        .map(Value::Number)
+        .ok_or_else(|| eyre!("Invalid number {value}"))
+}
+
+fn eval(expr: &evalexpr::Node, value: &Value) -> Result<Value> {
+    use evalexpr::*;
+
+    let mut context = HashMapContext::new();
+    context.set_type_safety_checks_disabled(true)?;
+    context.set_value("value".to_string(), serde_value_to_evalexpr(value)?)?;
+
+    let result = expr.eval_with_context_mut(&mut context)?;
+    evalexpr_value_to_serde(&result)
+}
+
+impl Transform {
+    /// Converts a value received from MQTT
+    pub fn apply(&self, value: Value) -> Result<Value> {
+        match self {
+            Transform::Map { values } => Ok(values
+                .iter()
+                .find(|(mqtt, _)| values_eq(mqtt, &value))
+                .map(|(_, homectl)| homectl.clone())
+                .unwrap_or(value)),
+            Transform::Convert { from, to } => number(convert(as_number(&value)?, *from, *to)?),
+            Transform::Clamp { min, max } => {
+                let value = as_number(&value)?;
+                let value = min.map_or(value, |min| value.max(min));
+                number(max.map_or(value, |max| value.min(max)))
+            }
+            Transform::Invert { min, max } => match value {
+                Value::Bool(value) => Ok(Value::Bool(!value)),
+                value => {
+                    let (min, max) = (min.unwrap_or(0.0), max.unwrap_or(1.0));
+                    number(min + max - as_number(&value)?)
+                }
+            },
+            Transform::ParseNumber => match value {
+                Value::String(s) => {
+                    let s = s.trim().trim_end_matches('%').trim_end();
+                    number(
+                        s.parse()
+                            .map_err(|_| eyre!("Expected a number, got {s:?}"))?,
+                    )
+                }
+                value => Ok(value),
+            },
+            Transform::Expr { expr, .. } => eval(expr, &value),
+        }
+    }
+
+    /// Converts a value that is about to be sent to MQTT
+    pub fn apply_inverse(&self, value: Value) -> Result<Value> {
+        match self {
+            Transform::Map { values } => Ok(values
+                .iter()
+                .find(|(_, homectl)| values_eq(homectl, &value))
+                .map(|(mqtt, _)| mqtt.clone())
+                .unwrap_or(value)),
+            Transform::Convert { from, to } => number(convert(as_number(&value)?, *to, *from)?),
+            Transform::Clamp { .. } | Transform::Invert { .. } => self.apply(value),
+            // Formatting as f64 drops the trailing ".0" of whole numbers
+            Transform::ParseNumber => match value.as_f64() {
+                Some(n) => Ok(Value::String(n.to_string())),
+                None => Ok(value),
+            },
+            Transform::Expr { inverse, .. } => {
+                let inverse = inverse
+                    .as_ref()
+                    .ok_or_else(|| eyre!("Expression transform has no inverse expression"))?;
+                eval(inverse, &value)
+            }
+        }
+    }
+}
+
+/// Runs the transforms on the values of an incoming message. Missing and null
+/// values are skipped.
+pub fn transform_incoming(value: &mut Value, transforms: &Transforms) -> Result<()> {
+    for (pointer, pipeline) in transforms {
+        let Ok(field) = pointer.resolve_mut(value) else {
+            continue;
+        };
+
+        if field.is_null() {
+            continue;
+        }
+
+        *field = pipeline
+            .iter()
+            .try_fold(field.take(), |value, transform| transform.apply(value))
+            .map_err(|e| eyre!("Failed to transform '{pointer}': {e}"))?;
+    }
+
+    Ok(())
+}
+
+/// Runs the inverse transforms in reverse order on the values of an outgoing
+/// message
+pub fn transform_outgoing(value: &mut Value, transforms: &Transforms) -> Result<()> {
+    for (pointer, pipeline) in transforms {
+        let Ok(field) = pointer.resolve_mut(value) else {
+            continue;
+        };
+
+        if field.is_null() {
+            continue;
+        }
+
+        *field = pipeline
+            .iter()
+            .rev()
+            .try_fold(field.take(), |value, transform| {
+                transform.apply_inverse(value)
+            })
+            .map_err(|e| eyre!("Failed to transform '{pointer}': {e}"))?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn transforms(value: Value) -> Transforms {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let transforms = transforms(json!({
+            "/state": [{ "type": "map", "values": [["ON", true], ["OFF", false]] }],
+            "/dimmer": [
+                { "type": "parse_number" },
+                { "type": "convert", "from": "percent", "to": "ratio" },
+                { "type": "clamp", "min": 0.0, "max": 1.0 }
+            ],
+            "/color/ct": [{ "type": "convert", "from": "mired", "to": "kelvin" }],
+            "/closed": [{ "type": "invert" }]
+        }));
+
+        let mut value = json!({
+            "state": "ON",
+            "dimmer": "120 %",
+            "color": { "ct": 250 },
+            "closed": false
+        });
+
+        transform_incoming(&mut value, &transforms).unwrap();
+        assert_eq!(
+            value,
+            json!({ "state": true, "dimmer": 1.0, "color": { "ct": 4000.0 }, "closed": true })
+        );
+
+        value["state"] = json!(false);
+        value["dimmer"] = json!(0.5);
+
+        transform_outgoing(&mut value, &transforms).unwrap();
+        assert_eq!(
+            value,
+            json!({ "state": "OFF", "dimmer": "50", "color": { "ct": 250.0 }, "closed": false })
+        );
+    }
+
+    #[test]
+    fn test_expr() {
+        let transforms = transforms(json!({
+            "/temperature": [{ "type": "expr", "expr": "value / 10" }]
+        }));
+
+        let mut value = json!({ "temperature": 215 });
+        transform_incoming(&mut value, &transforms).unwrap();
+        assert_eq!(value, json!({ "temperature": 21.5 }));
+
+        // Sending values requires an inverse expression
+        assert!(transform_outgoing(&mut value, &transforms).is_err());
+    }
+
+    #[test]
+    fn test_convert_units() {
+        let celsius = convert(68.0, Unit::Fahrenheit, Unit::Celsius).unwrap();
+        assert!((celsius - 20.0).abs() < 1e-9);
+
+        let deciseconds = convert(1500.0, Unit::Milliseconds, Unit::Deciseconds).unwrap();
+        assert!((deciseconds - 15.0).abs() < 1e-9);
+
+        assert!(convert(1.0, Unit::Seconds, Unit::Percent).is_err());
+    }
+}
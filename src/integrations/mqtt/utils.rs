@@ -1,27 +1,165 @@
+use crate::integrations::mqtt::transform::{transform_incoming, transform_outgoing};
 use crate::integrations::mqtt::MqttConfig;
 use crate::types::color::{Capabilities, DeviceColor};
 use crate::types::{
-    device::{ControllableDevice, Device, DeviceData, DeviceId, SensorDevice},
+    device::{
+        ControllableDevice, Device, DeviceData, DeviceId, SensorClass, SensorDevice, SensorMeta,
+    },
     integration::IntegrationId,
 };
+use crate::utils::clock;
 use color_eyre::Result;
 use jsonptr::{Assign, Pointer};
 use ordered_float::OrderedFloat;
 
+/// Values captured from a topic by the `{id}` and `{name}` placeholders of a
+/// topic pattern
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TopicCaptures {
+    pub id: Option<String>,
+    pub name: Option<String>,
+}
+
+/// Converts a topic pattern into a subscription filter by replacing
+/// placeholders with single level wildcards
+pub fn topic_filter(pattern: &str) -> String {
+    pattern.replace("{id}", "+").replace("{name}", "+")
+}
+
+/// Matches a topic against a topic pattern, which may contain the `+` and `#`
+/// wildcards as well as `{id}` and `{name}` placeholders
+pub fn match_topic(pattern: &str, topic: &str) -> Option<TopicCaptures> {
+    let mut captures = TopicCaptures::default();
+    let mut levels = topic.split('/');
+
+    for pattern_level in pattern.split('/') {
+        if pattern_level == "#" {
+            return Some(captures);
+        }
+
+        let level = levels.next()?;
+
+        match pattern_level {
+            "+" => {}
+            "{id}" => captures.id = Some(level.to_string()),
+            "{name}" => captures.name = Some(level.to_string()),
+            pattern_level if pattern_level == level => {}
+            _ => return None,
+        }
+    }
+
+    levels.next().is_none().then_some(captures)
+}
+
+/// Replaces the `{id}` and `{name}` placeholders of a topic pattern with the
+/// device's id and name
+pub fn device_topic(pattern: &str, device: &Device) -> String {
+    pattern
+        .replace("{id}", &device.id.to_string())
+        .replace("{name}", &device.name.to_string())
+}
+
+/// Parses a message payload as JSON, falling back to a plain string for
+/// payloads such as `on` that aren't valid JSON
+pub fn parse_payload(payload: &[u8]) -> serde_json::Value {
+    serde_json::from_slice(payload).unwrap_or_else(|_| {
+        serde_json::Value::String(String::from_utf8_lossy(payload).trim().to_string())
+    })
+}
+
+/// Encodes a value as a message payload, strings are sent without quotes
+pub fn encode_payload(value: &serde_json::Value) -> Vec<u8> {
+    match value {
+        serde_json::Value::String(s) => s.clone().into_bytes(),
+        value => value.to_string().into_bytes(),
+    }
+}
+
+/// Fills in the id and name fields of a message from the topic, unless the
+/// message already contains them. The id is used as name if the topic pattern
+/// has no `{name}` placeholder.
+pub fn insert_topic_captures(
+    value: &mut serde_json::Value,
+    captures: TopicCaptures,
+    config: &MqttConfig,
+) -> Result<()> {
+    let id_field = config
+        .id_field
+        .as_deref()
+        .unwrap_or(Pointer::from_static("/id"));
+    let name_field = config
+        .name_field
+        .as_deref()
+        .unwrap_or(Pointer::from_static("/name"));
+
+    let name = captures.name.or_else(|| captures.id.clone());
+
+    if let Some(id) = captures.id {
+        if id_field.resolve(value).is_err() {
+            value.assign(id_field, serde_json::Value::String(id))?;
+        }
+    }
+
+    if let Some(name) = name {
+        if name_field.resolve(value).is_err() {
+            value.assign(name_field, serde_json::Value::String(name))?;
+        }
+    }
+
+    Ok(())
+}
+
 pub fn mqtt_to_homectl(
     payload: &[u8],
     topic: &str,
     integration_id: IntegrationId,
     config: &MqttConfig,
 ) -> Option<Device> {
-    let value: Result<serde_json::Value, serde_json::Error> = serde_json::from_slice(payload);
+    let mut value = parse_payload(payload);
+
+    // Plain values such as `on` or `21.5` are stored at value_field
+    if !value.is_object() {
+        let value_field = config
+            .value_field
+            .as_deref()
+            .unwrap_or(Pointer::from_static("/sensor_value"));
 
-    let value = match value {
-        Ok(value) => value,
-        Err(err) => {
+        let mut message = serde_json::Value::default();
+        if let Err(err) = message.assign(value_field, value) {
             error!("Failed to parse MQTT message: {topic} {err}");
             return None;
         }
+        value = message;
+    }
+
+    if let Some(captures) = match_topic(&config.topic, topic) {
+        if let Err(err) = insert_topic_captures(&mut value, captures, config) {
+            error!("Failed to parse MQTT message: {topic} {err}");
+            return None;
+        }
+    }
+
+    json_to_homectl(&value, topic, integration_id, config)
+}
+
+/// Like [mqtt_to_homectl], but for an already parsed message
+pub fn json_to_homectl(
+    value: &serde_json::Value,
+    topic: &str,
+    integration_id: IntegrationId,
+    config: &MqttConfig,
+) -> Option<Device> {
+    let transformed;
+    let value = if config.transforms.is_empty() {
+        value
+    } else {
+        let mut value = value.clone();
+        if let Err(err) = transform_incoming(&mut value, &config.transforms) {
+            error!("Failed to transform MQTT message: {topic} {err}");
+            return None;
+        }
+        transformed = value;
+        &transformed
     };
 
     let id_field = config
@@ -59,7 +197,7 @@ pub fn mqtt_to_homectl(
         .unwrap_or(Pointer::from_static("/capabilities"));
 
     let id = id_field
-        .resolve(&value)
+        .resolve(value)
         .ok()
         .and_then(serde_json::Value::as_str)
         .map(|id| id.to_string());
@@ -70,7 +208,7 @@ pub fn mqtt_to_homectl(
     };
 
     let name = name_field
-        .resolve(&value)
+        .resolve(value)
         .ok()
         .and_then(serde_json::Value::as_str)
         .map(|name| name.to_string());
@@ -81,11 +219,11 @@ pub fn mqtt_to_homectl(
     };
 
     let color = color_field
-        .resolve(&value)
+        .resolve(value)
         .ok()
         .and_then(|value| serde_json::from_value::<DeviceColor>(value.clone()).ok());
 
-    let power = power_field.resolve(&value).ok().and_then(|value| {
+    let power = power_field.resolve(value).ok().and_then(|value| {
         if config
             .power_on_value
             .as_ref()
@@ -109,7 +247,7 @@ pub fn mqtt_to_homectl(
         let range = config.brightness_range.unwrap_or((0.0, 1.0));
 
         brightness_field
-            .resolve(&value)
+            .resolve(value)
             .ok()
             .and_then(serde_json::Value::as_f64)
             .map(|value| value as f32)
@@ -121,7 +259,7 @@ pub fn mqtt_to_homectl(
         let range = config.transition_range.unwrap_or((0.0, 1.0));
 
         transition_field
-            .resolve(&value)
+            .resolve(value)
             .ok()
             .and_then(serde_json::Value::as_f64)
             .map(|value| value as f32)
@@ -131,38 +269,26 @@ pub fn mqtt_to_homectl(
 
     let resolved_sensor_value_field = sensor_value_fields
         .iter()
-        .find_map(|field| Some((field, field.resolve(&value).ok()?)))
+        .find_map(|field| Some((field, field.resolve(value).ok()?)))
         .filter(|(_, v)| !v.is_null());
     let device_state = if let Some((field, value)) = resolved_sensor_value_field {
-        DeviceData::Sensor(match value {
-            serde_json::Value::Number(value) => SensorDevice::Number {
-                value: value.as_f64().unwrap(),
-            },
-
-            serde_json::Value::Bool(value) => SensorDevice::Boolean { value: *value },
-
-            // TODO: get rid of this hack and use proper booleans
-            serde_json::Value::String(value) if value == "true" => {
-                SensorDevice::Boolean { value: true }
-            }
-            serde_json::Value::String(value) if value == "false" => {
-                SensorDevice::Boolean { value: false }
-            }
-
-            serde_json::Value::String(value) => SensorDevice::Text {
-                value: value.clone(),
-            },
-            _ => {
-                error!("Unsupported value for sensor field '{field}'");
-                return None;
-            }
-        })
+        let sensor = match config.sensor_class {
+            Some(class) => typed_sensor(value, class, config),
+            None => untyped_sensor(value),
+        };
+
+        let Some(sensor) = sensor else {
+            error!("Unsupported value for sensor field '{field}'");
+            return None;
+        };
+
+        DeviceData::Sensor(sensor)
     } else if power.is_none() && brightness.is_none() && color.is_none() {
         warn!("Unable to determine device type for {topic}, discarding MQTT message");
         return None;
     } else {
         let capabilities: Capabilities = capabilities_field
-            .resolve(&value)
+            .resolve(value)
             .ok()
             .and_then(|value| serde_json::from_value(value.clone()).ok())
             .or_else(|| config.capabilities_override.clone())
@@ -185,7 +311,7 @@ pub fn mqtt_to_homectl(
         .raw_field
         .as_deref()
         .unwrap_or(Pointer::from_static("/raw"))
-        .resolve(&value)
+        .resolve(value)
         .ok()
         .cloned();
 
@@ -198,6 +324,75 @@ pub fn mqtt_to_homectl(
     })
 }
 
+/// Guesses the sensor type from the value
+fn untyped_sensor(value: &serde_json::Value) -> Option<SensorDevice> {
+    match value {
+        serde_json::Value::Number(value) => Some(SensorDevice::Number {
+            value: value.as_f64()?,
+            meta: None,
+        }),
+
+        serde_json::Value::Bool(value) => Some(SensorDevice::Boolean {
+            value: *value,
+            meta: None,
+        }),
+
+        serde_json::Value::String(value) => match parse_bool(value) {
+            Some(value) => Some(SensorDevice::Boolean { value, meta: None }),
+            None => Some(SensorDevice::Text {
+                value: value.clone(),
+                meta: None,
+            }),
+        },
+        _ => None,
+    }
+}
+
+/// Parses booleans sent as strings, e.g. `"on"` or `"1"`
+fn parse_bool(value: &str) -> Option<bool> {
+    match value.trim().to_lowercase().as_str() {
+        "true" | "on" | "1" => Some(true),
+        "false" | "off" | "0" => Some(false),
+        _ => None,
+    }
+}
+
+/// Parses a sensor value of the configured class, accepting numbers and
+/// booleans sent as strings
+fn typed_sensor(
+    value: &serde_json::Value,
+    class: SensorClass,
+    config: &MqttConfig,
+) -> Option<SensorDevice> {
+    let meta = Some(SensorMeta {
+        class: Some(class),
+        unit: config
+            .sensor_unit
+            .clone()
+            .or_else(|| class.default_unit().map(str::to_string)),
+        updated_at: Some(clock::now()),
+    });
+
+    if class.is_binary() {
+        let value = match value {
+            serde_json::Value::Bool(value) => *value,
+            serde_json::Value::Number(value) => value.as_f64()? != 0.0,
+            serde_json::Value::String(value) => parse_bool(value)?,
+            _ => return None,
+        };
+
+        Some(SensorDevice::Boolean { value, meta })
+    } else {
+        let value = match value {
+            serde_json::Value::Number(value) => value.as_f64()?,
+            serde_json::Value::String(value) => value.trim().parse().ok()?,
+            _ => return None,
+        };
+
+        Some(SensorDevice::Number { value, meta })
+    }
+}
+
 pub fn homectl_to_mqtt(device: Device, config: &MqttConfig) -> Result<serde_json::Value> {
     let mut payload = serde_json::Value::default();
 
@@ -278,13 +473,15 @@ pub fn homectl_to_mqtt(device: Device, config: &MqttConfig) -> Result<serde_json
         }
     };
 
+    transform_outgoing(&mut payload, &config.transforms)?;
+
     Ok(payload)
 }
 
 #[cfg(test)]
 mod tests {
     use crate::types::{
-        color::{Capabilities, ColorMode, Hs},
+        color::{Capabilities, ColorMode, Ct, Hs},
         device::ManageKind,
     };
 
@@ -316,8 +513,6 @@ mod tests {
         };
 
         let config = MqttConfig {
-            host: "localhost".to_string(),
-            port: 1883,
             topic: "homectl/devices/{id}".to_string(),
             topic_set: "homectl/set/{id}".to_string(),
             include_id_name_in_set_payload: Some(true),
@@ -350,8 +545,6 @@ mod tests {
         });
 
         let config = MqttConfig {
-            host: "localhost".to_string(),
-            port: 1883,
             topic: "homectl/devices/{id}".to_string(),
             topic_set: "homectl/set/{id}".to_string(),
             managed: Some(ManageKind::Unmanaged),
@@ -400,8 +593,6 @@ mod tests {
         });
 
         let config = MqttConfig {
-            host: "localhost".to_string(),
-            port: 1883,
             topic: "homectl/devices/{id}".to_string(),
             topic_set: "homectl/set/{id}".to_string(),
             managed: Some(ManageKind::Unmanaged),
@@ -421,4 +612,178 @@ mod tests {
 
         assert_eq!(mqtt_json, mqtt_message_value);
     }
+
+    #[test]
+    fn test_transforms() {
+        let mqtt_json = json!({
+            "id": "device1",
+            "name": "Device 1",
+            "state": "ON",
+            "dimmer": "50",
+            "color": { "ct": 250 },
+        });
+
+        let config = MqttConfig {
+            power_field: Some(jsonptr::PointerBuf::from_tokens(["state"])),
+            brightness_field: Some(jsonptr::PointerBuf::from_tokens(["dimmer"])),
+            transforms: serde_json::from_value(json!({
+                "/state": [{ "type": "map", "values": [["ON", true], ["OFF", false]] }],
+                "/dimmer": [
+                    { "type": "parse_number" },
+                    { "type": "convert", "from": "percent", "to": "ratio" }
+                ],
+                "/color/ct": [{ "type": "convert", "from": "mired", "to": "kelvin" }]
+            }))
+            .unwrap(),
+            ..Default::default()
+        };
+
+        let integration_id = IntegrationId::from_str("mqtt").unwrap();
+        let device = mqtt_to_homectl(
+            mqtt_json.to_string().as_bytes(),
+            "homectl/devices/device1",
+            integration_id,
+            &config,
+        )
+        .unwrap();
+
+        let DeviceData::Controllable(controllable) = &device.data else {
+            panic!("Expected a controllable device");
+        };
+        assert!(controllable.state.power);
+        assert_eq!(controllable.state.brightness, Some(OrderedFloat(0.5)));
+        assert_eq!(
+            controllable.state.color,
+            Some(DeviceColor::Ct(Ct { ct: 4000 }))
+        );
+
+        let mqtt_message_value = homectl_to_mqtt(device, &config).unwrap();
+
+        assert_eq!(
+            mqtt_message_value,
+            json!({ "state": "ON", "dimmer": "50", "color": { "ct": 250.0 } })
+        );
+    }
+
+    #[test]
+    fn test_match_topic() {
+        assert_eq!(
+            match_topic("shellies/{id}/relay/+", "shellies/plug-1/relay/0"),
+            Some(TopicCaptures {
+                id: Some("plug-1".to_string()),
+                name: None
+            })
+        );
+        assert_eq!(
+            match_topic("tele/{id}/#", "tele/sensor/SENSOR"),
+            Some(TopicCaptures {
+                id: Some("sensor".to_string()),
+                name: None
+            })
+        );
+        assert_eq!(
+            match_topic("shellies/{id}/relay/0", "shellies/plug-1"),
+            None
+        );
+        assert_eq!(
+            match_topic("shellies/{id}", "shellies/plug-1/relay/0"),
+            None
+        );
+        assert_eq!(topic_filter("home/{id}/{name}/set"), "home/+/+/set");
+    }
+
+    #[test]
+    fn test_plain_payload() {
+        let config = MqttConfig {
+            topic: "tele/{id}/temperature".to_string(),
+            ..Default::default()
+        };
+
+        let integration_id = IntegrationId::from_str("mqtt").unwrap();
+        let device = mqtt_to_homectl(
+            b"21.5",
+            "tele/bedroom/temperature",
+            integration_id.clone(),
+            &config,
+        )
+        .unwrap();
+
+        let expected = Device {
+            id: DeviceId::new("bedroom"),
+            name: "bedroom".to_string(),
+            integration_id,
+            data: DeviceData::Sensor(SensorDevice::Number {
+                value: 21.5,
+                meta: None,
+            }),
+            raw: None,
+        };
+
+        assert_eq!(device, expected);
+    }
+
+    #[test]
+    fn test_sensor_class() {
+        let config = MqttConfig {
+            topic: "sensors/{id}/{name}".to_string(),
+            sensor_class: Some(SensorClass::Temperature),
+            ..Default::default()
+        };
+
+        let integration_id = IntegrationId::from_str("mqtt").unwrap();
+        let device = mqtt_to_homectl(
+            b"\"21.5\"",
+            "sensors/bedroom/Bedroom",
+            integration_id.clone(),
+            &config,
+        )
+        .unwrap();
+
+        let DeviceData::Sensor(SensorDevice::Number { value, meta }) = &device.data else {
+            panic!("Expected a number sensor, got {:?}", device.data);
+        };
+        let meta = meta.as_ref().unwrap();
+        assert_eq!(*value, 21.5);
+        assert_eq!(meta.class, Some(SensorClass::Temperature));
+        assert_eq!(meta.unit.as_deref(), Some("°C"));
+        assert!(meta.updated_at.is_some());
+
+        let config = MqttConfig {
+            sensor_class: Some(SensorClass::Motion),
+            ..config
+        };
+
+        let device =
+            mqtt_to_homectl(b"ON", "sensors/hallway/Hallway", integration_id, &config).unwrap();
+
+        assert!(matches!(
+            device.data,
+            DeviceData::Sensor(SensorDevice::Boolean { value: true, .. })
+        ));
+    }
+
+    #[test]
+    fn test_untyped_sensor_strings() {
+        assert_eq!(
+            untyped_sensor(&json!("false")),
+            Some(SensorDevice::Boolean {
+                value: false,
+                meta: None
+            })
+        );
+        assert_eq!(
+            untyped_sensor(&json!("ON")),
+            Some(SensorDevice::Boolean {
+                value: true,
+                meta: None
+            })
+        );
+        assert_eq!(
+            untyped_sensor(&json!("idle")),
+            Some(SensorDevice::Text {
+                value: "idle".to_string(),
+                meta: None
+            })
+        );
+    }
 }
@@ -0,0 +1,730 @@
+//! Integration for Shelly Gen2 relays and dimmers using their local RPC API.
+//!
+//! Each configured device is kept up to date with status notifications over a
+//! WebSocket connection, falling back to polling while the connection is down.
+//! Switch and light components show up as controllable devices, and their
+//! power meters as power and energy sensors.
+
+mod rpc;
+
+use crate::{
+    types::{
+        device::{
+            ControllableDevice, ControllableState, Device, DeviceData, DeviceId, ManageKind,
+            SensorClass, SensorDevice, SensorMeta,
+        },
+        event::{Event, TxEventChannel},
+        integration::{Integration, IntegrationActionPayload, IntegrationId},
+    },
+    utils::{cli::Cli, clock},
+};
+use async_trait::async_trait;
+use color_eyre::Result;
+use eyre::Context;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::RwLock;
+use tokio::task::{self, JoinHandle};
+
+use self::rpc::{Notifications, Update};
+
+const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(30);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ShellyDeviceConfig {
+    /// Address of the device, optionally followed by a port
+    host: String,
+
+    /// Defaults to the name configured on the device, or its id
+    name: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ShellyConfig {
+    devices: Vec<ShellyDeviceConfig>,
+
+    /// How often devices are polled while they can't be reached over
+    /// WebSocket, defaults to 30 seconds
+    poll_interval_seconds: Option<u64>,
+
+    /// Receive status notifications over WebSocket, defaults to true. When
+    /// disabled devices are only polled.
+    websocket: Option<bool>,
+
+    /// Whether homectl should keep track of the devices' expected states,
+    /// defaults to "Full"
+    managed: Option<ManageKind>,
+
+    /// Transition time in seconds used for dimmers when none is given
+    default_transition: Option<f32>,
+}
+
+impl ShellyConfig {
+    fn poll_interval(&self) -> Duration {
+        self.poll_interval_seconds
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_POLL_INTERVAL)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ComponentKind {
+    Switch,
+    Light,
+}
+
+/// A controllable component of a Shelly, e.g. `switch:0`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Component {
+    kind: ComponentKind,
+    id: u64,
+}
+
+impl Component {
+    fn parse(key: &str) -> Option<Component> {
+        let (kind, id) = key.split_once(':')?;
+
+        let kind = match kind {
+            "switch" => ComponentKind::Switch,
+            "light" => ComponentKind::Light,
+            _ => return None,
+        };
+
+        Some(Component {
+            kind,
+            id: id.parse().ok()?,
+        })
+    }
+
+    fn name(&self) -> &'static str {
+        match self.kind {
+            ComponentKind::Switch => "switch",
+            ComponentKind::Light => "light",
+        }
+    }
+
+    /// Converts the status of the component into a controllable device,
+    /// followed by sensors for its power meter if it has one
+    fn to_homectl(
+        self,
+        integration_id: &IntegrationId,
+        device_id: &str,
+        name: &str,
+        status: &Value,
+        managed: &ManageKind,
+    ) -> Vec<Device> {
+        let Some(power) = status.get("output").and_then(Value::as_bool) else {
+            return vec![];
+        };
+
+        let brightness = match self.kind {
+            ComponentKind::Light => status
+                .get("brightness")
+                .and_then(Value::as_f64)
+                .map(|brightness| (brightness / 100.0) as f32),
+            ComponentKind::Switch => None,
+        };
+
+        let device_id = format!("{device_id}/{}:{}", self.name(), self.id);
+
+        let controllable = Device::new(
+            integration_id.clone(),
+            DeviceId::new(&device_id),
+            name.to_string(),
+            DeviceData::Controllable(ControllableDevice::new(
+                None,
+                power,
+                brightness,
+                None,
+                None,
+                Default::default(),
+                managed.clone(),
+            )),
+            Some(status.clone()),
+        );
+
+        let meter = |suffix: &str, class: SensorClass, value: Option<f64>| {
+            let meta = SensorMeta {
+                class: Some(class),
+                unit: class.default_unit().map(str::to_string),
+                updated_at: Some(clock::now()),
+            };
+
+            Some(Device::new(
+                integration_id.clone(),
+                DeviceId::new(&format!("{device_id}/{suffix}")),
+                format!("{name} {suffix}"),
+                DeviceData::Sensor(SensorDevice::Number {
+                    value: value?,
+                    meta: Some(meta),
+                }),
+                None,
+            ))
+        };
+
+        let power_meter = meter(
+            "power",
+            SensorClass::Power,
+            status.get("apower").and_then(Value::as_f64),
+        );
+        // Energy is reported in Wh
+        let energy_meter = meter(
+            "energy",
+            SensorClass::Energy,
+            status
+                .pointer("/aenergy/total")
+                .and_then(Value::as_f64)
+                .map(|wh| wh / 1000.0),
+        );
+
+        [Some(controllable), power_meter, energy_meter]
+            .into_iter()
+            .flatten()
+            .collect()
+    }
+
+    /// Builds the RPC call for setting the component to the given state
+    fn to_command(
+        self,
+        state: &ControllableState,
+        default_transition: Option<f32>,
+    ) -> (&'static str, Value) {
+        match self.kind {
+            ComponentKind::Switch => ("Switch.Set", json!({ "id": self.id, "on": state.power })),
+            ComponentKind::Light => {
+                let mut params = json!({ "id": self.id, "on": state.power });
+
+                if let Some(brightness) = state.brightness {
+                    let brightness = (*brightness * 100.0).round().clamp(0.0, 100.0);
+                    params["brightness"] = json!(brightness as u8);
+                }
+
+                let transition = state.transition.map(|t| *t).or(default_transition);
+                if let Some(transition) = transition {
+                    params["transition_duration"] = json!(transition);
+                }
+
+                ("Light.Set", params)
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct DeviceInfo {
+    id: String,
+    name: Option<String>,
+}
+
+/// State shared between the integration and its device tasks
+struct Shared {
+    id: IntegrationId,
+    config: ShellyConfig,
+    event_tx: TxEventChannel,
+    http: reqwest::Client,
+
+    /// Host and component of each controllable device
+    components: RwLock<HashMap<DeviceId, (String, Component)>>,
+
+    /// Hosts that could not be reached on the latest attempt
+    unreachable: RwLock<BTreeSet<String>>,
+}
+
+impl Shared {
+    async fn set_reachable(&self, host: &str, reachable: bool) {
+        let mut unreachable = self.unreachable.write().await;
+
+        if reachable {
+            unreachable.remove(host);
+        } else {
+            unreachable.insert(host.to_string());
+        }
+    }
+}
+
+/// Latest known state of a configured Shelly
+struct ShellyDevice {
+    config: ShellyDeviceConfig,
+    info: Option<DeviceInfo>,
+    status: Value,
+}
+
+impl ShellyDevice {
+    async fn fetch_info(&mut self, shared: &Shared) -> Result<DeviceInfo> {
+        if let Some(info) = &self.info {
+            return Ok(info.clone());
+        }
+
+        let info = rpc::call(
+            &shared.http,
+            &self.config.host,
+            "Shelly.GetDeviceInfo",
+            json!({}),
+        )
+        .await?;
+        let info: DeviceInfo = serde_json::from_value(info)?;
+
+        self.info = Some(info.clone());
+        Ok(info)
+    }
+
+    async fn poll(&mut self, shared: &Shared) -> Result<()> {
+        let info = self.fetch_info(shared).await?;
+        self.status = rpc::call(
+            &shared.http,
+            &self.config.host,
+            "Shelly.GetStatus",
+            json!({}),
+        )
+        .await?;
+
+        let keys: Vec<String> = self
+            .status
+            .as_object()
+            .into_iter()
+            .flatten()
+            .map(|(key, _)| key.clone())
+            .collect();
+        self.send_components(shared, &info, keys).await;
+
+        Ok(())
+    }
+
+    /// Receives status notifications until the connection is closed
+    async fn watch(&mut self, shared: &Shared) -> Result<()> {
+        let info = self.fetch_info(shared).await?;
+        let mut notifications = Notifications::connect(&self.config.host).await?;
+        shared.set_reachable(&self.config.host, true).await;
+
+        while let Some(update) = notifications.next().await? {
+            let update = match update {
+                Update::Full(status) => {
+                    self.status = status;
+                    self.status.clone()
+                }
+                Update::Partial(update) => {
+                    rpc::merge(&mut self.status, update.clone());
+                    update
+                }
+            };
+
+            let keys = update
+                .as_object()
+                .into_iter()
+                .flatten()
+                .map(|(key, _)| key.clone())
+                .collect();
+            self.send_components(shared, &info, keys).await;
+        }
+
+        Ok(())
+    }
+
+    /// Sends the devices of the given components
+    async fn send_components(&self, shared: &Shared, info: &DeviceInfo, keys: Vec<String>) {
+        let Some(status) = self.status.as_object() else {
+            return;
+        };
+
+        let base_name = self
+            .config
+            .name
+            .as_ref()
+            .or(info.name.as_ref())
+            .unwrap_or(&info.id);
+        let single_component = status
+            .keys()
+            .filter(|key| Component::parse(key).is_some())
+            .count()
+            == 1;
+        let managed = shared.config.managed.clone().unwrap_or_default();
+
+        for key in keys {
+            let (Some(component), Some(component_status)) =
+                (Component::parse(&key), status.get(&key))
+            else {
+                continue;
+            };
+
+            let name = if single_component {
+                base_name.clone()
+            } else {
+                format!("{base_name} {} {}", component.name(), component.id)
+            };
+
+            let devices =
+                component.to_homectl(&shared.id, &info.id, &name, component_status, &managed);
+
+            if let Some(controllable) = devices.first() {
+                shared.components.write().await.insert(
+                    controllable.id.clone(),
+                    (self.config.host.clone(), component),
+                );
+            }
+
+            for device in devices {
+                shared.event_tx.send(Event::ExternalStateUpdate { device });
+            }
+        }
+    }
+}
+
+/// Keeps the state of a Shelly up to date, using WebSocket notifications when
+/// possible and polling otherwise
+async fn run_device(shared: Arc<Shared>, config: ShellyDeviceConfig) {
+    let target = format!("homectl_server::integrations::shelly::{}", shared.id);
+    let host = config.host.clone();
+
+    let mut device = ShellyDevice {
+        config,
+        info: None,
+        status: Value::Null,
+    };
+
+    loop {
+        if shared.config.websocket.unwrap_or(true) {
+            match device.watch(&shared).await {
+                Ok(()) => debug!(target: &target, "WebSocket connection to {host} was closed"),
+                Err(e) => debug!(target: &target, "WebSocket connection to {host} failed: {e}"),
+            }
+        }
+
+        match device.poll(&shared).await {
+            Ok(()) => shared.set_reachable(&host, true).await,
+            Err(e) => {
+                warn!(target: &target, "Failed to poll Shelly at {host}: {e}");
+                shared.set_reachable(&host, false).await;
+            }
+        }
+
+        tokio::time::sleep(shared.config.poll_interval()).await;
+    }
+}
+
+pub struct Shelly {
+    shared: Arc<Shared>,
+    cli: Cli,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+#[async_trait]
+impl Integration for Shelly {
+    fn new(
+        id: &IntegrationId,
+        config: &config::Value,
+        cli: &Cli,
+        event_tx: TxEventChannel,
+    ) -> Result<Self> {
+        let config: ShellyConfig = config
+            .clone()
+            .try_deserialize()
+            .wrap_err("Failed to deserialize config of Shelly integration")?;
+
+        let http = reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()?;
+
+        Ok(Shelly {
+            shared: Arc::new(Shared {
+                id: id.clone(),
+                config,
+                event_tx,
+                http,
+                components: Default::default(),
+                unreachable: Default::default(),
+            }),
+            cli: cli.clone(),
+            tasks: vec![],
+        })
+    }
+
+    async fn start(&mut self) -> Result<()> {
+        for device in &self.shared.config.devices {
+            let task = task::spawn(run_device(self.shared.clone(), device.clone()));
+            self.tasks.push(task);
+        }
+
+        Ok(())
+    }
+
+    async fn set_integration_device_state(&mut self, device: &Device) -> Result<()> {
+        let DeviceData::Controllable(controllable) = &device.data else {
+            return Ok(());
+        };
+
+        let Some((host, component)) = self.shared.components.read().await.get(&device.id).cloned()
+        else {
+            warn!("Unknown Shelly device {device}, ignoring state update");
+            return Ok(());
+        };
+
+        let (method, params) =
+            component.to_command(&controllable.state, self.shared.config.default_transition);
+
+        if !self.cli.dry_run {
+            rpc::call(&self.shared.http, &host, method, params).await?;
+        } else {
+            debug!("(dry run) would call {method} on {host}: {params}");
+        }
+
+        Ok(())
+    }
+
+    /// Calls an arbitrary RPC method on a device, e.g. for running scripts
+    async fn run_integration_action(&mut self, payload: &IntegrationActionPayload) -> Result<()> {
+        let action: CustomShellyAction = serde_json::from_str(&payload.to_string())?;
+
+        rpc::call(
+            &self.shared.http,
+            &action.host,
+            &action.method,
+            action.params.unwrap_or_else(|| json!({})),
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    async fn stop(&mut self) -> Result<()> {
+        for task in self.tasks.drain(..) {
+            task.abort();
+        }
+
+        Ok(())
+    }
+
+    async fn health_check(&mut self) -> Result<()> {
+        if self.tasks.iter().any(|task| task.is_finished()) {
+            return Err(eyre!("Shelly device task has stopped"));
+        }
+
+        let unreachable = self.shared.unreachable.read().await;
+        if !unreachable.is_empty() {
+            let hosts: Vec<_> = unreachable.iter().map(String::as_str).collect();
+            return Err(eyre!("Unable to reach Shelly at {}", hosts.join(", ")));
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct CustomShellyAction {
+    host: String,
+    method: String,
+    params: Option<Value>,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+    use std::sync::Mutex;
+
+    use futures_util::{SinkExt, StreamExt};
+    use ordered_float::OrderedFloat;
+    use warp::Filter;
+
+    use super::*;
+    use crate::types::event::{mk_event_channel, RxEventChannel};
+
+    type Calls = Arc<Mutex<Vec<(String, Value)>>>;
+
+    /// Starts a stand-in for a Shelly Plus 1PM that answers RPC calls over
+    /// HTTP, and over WebSocket sends the given notification after the
+    /// initial status
+    async fn mock_shelly(status: Value, notification: Option<Value>) -> (String, Calls) {
+        let calls: Calls = Default::default();
+
+        let http_status = status.clone();
+        let http_calls = calls.clone();
+        let http = warp::path("rpc")
+            .and(warp::post())
+            .and(warp::body::json())
+            .map(move |request: Value| {
+                let method = request["method"].as_str().unwrap_or_default().to_string();
+                let result = match method.as_str() {
+                    "Shelly.GetDeviceInfo" => json!({ "id": "shellyplus1pm-abc", "name": null }),
+                    "Shelly.GetStatus" => http_status.clone(),
+                    _ => json!({ "was_on": true }),
+                };
+
+                http_calls
+                    .lock()
+                    .unwrap()
+                    .push((method, request["params"].clone()));
+
+                warp::reply::json(&json!({ "id": request["id"], "result": result }))
+            });
+
+        let ws = warp::path("rpc")
+            .and(warp::ws())
+            .map(move |ws: warp::ws::Ws| {
+                let status = status.clone();
+                let notification = notification.clone();
+
+                ws.on_upgrade(move |mut socket| async move {
+                    let Some(Ok(message)) = socket.next().await else {
+                        return;
+                    };
+                    let request: Value = serde_json::from_str(message.to_str().unwrap()).unwrap();
+
+                    let response = json!({ "id": request["id"], "result": status });
+                    let _ = socket
+                        .send(warp::ws::Message::text(response.to_string()))
+                        .await;
+
+                    if let Some(notification) = notification {
+                        let _ = socket
+                            .send(warp::ws::Message::text(notification.to_string()))
+                            .await;
+                    }
+
+                    while socket.next().await.is_some() {}
+                })
+            });
+
+        let (addr, server) = warp::serve(ws.or(http)).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        (addr.to_string(), calls)
+    }
+
+    fn mk_shelly(host: &str, websocket: bool, event_tx: TxEventChannel) -> Shelly {
+        let device = config::Value::from(config::Map::from([
+            ("host".to_string(), config::Value::from(host)),
+            ("name".to_string(), config::Value::from("Kitchen")),
+        ]));
+        let config = config::Value::from(config::Map::from([
+            ("devices".to_string(), config::Value::from(vec![device])),
+            ("websocket".to_string(), config::Value::from(websocket)),
+        ]));
+
+        let cli = Cli {
+            dry_run: false,
+            record: None,
+            command: None,
+        };
+
+        Shelly::new(
+            &IntegrationId::from_str("shelly").unwrap(),
+            &config,
+            &cli,
+            event_tx,
+        )
+        .unwrap()
+    }
+
+    /// Waits for a device update matching the predicate
+    async fn recv_device(event_rx: &mut RxEventChannel, f: impl Fn(&Device) -> bool) -> Device {
+        let recv = async {
+            loop {
+                if let Event::ExternalStateUpdate { device } = event_rx.recv().await.event {
+                    if f(&device) {
+                        return device;
+                    }
+                }
+            }
+        };
+
+        tokio::time::timeout(Duration::from_secs(5), recv)
+            .await
+            .expect("Timed out waiting for device update")
+    }
+
+    fn status(output: bool) -> Value {
+        json!({
+            "switch:0": {
+                "id": 0,
+                "output": output,
+                "apower": 12.5,
+                "aenergy": { "total": 1500.0 }
+            },
+            "sys": { "uptime": 100 }
+        })
+    }
+
+    fn power(device: &Device) -> Option<bool> {
+        match &device.data {
+            DeviceData::Controllable(controllable) => Some(controllable.state.power),
+            DeviceData::Sensor(_) => None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_polling() {
+        let (host, calls) = mock_shelly(status(true), None).await;
+        let (event_tx, mut event_rx) = mk_event_channel();
+        let mut shelly = mk_shelly(&host, false, event_tx);
+
+        shelly.start().await.unwrap();
+
+        let switch = recv_device(&mut event_rx, |device| power(device).is_some()).await;
+        assert_eq!(switch.id, DeviceId::new("shellyplus1pm-abc/switch:0"));
+        assert_eq!(switch.name, "Kitchen");
+        assert_eq!(power(&switch), Some(true));
+
+        let energy = recv_device(&mut event_rx, |device| device.name == "Kitchen energy").await;
+        assert!(matches!(
+            energy.data,
+            DeviceData::Sensor(SensorDevice::Number { value, .. }) if value == 1.5
+        ));
+
+        shelly.health_check().await.unwrap();
+
+        let mut off = switch.clone();
+        if let DeviceData::Controllable(ref mut controllable) = off.data {
+            controllable.state.power = false;
+        }
+        shelly.set_integration_device_state(&off).await.unwrap();
+
+        let calls = calls.lock().unwrap();
+        assert_eq!(
+            calls.last().unwrap(),
+            &("Switch.Set".to_string(), json!({ "id": 0, "on": false }))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_websocket_notifications() {
+        let notification = json!({
+            "src": "shellyplus1pm-abc",
+            "dst": "homectl",
+            "method": "NotifyStatus",
+            "params": { "ts": 1.0, "switch:0": { "id": 0, "output": true } }
+        });
+        let (host, _) = mock_shelly(status(false), Some(notification)).await;
+        let (event_tx, mut event_rx) = mk_event_channel();
+        let mut shelly = mk_shelly(&host, true, event_tx);
+
+        shelly.start().await.unwrap();
+
+        let switch = recv_device(&mut event_rx, |device| power(device) == Some(true)).await;
+        assert_eq!(switch.id, DeviceId::new("shellyplus1pm-abc/switch:0"));
+
+        shelly.stop().await.unwrap();
+    }
+
+    #[test]
+    fn test_light_command() {
+        let component = Component::parse("light:1").unwrap();
+        let state = ControllableState {
+            power: true,
+            brightness: Some(OrderedFloat(0.42)),
+            color: None,
+            transition: None,
+        };
+
+        assert_eq!(
+            component.to_command(&state, Some(0.5)),
+            (
+                "Light.Set",
+                json!({ "id": 1, "on": true, "brightness": 42, "transition_duration": 0.5 })
+            )
+        );
+        assert_eq!(Component::parse("input:0"), None);
+    }
+}
@@ -0,0 +1,160 @@
+//! Client for the local [RPC
+//! API](https://shelly-api-docs.shelly.cloud/gen2/General/RPCProtocol) of
+//! Shelly Gen2 devices, over HTTP and WebSocket.
+
+use color_eyre::Result;
+use futures_util::{SinkExt, StreamExt};
+use reqwest::header::CONTENT_TYPE;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::net::TcpStream;
+use tokio_tungstenite::{connect_async, tungstenite::Message, MaybeTlsStream, WebSocketStream};
+
+/// Identifies homectl to the device. Devices only send notifications to
+/// WebSocket clients that have sent a request with a `src`.
+const SRC: &str = "homectl";
+
+#[derive(Debug, Deserialize)]
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+/// Any message sent by a device, i.e. a response or a notification
+#[derive(Debug, Deserialize)]
+struct Frame {
+    id: Option<u64>,
+    method: Option<String>,
+    params: Option<Value>,
+    result: Option<Value>,
+    error: Option<RpcError>,
+}
+
+impl Frame {
+    fn into_result(self) -> Result<Value> {
+        match self.error {
+            Some(error) => Err(eyre!("RPC error {}: {}", error.code, error.message)),
+            None => Ok(self.result.unwrap_or_default()),
+        }
+    }
+}
+
+fn request(id: u64, method: &str, params: Value) -> Value {
+    json!({ "id": id, "src": SRC, "method": method, "params": params })
+}
+
+/// Calls a method over HTTP and returns its result
+pub async fn call(
+    http: &reqwest::Client,
+    host: &str,
+    method: &str,
+    params: Value,
+) -> Result<Value> {
+    let response = http
+        .post(format!("http://{host}/rpc"))
+        .header(CONTENT_TYPE, "application/json")
+        .body(request(1, method, params).to_string())
+        .send()
+        .await?
+        .error_for_status()?
+        .bytes()
+        .await?;
+
+    let frame: Frame = serde_json::from_slice(&response)?;
+    frame.into_result()
+}
+
+/// Status updates received over a WebSocket connection
+#[derive(Debug, PartialEq)]
+pub enum Update {
+    /// Status of all components
+    Full(Value),
+
+    /// Changed fields of some components
+    Partial(Value),
+}
+
+/// WebSocket connection that receives status notifications from a device
+pub struct Notifications {
+    ws: WebSocketStream<MaybeTlsStream<TcpStream>>,
+}
+
+impl Notifications {
+    /// Connects to the device and requests its full status, which also
+    /// subscribes the connection to notifications
+    pub async fn connect(host: &str) -> Result<Notifications> {
+        let (mut ws, _) = connect_async(format!("ws://{host}/rpc")).await?;
+
+        let request = request(1, "Shelly.GetStatus", json!({}));
+        ws.send(Message::Text(request.to_string())).await?;
+
+        Ok(Notifications { ws })
+    }
+
+    /// Waits for the next status update, returns None once the connection has
+    /// been closed
+    pub async fn next(&mut self) -> Result<Option<Update>> {
+        while let Some(message) = self.ws.next().await {
+            let text = match message? {
+                Message::Text(text) => text,
+                Message::Close(_) => break,
+                _ => continue,
+            };
+
+            let frame: Frame = serde_json::from_str(&text)?;
+
+            let update = match frame.method.as_deref() {
+                Some("NotifyFullStatus") => frame.params.map(Update::Full),
+                Some("NotifyStatus") => frame.params.map(Update::Partial),
+                Some(_) => None,
+                None if frame.id.is_some() => Some(Update::Full(frame.into_result()?)),
+                None => None,
+            };
+
+            if let Some(update) = update {
+                return Ok(Some(update));
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+/// Merges the fields of a partial status into a full status
+pub fn merge(status: &mut Value, update: Value) {
+    match (status, update) {
+        (Value::Object(status), Value::Object(update)) => {
+            for (key, value) in update {
+                merge(status.entry(key).or_insert(Value::Null), value);
+            }
+        }
+        (status, update) => *status = update,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_merge() {
+        let mut status = json!({
+            "switch:0": { "id": 0, "output": false, "apower": 0.0 },
+            "sys": { "uptime": 10 }
+        });
+
+        merge(
+            &mut status,
+            json!({ "ts": 1.5, "switch:0": { "output": true, "source": "button" } }),
+        );
+
+        assert_eq!(
+            status,
+            json!({
+                "switch:0": { "id": 0, "output": true, "apower": 0.0, "source": "button" },
+                "sys": { "uptime": 10 },
+                "ts": 1.5
+            })
+        );
+    }
+}
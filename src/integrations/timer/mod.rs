@@ -4,14 +4,14 @@ use crate::{
         event::{Event, TxEventChannel},
         integration::{Integration, IntegrationActionPayload, IntegrationId},
     },
-    utils::cli::Cli,
+    utils::{cli::Cli, clock},
 };
 use async_trait::async_trait;
 use color_eyre::Result;
 use eyre::Context;
 use serde::Deserialize;
 use serde_json::json;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::time::Duration;
 use tokio::task::JoinHandle;
 use tokio::time;
 
@@ -59,7 +59,7 @@ impl Integration for Timer {
     async fn run_integration_action(&mut self, action: &IntegrationActionPayload) -> Result<()> {
         let payload = action.to_string();
         let timeout_ms: u64 = payload.parse()?;
-        let started_at = SystemTime::now().duration_since(UNIX_EPOCH)?;
+        let started_at = Duration::from_millis(clock::now().timestamp_millis() as u64);
 
         let device = mk_timer_device(
             &self.id,
@@ -99,7 +99,7 @@ fn mk_timer_device(
     started_at: Option<Duration>,
     timeout_ms: Option<u64>,
 ) -> Device {
-    let state = DeviceData::Sensor(SensorDevice::Boolean { value });
+    let state = DeviceData::Sensor(SensorDevice::Boolean { value, meta: None });
 
     Device {
         id: DeviceId::new("timer"),
@@ -0,0 +1,496 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex as StdMutex},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use rand::{distributions::Alphanumeric, Rng};
+use rumqttc::{AsyncClient, MqttOptions, QoS};
+use tokio::{net::UdpSocket, sync::mpsc, task::JoinHandle};
+use wasmtime::{ResourceLimiter, StoreLimits};
+
+use crate::types::{
+    device::{Device, DeviceId},
+    event::{Event, TxEventChannel},
+    integration::IntegrationId,
+};
+
+use super::{
+    homectl::plugin::{
+        host,
+        types::{self, HttpRequest, HttpResponse, LogLevel},
+    },
+    PluginDevice, WasmCapabilities, WasmMqttConfig,
+};
+
+const MAX_HTTP_REDIRECTS: usize = 10;
+
+/// Calls into the plugin that are triggered by the host
+pub enum Callback {
+    Timer(u32),
+    MqttMessage { topic: String, payload: Vec<u8> },
+    UdpMessage { from: String, payload: Vec<u8> },
+}
+
+struct Mqtt {
+    client: AsyncClient,
+    subscriptions: Arc<StdMutex<Vec<String>>>,
+    event_loop: JoinHandle<()>,
+}
+
+/// State available to host functions called by a plugin
+pub struct HostState {
+    integration_id: IntegrationId,
+    capabilities: WasmCapabilities,
+    dry_run: bool,
+    event_tx: TxEventChannel,
+    callbacks_tx: mpsc::UnboundedSender<Callback>,
+    limits: StoreLimits,
+    log_target: String,
+
+    timers: HashMap<u32, JoinHandle<()>>,
+    http: reqwest::Client,
+    mqtt: Option<Mqtt>,
+    udp: Option<(Arc<UdpSocket>, JoinHandle<()>)>,
+}
+
+impl HostState {
+    pub fn new(
+        integration_id: &IntegrationId,
+        capabilities: WasmCapabilities,
+        dry_run: bool,
+        event_tx: TxEventChannel,
+        callbacks_tx: mpsc::UnboundedSender<Callback>,
+        limits: StoreLimits,
+    ) -> Self {
+        let mqtt = capabilities
+            .mqtt
+            .as_ref()
+            .map(|config| connect_mqtt(integration_id, config, callbacks_tx.clone()));
+        let http = http_client(capabilities.http_hosts.clone());
+
+        HostState {
+            integration_id: integration_id.clone(),
+            capabilities,
+            dry_run,
+            event_tx,
+            callbacks_tx,
+            limits,
+            log_target: format!("homectl_server::integrations::wasm::{integration_id}"),
+            timers: HashMap::new(),
+            http,
+            mqtt,
+            udp: None,
+        }
+    }
+
+    pub fn integration_id(&self) -> &IntegrationId {
+        &self.integration_id
+    }
+
+    pub fn limits(&mut self) -> &mut dyn ResourceLimiter {
+        &mut self.limits
+    }
+}
+
+impl Drop for HostState {
+    fn drop(&mut self) {
+        for (_, timer) in self.timers.drain() {
+            timer.abort();
+        }
+
+        if let Some(mqtt) = &self.mqtt {
+            mqtt.event_loop.abort();
+        }
+
+        if let Some((_, receiver)) = &self.udp {
+            receiver.abort();
+        }
+    }
+}
+
+fn is_allowed_host(http_hosts: &[String], url: &reqwest::Url) -> bool {
+    let host = url.host_str().unwrap_or_default();
+    http_hosts.iter().any(|h| h == host)
+}
+
+/// Creates an HTTP client that only follows redirects to allowed hosts
+fn http_client(http_hosts: Vec<String>) -> reqwest::Client {
+    let policy = reqwest::redirect::Policy::custom(move |attempt| {
+        if !is_allowed_host(&http_hosts, attempt.url()) {
+            let host = attempt.url().host_str().unwrap_or_default().to_string();
+            attempt.error(format!("Redirects to {host} are not allowed"))
+        } else if attempt.previous().len() >= MAX_HTTP_REDIRECTS {
+            attempt.error("Too many redirects")
+        } else {
+            attempt.follow()
+        }
+    });
+
+    reqwest::Client::builder()
+        .redirect(policy)
+        .build()
+        .expect("Failed to create HTTP client")
+}
+
+fn connect_mqtt(
+    integration_id: &IntegrationId,
+    config: &WasmMqttConfig,
+    callbacks_tx: mpsc::UnboundedSender<Callback>,
+) -> Mqtt {
+    let random_string: String = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(8)
+        .map(char::from)
+        .collect();
+
+    let mut options = MqttOptions::new(
+        format!("{integration_id}-{random_string}"),
+        config.host.clone(),
+        config.port,
+    );
+    options.set_keep_alive(Duration::from_secs(5));
+
+    if let Some(username) = &config.username {
+        options.set_credentials(username, config.password.as_deref().unwrap_or(""));
+    }
+
+    let (client, mut eventloop) = AsyncClient::new(options, 10);
+    let subscriptions: Arc<StdMutex<Vec<String>>> = Default::default();
+    let target = format!("homectl_server::integrations::wasm::{integration_id}");
+
+    let event_loop = {
+        let client = client.clone();
+        let subscriptions = subscriptions.clone();
+
+        tokio::spawn(async move {
+            loop {
+                match eventloop.poll().await {
+                    // Subscriptions are lost when reconnecting
+                    Ok(rumqttc::Event::Incoming(rumqttc::Packet::ConnAck(_))) => {
+                        let topics = subscriptions.lock().unwrap().clone();
+                        for topic in topics {
+                            client.subscribe(topic, QoS::AtMostOnce).await.ok();
+                        }
+                    }
+                    Ok(rumqttc::Event::Incoming(rumqttc::Packet::Publish(msg))) => {
+                        callbacks_tx
+                            .send(Callback::MqttMessage {
+                                topic: msg.topic,
+                                payload: msg.payload.to_vec(),
+                            })
+                            .ok();
+                    }
+                    Ok(_) => {}
+                    Err(e) => {
+                        error!(target: &target, "MQTT error: {e:?}");
+                        tokio::time::sleep(Duration::from_secs(1)).await;
+                    }
+                }
+            }
+        })
+    };
+
+    Mqtt {
+        client,
+        subscriptions,
+        event_loop,
+    }
+}
+
+impl types::Host for HostState {}
+
+#[async_trait]
+impl host::Host for HostState {
+    async fn send_device_update(&mut self, device: PluginDevice) {
+        let data = match serde_json::from_str(&device.data_json) {
+            Ok(data) => data,
+            Err(e) => {
+                warn!(target: &self.log_target, "Invalid device data for {}: {e}", device.id);
+                return;
+            }
+        };
+        let raw = device
+            .raw_json
+            .and_then(|raw| serde_json::from_str(&raw).ok());
+
+        let device = Device::new(
+            self.integration_id.clone(),
+            DeviceId::new(&device.id),
+            device.name,
+            data,
+            raw,
+        );
+
+        self.event_tx.send(Event::ExternalStateUpdate { device });
+    }
+
+    async fn log(&mut self, level: LogLevel, message: String) {
+        let target = &self.log_target;
+
+        match level {
+            LogLevel::Error => error!(target: target, "{message}"),
+            LogLevel::Warn => warn!(target: target, "{message}"),
+            LogLevel::Info => info!(target: target, "{message}"),
+            LogLevel::Debug => debug!(target: target, "{message}"),
+            LogLevel::Trace => trace!(target: target, "{message}"),
+        }
+    }
+
+    async fn set_timer(&mut self, id: u32, delay_ms: u64) {
+        let callbacks_tx = self.callbacks_tx.clone();
+        let timer = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(delay_ms)).await;
+            callbacks_tx.send(Callback::Timer(id)).ok();
+        });
+
+        if let Some(previous) = self.timers.insert(id, timer) {
+            previous.abort();
+        }
+    }
+
+    async fn cancel_timer(&mut self, id: u32) {
+        if let Some(timer) = self.timers.remove(&id) {
+            timer.abort();
+        }
+    }
+
+    async fn http_request(&mut self, request: HttpRequest) -> Result<HttpResponse, String> {
+        let url = reqwest::Url::parse(&request.url).map_err(|e| e.to_string())?;
+
+        if !is_allowed_host(&self.capabilities.http_hosts, &url) {
+            let host = url.host_str().unwrap_or_default();
+            return Err(format!("HTTP requests to {host} are not allowed"));
+        }
+
+        let method =
+            reqwest::Method::from_bytes(request.method.as_bytes()).map_err(|e| e.to_string())?;
+
+        if self.dry_run && method != reqwest::Method::GET {
+            debug!(target: &self.log_target, "(dry run) would send HTTP {method} {url}");
+            return Ok(HttpResponse {
+                status: 200,
+                headers: vec![],
+                body: vec![],
+            });
+        }
+
+        let mut builder = self.http.request(method, url);
+        for (name, value) in request.headers {
+            builder = builder.header(name, value);
+        }
+        if let Some(body) = request.body {
+            builder = builder.body(body);
+        }
+
+        let response = builder.send().await.map_err(|e| e.to_string())?;
+
+        let status = response.status().as_u16();
+        let headers = response
+            .headers()
+            .iter()
+            .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+            .collect();
+        let body = response.bytes().await.map_err(|e| e.to_string())?.to_vec();
+
+        Ok(HttpResponse {
+            status,
+            headers,
+            body,
+        })
+    }
+
+    async fn mqtt_subscribe(&mut self, topic: String) -> Result<(), String> {
+        let mqtt = self
+            .mqtt
+            .as_ref()
+            .ok_or_else(|| "MQTT is not allowed".to_string())?;
+
+        mqtt.subscriptions.lock().unwrap().push(topic.clone());
+        mqtt.client
+            .subscribe(topic, QoS::AtMostOnce)
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    async fn mqtt_publish(
+        &mut self,
+        topic: String,
+        payload: Vec<u8>,
+        retain: bool,
+    ) -> Result<(), String> {
+        let mqtt = self
+            .mqtt
+            .as_ref()
+            .ok_or_else(|| "MQTT is not allowed".to_string())?;
+
+        if self.dry_run {
+            debug!(target: &self.log_target, "(dry run) would publish to {topic}");
+            return Ok(());
+        }
+
+        mqtt.client
+            .publish(topic, QoS::AtLeastOnce, retain, payload)
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    async fn udp_bind(&mut self, port: u16) -> Result<(), String> {
+        if !self.capabilities.udp {
+            return Err("UDP is not allowed".to_string());
+        }
+
+        if let Some((_, receiver)) = self.udp.take() {
+            receiver.abort();
+        }
+
+        let socket = UdpSocket::bind(("0.0.0.0", port))
+            .await
+            .map_err(|e| e.to_string())?;
+        socket.set_broadcast(true).map_err(|e| e.to_string())?;
+        let socket = Arc::new(socket);
+
+        let receiver = {
+            let socket = socket.clone();
+            let callbacks_tx = self.callbacks_tx.clone();
+
+            tokio::spawn(async move {
+                let mut buf = vec![0; 65536];
+                while let Ok((len, from)) = socket.recv_from(&mut buf).await {
+                    callbacks_tx
+                        .send(Callback::UdpMessage {
+                            from: from.to_string(),
+                            payload: buf[..len].to_vec(),
+                        })
+                        .ok();
+                }
+            })
+        };
+
+        self.udp = Some((socket, receiver));
+
+        Ok(())
+    }
+
+    async fn udp_send(&mut self, address: String, payload: Vec<u8>) -> Result<(), String> {
+        if !self.capabilities.udp {
+            return Err("UDP is not allowed".to_string());
+        }
+
+        if self.udp.is_none() {
+            self.udp_bind(0).await?;
+        }
+
+        if self.dry_run {
+            debug!(target: &self.log_target, "(dry run) would send UDP datagram to {address}");
+            return Ok(());
+        }
+
+        let (socket, _) = self.udp.as_ref().unwrap();
+        socket
+            .send_to(&payload, &address)
+            .await
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use warp::Filter;
+    use wasmtime::StoreLimitsBuilder;
+
+    use super::*;
+    use crate::{integrations::wasm::homectl::plugin::host::Host, types::event::mk_event_channel};
+
+    /// Starts a server on 127.0.0.1 which responds to `/target`, and
+    /// redirects `/redirect/<host>` to `/target` on the given host
+    fn mock_server() -> u16 {
+        let target = warp::path!("target").map(|| "ok");
+        let redirect = warp::path!("redirect" / String)
+            .and(warp::header::<String>("host"))
+            .map(|target_host: String, host: String| {
+                let port = host.rsplit(':').next().unwrap_or_default().to_string();
+                let uri = format!("http://{target_host}:{port}/target");
+                warp::redirect::temporary(warp::http::Uri::from_str(&uri).unwrap())
+            });
+
+        let (addr, server) = warp::serve(target.or(redirect)).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        addr.port()
+    }
+
+    fn mk_host_state(http_hosts: &[&str]) -> HostState {
+        let (event_tx, _event_rx) = mk_event_channel();
+        let (callbacks_tx, _callbacks_rx) = mpsc::unbounded_channel();
+
+        let capabilities = WasmCapabilities {
+            http_hosts: http_hosts.iter().map(|host| host.to_string()).collect(),
+            ..Default::default()
+        };
+
+        HostState::new(
+            &IntegrationId::from_str("wasm").unwrap(),
+            capabilities,
+            false,
+            event_tx,
+            callbacks_tx,
+            StoreLimitsBuilder::new().build(),
+        )
+    }
+
+    fn get(url: String) -> HttpRequest {
+        HttpRequest {
+            method: "GET".to_string(),
+            url,
+            headers: vec![],
+            body: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_http_request_to_disallowed_host() {
+        let port = mock_server();
+        let mut state = mk_host_state(&["127.0.0.1"]);
+
+        let result = state
+            .http_request(get(format!("http://localhost:{port}/target")))
+            .await;
+
+        assert_eq!(
+            result.unwrap_err(),
+            "HTTP requests to localhost are not allowed"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_http_redirect_to_allowed_host() {
+        let port = mock_server();
+        let mut state = mk_host_state(&["127.0.0.1"]);
+
+        let response = state
+            .http_request(get(format!("http://127.0.0.1:{port}/redirect/127.0.0.1")))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body, b"ok");
+    }
+
+    #[tokio::test]
+    async fn test_http_redirect_to_disallowed_host() {
+        let port = mock_server();
+        let mut state = mk_host_state(&["127.0.0.1"]);
+
+        let error = state
+            .http_request(get(format!("http://127.0.0.1:{port}/redirect/localhost")))
+            .await
+            .unwrap_err();
+
+        assert!(error.contains("redirect"), "unexpected error: {error}");
+    }
+}
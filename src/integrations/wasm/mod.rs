@@ -0,0 +1,314 @@
+mod host;
+
+use crate::{
+    types::{
+        device::Device,
+        event::TxEventChannel,
+        integration::{Integration, IntegrationActionPayload, IntegrationId},
+    },
+    utils::cli::Cli,
+};
+use async_trait::async_trait;
+use color_eyre::Result;
+use eyre::Context;
+use serde::Deserialize;
+use std::{path::PathBuf, sync::Arc, time::Duration};
+use tokio::{
+    sync::{mpsc, Mutex},
+    task::JoinHandle,
+};
+use wasmtime::{
+    component::{Component, Linker},
+    Config, Engine, Store, StoreLimitsBuilder,
+};
+
+use self::host::{Callback, HostState};
+
+wasmtime::component::bindgen!({
+    world: "homectl-integration",
+    path: "wit",
+    async: true,
+});
+
+use self::homectl::plugin::types::Device as PluginDevice;
+
+const DEFAULT_MAX_MEMORY_MB: usize = 64;
+
+/// How often running plugins yield back to the async runtime, which allows
+/// timeouts to interrupt plugins that are stuck in a loop
+const EPOCH_INTERVAL: Duration = Duration::from_millis(10);
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct WasmMqttConfig {
+    host: String,
+    port: u16,
+    username: Option<String>,
+    password: Option<String>,
+}
+
+/// Networking that the plugin is allowed to do through host functions. By
+/// default plugins can't access the network at all.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct WasmCapabilities {
+    /// Hosts the plugin may send HTTP requests to
+    #[serde(default)]
+    http_hosts: Vec<String>,
+
+    /// MQTT broker the plugin may publish and subscribe to
+    mqtt: Option<WasmMqttConfig>,
+
+    /// Whether the plugin may send and receive UDP datagrams
+    #[serde(default)]
+    udp: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct WasmConfig {
+    /// Path to a WebAssembly component implementing the
+    /// `homectl-integration` world in `wit/homectl-plugin.wit`
+    path: PathBuf,
+
+    #[serde(default)]
+    capabilities: WasmCapabilities,
+
+    /// Maximum linear memory of the plugin, defaults to 64 MB
+    max_memory_mb: Option<usize>,
+
+    /// Passed as JSON to the plugin's `init` function
+    #[serde(default)]
+    config: serde_json::Value,
+}
+
+struct Instance {
+    store: Store<HostState>,
+    bindings: HomectlIntegration,
+}
+
+/// Runs an integration compiled to a WebAssembly component in a sandbox.
+pub struct Wasm {
+    id: IntegrationId,
+    config: WasmConfig,
+    cli: Cli,
+    event_tx: TxEventChannel,
+    engine: Engine,
+    component: Component,
+    instance: Option<Arc<Mutex<Instance>>>,
+
+    /// Background tasks that need to be stopped along with the plugin
+    tasks: Vec<JoinHandle<()>>,
+}
+
+fn to_plugin_device(device: &Device) -> Result<PluginDevice> {
+    Ok(PluginDevice {
+        id: device.id.to_string(),
+        name: device.name.clone(),
+        data_json: serde_json::to_string(&device.data)?,
+        raw_json: device.raw.as_ref().map(serde_json::to_string).transpose()?,
+    })
+}
+
+fn plugin_result(method: &str, result: Result<(), String>) -> Result<()> {
+    result.map_err(|e| eyre!("Plugin {method} failed: {e}"))
+}
+
+impl Wasm {
+    fn instance(&self) -> Result<Arc<Mutex<Instance>>> {
+        self.instance
+            .clone()
+            .ok_or_else(|| eyre!("Plugin of integration {} is not running", self.id))
+    }
+
+    /// Delivers timers and incoming network messages to the plugin
+    fn spawn_dispatcher(
+        instance: Arc<Mutex<Instance>>,
+        mut callbacks_rx: mpsc::UnboundedReceiver<Callback>,
+    ) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            while let Some(callback) = callbacks_rx.recv().await {
+                let mut instance = instance.lock().await;
+                let Instance { store, bindings } = &mut *instance;
+                let plugin = bindings.homectl_plugin_plugin();
+
+                let result = match callback {
+                    Callback::Timer(id) => plugin.call_on_timer(store, id).await,
+                    Callback::MqttMessage { topic, payload } => {
+                        plugin.call_on_mqtt_message(store, &topic, &payload).await
+                    }
+                    Callback::UdpMessage { from, payload } => {
+                        plugin.call_on_udp_message(store, &from, &payload).await
+                    }
+                };
+
+                if let Err(e) = result {
+                    error!(
+                        target: &format!(
+                            "homectl_server::integrations::wasm::{}",
+                            store.data().integration_id()
+                        ),
+                        "Plugin trapped while handling callback: {e:?}"
+                    );
+                }
+            }
+        })
+    }
+}
+
+#[async_trait]
+impl Integration for Wasm {
+    fn new(
+        id: &IntegrationId,
+        config: &config::Value,
+        cli: &Cli,
+        event_tx: TxEventChannel,
+    ) -> Result<Self> {
+        let config: WasmConfig = config
+            .clone()
+            .try_deserialize()
+            .wrap_err("Failed to deserialize config of Wasm integration")?;
+
+        let mut engine_config = Config::new();
+        engine_config
+            .async_support(true)
+            .wasm_component_model(true)
+            .epoch_interruption(true);
+        let engine = Engine::new(&engine_config)?;
+
+        let component = Component::from_file(&engine, &config.path).wrap_err_with(|| {
+            format!(
+                "Failed to load WebAssembly component from {}",
+                config.path.display()
+            )
+        })?;
+
+        Ok(Wasm {
+            id: id.clone(),
+            config,
+            cli: cli.clone(),
+            event_tx,
+            engine,
+            component,
+            instance: None,
+            tasks: vec![],
+        })
+    }
+
+    async fn register(&mut self) -> Result<()> {
+        let (callbacks_tx, callbacks_rx) = mpsc::unbounded_channel();
+
+        let max_memory = self.config.max_memory_mb.unwrap_or(DEFAULT_MAX_MEMORY_MB) * 1024 * 1024;
+        let limits = StoreLimitsBuilder::new().memory_size(max_memory).build();
+
+        let state = HostState::new(
+            &self.id,
+            self.config.capabilities.clone(),
+            self.cli.dry_run,
+            self.event_tx.clone(),
+            callbacks_tx,
+            limits,
+        );
+
+        let mut store = Store::new(&self.engine, state);
+        store.limiter(|state| state.limits());
+        store.epoch_deadline_async_yield_and_update(1);
+
+        {
+            let engine = self.engine.clone();
+            self.tasks.push(tokio::spawn(async move {
+                loop {
+                    tokio::time::sleep(EPOCH_INTERVAL).await;
+                    engine.increment_epoch();
+                }
+            }));
+        }
+
+        let mut linker = Linker::new(&self.engine);
+        HomectlIntegration::add_to_linker(&mut linker, |state: &mut HostState| state)?;
+
+        let bindings =
+            HomectlIntegration::instantiate_async(&mut store, &self.component, &linker).await?;
+
+        let plugin = bindings.homectl_plugin_plugin();
+        let config_json = serde_json::to_string(&self.config.config)?;
+        plugin_result(
+            "init",
+            plugin
+                .call_init(&mut store, &self.id.to_string(), &config_json)
+                .await?,
+        )?;
+        plugin_result("register", plugin.call_register(&mut store).await?)?;
+
+        let instance = Arc::new(Mutex::new(Instance { store, bindings }));
+        self.tasks
+            .push(Wasm::spawn_dispatcher(instance.clone(), callbacks_rx));
+        self.instance = Some(instance);
+
+        Ok(())
+    }
+
+    async fn start(&mut self) -> Result<()> {
+        let instance = self.instance()?;
+        let mut instance = instance.lock().await;
+        let Instance { store, bindings } = &mut *instance;
+
+        let result = bindings.homectl_plugin_plugin().call_start(store).await?;
+        plugin_result("start", result)
+    }
+
+    async fn set_integration_device_state(&mut self, device: &Device) -> Result<()> {
+        let device = to_plugin_device(device)?;
+
+        let instance = self.instance()?;
+        let mut instance = instance.lock().await;
+        let Instance { store, bindings } = &mut *instance;
+
+        let result = bindings
+            .homectl_plugin_plugin()
+            .call_set_device_state(store, &device)
+            .await?;
+        plugin_result("set-device-state", result)
+    }
+
+    async fn run_integration_action(&mut self, payload: &IntegrationActionPayload) -> Result<()> {
+        let instance = self.instance()?;
+        let mut instance = instance.lock().await;
+        let Instance { store, bindings } = &mut *instance;
+
+        let result = bindings
+            .homectl_plugin_plugin()
+            .call_run_action(store, &payload.to_string())
+            .await?;
+        plugin_result("run-action", result)
+    }
+
+    async fn health_check(&mut self) -> Result<()> {
+        let instance = self.instance()?;
+        let mut instance = instance.lock().await;
+        let Instance { store, bindings } = &mut *instance;
+
+        let result = bindings
+            .homectl_plugin_plugin()
+            .call_health_check(store)
+            .await?;
+        plugin_result("health-check", result)
+    }
+
+    async fn stop(&mut self) -> Result<()> {
+        let Some(instance) = self.instance.take() else {
+            return Ok(());
+        };
+
+        let result = {
+            let mut instance = instance.lock().await;
+            let Instance { store, bindings } = &mut *instance;
+
+            bindings.homectl_plugin_plugin().call_stop(store).await
+        };
+
+        // Dropping the instance stops its timers and network tasks
+        for task in self.tasks.drain(..) {
+            task.abort();
+        }
+
+        plugin_result("stop", result?)
+    }
+}
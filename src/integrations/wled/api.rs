@@ -0,0 +1,77 @@
+//! Types and requests of the WLED [JSON API](https://kno.wled.ge/interfaces/json-api/).
+
+use color_eyre::Result;
+use reqwest::header::CONTENT_TYPE;
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::Value;
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Leds {
+    pub count: usize,
+}
+
+/// Response of `/json/info`
+#[derive(Clone, Debug, Deserialize)]
+pub struct Info {
+    pub name: String,
+    pub mac: String,
+    pub leds: Leds,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct Segment {
+    pub id: u64,
+
+    /// Name of the segment, if one has been given
+    pub n: Option<String>,
+
+    pub on: Option<bool>,
+    pub bri: Option<u8>,
+
+    /// Primary, secondary and tertiary colors as [r, g, b] or [r, g, b, w]
+    #[serde(default)]
+    pub col: Vec<Vec<u8>>,
+}
+
+/// Response of `/json/state`
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct State {
+    pub on: bool,
+    pub bri: u8,
+
+    /// Id of the main segment
+    #[serde(default)]
+    pub mainseg: u64,
+
+    #[serde(default)]
+    pub seg: Vec<Segment>,
+}
+
+pub async fn get<T: DeserializeOwned>(http: &reqwest::Client, host: &str, path: &str) -> Result<T> {
+    let response = http
+        .get(format!("http://{host}{path}"))
+        .send()
+        .await?
+        .error_for_status()?
+        .bytes()
+        .await?;
+
+    Ok(serde_json::from_slice(&response)?)
+}
+
+/// Posts a partial state to the device, returns the device's new state
+pub async fn post_state(http: &reqwest::Client, host: &str, mut state: Value) -> Result<Value> {
+    state["v"] = Value::Bool(true);
+
+    let response = http
+        .post(format!("http://{host}/json/state"))
+        .header(CONTENT_TYPE, "application/json")
+        .body(state.to_string())
+        .send()
+        .await?
+        .error_for_status()?
+        .bytes()
+        .await?;
+
+    Ok(serde_json::from_slice(&response)?)
+}
@@ -0,0 +1,743 @@
+//! Integration for [WLED](https://kno.wled.ge/) LED strips using the local
+//! JSON API.
+//!
+//! Each strip shows up as a device, and strips with several segments also get
+//! a device per segment. Presets, effects and frames sent over the realtime
+//! UDP protocol are available as custom actions.
+
+mod api;
+mod realtime;
+
+use crate::{
+    types::{
+        color::{Capabilities, ColorMode, DeviceColor},
+        device::{ControllableDevice, ControllableState, Device, DeviceData, DeviceId, ManageKind},
+        event::{Event, TxEventChannel},
+        integration::{Integration, IntegrationActionPayload, IntegrationId},
+    },
+    utils::cli::Cli,
+};
+use async_trait::async_trait;
+use color_eyre::Result;
+use eyre::Context;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::RwLock;
+use tokio::task::{self, JoinHandle};
+
+use self::api::{Info, Segment, State};
+
+const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(10);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct WledDeviceConfig {
+    /// Address of the device, optionally followed by a port
+    host: String,
+
+    /// Defaults to the name configured on the device
+    name: Option<String>,
+
+    /// UDP port used for realtime frames, defaults to 21324
+    realtime_port: Option<u16>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct WledConfig {
+    devices: Vec<WledDeviceConfig>,
+
+    /// How often device states are polled, defaults to 10 seconds
+    poll_interval_seconds: Option<u64>,
+
+    /// Whether homectl should keep track of the devices' expected states,
+    /// defaults to "Full"
+    managed: Option<ManageKind>,
+
+    /// Transition time in seconds used when none is given
+    default_transition: Option<f32>,
+}
+
+impl WledConfig {
+    fn poll_interval(&self) -> Duration {
+        self.poll_interval_seconds
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_POLL_INTERVAL)
+    }
+
+    fn device(&self, host: &str) -> Option<&WledDeviceConfig> {
+        self.devices.iter().find(|device| device.host == host)
+    }
+}
+
+/// Part of a strip that a device controls
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Target {
+    /// The whole strip
+    Strip,
+    Segment(u64),
+}
+
+fn rgb_capabilities() -> Capabilities {
+    Capabilities::singleton(ColorMode::Rgb)
+}
+
+/// Reads the primary color of a segment
+fn segment_color(segment: &Segment) -> Option<DeviceColor> {
+    match segment.col.first()?.as_slice() {
+        [r, g, b, ..] => Some(DeviceColor::new_from_rgb(*r, *g, *b)),
+        _ => None,
+    }
+}
+
+/// Converts the state of a strip into devices, the strip itself followed by
+/// its segments if it has more than one
+fn to_homectl(
+    integration_id: &IntegrationId,
+    info: &Info,
+    name: &str,
+    state: &State,
+    raw: &Value,
+    managed: &ManageKind,
+) -> Vec<Device> {
+    let controllable = |power: bool, brightness: u8, color: Option<DeviceColor>| {
+        DeviceData::Controllable(ControllableDevice::new(
+            None,
+            power,
+            Some(brightness as f32 / 255.0),
+            color,
+            None,
+            rgb_capabilities(),
+            managed.clone(),
+        ))
+    };
+
+    let main_segment = state
+        .seg
+        .iter()
+        .find(|segment| segment.id == state.mainseg)
+        .or(state.seg.first());
+
+    let strip = Device::new(
+        integration_id.clone(),
+        DeviceId::new(&info.mac),
+        name.to_string(),
+        controllable(state.on, state.bri, main_segment.and_then(segment_color)),
+        Some(raw.clone()),
+    );
+
+    // A single segment is the same as the whole strip
+    let segments: &[Segment] = if state.seg.len() > 1 { &state.seg } else { &[] };
+
+    let segments = segments.iter().map(|segment| {
+        let segment_name = match &segment.n {
+            Some(n) => format!("{name} {n}"),
+            None => format!("{name} segment {}", segment.id),
+        };
+
+        Device::new(
+            integration_id.clone(),
+            DeviceId::new(&format!("{}/segment:{}", info.mac, segment.id)),
+            segment_name,
+            controllable(
+                segment.on.unwrap_or(true),
+                segment.bri.unwrap_or(u8::MAX),
+                segment_color(segment),
+            ),
+            None,
+        )
+    });
+
+    std::iter::once(strip).chain(segments).collect()
+}
+
+/// Builds the partial state for setting a strip or segment to the given state
+fn to_command(target: Target, state: &ControllableState, default_transition: Option<f32>) -> Value {
+    let mut command = json!({});
+
+    let transition = state.transition.map(|t| *t).or(default_transition);
+    if let Some(transition) = transition {
+        // In units of 100 ms
+        command["transition"] = json!((transition * 10.0).round() as u64);
+    }
+
+    let mut fields = json!({ "on": state.power });
+
+    if let Some(brightness) = state.brightness {
+        let brightness = (*brightness * 255.0).round().clamp(0.0, 255.0);
+        fields["bri"] = json!(brightness as u8);
+    }
+
+    let color = state
+        .color
+        .as_ref()
+        .and_then(|color| color.to_device_preferred_mode(&rgb_capabilities()));
+    let col = match color {
+        Some(DeviceColor::Rgb(rgb)) => Some(json!([[rgb.r, rgb.g, rgb.b]])),
+        _ => None,
+    };
+
+    match target {
+        Target::Strip => {
+            command["on"] = fields["on"].take();
+            if let Some(bri) = fields.get_mut("bri") {
+                command["bri"] = bri.take();
+            }
+
+            // Applies to the selected segments
+            if let Some(col) = col {
+                command["seg"] = json!({ "col": col });
+            }
+        }
+        Target::Segment(id) => {
+            fields["id"] = json!(id);
+            if let Some(col) = col {
+                fields["col"] = col;
+            }
+
+            // Segments are only visible while the strip is on
+            if state.power {
+                command["on"] = json!(true);
+            }
+
+            command["seg"] = json!([fields]);
+        }
+    }
+
+    command
+}
+
+/// State shared between the integration and its polling tasks
+struct Shared {
+    id: IntegrationId,
+    config: WledConfig,
+    event_tx: TxEventChannel,
+    http: reqwest::Client,
+
+    /// Info of each strip by host, once fetched
+    infos: RwLock<HashMap<String, Info>>,
+
+    /// Host and target of each device
+    targets: RwLock<HashMap<DeviceId, (String, Target)>>,
+
+    /// Hosts that could not be reached on the latest attempt
+    unreachable: RwLock<BTreeSet<String>>,
+}
+
+impl Shared {
+    async fn info(&self, host: &str) -> Result<Info> {
+        if let Some(info) = self.infos.read().await.get(host) {
+            return Ok(info.clone());
+        }
+
+        let info: Info = api::get(&self.http, host, "/json/info").await?;
+        self.infos
+            .write()
+            .await
+            .insert(host.to_string(), info.clone());
+
+        Ok(info)
+    }
+
+    /// Sends the devices of a strip given its full state
+    async fn send_state(&self, host: &str, raw: Value) -> Result<()> {
+        let info = self.info(host).await?;
+        let state: State = serde_json::from_value(raw.clone())?;
+
+        let config = self.config.device(host);
+        let name = config
+            .and_then(|config| config.name.as_ref())
+            .unwrap_or(&info.name);
+        let managed = self.config.managed.clone().unwrap_or_default();
+
+        let devices = to_homectl(&self.id, &info, name, &state, &raw, &managed);
+
+        let mut targets = self.targets.write().await;
+        for (device, target) in devices.iter().zip(
+            std::iter::once(Target::Strip)
+                .chain(state.seg.iter().map(|segment| Target::Segment(segment.id))),
+        ) {
+            targets.insert(device.id.clone(), (host.to_string(), target));
+        }
+
+        for device in devices {
+            self.event_tx.send(Event::ExternalStateUpdate { device });
+        }
+
+        Ok(())
+    }
+
+    async fn poll(&self, host: &str) -> Result<()> {
+        let state: Value = api::get(&self.http, host, "/json/state").await?;
+        self.send_state(host, state).await
+    }
+
+    async fn set_reachable(&self, host: &str, reachable: bool) {
+        let mut unreachable = self.unreachable.write().await;
+
+        if reachable {
+            unreachable.remove(host);
+        } else {
+            unreachable.insert(host.to_string());
+        }
+    }
+}
+
+async fn poll_device(shared: Arc<Shared>, host: String) {
+    let target = format!("homectl_server::integrations::wled::{}", shared.id);
+
+    loop {
+        match shared.poll(&host).await {
+            Ok(()) => shared.set_reachable(&host, true).await,
+            Err(e) => {
+                warn!(target: &target, "Failed to poll WLED at {host}: {e}");
+                shared.set_reachable(&host, false).await;
+            }
+        }
+
+        tokio::time::sleep(shared.config.poll_interval()).await;
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(untagged)]
+pub enum Effect {
+    Id(u64),
+    Name(String),
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct RealtimeFrame {
+    /// Colors of the LEDs from the first one, repeated to fill the strip
+    colors: Vec<[u8; 3]>,
+
+    /// Seconds until the strip returns to its normal mode, defaults to 2.
+    /// 255 keeps the frame until the strip is restarted.
+    timeout_seconds: Option<u8>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct CustomWledAction {
+    host: String,
+
+    /// Applies the preset with the given id
+    preset: Option<u64>,
+
+    /// Starts an effect by id or name
+    effect: Option<Effect>,
+
+    /// Segment that the effect applies to, defaults to the selected segments
+    segment: Option<u64>,
+
+    /// Arbitrary fields posted to `/json/state`
+    state: Option<Value>,
+
+    /// Frame shown using the realtime protocol
+    realtime: Option<RealtimeFrame>,
+}
+
+pub struct Wled {
+    shared: Arc<Shared>,
+    cli: Cli,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl Wled {
+    async fn effect_id(&self, host: &str, effect: &Effect) -> Result<u64> {
+        match effect {
+            Effect::Id(id) => Ok(*id),
+            Effect::Name(name) => {
+                let effects: Vec<String> = api::get(&self.shared.http, host, "/json/eff").await?;
+
+                effects
+                    .iter()
+                    .position(|effect| effect.eq_ignore_ascii_case(name))
+                    .map(|id| id as u64)
+                    .ok_or_else(|| eyre!("Unknown WLED effect {name}"))
+            }
+        }
+    }
+
+    async fn post_state(&self, host: &str, command: Value) -> Result<()> {
+        if self.cli.dry_run {
+            debug!("(dry run) would post state to WLED at {host}: {command}");
+            return Ok(());
+        }
+
+        let state = api::post_state(&self.shared.http, host, command).await?;
+        self.shared.send_state(host, state).await
+    }
+}
+
+#[async_trait]
+impl Integration for Wled {
+    fn new(
+        id: &IntegrationId,
+        config: &config::Value,
+        cli: &Cli,
+        event_tx: TxEventChannel,
+    ) -> Result<Self> {
+        let config: WledConfig = config
+            .clone()
+            .try_deserialize()
+            .wrap_err("Failed to deserialize config of WLED integration")?;
+
+        let http = reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()?;
+
+        Ok(Wled {
+            shared: Arc::new(Shared {
+                id: id.clone(),
+                config,
+                event_tx,
+                http,
+                infos: Default::default(),
+                targets: Default::default(),
+                unreachable: Default::default(),
+            }),
+            cli: cli.clone(),
+            tasks: vec![],
+        })
+    }
+
+    async fn start(&mut self) -> Result<()> {
+        for device in &self.shared.config.devices {
+            let task = task::spawn(poll_device(self.shared.clone(), device.host.clone()));
+            self.tasks.push(task);
+        }
+
+        Ok(())
+    }
+
+    async fn set_integration_device_state(&mut self, device: &Device) -> Result<()> {
+        let DeviceData::Controllable(controllable) = &device.data else {
+            return Ok(());
+        };
+
+        let Some((host, target)) = self.shared.targets.read().await.get(&device.id).cloned() else {
+            warn!("Unknown WLED device {device}, ignoring state update");
+            return Ok(());
+        };
+
+        let command = to_command(
+            target,
+            &controllable.state,
+            self.shared.config.default_transition,
+        );
+
+        self.post_state(&host, command).await
+    }
+
+    async fn run_integration_action(&mut self, payload: &IntegrationActionPayload) -> Result<()> {
+        let action: CustomWledAction = serde_json::from_str(&payload.to_string())?;
+        let host = &action.host;
+
+        let mut command = action.state.unwrap_or_else(|| json!({}));
+        if !command.is_object() {
+            return Err(eyre!(
+                "Expected state of WLED action to be a JSON object, got {command}"
+            ));
+        }
+
+        if let Some(preset) = action.preset {
+            command["ps"] = json!(preset);
+        }
+
+        if let Some(effect) = &action.effect {
+            let fx = self.effect_id(host, effect).await?;
+
+            command["seg"] = match action.segment {
+                Some(id) => json!([{ "id": id, "fx": fx }]),
+                None => json!({ "fx": fx }),
+            };
+        }
+
+        if command
+            .as_object()
+            .is_some_and(|command| !command.is_empty())
+        {
+            self.post_state(host, command).await?;
+        }
+
+        if let Some(frame) = action.realtime {
+            let info = self.shared.info(host).await?;
+            let colors: Vec<[u8; 3]> = frame
+                .colors
+                .iter()
+                .cycle()
+                .take(info.leds.count.max(frame.colors.len()))
+                .copied()
+                .collect();
+
+            let port = self
+                .shared
+                .config
+                .device(host)
+                .and_then(|device| device.realtime_port)
+                .unwrap_or(realtime::DEFAULT_PORT);
+
+            // The host may include the port of the HTTP API
+            let url = reqwest::Url::parse(&format!("http://{host}"))?;
+            let address = url
+                .host_str()
+                .ok_or_else(|| eyre!("Invalid WLED host {host}"))?;
+
+            if !self.cli.dry_run {
+                realtime::send(address, port, &colors, frame.timeout_seconds.unwrap_or(2)).await?;
+            } else {
+                debug!("(dry run) would send realtime frame to WLED at {host}");
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn stop(&mut self) -> Result<()> {
+        for task in self.tasks.drain(..) {
+            task.abort();
+        }
+
+        Ok(())
+    }
+
+    async fn health_check(&mut self) -> Result<()> {
+        if self.tasks.iter().any(|task| task.is_finished()) {
+            return Err(eyre!("WLED polling task has stopped"));
+        }
+
+        let unreachable = self.shared.unreachable.read().await;
+        if !unreachable.is_empty() {
+            let hosts: Vec<_> = unreachable.iter().map(String::as_str).collect();
+            return Err(eyre!("Unable to reach WLED at {}", hosts.join(", ")));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+    use std::sync::Mutex;
+
+    use ordered_float::OrderedFloat;
+    use tokio::net::UdpSocket;
+    use warp::Filter;
+
+    use super::*;
+    use crate::types::event::{mk_event_channel, RxEventChannel};
+
+    type Posts = Arc<Mutex<Vec<Value>>>;
+
+    fn mock_state() -> Value {
+        json!({
+            "on": true,
+            "bri": 128,
+            "transition": 7,
+            "ps": -1,
+            "mainseg": 0,
+            "seg": [
+                { "id": 0, "start": 0, "stop": 20, "on": true, "bri": 255, "col": [[255, 160, 0], [0, 0, 0], [0, 0, 0]], "fx": 0 },
+                { "id": 1, "start": 20, "stop": 30, "n": "Shelf", "on": false, "bri": 51, "col": [[0, 0, 255], [0, 0, 0], [0, 0, 0]], "fx": 0 }
+            ]
+        })
+    }
+
+    /// Starts a stand-in for a WLED strip with two segments, which records
+    /// posted states and responds with the unchanged state
+    async fn mock_wled() -> (String, Posts) {
+        let posts: Posts = Default::default();
+
+        let info = warp::path!("json" / "info").and(warp::get()).map(|| {
+            warp::reply::json(&json!({
+                "name": "Desk",
+                "mac": "a8032ab12345",
+                "leds": { "count": 30 }
+            }))
+        });
+        let effects = warp::path!("json" / "eff")
+            .and(warp::get())
+            .map(|| warp::reply::json(&json!(["Solid", "Blink", "Rainbow"])));
+        let state = warp::path!("json" / "state")
+            .and(warp::get())
+            .map(|| warp::reply::json(&mock_state()));
+
+        let post_posts = posts.clone();
+        let post = warp::path!("json" / "state")
+            .and(warp::post())
+            .and(warp::body::json())
+            .map(move |body: Value| {
+                post_posts.lock().unwrap().push(body);
+                warp::reply::json(&mock_state())
+            });
+
+        let routes = info.or(effects).or(state).or(post);
+        let (addr, server) = warp::serve(routes).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        (addr.to_string(), posts)
+    }
+
+    fn mk_wled(host: &str, realtime_port: u16, event_tx: TxEventChannel) -> Wled {
+        let device = config::Value::from(config::Map::from([
+            ("host".to_string(), config::Value::from(host)),
+            (
+                "realtime_port".to_string(),
+                config::Value::from(realtime_port as i64),
+            ),
+        ]));
+        let config = config::Value::from(config::Map::from([(
+            "devices".to_string(),
+            config::Value::from(vec![device]),
+        )]));
+
+        let cli = Cli {
+            dry_run: false,
+            record: None,
+            command: None,
+        };
+
+        Wled::new(
+            &IntegrationId::from_str("wled").unwrap(),
+            &config,
+            &cli,
+            event_tx,
+        )
+        .unwrap()
+    }
+
+    async fn recv_device(event_rx: &mut RxEventChannel, id: &str) -> Device {
+        let recv = async {
+            loop {
+                if let Event::ExternalStateUpdate { device } = event_rx.recv().await.event {
+                    if device.id.to_string() == id {
+                        return device;
+                    }
+                }
+            }
+        };
+
+        tokio::time::timeout(Duration::from_secs(5), recv)
+            .await
+            .expect("Timed out waiting for device update")
+    }
+
+    fn state(device: &Device) -> &ControllableState {
+        match &device.data {
+            DeviceData::Controllable(controllable) => &controllable.state,
+            data => panic!("Expected controllable device, got {data:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_segments() {
+        let (host, posts) = mock_wled().await;
+        let (event_tx, mut event_rx) = mk_event_channel();
+        let mut wled = mk_wled(&host, realtime::DEFAULT_PORT, event_tx);
+
+        wled.start().await.unwrap();
+
+        let strip = recv_device(&mut event_rx, "a8032ab12345").await;
+        assert_eq!(strip.name, "Desk");
+        assert!(state(&strip).power);
+        assert_eq!(state(&strip).brightness, Some(OrderedFloat(128.0 / 255.0)));
+        assert_eq!(
+            state(&strip).color,
+            Some(DeviceColor::new_from_rgb(255, 160, 0))
+        );
+
+        let mut shelf = recv_device(&mut event_rx, "a8032ab12345/segment:1").await;
+        assert_eq!(shelf.name, "Desk Shelf");
+        assert!(!state(&shelf).power);
+
+        if let DeviceData::Controllable(ref mut controllable) = shelf.data {
+            controllable.state.power = true;
+            controllable.state.brightness = Some(OrderedFloat(1.0));
+            controllable.state.color = Some(DeviceColor::new_from_rgb(255, 0, 0));
+        }
+        wled.set_integration_device_state(&shelf).await.unwrap();
+
+        assert_eq!(
+            posts.lock().unwrap().last().unwrap(),
+            &json!({
+                "on": true,
+                "seg": [{ "id": 1, "on": true, "bri": 255, "col": [[255, 0, 0]] }],
+                "v": true
+            })
+        );
+
+        wled.health_check().await.unwrap();
+        wled.stop().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_actions() {
+        let (host, posts) = mock_wled().await;
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let port = socket.local_addr().unwrap().port();
+
+        let (event_tx, _event_rx) = mk_event_channel();
+        let mut wled = mk_wled(&host, port, event_tx);
+
+        let payload = json!({ "host": host, "effect": "rainbow", "segment": 1 });
+        wled.run_integration_action(&IntegrationActionPayload::from(payload.to_string()))
+            .await
+            .unwrap();
+
+        assert_eq!(
+            posts.lock().unwrap().last().unwrap(),
+            &json!({ "seg": [{ "id": 1, "fx": 2 }], "v": true })
+        );
+
+        let payload = json!({ "host": host, "realtime": { "colors": [[255, 0, 0], [0, 0, 255]] } });
+        wled.run_integration_action(&IntegrationActionPayload::from(payload.to_string()))
+            .await
+            .unwrap();
+
+        let mut buf = [0; 1024];
+        let len = tokio::time::timeout(Duration::from_secs(5), socket.recv(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+
+        // Header followed by the two colors repeated for all 30 LEDs
+        assert_eq!(len, 4 + 30 * 3);
+        assert_eq!(buf[..10], [4, 2, 0, 0, 255, 0, 0, 0, 0, 255]);
+    }
+
+    #[tokio::test]
+    async fn test_action_state_must_be_object() {
+        let (host, posts) = mock_wled().await;
+        let (event_tx, _event_rx) = mk_event_channel();
+        let mut wled = mk_wled(&host, realtime::DEFAULT_PORT, event_tx);
+
+        for state in [json!("on"), json!([1, 2]), json!(true)] {
+            let payload = json!({ "host": host, "preset": 1, "state": state });
+            let result = wled
+                .run_integration_action(&IntegrationActionPayload::from(payload.to_string()))
+                .await;
+
+            assert!(result.is_err());
+        }
+
+        assert!(posts.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_strip_command() {
+        let state = ControllableState {
+            power: true,
+            brightness: Some(OrderedFloat(0.5)),
+            color: None,
+            transition: Some(OrderedFloat(0.4)),
+        };
+
+        assert_eq!(
+            to_command(Target::Strip, &state, None),
+            json!({ "on": true, "bri": 128, "transition": 4 })
+        );
+    }
+}
@@ -0,0 +1,59 @@
+//! WLED's [realtime UDP protocol](https://kno.wled.ge/interfaces/udp-realtime/),
+//! which lets homectl drive individual LEDs, e.g. for effects computed on the
+//! server.
+
+use color_eyre::Result;
+use tokio::net::UdpSocket;
+
+pub const DEFAULT_PORT: u16 = 21324;
+
+/// DNRGB: RGB values of LEDs starting from an index
+const PROTOCOL_DNRGB: u8 = 4;
+
+/// Maximum number of LEDs in one DNRGB packet
+const LEDS_PER_PACKET: usize = 489;
+
+/// Builds the packets for showing the given colors, starting from the first
+/// LED. The device returns to its normal mode once no packets have been
+/// received for `timeout_seconds`, or never if it is 255.
+pub fn packets(colors: &[[u8; 3]], timeout_seconds: u8) -> Vec<Vec<u8>> {
+    colors
+        .chunks(LEDS_PER_PACKET)
+        .enumerate()
+        .map(|(index, chunk)| {
+            let start = (index * LEDS_PER_PACKET) as u16;
+
+            let mut packet = vec![PROTOCOL_DNRGB, timeout_seconds];
+            packet.extend(start.to_be_bytes());
+            packet.extend(chunk.iter().flatten());
+            packet
+        })
+        .collect()
+}
+
+pub async fn send(host: &str, port: u16, colors: &[[u8; 3]], timeout_seconds: u8) -> Result<()> {
+    let socket = UdpSocket::bind("0.0.0.0:0").await?;
+
+    for packet in packets(colors, timeout_seconds) {
+        socket.send_to(&packet, (host, port)).await?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_packets() {
+        let colors = vec![[1, 2, 3]; 500];
+        let packets = packets(&colors, 2);
+
+        assert_eq!(packets.len(), 2);
+        assert_eq!(packets[0][..7], [4, 2, 0, 0, 1, 2, 3]);
+        assert_eq!(packets[0].len(), 4 + 489 * 3);
+        assert_eq!(packets[1][..4], [4, 2, 1, 233]);
+        assert_eq!(packets[1].len(), 4 + 11 * 3);
+    }
+}
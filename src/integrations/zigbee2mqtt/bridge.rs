@@ -0,0 +1,379 @@
+//! Keeps track of the devices and groups announced on the `bridge/devices` and
+//! `bridge/groups` topics of Zigbee2MQTT.
+
+use std::collections::{HashMap, HashSet};
+
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::types::device::DeviceId;
+
+use super::exposes::{Controllable, Expose, Sensor};
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct BridgeDefinition {
+    #[serde(default)]
+    pub exposes: Vec<Expose>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct BridgeDevice {
+    pub ieee_address: String,
+    pub friendly_name: String,
+    #[serde(rename = "type")]
+    pub kind: String,
+    #[serde(default = "default_true")]
+    pub supported: bool,
+    #[serde(default)]
+    pub disabled: bool,
+    pub definition: Option<BridgeDefinition>,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct BridgeGroupMember {
+    pub ieee_address: String,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct BridgeGroup {
+    pub id: u64,
+    pub friendly_name: String,
+    #[serde(default)]
+    pub members: Vec<BridgeGroupMember>,
+}
+
+/// Devices backed by one Zigbee2MQTT device or group, all of which share the
+/// same state topic
+#[derive(Clone, Debug, Default)]
+pub struct Entry {
+    pub friendly_name: String,
+    pub controllables: Vec<Controllable>,
+    pub sensors: Vec<Sensor>,
+}
+
+impl Entry {
+    fn from_device(device: &BridgeDevice) -> Entry {
+        let id = &device.ieee_address;
+        let name = &device.friendly_name;
+        let exposes = device
+            .definition
+            .as_ref()
+            .map(|definition| definition.exposes.as_slice())
+            .unwrap_or_default();
+
+        let controllables = exposes
+            .iter()
+            .filter(|expose| matches!(expose.kind.as_str(), "light" | "switch"))
+            .filter_map(|expose| Controllable::from_expose(expose, id, name))
+            .collect();
+
+        let has_property = |property: &str| {
+            exposes
+                .iter()
+                .any(|e| e.property.as_deref() == Some(property))
+        };
+
+        let sensors = exposes
+            .iter()
+            // Raw illuminance values are not in lux, see Sensor::from_expose
+            .filter(|expose| {
+                expose.property.as_deref() != Some("illuminance")
+                    || !has_property("illuminance_lux")
+            })
+            .filter_map(|expose| Sensor::from_expose(expose, id, name))
+            .collect();
+
+        Entry {
+            friendly_name: name.clone(),
+            controllables,
+            sensors,
+        }
+    }
+
+    fn from_group(group: &BridgeGroup, devices: &HashMap<String, Entry>) -> Option<Entry> {
+        let members: Vec<&Controllable> = group
+            .members
+            .iter()
+            .filter_map(|member| devices.get(&member.ieee_address)?.controllables.first())
+            .collect();
+
+        if members.is_empty() {
+            return None;
+        }
+
+        let id = DeviceId::new(&format!("group/{}", group.id));
+        let controllable = Controllable::merge(id, group.friendly_name.clone(), &members);
+
+        Some(Entry {
+            friendly_name: group.friendly_name.clone(),
+            controllables: vec![controllable],
+            sensors: vec![],
+        })
+    }
+}
+
+/// Parses availability payloads, which are either plain `online` / `offline`
+/// strings or JSON objects like `{"state": "online"}`
+pub fn parse_availability(payload: &[u8]) -> Option<bool> {
+    let value = serde_json::from_slice::<Value>(payload).ok();
+    let state = match &value {
+        Some(Value::Object(object)) => object.get("state")?.as_str()?,
+        Some(Value::String(state)) => state.as_str(),
+        _ => std::str::from_utf8(payload).ok()?.trim(),
+    };
+
+    match state {
+        "online" => Some(true),
+        "offline" => Some(false),
+        _ => None,
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Bridge {
+    /// Entries of devices by IEEE address
+    devices: HashMap<String, Entry>,
+    groups: Vec<BridgeGroup>,
+
+    /// Entries of both devices and groups by friendly name
+    entries: HashMap<String, Entry>,
+
+    /// Friendly names of devices that Zigbee2MQTT reports as offline
+    offline: HashSet<String>,
+
+    /// Whether Zigbee2MQTT itself is running, as reported on `bridge/state`
+    pub online: Option<bool>,
+}
+
+impl Bridge {
+    pub fn set_devices(&mut self, devices: Vec<BridgeDevice>) {
+        self.devices = devices
+            .iter()
+            .filter(|device| device.kind != "Coordinator" && device.supported && !device.disabled)
+            .map(|device| (device.ieee_address.clone(), Entry::from_device(device)))
+            .collect();
+
+        self.update_entries();
+    }
+
+    pub fn set_groups(&mut self, groups: Vec<BridgeGroup>) {
+        self.groups = groups;
+        self.update_entries();
+    }
+
+    fn update_entries(&mut self) {
+        let groups = self
+            .groups
+            .iter()
+            .filter_map(|group| Entry::from_group(group, &self.devices));
+
+        self.entries = self
+            .devices
+            .values()
+            .cloned()
+            .chain(groups)
+            .map(|entry| (entry.friendly_name.clone(), entry))
+            .collect();
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = &Entry> {
+        self.entries.values()
+    }
+
+    pub fn entry(&self, friendly_name: &str) -> Option<&Entry> {
+        self.entries.get(friendly_name)
+    }
+
+    /// Finds the controllable device with the given id, along with the entry
+    /// it belongs to
+    pub fn controllable(&self, id: &DeviceId) -> Option<(&Entry, &Controllable)> {
+        self.entries.values().find_map(|entry| {
+            let controllable = entry.controllables.iter().find(|c| &c.id == id)?;
+            Some((entry, controllable))
+        })
+    }
+
+    pub fn set_available(&mut self, friendly_name: &str, available: bool) {
+        if available {
+            self.offline.remove(friendly_name);
+        } else {
+            self.offline.insert(friendly_name.to_string());
+        }
+    }
+
+    pub fn is_available(&self, friendly_name: &str) -> bool {
+        !self.offline.contains(friendly_name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use ordered_float::OrderedFloat;
+    use serde_json::json;
+
+    use crate::types::{
+        color::DeviceColor,
+        device::{DeviceData, ManageKind, SensorDevice},
+        integration::IntegrationId,
+    };
+
+    use super::*;
+
+    fn bridge() -> Bridge {
+        let devices = json!([
+            {
+                "ieee_address": "0x00124b0000000000",
+                "friendly_name": "Coordinator",
+                "type": "Coordinator"
+            },
+            {
+                "ieee_address": "0x0017880104b0c3ea",
+                "friendly_name": "Kitchen table",
+                "type": "Router",
+                "supported": true,
+                "definition": {
+                    "exposes": [
+                        {
+                            "type": "light",
+                            "features": [
+                                { "type": "binary", "name": "state", "property": "state", "value_on": "ON", "value_off": "OFF", "access": 7 },
+                                { "type": "numeric", "name": "brightness", "property": "brightness", "value_min": 0, "value_max": 254, "access": 7 },
+                                { "type": "numeric", "name": "color_temp", "property": "color_temp", "value_min": 153, "value_max": 454, "unit": "mired", "access": 7 },
+                                { "type": "composite", "name": "color_xy", "property": "color", "access": 7, "features": [] }
+                            ]
+                        },
+                        { "type": "numeric", "name": "linkquality", "property": "linkquality", "access": 1 }
+                    ]
+                }
+            },
+            {
+                "ieee_address": "0x00158d0001",
+                "friendly_name": "Hallway sensor",
+                "type": "EndDevice",
+                "supported": true,
+                "definition": {
+                    "exposes": [
+                        { "type": "binary", "name": "occupancy", "label": "Occupancy", "property": "occupancy", "value_on": true, "value_off": false, "access": 1 },
+                        { "type": "numeric", "name": "illuminance", "property": "illuminance", "access": 1 },
+                        { "type": "numeric", "name": "illuminance_lux", "property": "illuminance_lux", "unit": "lx", "access": 1 },
+                        { "type": "numeric", "name": "battery", "label": "Battery", "property": "battery", "unit": "%", "access": 1 },
+                        { "type": "numeric", "name": "occupancy_timeout", "property": "occupancy_timeout", "unit": "s", "access": 3 }
+                    ]
+                }
+            }
+        ]);
+
+        let groups = json!([
+            { "id": 1, "friendly_name": "Kitchen", "members": [{ "ieee_address": "0x0017880104b0c3ea", "endpoint": 11 }] }
+        ]);
+
+        let mut bridge = Bridge::default();
+        bridge.set_devices(serde_json::from_value(devices).unwrap());
+        bridge.set_groups(serde_json::from_value(groups).unwrap());
+        bridge
+    }
+
+    #[test]
+    fn test_light() {
+        let bridge = bridge();
+        let entry = bridge.entry("Kitchen table").unwrap();
+        assert!(entry.sensors.is_empty());
+
+        let light = &entry.controllables[0];
+        assert_eq!(light.capabilities().ct, Some(2203..6536));
+
+        let integration_id = IntegrationId::from_str("zigbee2mqtt").unwrap();
+        let state = json!({
+            "state": "ON",
+            "brightness": 127,
+            "color_mode": "xy",
+            "color": { "x": 0.46, "y": 0.41 },
+            "color_temp": 370
+        });
+        let device = light
+            .to_homectl(&state, &integration_id, &ManageKind::Full)
+            .unwrap();
+
+        let DeviceData::Controllable(data) = &device.data else {
+            panic!("Expected a controllable device");
+        };
+        assert_eq!(data.state.brightness, Some(OrderedFloat(0.5)));
+        assert_eq!(data.state.color, Some(DeviceColor::new_from_xy(0.46, 0.41)));
+
+        let mut state = data.state.clone();
+        state.color = Some(DeviceColor::new_from_ct(2000));
+        let command = light
+            .to_command(&device.set_controllable_state(state), None)
+            .unwrap();
+
+        // Color temperature is clamped to what the light supports
+        assert_eq!(
+            command,
+            json!({ "state": "ON", "brightness": 127.0, "color_temp": 454.0 })
+        );
+    }
+
+    #[test]
+    fn test_sensors() {
+        let bridge = bridge();
+        let entry = bridge.entry("Hallway sensor").unwrap();
+
+        let names: Vec<&str> = entry.sensors.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(
+            names,
+            [
+                "Hallway sensor Occupancy",
+                "Hallway sensor Illuminance",
+                "Hallway sensor Battery"
+            ]
+        );
+
+        let integration_id = IntegrationId::from_str("zigbee2mqtt").unwrap();
+        let state = json!({ "occupancy": true, "illuminance": 17500, "illuminance_lux": 56 });
+        let devices: Vec<_> = entry
+            .sensors
+            .iter()
+            .filter_map(|sensor| sensor.to_homectl(&state, &integration_id))
+            .map(|device| device.data)
+            .collect();
+
+        assert_eq!(
+            devices,
+            [
+                DeviceData::Sensor(SensorDevice::Boolean {
+                    value: true,
+                    meta: None
+                }),
+                DeviceData::Sensor(SensorDevice::Number {
+                    value: 56.0,
+                    meta: None
+                }),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_group() {
+        let bridge = bridge();
+        let entry = bridge.entry("Kitchen").unwrap();
+        let group = &entry.controllables[0];
+
+        assert_eq!(group.id, DeviceId::new("group/1"));
+        assert_eq!(group.capabilities().ct, Some(2203..6536));
+        assert!(group.capabilities().xy);
+        assert!(bridge.entry("Coordinator").is_none());
+    }
+
+    #[test]
+    fn test_parse_availability() {
+        assert_eq!(parse_availability(b"online"), Some(true));
+        assert_eq!(parse_availability(br#"{"state":"offline"}"#), Some(false));
+        assert_eq!(parse_availability(b"unknown"), None);
+    }
+}
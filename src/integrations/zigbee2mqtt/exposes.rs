@@ -0,0 +1,453 @@
+//! Maps the [exposes](https://www.zigbee2mqtt.io/guide/usage/exposes.html) of
+//! Zigbee2MQTT devices to homectl devices, and converts state messages in both
+//! directions.
+
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+
+use crate::types::{
+    color::{Capabilities, DeviceColor},
+    device::{ControllableDevice, Device, DeviceData, DeviceId, ManageKind, SensorDevice},
+    integration::IntegrationId,
+};
+
+/// Property is published in state messages
+const ACCESS_STATE: u8 = 1;
+
+/// Property can be set with a `/set` message
+const ACCESS_SET: u8 = 2;
+
+const DEFAULT_MAX_BRIGHTNESS: f64 = 254.0;
+const DEFAULT_MIN_MIREDS: f64 = 150.0;
+const DEFAULT_MAX_MIREDS: f64 = 500.0;
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Expose {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub name: Option<String>,
+    pub label: Option<String>,
+    pub property: Option<String>,
+    pub endpoint: Option<String>,
+    #[serde(default)]
+    pub access: u8,
+    pub value_on: Option<Value>,
+    pub value_off: Option<Value>,
+    pub value_min: Option<f64>,
+    pub value_max: Option<f64>,
+    pub unit: Option<String>,
+    #[serde(default)]
+    pub features: Vec<Expose>,
+}
+
+impl Expose {
+    fn feature(&self, name: &str) -> Option<&Expose> {
+        self.features
+            .iter()
+            .find(|feature| feature.name.as_deref() == Some(name))
+    }
+}
+
+fn mireds_to_kelvin(mireds: f64) -> u16 {
+    (1_000_000.0 / mireds).round() as u16
+}
+
+fn kelvin_to_mireds(kelvin: u64) -> f64 {
+    (1_000_000.0 / kelvin as f64).round()
+}
+
+/// Zigbee2MQTT uses both JSON values and strings for binary properties
+fn value_matches(value: &Value, expected: &Value) -> bool {
+    match (value, expected) {
+        (Value::String(a), Value::String(b)) => a.eq_ignore_ascii_case(b),
+        (a, b) => a == b,
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct BinaryFeature {
+    pub property: String,
+    pub value_on: Value,
+    pub value_off: Value,
+}
+
+impl Default for BinaryFeature {
+    fn default() -> Self {
+        BinaryFeature {
+            property: "state".to_string(),
+            value_on: json!("ON"),
+            value_off: json!("OFF"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct NumericFeature {
+    pub property: String,
+    pub min: f64,
+    pub max: f64,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ColorFeature {
+    pub property: String,
+    pub xy: bool,
+    pub hs: bool,
+}
+
+/// A light or switch, or one endpoint of a device with several of them
+#[derive(Clone, Debug, PartialEq)]
+pub struct Controllable {
+    pub id: DeviceId,
+    pub name: String,
+    pub state: BinaryFeature,
+
+    /// Brightness range, usually 0 - 254
+    pub brightness: Option<NumericFeature>,
+
+    /// Color temperature range in mireds
+    pub color_temp: Option<NumericFeature>,
+
+    pub color: Option<ColorFeature>,
+}
+
+impl Controllable {
+    /// Builds a controllable device out of a `light` or `switch` expose
+    pub fn from_expose(expose: &Expose, id: &str, name: &str) -> Option<Controllable> {
+        let state = expose.feature("state")?;
+        let state = BinaryFeature {
+            property: state.property.clone()?,
+            value_on: state.value_on.clone().unwrap_or(json!("ON")),
+            value_off: state.value_off.clone().unwrap_or(json!("OFF")),
+        };
+
+        let brightness = expose.feature("brightness").and_then(|feature| {
+            Some(NumericFeature {
+                property: feature.property.clone()?,
+                min: feature.value_min.unwrap_or(0.0),
+                max: feature.value_max.unwrap_or(DEFAULT_MAX_BRIGHTNESS),
+            })
+        });
+
+        let color_temp = expose.feature("color_temp").and_then(|feature| {
+            Some(NumericFeature {
+                property: feature.property.clone()?,
+                min: feature.value_min.unwrap_or(DEFAULT_MIN_MIREDS),
+                max: feature.value_max.unwrap_or(DEFAULT_MAX_MIREDS),
+            })
+        });
+
+        let xy = expose.feature("color_xy");
+        let hs = expose.feature("color_hs");
+        let color = xy.or(hs).and_then(|feature| {
+            Some(ColorFeature {
+                property: feature.property.clone()?,
+                xy: xy.is_some(),
+                hs: hs.is_some(),
+            })
+        });
+
+        let (id, name) = match &expose.endpoint {
+            Some(endpoint) => (format!("{id}/{endpoint}"), format!("{name} {endpoint}")),
+            None => (id.to_string(), name.to_string()),
+        };
+
+        Some(Controllable {
+            id: DeviceId::new(&id),
+            name,
+            state,
+            brightness,
+            color_temp,
+            color,
+        })
+    }
+
+    /// Combines the features of group members, so that the group supports
+    /// anything that at least one of its members does
+    pub fn merge(id: DeviceId, name: String, members: &[&Controllable]) -> Controllable {
+        let brightness = members
+            .iter()
+            .any(|member| member.brightness.is_some())
+            .then(|| NumericFeature {
+                property: "brightness".to_string(),
+                min: 0.0,
+                max: DEFAULT_MAX_BRIGHTNESS,
+            });
+
+        let color_temp = members
+            .iter()
+            .filter_map(|member| member.color_temp.as_ref())
+            .fold(None, |range: Option<NumericFeature>, feature| {
+                Some(NumericFeature {
+                    property: "color_temp".to_string(),
+                    min: range
+                        .as_ref()
+                        .map_or(feature.min, |r| r.min.min(feature.min)),
+                    max: range
+                        .as_ref()
+                        .map_or(feature.max, |r| r.max.max(feature.max)),
+                })
+            });
+
+        let colors = members.iter().filter_map(|member| member.color.as_ref());
+        let xy = colors.clone().any(|color| color.xy);
+        let hs = colors.clone().any(|color| color.hs);
+        let color = (xy || hs).then(|| ColorFeature {
+            property: "color".to_string(),
+            xy,
+            hs,
+        });
+
+        Controllable {
+            id,
+            name,
+            state: BinaryFeature::default(),
+            brightness,
+            color_temp,
+            color,
+        }
+    }
+
+    pub fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            xy: self.color.as_ref().is_some_and(|color| color.xy),
+            hs: self.color.as_ref().is_some_and(|color| color.hs),
+            rgb: false,
+            ct: self
+                .color_temp
+                .as_ref()
+                .map(|range| mireds_to_kelvin(range.max)..mireds_to_kelvin(range.min)),
+        }
+    }
+
+    fn read_color(&self, state: &Value) -> Option<DeviceColor> {
+        let color = self
+            .color
+            .as_ref()
+            .and_then(|feature| state.get(&feature.property));
+
+        let color_temp = || {
+            let feature = self.color_temp.as_ref()?;
+            let mireds = state.get(&feature.property)?.as_f64()?;
+            Some(DeviceColor::new_from_ct(mireds_to_kelvin(mireds)))
+        };
+        let xy = || {
+            let color = color?;
+            let x = color.get("x")?.as_f64()?;
+            let y = color.get("y")?.as_f64()?;
+            Some(DeviceColor::new_from_xy(x as f32, y as f32))
+        };
+        // Saturation is reported in the range 0 - 100
+        let hs = || {
+            let color = color?;
+            let h = color.get("hue").or(color.get("h"))?.as_f64()?;
+            let s = color.get("saturation").or(color.get("s"))?.as_f64()?;
+            Some(DeviceColor::new_from_hs(h.round() as u16, s as f32 / 100.0))
+        };
+
+        match state.get("color_mode").and_then(Value::as_str) {
+            Some("color_temp") => color_temp(),
+            Some("hs") => hs().or_else(xy),
+            Some("xy") => xy(),
+            _ => xy().or_else(hs).or_else(color_temp),
+        }
+    }
+
+    /// Reads the device state from a Zigbee2MQTT state message, returns None
+    /// if the message doesn't contain the device's state.
+    pub fn to_homectl(
+        &self,
+        state: &Value,
+        integration_id: &IntegrationId,
+        managed: &ManageKind,
+    ) -> Option<Device> {
+        let power = value_matches(state.get(&self.state.property)?, &self.state.value_on);
+
+        let brightness = self.brightness.as_ref().and_then(|feature| {
+            let value = state.get(&feature.property)?.as_f64()?;
+            let brightness = (value - feature.min) / (feature.max - feature.min);
+            Some(brightness.clamp(0.0, 1.0) as f32)
+        });
+
+        let device = ControllableDevice::new(
+            None,
+            power,
+            brightness,
+            self.read_color(state),
+            None,
+            self.capabilities(),
+            managed.clone(),
+        );
+
+        Some(Device::new(
+            integration_id.clone(),
+            self.id.clone(),
+            self.name.clone(),
+            DeviceData::Controllable(device),
+            Some(state.clone()),
+        ))
+    }
+
+    /// Builds the payload of a `/set` message for the device
+    pub fn to_command(&self, device: &Device, default_transition: Option<f32>) -> Option<Value> {
+        let state = device.get_controllable_state()?;
+        let mut command = Map::new();
+
+        let power = if state.power {
+            &self.state.value_on
+        } else {
+            &self.state.value_off
+        };
+        command.insert(self.state.property.clone(), power.clone());
+
+        let transition = state.transition.map(|t| *t).or(default_transition);
+        if let Some(transition) = transition {
+            command.insert("transition".to_string(), json!(transition));
+        }
+
+        // Setting brightness or color would turn the light back on
+        if !state.power {
+            return Some(Value::Object(command));
+        }
+
+        if let (Some(feature), Some(brightness)) = (&self.brightness, state.brightness) {
+            let value = *brightness as f64 * (feature.max - feature.min) + feature.min;
+            command.insert(feature.property.clone(), json!(value.round()));
+        }
+
+        match (&state.color, &self.color, &self.color_temp) {
+            (Some(DeviceColor::Ct(ct)), _, Some(feature)) => {
+                let mireds = kelvin_to_mireds(ct.ct).clamp(feature.min, feature.max);
+                command.insert(feature.property.clone(), json!(mireds));
+            }
+            (Some(DeviceColor::Xy(xy)), Some(feature), _) => {
+                command.insert(feature.property.clone(), json!({ "x": xy.x, "y": xy.y }));
+            }
+            (Some(DeviceColor::Hs(hs)), Some(feature), _) => {
+                command.insert(
+                    feature.property.clone(),
+                    json!({ "hue": hs.h, "saturation": *hs.s * 100.0 }),
+                );
+            }
+            (Some(color), _, _) => {
+                debug!("{} does not support color {color:?}", self.name);
+            }
+            (None, _, _) => {}
+        }
+
+        Some(Value::Object(command))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum SensorKind {
+    /// Reports true when the sensor is triggered, e.g. when motion is
+    /// detected or a door is opened
+    Binary {
+        value_on: Value,
+        value_off: Value,
+    },
+    Numeric {
+        unit: Option<String>,
+    },
+    Enum,
+}
+
+/// A read-only property of a device, e.g. occupancy or battery level
+#[derive(Clone, Debug, PartialEq)]
+pub struct Sensor {
+    pub id: DeviceId,
+    pub name: String,
+    pub property: String,
+    pub kind: SensorKind,
+}
+
+impl Sensor {
+    /// Builds a sensor out of a read-only `binary`, `numeric` or `enum`
+    /// expose. Settable properties are device settings rather than sensors,
+    /// and are skipped.
+    pub fn from_expose(expose: &Expose, id: &str, name: &str) -> Option<Sensor> {
+        if expose.access & ACCESS_STATE == 0 || expose.access & ACCESS_SET != 0 {
+            return None;
+        }
+
+        let property = expose.property.clone()?;
+        if property == "linkquality" {
+            return None;
+        }
+
+        let kind = match expose.kind.as_str() {
+            "binary" => SensorKind::Binary {
+                value_on: expose.value_on.clone()?,
+                value_off: expose.value_off.clone()?,
+            },
+            "numeric" => SensorKind::Numeric {
+                unit: expose.unit.clone(),
+            },
+            "enum" => SensorKind::Enum,
+            _ => return None,
+        };
+
+        // Prefer the calibrated illuminance_lux over raw illuminance values
+        // reported by older devices
+        let label = match property.as_str() {
+            "illuminance_lux" => "Illuminance".to_string(),
+            _ => expose.label.clone().unwrap_or_else(|| property.clone()),
+        };
+
+        Some(Sensor {
+            id: DeviceId::new(&format!("{id}/{property}")),
+            name: format!("{name} {label}"),
+            property,
+            kind,
+        })
+    }
+
+    /// Reads the sensor value from a Zigbee2MQTT state message, returns None
+    /// if the message doesn't contain the property.
+    pub fn to_homectl(&self, state: &Value, integration_id: &IntegrationId) -> Option<Device> {
+        let value = state.get(&self.property).filter(|value| !value.is_null())?;
+
+        let sensor = match &self.kind {
+            SensorKind::Binary {
+                value_on,
+                value_off,
+            } => {
+                if value_matches(value, value_on) {
+                    SensorDevice::Boolean {
+                        value: true,
+                        meta: None,
+                    }
+                } else if value_matches(value, value_off) {
+                    SensorDevice::Boolean {
+                        value: false,
+                        meta: None,
+                    }
+                } else {
+                    return None;
+                }
+            }
+            SensorKind::Numeric { .. } => SensorDevice::Number {
+                value: value.as_f64()?,
+                meta: None,
+            },
+            SensorKind::Enum => SensorDevice::Text {
+                value: value.as_str()?.to_string(),
+                meta: None,
+            },
+        };
+
+        let raw = match &self.kind {
+            SensorKind::Numeric { unit: Some(unit) } => json!({ "value": value, "unit": unit }),
+            _ => json!({ "value": value }),
+        };
+
+        Some(Device::new(
+            integration_id.clone(),
+            self.id.clone(),
+            self.name.clone(),
+            DeviceData::Sensor(sensor),
+            Some(raw),
+        ))
+    }
+}
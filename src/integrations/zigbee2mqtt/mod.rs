@@ -0,0 +1,384 @@
+mod bridge;
+mod exposes;
+
+use crate::{
+    integrations::mqtt::client::{self, MqttClient, MqttConnectionConfig, Notification},
+    types::{
+        device::{Device, ManageKind},
+        event::{Event, TxEventChannel},
+        integration::{Integration, IntegrationActionPayload, IntegrationId},
+    },
+    utils::cli::Cli,
+};
+use async_trait::async_trait;
+use color_eyre::Result;
+use eyre::Context;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::RwLock;
+use tokio::task::{self, JoinHandle};
+
+use self::bridge::{parse_availability, Bridge};
+
+const DEFAULT_BASE_TOPIC: &str = "zigbee2mqtt";
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct Zigbee2MqttConfig {
+    /// Broker connection settings, same as for the `mqtt` integration
+    #[serde(flatten)]
+    connection: MqttConnectionConfig,
+
+    /// Base topic of Zigbee2MQTT, defaults to "zigbee2mqtt"
+    base_topic: Option<String>,
+
+    /// Whether homectl should keep track of the devices' expected states,
+    /// defaults to "Full"
+    managed: Option<ManageKind>,
+
+    /// Transition time in seconds used when none is given
+    default_transition: Option<f32>,
+}
+
+impl Zigbee2MqttConfig {
+    fn base_topic(&self) -> &str {
+        self.base_topic.as_deref().unwrap_or(DEFAULT_BASE_TOPIC)
+    }
+}
+
+pub struct Zigbee2Mqtt {
+    id: IntegrationId,
+    event_tx: TxEventChannel,
+    config: Zigbee2MqttConfig,
+    cli: Cli,
+    client: Option<Arc<MqttClient>>,
+    event_loop: Option<JoinHandle<()>>,
+    connected: Arc<AtomicBool>,
+    bridge: Arc<RwLock<Bridge>>,
+}
+
+/// Publishes an arbitrary payload to the `/set` topic of a device, e.g. for
+/// triggering effects
+#[derive(Debug, Deserialize, Clone)]
+pub struct CustomZigbee2MqttAction {
+    friendly_name: String,
+    payload: Value,
+}
+
+/// Sends the devices of an entry that are included in a state message
+fn send_state(
+    id: &IntegrationId,
+    config: &Zigbee2MqttConfig,
+    entry: &bridge::Entry,
+    state: &Value,
+    event_tx: &TxEventChannel,
+) {
+    let managed = config.managed.clone().unwrap_or_default();
+
+    let controllables = entry
+        .controllables
+        .iter()
+        .filter_map(|controllable| controllable.to_homectl(state, id, &managed));
+    let sensors = entry
+        .sensors
+        .iter()
+        .filter_map(|sensor| sensor.to_homectl(state, id));
+
+    for device in controllables.chain(sensors) {
+        event_tx.send(Event::ExternalStateUpdate { device });
+    }
+}
+
+/// Requests for Zigbee2MQTT to publish the current state of all lights and
+/// switches, so that we don't need to wait for them to report changes
+fn state_requests(base_topic: &str, bridge: &Bridge) -> Result<Vec<(String, Vec<u8>)>> {
+    let mut requests = vec![];
+
+    for entry in bridge.entries() {
+        let Some(controllable) = entry.controllables.first() else {
+            continue;
+        };
+
+        let topic = format!("{base_topic}/{}/get", entry.friendly_name);
+        let payload = json!({ &controllable.state.property: "" });
+        requests.push((topic, serde_json::to_vec(&payload)?));
+    }
+
+    Ok(requests)
+}
+
+/// Publishes the messages on a separate task. The client's request queue is
+/// only drained while the event loop is polled, so the event loop task must
+/// not wait for it.
+fn spawn_publish(client: &Arc<MqttClient>, messages: Vec<(String, Vec<u8>)>, target: &str) {
+    let client = client.clone();
+    let target = target.to_string();
+
+    task::spawn(async move {
+        for (topic, payload) in messages {
+            if let Err(e) = client.publish(topic.clone(), false, payload).await {
+                error!(target: &target, "Failed to publish to {topic}: {e:?}");
+            }
+        }
+    });
+}
+
+/// Handles notifications on the integration's MQTT event loop task. Client
+/// requests must not be awaited here, see [spawn_publish].
+struct Handler {
+    id: IntegrationId,
+    event_tx: TxEventChannel,
+    config: Zigbee2MqttConfig,
+    client: Arc<MqttClient>,
+    connected: Arc<AtomicBool>,
+    bridge: Arc<RwLock<Bridge>>,
+}
+
+impl Handler {
+    fn log_target(&self) -> String {
+        format!("homectl_server::integrations::zigbee2mqtt::{}", self.id)
+    }
+
+    async fn handle_notification(&self, notification: Notification) -> Result<()> {
+        match notification {
+            Notification::Connected => {
+                self.connected.store(true, Ordering::Relaxed);
+
+                let client = self.client.clone();
+                let topic = format!("{}/#", self.config.base_topic());
+                let target = self.log_target();
+                task::spawn(async move {
+                    if let Err(e) = client.subscribe(topic).await {
+                        error!(target: &target, "Failed to subscribe: {e:?}");
+                    }
+                });
+            }
+            Notification::Message { topic, payload } => {
+                let Some(topic) = topic
+                    .strip_prefix(self.config.base_topic())
+                    .and_then(|topic| topic.strip_prefix('/'))
+                else {
+                    return Ok(());
+                };
+
+                self.handle_message(topic, &payload).await?;
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    /// Handles a message on a topic below the base topic
+    async fn handle_message(&self, topic: &str, payload: &[u8]) -> Result<()> {
+        let base_topic = self.config.base_topic();
+
+        match topic {
+            "bridge/devices" => {
+                let devices =
+                    serde_json::from_slice(payload).wrap_err("Invalid bridge/devices message")?;
+                let requests = {
+                    let mut bridge = self.bridge.write().await;
+                    bridge.set_devices(devices);
+                    state_requests(base_topic, &bridge)?
+                };
+                spawn_publish(&self.client, requests, &self.log_target());
+            }
+            "bridge/groups" => {
+                let groups =
+                    serde_json::from_slice(payload).wrap_err("Invalid bridge/groups message")?;
+                let requests = {
+                    let mut bridge = self.bridge.write().await;
+                    bridge.set_groups(groups);
+                    state_requests(base_topic, &bridge)?
+                };
+                spawn_publish(&self.client, requests, &self.log_target());
+            }
+            "bridge/state" => {
+                self.bridge.write().await.online = parse_availability(payload);
+            }
+            topic if topic.starts_with("bridge/") => {}
+            topic if topic.ends_with("/set") || topic.ends_with("/get") => {}
+            topic => {
+                if let Some(name) = topic.strip_suffix("/availability") {
+                    let mut bridge = self.bridge.write().await;
+
+                    if let Some(available) = parse_availability(payload) {
+                        if bridge.is_available(name) != available {
+                            info!(
+                                target: &self.log_target(),
+                                "{name} is {}",
+                                if available { "online" } else { "offline" }
+                            );
+                        }
+                        bridge.set_available(name, available);
+                    }
+
+                    return Ok(());
+                }
+
+                let bridge = self.bridge.read().await;
+                if let Some(entry) = bridge.entry(topic) {
+                    let state: Value = serde_json::from_slice(payload)
+                        .wrap_err_with(|| format!("Invalid state message from {topic}"))?;
+                    send_state(&self.id, &self.config, entry, &state, &self.event_tx);
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Integration for Zigbee2Mqtt {
+    fn new(
+        id: &IntegrationId,
+        config: &config::Value,
+        cli: &Cli,
+        event_tx: TxEventChannel,
+    ) -> Result<Self> {
+        let config = config
+            .clone()
+            .try_deserialize()
+            .wrap_err("Failed to deserialize config of Zigbee2Mqtt integration")?;
+
+        Ok(Zigbee2Mqtt {
+            id: id.clone(),
+            config,
+            cli: cli.clone(),
+            event_tx,
+            client: None,
+            event_loop: None,
+            connected: Default::default(),
+            bridge: Default::default(),
+        })
+    }
+
+    async fn start(&mut self) -> Result<()> {
+        let (client, mut eventloop) = client::connect(&self.id, &self.config.connection)?;
+        let client = Arc::new(client);
+
+        self.client = Some(client.clone());
+
+        let handler = Handler {
+            id: self.id.clone(),
+            event_tx: self.event_tx.clone(),
+            config: self.config.clone(),
+            client,
+            connected: self.connected.clone(),
+            bridge: self.bridge.clone(),
+        };
+        let target = handler.log_target();
+
+        let event_loop = task::spawn(async move {
+            loop {
+                let notification = match eventloop.poll().await {
+                    Ok(Notification::Disconnected) => {
+                        debug!(target: &target, "Disconnected from MQTT broker");
+                        break;
+                    }
+                    Ok(notification) => notification,
+                    Err(e) => {
+                        handler.connected.store(false, Ordering::Relaxed);
+                        error!(target: &target, "MQTT error: {e:?}");
+                        tokio::time::sleep(Duration::from_secs(1)).await;
+                        continue;
+                    }
+                };
+
+                if let Err(e) = handler.handle_notification(notification).await {
+                    error!(target: &target, "{e:?}");
+                }
+            }
+        });
+
+        self.event_loop = Some(event_loop);
+
+        Ok(())
+    }
+
+    async fn set_integration_device_state(&mut self, device: &Device) -> Result<()> {
+        let client = self
+            .client
+            .as_ref()
+            .expect("Expected self.client to be set in start phase");
+
+        let bridge = self.bridge.read().await;
+        let Some((entry, controllable)) = bridge.controllable(&device.id) else {
+            warn!("Unknown Zigbee2MQTT device {device}, ignoring state update");
+            return Ok(());
+        };
+
+        if !bridge.is_available(&entry.friendly_name) {
+            debug!(
+                "{} is offline, not sending state {device}",
+                entry.friendly_name
+            );
+            return Ok(());
+        }
+
+        let Some(payload) = controllable.to_command(device, self.config.default_transition) else {
+            return Ok(());
+        };
+
+        let topic = format!("{}/{}/set", self.config.base_topic(), entry.friendly_name);
+
+        if !self.cli.dry_run {
+            client
+                .publish(topic, false, serde_json::to_vec(&payload)?)
+                .await?;
+        } else {
+            debug!("(dry run) would publish device state: {device}");
+        }
+
+        Ok(())
+    }
+
+    async fn run_integration_action(&mut self, payload: &IntegrationActionPayload) -> Result<()> {
+        let action: CustomZigbee2MqttAction = serde_json::from_str(&payload.to_string())?;
+
+        let client = self
+            .client
+            .as_ref()
+            .expect("Expected self.client to be set in start phase");
+
+        let topic = format!("{}/{}/set", self.config.base_topic(), action.friendly_name);
+        client
+            .publish(topic, false, serde_json::to_vec(&action.payload)?)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Disconnects from the broker once queued publishes have been sent
+    async fn stop(&mut self) -> Result<()> {
+        if let Some(client) = self.client.take() {
+            client.disconnect().await?;
+        }
+
+        Ok(())
+    }
+
+    async fn health_check(&mut self) -> Result<()> {
+        if self
+            .event_loop
+            .as_ref()
+            .is_some_and(|event_loop| event_loop.is_finished())
+        {
+            return Err(eyre!("MQTT event loop has stopped"));
+        }
+
+        if !self.connected.load(Ordering::Relaxed) {
+            return Err(eyre!("Not connected to MQTT broker"));
+        }
+
+        if self.bridge.read().await.online == Some(false) {
+            return Err(eyre!("Zigbee2MQTT reports that it is offline"));
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,21 @@
+//! Internals of homectl-server. These are exposed as a library so that the
+//! benchmarks under `benches/` can use them, the server itself is in main.rs.
+
+#[macro_use]
+extern crate macro_attr;
+
+#[macro_use]
+extern crate newtype_derive;
+
+#[macro_use]
+extern crate log;
+
+#[macro_use]
+extern crate eyre;
+
+pub mod api;
+pub mod core;
+pub mod db;
+pub mod integrations;
+pub mod types;
+pub mod utils;
@@ -1,38 +1,24 @@
-#[macro_use]
-extern crate macro_attr;
-
-#[macro_use]
-extern crate newtype_derive;
-
 #[macro_use]
 extern crate log;
 
-#[macro_use]
-extern crate eyre;
-
-mod api;
-mod core;
-mod db;
-mod integrations;
-mod types;
-mod utils;
-
-use crate::core::expr::Expr;
-use crate::core::{
-    devices::Devices, event::handle_event, groups::Groups, integrations::Integrations,
-    routines::Routines, scenes::Scenes, state::AppState,
-};
-use crate::types::event::{mk_event_channel, Event};
-use api::init_api;
 use clap::Parser;
 use color_eyre::Result;
-use core::ui::Ui;
-use db::init_db;
 use eyre::eyre;
+use homectl_server::api::init_api;
+use homectl_server::core::commands::run_command;
+use homectl_server::core::expr::Expr;
+use homectl_server::core::ui::Ui;
+use homectl_server::core::{
+    audit::AuditLog, devices::Devices, event::process_event, event_queue::DEFAULT_EVENT_QUEUE_SIZE,
+    groups::Groups, history::History, integrations::Integrations, mqtt_bridge::MqttBridge,
+    recording::EventRecorder, routines::Routines, scenes::Scenes, shutdown, state::AppState,
+};
+use homectl_server::db::init_db;
+use homectl_server::types::event::{mk_event_channel_with_capacity, Event};
+use homectl_server::utils::cli::Cli;
 use std::time::Duration;
 use std::{error::Error, sync::Arc};
 use tokio::sync::RwLock;
-use utils::cli::Cli;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
@@ -43,11 +29,33 @@ async fn main() -> Result<(), Box<dyn Error>> {
     // Attempt connecting to Postgres
     init_db().await;
 
-    let (config, opaque_integrations_configs) = core::config::read_config()?;
+    if let Some(command) = &cli.command {
+        run_command(command).await?;
+        return Ok(());
+    }
+
+    let (config, opaque_integrations_configs) = homectl_server::core::config::read_config()?;
 
     trace!("Using config:\n    {:#?}", config);
 
-    let (event_tx, mut event_rx) = mk_event_channel();
+    let shutdown_timeout = config
+        .core
+        .as_ref()
+        .and_then(|c| c.shutdown_timeout_seconds)
+        .map(Duration::from_secs)
+        .unwrap_or(shutdown::DEFAULT_SHUTDOWN_TIMEOUT);
+
+    let (event_tx, mut event_rx) = mk_event_channel_with_capacity(
+        config
+            .core
+            .as_ref()
+            .and_then(|c| c.event_queue_size)
+            .unwrap_or(DEFAULT_EVENT_QUEUE_SIZE),
+    );
+
+    if let Some(path) = &cli.record {
+        event_tx.record_to(EventRecorder::create(path)?);
+    }
 
     let mut integrations = Integrations::new(event_tx.clone());
     let groups = Groups::new(config.groups.unwrap_or_default());
@@ -59,24 +67,22 @@ async fn main() -> Result<(), Box<dyn Error>> {
     let rules = Routines::new(config.routines.unwrap_or_default(), event_tx.clone());
     let mut ui = Ui::new();
     ui.refresh_db_state().await;
+    let history = History::new(config.history.unwrap_or_default(), &cli);
+    history.start();
+    let audit = AuditLog::new(config.audit.unwrap_or_default(), &cli);
+    audit.start();
+    let mqtt_bridge = MqttBridge::start(config.mqtt_bridge, &cli, event_tx.clone())?;
 
     for (id, integration_config) in &config.integrations.unwrap_or_default() {
         let opaque_integration_config: &config::Value = opaque_integrations_configs
             .get(id)
             .ok_or_else(|| eyre!("Expected to find config for integration with id {id}"))?;
 
-        integrations
-            .load_integration(
-                &integration_config.plugin,
-                id,
-                opaque_integration_config,
-                &cli,
-            )
-            .await?;
+        integrations.load_integration(integration_config, id, opaque_integration_config, &cli);
     }
 
-    integrations.run_register_pass().await?;
-    integrations.run_start_pass().await?;
+    integrations.run_register_pass().await;
+    integrations.run_start_pass().await;
 
     let state = AppState {
         warming_up: true,
@@ -88,12 +94,15 @@ async fn main() -> Result<(), Box<dyn Error>> {
         event_tx,
         expr,
         ui,
+        history,
+        audit,
+        mqtt_bridge,
         ws: Default::default(),
     };
 
     let state = Arc::new(RwLock::new(state));
 
-    init_api(&state)?;
+    init_api(&state).await?;
 
     {
         let state = state.clone();
@@ -108,21 +117,25 @@ async fn main() -> Result<(), Box<dyn Error>> {
         });
     }
 
+    let signal = shutdown::wait_for_signal();
+    tokio::pin!(signal);
+
     loop {
-        let event = event_rx
-            .recv()
-            .await
-            .expect("Expected sender end of channel to never be dropped");
+        let event = tokio::select! {
+            event = event_rx.recv() => event,
+            signal = &mut signal => {
+                info!("Received {signal}, shutting down");
+                break;
+            }
+        };
 
         // trace!("Received event: {:.100}", format!("{event:?}"));
 
-        let mut state = state.write().await;
-        let result = handle_event(&mut state, &event).await;
-
-        if let Err(err) = result {
-            error!(
-                "Error while handling event:\n    Event:\n    {event:#?}\n\n    Err:\n    {err:#?}",
-            );
-        }
+        process_event(&state, event).await;
     }
+
+    shutdown::shutdown(&state, event_rx, shutdown_timeout).await?;
+    info!("Shutdown complete");
+
+    Ok(())
 }
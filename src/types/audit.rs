@@ -0,0 +1,25 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use ts_rs::TS;
+
+use super::event::EventOrigin;
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct AuditConfig {
+    /// How long to keep audit log entries around, defaults to 30 days.
+    pub retention_days: Option<u64>,
+}
+
+/// An entry in the append-only log of actions and internal state changes.
+#[derive(TS, Clone, Debug, Deserialize, Serialize)]
+#[ts(export)]
+pub struct AuditLogEntry {
+    #[ts(type = "string")]
+    pub recorded_at: DateTime<Utc>,
+
+    /// The event that was handled, serialized as JSON.
+    #[ts(type = "any")]
+    pub event: serde_json::Value,
+
+    pub origin: EventOrigin,
+}
@@ -0,0 +1,46 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use super::{
+    device::DevicesState,
+    scene::{SceneOverridesConfig, ScenesConfig},
+};
+
+/// Bump this whenever the shape of [Backup] changes in an incompatible way.
+pub const BACKUP_VERSION: u32 = 1;
+
+/// Archive of all runtime state that homectl stores in the database.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct Backup {
+    pub version: u32,
+    pub created_at: DateTime<Utc>,
+
+    /// Scenes created via the UI
+    pub scenes: ScenesConfig,
+    pub scene_overrides: SceneOverridesConfig,
+    pub ui_state: HashMap<String, serde_json::Value>,
+
+    /// Last known device states
+    pub devices: DevicesState,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum RestoreMode {
+    /// Keeps existing state, entries from the backup take precedence.
+    #[default]
+    Merge,
+
+    /// Removes all existing state before restoring the backup.
+    Replace,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum BackupFormat {
+    #[default]
+    Json,
+    Toml,
+}
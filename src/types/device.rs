@@ -1,3 +1,4 @@
+use chrono::{DateTime, Utc};
 use eyre::Result;
 use ordered_float::OrderedFloat;
 use std::{
@@ -210,26 +211,112 @@ impl ControllableDevice {
     }
 }
 
+/// What a sensor measures
+#[derive(TS, Clone, Copy, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[ts(export)]
+#[serde(rename_all = "snake_case")]
+pub enum SensorClass {
+    Temperature,
+    Humidity,
+    Illuminance,
+    Motion,
+    Contact,
+    Power,
+    Energy,
+    Battery,
+}
+
+impl SensorClass {
+    /// Whether values of the sensor are booleans rather than numbers
+    pub fn is_binary(self) -> bool {
+        matches!(self, SensorClass::Motion | SensorClass::Contact)
+    }
+
+    /// Unit used unless one is configured
+    pub fn default_unit(self) -> Option<&'static str> {
+        match self {
+            SensorClass::Temperature => Some("°C"),
+            SensorClass::Humidity | SensorClass::Battery => Some("%"),
+            SensorClass::Illuminance => Some("lx"),
+            SensorClass::Power => Some("W"),
+            SensorClass::Energy => Some("kWh"),
+            SensorClass::Motion | SensorClass::Contact => None,
+        }
+    }
+}
+
+#[derive(TS, Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[ts(export)]
+pub struct SensorMeta {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub class: Option<SensorClass>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub unit: Option<String>,
+
+    /// When the sensor value last changed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[ts(type = "string | null")]
+    pub updated_at: Option<DateTime<Utc>>,
+}
+
 #[derive(TS, Clone, Debug, PartialEq, Deserialize, Serialize)]
 #[ts(export)]
 #[serde(untagged)]
 pub enum SensorDevice {
-    Boolean { value: bool },
-    Text { value: String },
-    Number { value: f64 },
+    Boolean {
+        value: bool,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        meta: Option<SensorMeta>,
+    },
+    Text {
+        value: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        meta: Option<SensorMeta>,
+    },
+    Number {
+        value: f64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        meta: Option<SensorMeta>,
+    },
     Color(ControllableState),
 }
 
+impl SensorDevice {
+    pub fn meta(&self) -> Option<&SensorMeta> {
+        match self {
+            SensorDevice::Boolean { meta, .. }
+            | SensorDevice::Text { meta, .. }
+            | SensorDevice::Number { meta, .. } => meta.as_ref(),
+            SensorDevice::Color(_) => None,
+        }
+    }
+
+    pub fn meta_mut(&mut self) -> Option<&mut SensorMeta> {
+        match self {
+            SensorDevice::Boolean { meta, .. }
+            | SensorDevice::Text { meta, .. }
+            | SensorDevice::Number { meta, .. } => meta.as_mut(),
+            SensorDevice::Color(_) => None,
+        }
+    }
+}
+
 impl Display for SensorDevice {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let s = match self {
-            SensorDevice::Boolean { value } => value.to_string(),
-            SensorDevice::Text { value } => value.to_string(),
-            SensorDevice::Number { value } => value.to_string(),
+            SensorDevice::Boolean { value, .. } => value.to_string(),
+            SensorDevice::Text { value, .. } => value.to_string(),
+            SensorDevice::Number { value, .. } => value.to_string(),
             SensorDevice::Color(state) => state.to_string(),
         };
 
-        f.write_str(&s)
+        let unit = self.meta().and_then(|meta| meta.unit.as_deref());
+
+        match unit {
+            Some(unit) => write!(f, "{s} {unit}"),
+            None => f.write_str(&s),
+        }
     }
 }
 
@@ -348,11 +435,19 @@ pub fn cmp_device_states(device: &ControllableDevice, expected: &ControllableSta
     true
 }
 
-/// Compares the state of two sensor devices.
-///
-/// If the states match, the function evaluates to true.
+/// Compares two sensor states, ignoring when their values were updated.
 fn cmp_sensor_states(sensor: &SensorDevice, previous: &SensorDevice) -> bool {
-    sensor == previous
+    let values_eq = match (sensor, previous) {
+        (SensorDevice::Boolean { value: a, .. }, SensorDevice::Boolean { value: b, .. }) => a == b,
+        (SensorDevice::Text { value: a, .. }, SensorDevice::Text { value: b, .. }) => a == b,
+        (SensorDevice::Number { value: a, .. }, SensorDevice::Number { value: b, .. }) => a == b,
+        (SensorDevice::Color(a), SensorDevice::Color(b)) => a == b,
+        _ => false,
+    };
+
+    let meta = |sensor: &SensorDevice| sensor.meta().map(|meta| (meta.class, meta.unit.clone()));
+
+    values_eq && meta(sensor) == meta(previous)
 }
 
 pub struct DeviceRow {
@@ -751,7 +846,10 @@ mod tests {
     #[test]
     fn test_sensor_device_serialization() {
         // Test Boolean variant
-        let boolean_sensor = SensorDevice::Boolean { value: true };
+        let boolean_sensor = SensorDevice::Boolean {
+            value: true,
+            meta: None,
+        };
         let serialized = serde_json::to_string(&boolean_sensor).unwrap();
         println!("Boolean sensor serialized: {}", serialized);
         assert_eq!(serialized, r#"{"value":true}"#);
@@ -759,13 +857,17 @@ mod tests {
         // Test Text variant
         let text_sensor = SensorDevice::Text {
             value: "test".to_string(),
+            meta: None,
         };
         let serialized = serde_json::to_string(&text_sensor).unwrap();
         println!("Text sensor serialized: {}", serialized);
         assert_eq!(serialized, r#"{"value":"test"}"#);
 
         // Test Number variant
-        let number_sensor = SensorDevice::Number { value: 42.5 };
+        let number_sensor = SensorDevice::Number {
+            value: 42.5,
+            meta: None,
+        };
         let serialized = serde_json::to_string(&number_sensor).unwrap();
         println!("Number sensor serialized: {}", serialized);
         assert_eq!(serialized, r#"{"value":42.5}"#);
@@ -790,7 +892,13 @@ mod tests {
         // Test Boolean variant
         let json = r#"{"value":true}"#;
         let deserialized: SensorDevice = serde_json::from_str(json).unwrap();
-        assert_eq!(deserialized, SensorDevice::Boolean { value: true });
+        assert_eq!(
+            deserialized,
+            SensorDevice::Boolean {
+                value: true,
+                meta: None
+            }
+        );
 
         // Test Text variant
         let json = r#"{"value":"test"}"#;
@@ -798,14 +906,21 @@ mod tests {
         assert_eq!(
             deserialized,
             SensorDevice::Text {
-                value: "test".to_string()
+                value: "test".to_string(),
+                meta: None
             }
         );
 
         // Test Number variant
         let json = r#"{"value":42.5}"#;
         let deserialized: SensorDevice = serde_json::from_str(json).unwrap();
-        assert_eq!(deserialized, SensorDevice::Number { value: 42.5 });
+        assert_eq!(
+            deserialized,
+            SensorDevice::Number {
+                value: 42.5,
+                meta: None
+            }
+        );
 
         // Test Color variant
         let json =
@@ -821,4 +936,29 @@ mod tests {
             })
         );
     }
+
+    #[test]
+    fn test_sensor_meta() {
+        let json = r#"{"value":21.5,"meta":{"class":"temperature","unit":"°C","updated_at":"2024-01-01T12:00:00Z"}}"#;
+        let sensor: SensorDevice = serde_json::from_str(json).unwrap();
+        assert_eq!(sensor.to_string(), "21.5 °C");
+        assert_eq!(
+            sensor.meta().and_then(|meta| meta.class),
+            Some(SensorClass::Temperature)
+        );
+
+        // Sensors are equal regardless of when their values were updated
+        let mut later = sensor.clone();
+        if let SensorDevice::Number {
+            meta: Some(meta), ..
+        } = &mut later
+        {
+            meta.updated_at = None;
+        }
+        assert!(cmp_sensor_states(&later, &sensor));
+
+        let json = r#"{"value":true,"meta":{"class":"motion"}}"#;
+        let sensor: SensorDevice = serde_json::from_str(json).unwrap();
+        assert!(matches!(sensor, SensorDevice::Boolean { value: true, .. }));
+    }
 }
@@ -1,10 +1,20 @@
 use serde::{Deserialize, Serialize};
-use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use std::sync::Arc;
 use ts_rs::TS;
 
 use super::scene::{SceneConfig, SceneId};
-
-use super::{action::Action, device::Device, device::DevicesState};
+use crate::core::{
+    event_queue::{EventQueue, DEFAULT_EVENT_QUEUE_SIZE},
+    recording::EventRecorder,
+};
+
+use super::{
+    action::Action,
+    device::{Device, DeviceId},
+    integration::IntegrationId,
+    metrics::EventQueueMetrics,
+    rule::RoutineId,
+};
 
 #[allow(clippy::large_enum_variant)]
 #[derive(TS, Clone, Debug, Deserialize, Serialize)]
@@ -17,12 +27,10 @@ pub enum Event {
 
     /// Internal device state update has taken place, need to take appropriate
     /// actions such as checking (and possibly triggering) routines.
-    InternalStateUpdate {
-        old_state: DevicesState,
-        new_state: DevicesState,
-        old: Option<Device>,
-        new: Device,
-    },
+    ///
+    /// Only the changed device is included, the rest of the state can be read
+    /// from [crate::core::devices::Devices].
+    InternalStateUpdate { old: Option<Device>, new: Device },
 
     /// Tell integration to trigger state change for a device.
     SetExternalState { device: Device },
@@ -57,24 +65,123 @@ pub enum Event {
     Action(Action),
 }
 
+/// Describes what caused an event to be sent.
+#[derive(TS, Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+#[serde(tag = "kind")]
+#[ts(export)]
+pub enum EventOrigin {
+    /// Sent by homectl core itself, e.g. as a result of scene recomputation.
+    #[default]
+    Internal,
+
+    /// Sent by an integration, e.g. when it reports device state.
+    Integration { integration_id: IntegrationId },
+
+    /// Sent by a cron schedule of a cron integration.
+    Cron {
+        integration_id: IntegrationId,
+        device_id: DeviceId,
+    },
+
+    /// Sent as a result of a routine being triggered.
+    Routine { routine_id: RoutineId },
+
+    /// Sent by an evaluated expression.
+    Expr { expr: String },
+
+    /// Sent by a connected WebSocket client.
+    WebSocket { user_id: usize },
+
+    /// Sent via the REST API.
+    Api { remote_addr: Option<String> },
+
+    /// Sent via a command topic of the MQTT bridge, see
+    /// [crate::core::mqtt_bridge].
+    Mqtt { topic: String },
+
+    /// Sent after homectl noticed that a device drifted from its expected state.
+    DriftCorrection,
+}
+
+/// An [Event] together with its [EventOrigin].
+#[derive(Clone, Debug)]
+pub struct OriginatedEvent {
+    pub event: Event,
+    pub origin: EventOrigin,
+}
+
 #[derive(Clone)]
-pub struct Sender<T> {
-    tx: UnboundedSender<T>,
+pub struct Sender {
+    queue: Arc<EventQueue>,
+    origin: EventOrigin,
+}
+
+impl Sender {
+    /// Sends an event, tagging it with the default origin of this sender.
+    pub fn send(&self, event: Event) {
+        self.send_with_origin(event, self.origin.clone());
+    }
+
+    pub fn send_with_origin(&self, event: Event, origin: EventOrigin) {
+        self.queue.push(OriginatedEvent { event, origin });
+    }
+
+    /// Returns a sender which tags all sent events with the given origin by
+    /// default.
+    pub fn with_origin(&self, origin: EventOrigin) -> Sender {
+        Sender {
+            queue: self.queue.clone(),
+            origin,
+        }
+    }
+
+    pub fn metrics(&self) -> EventQueueMetrics {
+        self.queue.metrics()
+    }
+
+    /// Records every event sent through this channel, see
+    /// [crate::core::recording].
+    pub fn record_to(&self, recorder: EventRecorder) {
+        self.queue.record_to(recorder);
+    }
 }
 
-impl<T: std::fmt::Debug> Sender<T> {
-    pub fn send(&self, event: T) {
-        self.tx.send(event).expect("Receiver end of channel closed");
+pub struct Receiver {
+    queue: Arc<EventQueue>,
+}
+
+impl Receiver {
+    /// Waits for the next event to handle.
+    pub async fn recv(&mut self) -> OriginatedEvent {
+        self.queue.pop().await
+    }
+
+    /// Returns the next event if one is immediately available.
+    pub fn try_recv(&mut self) -> Option<OriginatedEvent> {
+        self.queue.try_pop()
+    }
+}
+
+impl Drop for Receiver {
+    fn drop(&mut self) {
+        self.queue.close();
     }
 }
 
-pub type TxEventChannel = Sender<Event>;
-pub type RxEventChannel = UnboundedReceiver<Event>;
+pub type TxEventChannel = Sender;
+pub type RxEventChannel = Receiver;
 
 pub fn mk_event_channel() -> (TxEventChannel, RxEventChannel) {
-    let (tx, rx) = unbounded_channel::<Event>();
+    mk_event_channel_with_capacity(DEFAULT_EVENT_QUEUE_SIZE)
+}
+
+pub fn mk_event_channel_with_capacity(capacity: usize) -> (TxEventChannel, RxEventChannel) {
+    let queue = EventQueue::new(capacity);
 
-    let sender = Sender { tx };
+    let sender = Sender {
+        queue: queue.clone(),
+        origin: EventOrigin::Internal,
+    };
 
-    (sender, rx)
+    (sender, Receiver { queue })
 }
@@ -0,0 +1,122 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use ts_rs::TS;
+
+use super::{
+    device::{Device, DeviceData, DeviceRef},
+    integration::IntegrationId,
+};
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct HistoryConfig {
+    /// Record state changes of these devices.
+    pub devices: Option<Vec<DeviceRef>>,
+
+    /// Record state changes of every device belonging to these integrations.
+    pub integrations: Option<Vec<IntegrationId>>,
+
+    /// How long to keep history entries around, defaults to 30 days.
+    pub retention_days: Option<u64>,
+
+    /// Entries older than this are downsampled to `downsample_resolution_seconds`.
+    pub downsample_after_hours: Option<u64>,
+
+    /// Size of the time buckets that old entries are downsampled into, defaults
+    /// to 5 minutes.
+    pub downsample_resolution_seconds: Option<u64>,
+}
+
+impl HistoryConfig {
+    /// Returns true if state changes of the given device should be recorded.
+    pub fn is_tracked(&self, device: &Device) -> bool {
+        let integration_tracked = self
+            .integrations
+            .as_ref()
+            .map(|ids| ids.contains(&device.integration_id))
+            .unwrap_or_default();
+
+        let device_tracked = self
+            .devices
+            .as_ref()
+            .map(|refs| {
+                refs.iter().any(|device_ref| match device_ref {
+                    DeviceRef::Id(r) => {
+                        r.integration_id == device.integration_id && r.device_id == device.id
+                    }
+                    DeviceRef::Name(r) => {
+                        r.integration_id == device.integration_id && r.name == device.name
+                    }
+                })
+            })
+            .unwrap_or_default();
+
+        integration_tracked || device_tracked
+    }
+}
+
+/// A recorded device state at some point in time.
+#[derive(TS, Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[ts(export)]
+pub struct DeviceHistoryEntry {
+    #[ts(type = "string")]
+    pub recorded_at: DateTime<Utc>,
+    pub state: DeviceData,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::device::{DeviceId, SensorDevice};
+
+    fn mk_device(integration_id: &str, device_id: &str, name: &str) -> Device {
+        Device::new(
+            IntegrationId::from(integration_id.to_string()),
+            DeviceId::new(device_id),
+            name.to_string(),
+            DeviceData::Sensor(SensorDevice::Boolean {
+                value: true,
+                meta: None,
+            }),
+            None,
+        )
+    }
+
+    #[test]
+    fn test_nothing_tracked_by_default() {
+        let config = HistoryConfig::default();
+
+        assert!(!config.is_tracked(&mk_device("hue", "1", "Lamp")));
+    }
+
+    #[test]
+    fn test_tracked_integrations() {
+        let config = HistoryConfig {
+            integrations: Some(vec![IntegrationId::from("hue".to_string())]),
+            ..Default::default()
+        };
+
+        assert!(config.is_tracked(&mk_device("hue", "1", "Lamp")));
+        assert!(!config.is_tracked(&mk_device("mqtt", "1", "Lamp")));
+    }
+
+    #[test]
+    fn test_tracked_devices() {
+        let config = HistoryConfig {
+            devices: Some(vec![
+                DeviceRef::new_with_id(IntegrationId::from("hue".to_string()), DeviceId::new("1")),
+                DeviceRef::new_with_name(
+                    IntegrationId::from("mqtt".to_string()),
+                    "Motion".to_string(),
+                ),
+            ]),
+            ..Default::default()
+        };
+
+        assert!(config.is_tracked(&mk_device("hue", "1", "Lamp")));
+        assert!(!config.is_tracked(&mk_device("hue", "2", "Lamp")));
+        assert!(!config.is_tracked(&mk_device("mqtt", "1", "Lamp")));
+
+        assert!(config.is_tracked(&mk_device("mqtt", "sensor", "Motion")));
+        assert!(!config.is_tracked(&mk_device("hue", "sensor", "Motion")));
+    }
+}
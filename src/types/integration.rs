@@ -2,6 +2,7 @@ use crate::utils::cli::Cli;
 
 use super::{device::Device, event::TxEventChannel};
 use async_trait::async_trait;
+use chrono::{DateTime, Utc};
 use color_eyre::Result;
 use serde::{Deserialize, Serialize};
 use std::{collections::HashMap, convert::Infallible, str::FromStr};
@@ -21,9 +22,64 @@ impl FromStr for IntegrationId {
     }
 }
 
+/// Lifecycle state of a loaded integration
+#[derive(TS, Clone, Debug, PartialEq, Serialize)]
+#[ts(export)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum IntegrationStatus {
+    /// Integration is being created, registered or started
+    Loading,
+
+    /// Integration started successfully and passes its health checks
+    Running,
+
+    /// Integration is running but its latest health check failed
+    Degraded { reason: String },
+
+    /// Integration could not be started or stopped working, it will be
+    /// restarted after a backoff period
+    Failed { reason: String },
+}
+
+impl IntegrationStatus {
+    pub fn name(&self) -> &'static str {
+        match self {
+            IntegrationStatus::Loading => "loading",
+            IntegrationStatus::Running => "running",
+            IntegrationStatus::Degraded { .. } => "degraded",
+            IntegrationStatus::Failed { .. } => "failed",
+        }
+    }
+
+    pub fn reason(&self) -> Option<&str> {
+        match self {
+            IntegrationStatus::Degraded { reason } | IntegrationStatus::Failed { reason } => {
+                Some(reason)
+            }
+            _ => None,
+        }
+    }
+}
+
+#[derive(TS, Clone, Debug, Serialize)]
+#[ts(export)]
+pub struct IntegrationHealth {
+    pub status: IntegrationStatus,
+
+    /// When the integration entered its current status
+    pub since: DateTime<Utc>,
+
+    /// How many times the integration has been restarted
+    pub restarts: u32,
+}
+
 #[derive(Deserialize, Debug)]
 pub struct IntegrationConfig {
     pub plugin: String,
+
+    /// Optionally limit how many commands per second are sent to the
+    /// integration. Pending device state updates are coalesced meanwhile.
+    pub max_commands_per_second: Option<f64>,
     // NOTE: integration configs may contain other fields as well.
 
     // but since we don't know what fields those might be, they have to be
@@ -66,7 +122,39 @@ pub trait Integration: Send {
     async fn set_integration_device_state(&mut self, _device: &Device) -> Result<()> {
         Ok(())
     }
+
+    /// Return true if [Integration::set_integration_device_states] is
+    /// implemented to send multiple device states at once, e.g. as a single
+    /// group command.
+    fn supports_batch_updates(&self) -> bool {
+        false
+    }
+
+    /// Sets the state of multiple devices. Unless the integration supports
+    /// batch updates, this is only ever called with a single device.
+    async fn set_integration_device_states(&mut self, devices: &[Device]) -> Result<()> {
+        for device in devices {
+            self.set_integration_device_state(device).await?;
+        }
+
+        Ok(())
+    }
     async fn run_integration_action(&mut self, _payload: &IntegrationActionPayload) -> Result<()> {
         Ok(())
     }
+
+    /// Called once when homectl is shutting down, after all pending device
+    /// states and actions have been sent. Integrations should release any
+    /// resources here, e.g. disconnect from brokers.
+    async fn stop(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called periodically while the integration is running. Return an error
+    /// if the integration is not working properly, e.g. when a background task
+    /// has stopped or a connection was lost. Integrations that keep failing
+    /// health checks are restarted.
+    async fn health_check(&mut self) -> Result<()> {
+        Ok(())
+    }
 }
@@ -0,0 +1,26 @@
+use serde::{Deserialize, Serialize};
+use ts_rs::TS;
+
+/// Counters describing the state of the core event queue.
+#[derive(TS, Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[ts(export)]
+pub struct EventQueueMetrics {
+    /// Total number of events sent to the queue
+    pub received: u64,
+
+    /// Events that replaced a pending event for the same device
+    pub coalesced: u64,
+
+    /// Low priority events that were dropped because the queue was full
+    pub dropped: u64,
+
+    /// Normal and high priority events that were dropped because the queue was
+    /// full and had no low priority events left to drop
+    pub dropped_overflow: u64,
+
+    /// Number of events currently waiting to be handled
+    pub queued: usize,
+
+    /// Largest number of events that have been waiting at once
+    pub max_queued: usize,
+}
@@ -1,11 +1,17 @@
 pub mod action;
+pub mod audit;
+pub mod backup;
 pub mod color;
 pub mod device;
 pub mod dim;
 pub mod event;
 pub mod group;
+pub mod history;
 pub mod integration;
+pub mod metrics;
+pub mod mqtt_bridge;
 pub mod rule;
 pub mod scene;
+pub mod simulation;
 pub mod ui;
 pub mod websockets;
@@ -0,0 +1,28 @@
+use serde::Deserialize;
+
+use crate::integrations::mqtt::client::MqttConnectionConfig;
+
+/// Publishes homectl's own view of devices, groups and routines to an MQTT
+/// broker, see [crate::core::mqtt_bridge].
+///
+/// Topics may contain the placeholders `{integration}`, `{id}` and `{name}`
+/// (devices), `{group}` (groups) and `{routine}` (routines). Placeholders must
+/// make up a whole topic level, e.g. `homectl/{integration}/{name}`.
+#[derive(Clone, Debug, Deserialize)]
+pub struct MqttBridgeConfig {
+    /// Broker connection settings, same as for the `mqtt` integration
+    #[serde(flatten)]
+    pub connection: MqttConnectionConfig,
+
+    /// Defaults to `homectl/devices/{integration}/{name}`
+    pub device_topic: Option<String>,
+
+    /// Defaults to `homectl/groups/{group}`
+    pub group_topic: Option<String>,
+
+    /// Defaults to `homectl/routines/{routine}`
+    pub routine_topic: Option<String>,
+
+    /// Topic that accepts any action, defaults to `homectl/actions`
+    pub action_topic: Option<String>,
+}
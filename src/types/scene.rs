@@ -124,8 +124,59 @@ pub struct SceneConfig {
 
     /// Evaluates given expression to compute scene config.
     #[ts(skip)]
-    #[serde(skip_serializing)]
-    pub expr: Option<evalexpr::Node>,
+    pub expr: Option<SceneExpr>,
+}
+
+/// A parsed scene expression that keeps its source text, so that scenes can
+/// be stored and exported with the expression as it was written.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SceneExpr {
+    source: String,
+    node: evalexpr::Node,
+}
+
+impl SceneExpr {
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+}
+
+impl std::str::FromStr for SceneExpr {
+    type Err = evalexpr::EvalexprError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(SceneExpr {
+            source: s.to_string(),
+            node: evalexpr::build_operator_tree(s)?,
+        })
+    }
+}
+
+impl std::ops::Deref for SceneExpr {
+    type Target = evalexpr::Node;
+
+    fn deref(&self) -> &Self::Target {
+        &self.node
+    }
+}
+
+impl Serialize for SceneExpr {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.serialize_str(&self.source)
+    }
+}
+
+impl<'de> Deserialize<'de> for SceneExpr {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let source = String::deserialize(deserializer)?;
+        source.parse().map_err(serde::de::Error::custom)
+    }
 }
 
 pub type ScenesConfig = BTreeMap<SceneId, SceneConfig>;
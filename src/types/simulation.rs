@@ -0,0 +1,83 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use super::{
+    action::Action,
+    device::Device,
+    event::{Event, EventOrigin},
+    rule::RoutineId,
+    scene::SceneId,
+};
+
+/// Device state reports that are replayed by `homectl simulate`.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Timeline {
+    /// Virtual time at which the simulation starts, defaults to the time of
+    /// the first event
+    pub start: Option<DateTime<Utc>>,
+
+    /// Keep the simulation running until this time, defaults to the time of
+    /// the last event
+    pub end: Option<DateTime<Utc>>,
+
+    pub events: Vec<TimelineEvent>,
+}
+
+/// A device state reported by an integration at the given time.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct TimelineEvent {
+    pub at: DateTime<Utc>,
+    pub device: Device,
+}
+
+/// An event that entered the event queue at the given time, see
+/// [crate::core::recording].
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct RecordedEvent {
+    pub at: DateTime<Utc>,
+    pub origin: EventOrigin,
+    pub event: Event,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ReportFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+/// An action sent by a routine whose rules matched.
+#[derive(Clone, Debug, Serialize)]
+pub struct FiredRoutine {
+    pub at: DateTime<Utc>,
+    pub routine_id: RoutineId,
+    pub action: Action,
+}
+
+/// A device state that was sent to an integration.
+#[derive(Clone, Debug, Serialize)]
+pub struct SentCommand {
+    pub at: DateTime<Utc>,
+    pub device: Device,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ActivatedScene {
+    pub at: DateTime<Utc>,
+    pub scene_id: SceneId,
+    pub origin: EventOrigin,
+}
+
+/// Outcome of a simulation run.
+#[derive(Clone, Debug, Serialize)]
+pub struct SimulationReport {
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    pub routines: Vec<FiredRoutine>,
+    pub scenes: Vec<ActivatedScene>,
+    pub commands: Vec<SentCommand>,
+
+    /// Device states at the end of the simulation
+    pub devices: Vec<Device>,
+}
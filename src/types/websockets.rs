@@ -4,7 +4,8 @@ use serde::{Deserialize, Serialize};
 use ts_rs::TS;
 
 use super::{
-    device::DevicesState, event::Event, group::FlattenedGroupsConfig, scene::FlattenedScenesConfig,
+    audit::AuditLogEntry, device::DevicesState, event::Event, group::FlattenedGroupsConfig,
+    scene::FlattenedScenesConfig,
 };
 
 #[derive(TS, Deserialize, Serialize, Debug)]
@@ -26,4 +27,5 @@ pub struct StateUpdate {
 #[ts(export)]
 pub enum WebSocketResponse {
     State(StateUpdate),
+    AuditLogEntry(AuditLogEntry),
 }
@@ -1,8 +1,101 @@
-use clap::Parser;
+use std::path::PathBuf;
+
+use clap::{Parser, Subcommand};
+
+use crate::types::{
+    backup::{BackupFormat, RestoreMode},
+    scene::SceneId,
+};
+
+#[cfg(feature = "simulation")]
+use crate::types::simulation::ReportFormat;
 
 #[derive(Clone, Parser)]
 #[command(version, about, long_about = None)]
 pub struct Cli {
     #[arg(long, required = false, default_value_t = false)]
     pub dry_run: bool,
+
+    /// Append every event entering the event queue to this JSONL file, which
+    /// can be replayed with the `replay` command
+    #[arg(long)]
+    pub record: Option<PathBuf>,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Clone, Subcommand)]
+pub enum Command {
+    /// Exports scenes, scene overrides, UI state and device states stored in
+    /// the database into a backup archive
+    Export {
+        /// Write the archive to this file instead of stdout
+        #[arg(long, short)]
+        output: Option<PathBuf>,
+
+        #[arg(long, value_enum, default_value = "json")]
+        format: BackupFormat,
+    },
+
+    /// Restores state from a backup archive into the database
+    Import {
+        /// Path to the archive, files ending with .toml are parsed as TOML
+        path: PathBuf,
+
+        #[arg(long, value_enum, default_value = "merge")]
+        mode: RestoreMode,
+    },
+
+    /// Prints scenes stored in the database as TOML blocks that can be pasted
+    /// into Settings.toml
+    ExportScenes {
+        /// Only export these scenes, can be given multiple times
+        #[arg(long = "scene")]
+        scene_ids: Vec<SceneId>,
+
+        /// Merge scene overrides into the exported scenes
+        #[arg(long, default_value_t = false)]
+        overrides: bool,
+
+        /// Write the scenes to this file instead of stdout
+        #[arg(long, short)]
+        output: Option<PathBuf>,
+    },
+
+    /// Moves a scene from the database into Settings.toml, including any scene
+    /// overrides
+    PromoteScene { scene_id: SceneId },
+
+    /// Replays a timeline of device state reports against the configuration
+    /// with an accelerated clock, and reports which routines fired, which
+    /// scenes were activated and the resulting device states
+    #[cfg(feature = "simulation")]
+    Simulate {
+        /// Path to the timeline, files ending with .toml or .yaml are parsed
+        /// as TOML or YAML, anything else as JSON
+        timeline: PathBuf,
+
+        #[arg(long, value_enum, default_value = "text")]
+        format: ReportFormat,
+
+        /// Write the report to this file instead of stdout
+        #[arg(long, short)]
+        output: Option<PathBuf>,
+    },
+
+    /// Feeds a recording made with `--record` back into homectl with
+    /// integrations stubbed out, and reports what happened like `simulate`
+    #[cfg(feature = "simulation")]
+    Replay {
+        /// Path to the JSONL recording
+        recording: PathBuf,
+
+        #[arg(long, value_enum, default_value = "text")]
+        format: ReportFormat,
+
+        /// Write the report to this file instead of stdout
+        #[arg(long, short)]
+        output: Option<PathBuf>,
+    },
 }
@@ -0,0 +1,35 @@
+//! Wall clock time used throughout homectl. Simulations and tests replace it
+//! with a virtual clock that only moves along with paused tokio time, see
+//! [set_virtual].
+
+use std::cell::Cell;
+
+use chrono::{DateTime, Local, Utc};
+
+thread_local! {
+    /// Virtual wall clock time at the given tokio instant
+    static VIRTUAL_CLOCK: Cell<Option<(DateTime<Utc>, tokio::time::Instant)>> =
+        const { Cell::new(None) };
+}
+
+/// Current time in UTC
+pub fn now() -> DateTime<Utc> {
+    if let Some((start, instant)) = VIRTUAL_CLOCK.get() {
+        return start + instant.elapsed();
+    }
+
+    Utc::now()
+}
+
+/// Current time in the local timezone
+pub fn local_now() -> DateTime<Local> {
+    now().with_timezone(&Local)
+}
+
+/// Makes [now] return `start` from this point on, advancing only as tokio time
+/// advances. Combine with paused tokio time for deterministic results. Only
+/// affects the current thread, so this must be used with a current thread
+/// runtime.
+pub fn set_virtual(start: DateTime<Utc>) {
+    VIRTUAL_CLOCK.set(Some((start, tokio::time::Instant::now())));
+}
@@ -1,9 +1,8 @@
-use std::{collections::BTreeMap, hash::Hash};
-
 use color_eyre::Result;
 use serde::{de, Deserialize};
 
 pub mod cli;
+pub mod clock;
 
 pub fn from_hh_mm<'de, D>(d: D) -> Result<chrono::NaiveTime, D::Error>
 where
@@ -12,7 +11,3 @@ where
     let str = String::deserialize(d)?;
     chrono::NaiveTime::parse_from_str(&str, "%H:%M").map_err(serde::de::Error::custom)
 }
-
-pub fn keys_match<T: Eq + Hash + Ord, U, V>(map1: &BTreeMap<T, U>, map2: &BTreeMap<T, V>) -> bool {
-    map1.len() == map2.len() && map1.keys().all(|k| map2.contains_key(k))
-}